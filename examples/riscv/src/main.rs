@@ -140,14 +140,11 @@ fn virtio_gpu<T: Transport>(transport: T) {
 }
 
 fn virtio_input<T: Transport>(transport: T) {
-    //let mut event_buf = [0u64; 32];
-    let mut _input =
+    let mut input =
         VirtIOInput::<HalImpl, T>::new(transport).expect("failed to create input driver");
-    // loop {
-    //     input.ack_interrupt().expect("failed to ack");
-    //     info!("mouse: {:?}", input.mouse_xy());
-    // }
-    // TODO: handle external interrupt
+    while let Some(event) = input.poll_event().expect("failed to poll input event") {
+        info!("input event: {:?}", event);
+    }
 }
 
 fn virtio_net<T: Transport>(transport: T) {
@@ -0,0 +1,462 @@
+//! Driver for VirtIO memory balloon devices.
+
+use super::VirtioDevice;
+use crate::config::{read_config, write_config, ReadOnly, ReadWrite};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::{cmp::Ordering, mem};
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+const QUEUE_INFLATE: u16 = 0;
+const QUEUE_DEFLATE: u16 = 1;
+const QUEUE_SIZE: usize = 4;
+const SUPPORTED_FEATURES: BalloonFeature = BalloonFeature::MUST_TELL_HOST
+    .union(BalloonFeature::RING_INDIRECT_DESC)
+    .union(BalloonFeature::RING_EVENT_IDX)
+    .union(BalloonFeature::NOTIFICATION_DATA);
+
+/// The maximum number of page frame numbers sent to the device in a single request.
+const MAX_PFNS_PER_REQUEST: usize = 256;
+
+/// Driver for a VirtIO memory balloon device, which lets the host reclaim memory from the guest.
+///
+/// The host sets a target number of pages for the balloon, which the driver inflates to by
+/// allocating pages with [`Hal::dma_alloc`] and handing them to the device, or deflates to by
+/// taking pages back from the device and freeing them. The balloon always uses 4 KiB pages, which
+/// must be the same as [`PAGE_SIZE`].
+///
+/// The page frame numbers given to the device are worked out from the DMA addresses which the
+/// [`Hal`] returns for the pages, so this assumes that those are guest physical addresses, i.e.
+/// that there is no IOMMU translating the device's accesses.
+///
+/// As `VIRTIO_BALLOON_F_MUST_TELL_HOST` is negotiated, pages still in the balloon when the driver
+/// is dropped are leaked rather than freed, as they can't be used again until the device has been
+/// told. Call [`set_num_pages`](Self::set_num_pages) with 0 first to get them back.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::balloon::VirtIOBalloon;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut balloon = VirtIOBalloon::<HalImpl, _>::new(transport)?;
+///
+/// // Call this whenever the device raises a configuration change interrupt.
+/// let pages = balloon.handle_config_change()?;
+/// println!("Balloon now holds {} pages", pages);
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOBalloon<H: Hal, T: Transport> {
+    transport: T,
+    inflate_queue: VirtQueue<H, QUEUE_SIZE>,
+    deflate_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The pages which have been given to the device, in the order they were given.
+    pages: Vec<Dma<H>>,
+}
+
+impl<H: Hal, T: Transport> VirtIOBalloon<H, T> {
+    /// Creates a new VirtIO balloon driver.
+    ///
+    /// The balloon starts off empty; call [`handle_config_change`](Self::handle_config_change) to
+    /// inflate it to the size the host wants.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let inflate_queue = VirtQueue::new(
+            &mut transport,
+            QUEUE_INFLATE,
+            negotiated_features.contains(BalloonFeature::RING_INDIRECT_DESC),
+            negotiated_features.contains(BalloonFeature::RING_EVENT_IDX),
+        )?;
+        let deflate_queue = VirtQueue::new(
+            &mut transport,
+            QUEUE_DEFLATE,
+            negotiated_features.contains(BalloonFeature::RING_INDIRECT_DESC),
+            negotiated_features.contains(BalloonFeature::RING_EVENT_IDX),
+        )?;
+        transport.finish_init();
+        Ok(Self {
+            transport,
+            inflate_queue,
+            deflate_queue,
+            pages: Vec::new(),
+        })
+    }
+
+    /// Returns the number of pages which the host currently wants the balloon to hold.
+    pub fn num_pages(&self) -> Result<u32> {
+        read_config!(self.transport, Config, num_pages)
+    }
+
+    /// Returns the number of pages which the balloon currently holds.
+    pub fn actual_pages(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Inflates or deflates the balloon until it holds the given number of pages, and reports the
+    /// new size to the device.
+    ///
+    /// If allocating a page fails part way through inflating, the balloon is left holding the pages
+    /// which were successfully given to the device and the error is returned.
+    pub fn set_num_pages(&mut self, num_pages: u32) -> Result {
+        let result = loop {
+            let step = match self.actual_pages().cmp(&num_pages) {
+                Ordering::Less => self.inflate(num_pages - self.actual_pages()),
+                Ordering::Greater => self.deflate(self.actual_pages() - num_pages),
+                Ordering::Equal => break Ok(()),
+            };
+            if let Err(e) = step {
+                break Err(e);
+            }
+        };
+        let actual = self.actual_pages();
+        write_config!(self.transport, Config, actual, actual)?;
+        result
+    }
+
+    /// Reads the number of pages which the host wants the balloon to hold, and inflates or
+    /// deflates the balloon to match.
+    ///
+    /// This should be called when [`ack_interrupt`](Self::ack_interrupt) reports
+    /// [`InterruptStatus::CONFIG_CHANGE`]. Returns the number of pages the balloon then holds.
+    pub fn handle_config_change(&mut self) -> Result<u32> {
+        let num_pages = self.num_pages()?;
+        if num_pages != self.actual_pages() {
+            info!(
+                "balloon resizing from {} to {} pages",
+                self.actual_pages(),
+                num_pages
+            );
+            self.set_num_pages(num_pages)?;
+        }
+        Ok(self.actual_pages())
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns which interrupts were pending.
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    /// Allocates up to `count` pages and gives them to the device, in a single request.
+    fn inflate(&mut self, count: u32) -> Result {
+        let count = (count as usize).min(MAX_PFNS_PER_REQUEST);
+        let mut pfns = [0u32; MAX_PFNS_PER_REQUEST];
+        let mut pages = Vec::with_capacity(count);
+        for pfn in &mut pfns[..count] {
+            let page = Dma::new(1, BufferDirection::DriverToDevice)?;
+            // This assumes that the DMA address is the guest physical address.
+            *pfn = u32::try_from(page.paddr() / PAGE_SIZE)
+                .map_err(|_| Error::DmaAddressOutOfRange)?
+                .to_le();
+            pages.push(page);
+        }
+        self.inflate_queue.add_notify_wait_pop(
+            &[pfns[..count].as_bytes()],
+            &mut [],
+            &mut self.transport,
+        )?;
+        self.pages.append(&mut pages);
+        Ok(())
+    }
+
+    /// Takes up to `count` pages back from the device in a single request, and frees them.
+    fn deflate(&mut self, count: u32) -> Result {
+        let count = (count as usize).min(MAX_PFNS_PER_REQUEST);
+        let first = self.pages.len() - count;
+        let mut pfns = [0u32; MAX_PFNS_PER_REQUEST];
+        for (pfn, page) in pfns.iter_mut().zip(&self.pages[first..]) {
+            *pfn = ((page.paddr() / PAGE_SIZE) as u32).to_le();
+        }
+        // Always tell the device before freeing the pages, in case it negotiated
+        // `VIRTIO_BALLOON_F_MUST_TELL_HOST`.
+        self.deflate_queue.add_notify_wait_pop(
+            &[pfns[..count].as_bytes()],
+            &mut [],
+            &mut self.transport,
+        )?;
+        self.pages.truncate(first);
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOBalloon<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.transport.queue_unset(QUEUE_INFLATE);
+        self.transport.queue_unset(QUEUE_DEFLATE);
+        // SAFETY: The device has been reset so won't access the queues again, and the buffers in
+        // them are owned by the driver.
+        unsafe {
+            self.inflate_queue.reclaim_all();
+            self.deflate_queue.reclaim_all();
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBalloon<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_INFLATE);
+        self.transport.queue_unset(QUEUE_DEFLATE);
+        // Telling the device that the pages in the balloon are being taken back would mean
+        // blocking, so leak them instead so that they are never used again.
+        for page in self.pages.drain(..) {
+            mem::forget(page);
+        }
+    }
+}
+
+#[derive(FromBytes, Immutable, IntoBytes)]
+#[repr(C)]
+struct Config {
+    num_pages: ReadOnly<u32>,
+    actual: ReadWrite<u32>,
+    free_page_hint_cmd_id: ReadOnly<u32>,
+    poison_val: ReadWrite<u32>,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct BalloonFeature: u64 {
+        /// The host must be told before pages from the balloon are used.
+        const MUST_TELL_HOST        = 1 << 0;
+        /// A virtqueue for reporting guest memory statistics is present.
+        const STATS_VQ              = 1 << 1;
+        /// The guest may deflate the balloon when it runs out of memory.
+        const DEFLATE_ON_OOM        = 1 << 2;
+        /// The device supports free page hinting.
+        const FREE_PAGE_HINT        = 1 << 3;
+        /// The device wants free pages to be poisoned with `poison_val`.
+        const PAGE_POISON           = 1 << 4;
+        /// The device supports free page reporting.
+        const PAGE_REPORTING        = 1 << 5;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, QueueStatus, State},
+        PhysAddr,
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{cell::Cell, ptr::NonNull};
+    use std::{sync::Mutex, thread, thread_local};
+
+    /// The first fake page frame number which `LowHal` gives out.
+    const FIRST_FAKE_PFN: usize = 0x100;
+
+    thread_local! {
+        /// Whether `LowHal` should give out fake physical addresses on this thread.
+        static FAKE_ADDRESSES: Cell<bool> = const { Cell::new(false) };
+        /// The next fake page frame number which `LowHal` will give out on this thread.
+        static NEXT_FAKE_PFN: Cell<usize> = const { Cell::new(FIRST_FAKE_PFN) };
+        /// The number of pages with fake physical addresses which `LowHal` has freed on this
+        /// thread.
+        static FAKE_PAGES_FREED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A HAL which gives out small fake physical addresses for balloon pages once
+    /// `FAKE_ADDRESSES` is set, as real heap addresses don't fit in a 32-bit page frame number.
+    #[derive(Debug)]
+    struct LowHal;
+
+    unsafe impl Hal for LowHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            let (paddr, vaddr) = FakeHal::dma_alloc(pages, direction);
+            if FAKE_ADDRESSES.get() {
+                let pfn = NEXT_FAKE_PFN.get();
+                NEXT_FAKE_PFN.set(pfn + pages);
+                (pfn * PAGE_SIZE, vaddr)
+            } else {
+                (paddr, vaddr)
+            }
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            if (FIRST_FAKE_PFN..NEXT_FAKE_PFN.get()).contains(&(paddr / PAGE_SIZE)) {
+                FAKE_PAGES_FREED.set(FAKE_PAGES_FREED.get() + pages);
+            }
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+    }
+
+    /// Returns a fake transport for a balloon device whose host wants it to hold `num_pages`.
+    fn fake_transport(num_pages: u32) -> (FakeTransport<Config>, Arc<Mutex<State<Config>>>) {
+        let config_space = Config {
+            num_pages: ReadOnly::new(num_pages),
+            actual: ReadWrite::new(0),
+            free_page_hint_cmd_id: ReadOnly::new(0),
+            poison_val: ReadWrite::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::MemoryBalloon,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: BalloonFeature::MUST_TELL_HOST.bits(),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    /// Spawns a thread to simulate the device receiving the given number of requests on the given
+    /// queue, and returns all the page frame numbers it received.
+    fn receive_pfns(
+        state: &Arc<Mutex<State<Config>>>,
+        queue: u16,
+        requests: usize,
+    ) -> thread::JoinHandle<Vec<u32>> {
+        let state = state.clone();
+        thread::spawn(move || {
+            let mut pfns = Vec::new();
+            for _ in 0..requests {
+                State::wait_until_queue_notified(&state, queue);
+                let request = state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(queue);
+                pfns.extend(
+                    request
+                        .chunks(4)
+                        .map(|pfn| u32::from_le_bytes(pfn.try_into().unwrap())),
+                );
+            }
+            pfns
+        })
+    }
+
+    /// Spawns a thread to simulate the device receiving the given page frame numbers on the given
+    /// queue.
+    fn expect_pfns(
+        state: &Arc<Mutex<State<Config>>>,
+        queue: u16,
+        pfns: &'static [u32],
+    ) -> thread::JoinHandle<()> {
+        let state = state.clone();
+        thread::spawn(move || {
+            State::wait_until_queue_notified(&state, queue);
+            let request = state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(queue);
+            assert_eq!(request, pfns.as_bytes());
+        })
+    }
+
+    #[test]
+    fn inflate_deflate() {
+        let (transport, state) = fake_transport(3);
+        let mut balloon = VirtIOBalloon::<LowHal, FakeTransport<Config>>::new(transport).unwrap();
+        FAKE_ADDRESSES.set(true);
+        assert_eq!(balloon.actual_pages(), 0);
+
+        let handle = expect_pfns(&state, QUEUE_INFLATE, &[0x100, 0x101, 0x102]);
+        assert_eq!(balloon.handle_config_change(), Ok(3));
+        handle.join().unwrap();
+        assert_eq!(state.lock().unwrap().config_space.actual.0, 3);
+
+        // Deflating returns the most recently inflated pages.
+        let handle = expect_pfns(&state, QUEUE_DEFLATE, &[0x101, 0x102]);
+        assert_eq!(balloon.set_num_pages(1), Ok(()));
+        handle.join().unwrap();
+        assert_eq!(balloon.actual_pages(), 1);
+        assert_eq!(state.lock().unwrap().config_space.actual.0, 1);
+
+        // Nothing is sent if the target hasn't changed.
+        state.lock().unwrap().config_space.num_pages = ReadOnly::new(1);
+        assert_eq!(balloon.handle_config_change(), Ok(1));
+        assert!(!State::poll_queue_notified(&state, QUEUE_INFLATE));
+        assert!(!State::poll_queue_notified(&state, QUEUE_DEFLATE));
+    }
+
+    #[test]
+    fn inflate_deflate_round_trip() {
+        let (transport, state) = fake_transport(0);
+        let mut balloon = VirtIOBalloon::<LowHal, FakeTransport<Config>>::new(transport).unwrap();
+        FAKE_ADDRESSES.set(true);
+
+        // Inflating by more than fits in one request takes several.
+        let num_pages = MAX_PFNS_PER_REQUEST as u32 + 2;
+        let handle = receive_pfns(&state, QUEUE_INFLATE, 2);
+        assert_eq!(balloon.set_num_pages(num_pages), Ok(()));
+        let mut inflated = handle.join().unwrap();
+        assert_eq!(inflated.len(), num_pages as usize);
+        assert_eq!(balloon.actual_pages(), num_pages);
+        assert_eq!(state.lock().unwrap().config_space.actual.0, num_pages);
+
+        // Deflating to empty should give back exactly the pages which were inflated, and free them.
+        let handle = receive_pfns(&state, QUEUE_DEFLATE, 2);
+        assert_eq!(balloon.set_num_pages(0), Ok(()));
+        let mut deflated = handle.join().unwrap();
+        inflated.sort_unstable();
+        deflated.sort_unstable();
+        assert_eq!(deflated, inflated);
+        assert_eq!(balloon.actual_pages(), 0);
+        assert_eq!(state.lock().unwrap().config_space.actual.0, 0);
+        assert_eq!(FAKE_PAGES_FREED.get(), num_pages as usize);
+    }
+
+    #[test]
+    fn drop_leaks_pages() {
+        let (transport, state) = fake_transport(2);
+        let mut balloon = VirtIOBalloon::<LowHal, FakeTransport<Config>>::new(transport).unwrap();
+        FAKE_ADDRESSES.set(true);
+
+        let handle = receive_pfns(&state, QUEUE_INFLATE, 1);
+        assert_eq!(balloon.handle_config_change(), Ok(2));
+        assert_eq!(handle.join().unwrap().len(), 2);
+
+        // The device hasn't been told that the pages are being taken back, so they mustn't be
+        // freed.
+        drop(balloon);
+        assert_eq!(FAKE_PAGES_FREED.get(), 0);
+        assert_eq!(state.lock().unwrap().queues[0].descriptors, 0);
+    }
+}
@@ -1,20 +1,42 @@
 //! Driver for VirtIO block devices.
 
+mod future;
+
+use super::VirtioDevice;
 use crate::config::{read_config, ReadOnly};
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
+use crate::queue::{Queue, SomeQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
 use bitflags::bitflags;
-use log::info;
+use core::array;
+pub use future::{BlkFuture, BlkWaker};
+use log::{info, warn};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 const QUEUE: u16 = 0;
 const QUEUE_SIZE: u16 = 16;
 const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
+    .union(BlkFeature::SIZE_MAX)
+    .union(BlkFeature::BLK_SIZE)
     .union(BlkFeature::FLUSH)
+    .union(BlkFeature::TOPOLOGY)
+    .union(BlkFeature::MQ)
+    .union(BlkFeature::DISCARD)
+    .union(BlkFeature::WRITE_ZEROES)
     .union(BlkFeature::RING_INDIRECT_DESC)
-    .union(BlkFeature::RING_EVENT_IDX);
+    .union(BlkFeature::RING_EVENT_IDX)
+    .union(BlkFeature::RING_PACKED)
+    .union(BlkFeature::NOTIFICATION_DATA);
+/// The length in bytes of the buffer used by `VirtIOBlk::verify_blocks` to read back data. This is
+/// a multiple of any block size up to 4 KiB, as they are all powers of two.
+const VERIFY_CHUNK_LEN: usize = 8 * SECTOR_SIZE;
+/// The maximum length in bytes of a merged write, if no limit is set with
+/// `VirtIOBlk::set_max_request_len` or by the device's `size_max`.
+#[cfg(feature = "alloc")]
+const DEFAULT_MAX_MERGED_WRITE_LEN: usize = 64 * 1024;
 
 /// Driver for a VirtIO block device.
 ///
@@ -23,6 +45,13 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// Read and write requests (and other exotic requests) are placed in the queue and serviced
 /// (probably out of order) by the device except where noted.
 ///
+/// `NUM_QUEUES` is the number of request queues to set up, which must be no more than the number
+/// the device supports with the `VIRTIO_BLK_F_MQ` feature. Blocking requests always use queue 0,
+/// while non-blocking requests may be submitted on any queue with methods such as
+/// [`read_blocks_nb_on`](Self::read_blocks_nb_on), e.g. so that each CPU can use its own queue.
+/// [`flush`](Self::flush) only sends a flush on queue 0; use
+/// [`flush_all_queues`](Self::flush_all_queues) to flush on every queue.
+///
 /// # Example
 ///
 /// ```
@@ -42,105 +71,485 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// # Ok(())
 /// # }
 /// ```
-pub struct VirtIOBlk<H: Hal, T: Transport> {
+pub struct VirtIOBlk<H: Hal, T: Transport, const NUM_QUEUES: usize = 1> {
     transport: T,
-    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    queues: [SomeQueue<H, { QUEUE_SIZE as usize }>; NUM_QUEUES],
     capacity: u64,
+    /// The logical block size in bytes, which all requests must be aligned to.
+    block_size: usize,
     negotiated_features: BlkFeature,
+    /// The maximum number of bytes to transfer in a single request, if limited.
+    max_request_len: Option<usize>,
+    /// The device's `size_max` rounded down to the block size, if it limits the length of a
+    /// segment. Every request has a single data segment, so `seg_max` never limits it.
+    max_segment_len: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    /// The user tags associated with in-flight non-blocking requests on queue 0, indexed by token.
+    tags: [u64; QUEUE_SIZE as usize],
+    /// Writes which have been buffered to be merged, if write buffering is enabled.
+    #[cfg(feature = "alloc")]
+    write_buffer: Option<WriteBuffer>,
+    /// The optimal I/O alignment and sizes, if the device reports them.
+    topology: Option<BlkTopology>,
+    /// The limits on discard requests, if the device supports them.
+    discard_limits: Option<SegmentLimits>,
+    /// The limits on write zeroes requests, if the device supports them.
+    write_zeroes_limits: Option<SegmentLimits>,
+}
+
+/// A run of contiguous sectors which have been written but not yet submitted to the device.
+#[cfg(feature = "alloc")]
+struct WriteBuffer {
+    /// The first sector of the run.
+    sector: usize,
+    /// The data to write, a multiple of `SECTOR_SIZE` bytes long.
+    data: Vec<u8>,
 }
 
-impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> VirtIOBlk<H, T, NUM_QUEUES> {
     /// Create a new VirtIO-Blk driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if `NUM_QUEUES` is 0 or more than the device supports.
     pub fn new(mut transport: T) -> Result<Self> {
+        if NUM_QUEUES == 0 {
+            return Err(Error::InvalidParam);
+        }
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let device_queues = if negotiated_features.contains(BlkFeature::MQ) {
+            read_config!(transport, BlkConfig, num_queues)?.into()
+        } else {
+            1
+        };
+        if NUM_QUEUES > device_queues {
+            return Err(Error::InvalidParam);
+        }
 
         // Read configuration space.
-        let capacity = transport.read_consistent(|| {
-            Ok(read_config!(transport, BlkConfig, capacity_low)? as u64
-                | (read_config!(transport, BlkConfig, capacity_high)? as u64) << 32)
-        })?;
+        let capacity = read_capacity(&transport)?;
         info!("found a block device of size {}KB", capacity / 2);
+        let block_size = if negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            let blk_size = read_config!(transport, BlkConfig, blk_size)? as usize;
+            if blk_size.is_power_of_two() && blk_size >= SECTOR_SIZE {
+                blk_size
+            } else {
+                warn!("Ignoring invalid block size {}", blk_size);
+                SECTOR_SIZE
+            }
+        } else {
+            SECTOR_SIZE
+        };
+        let max_segment_len = if negotiated_features.contains(BlkFeature::SIZE_MAX) {
+            let size_max = read_config!(transport, BlkConfig, size_max)? as usize;
+            if size_max >= block_size {
+                Some(size_max - size_max % block_size)
+            } else {
+                warn!("Ignoring invalid maximum segment size {}", size_max);
+                None
+            }
+        } else {
+            None
+        };
+        let topology = if negotiated_features.contains(BlkFeature::TOPOLOGY) {
+            Some(transport.read_consistent(|| {
+                Ok(BlkTopology {
+                    physical_block_exp: read_config!(transport, BlkConfig, physical_block_exp)?,
+                    alignment_offset: read_config!(transport, BlkConfig, alignment_offset)?,
+                    min_io_size: read_config!(transport, BlkConfig, min_io_size)?,
+                    opt_io_size: read_config!(transport, BlkConfig, opt_io_size)?,
+                })
+            })?)
+        } else {
+            None
+        };
+        let discard_limits = if negotiated_features.contains(BlkFeature::DISCARD) {
+            Some(transport.read_consistent(|| {
+                Ok(SegmentLimits {
+                    max_sectors: read_config!(transport, BlkConfig, max_discard_sectors)?,
+                    max_segments: read_config!(transport, BlkConfig, max_discard_seg)?,
+                })
+            })?)
+        } else {
+            None
+        };
+        let write_zeroes_limits = if negotiated_features.contains(BlkFeature::WRITE_ZEROES) {
+            Some(transport.read_consistent(|| {
+                Ok(SegmentLimits {
+                    max_sectors: read_config!(transport, BlkConfig, max_write_zeroes_sectors)?,
+                    max_segments: read_config!(transport, BlkConfig, max_write_zeroes_seg)?,
+                })
+            })?)
+        } else {
+            None
+        };
 
-        let queue = VirtQueue::new(
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let queues = array::from_fn(|i| {
+            SomeQueue::new_clamped(
+                &mut transport,
+                i as u16,
+                negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
+                negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
+            )
+        });
+        if let Some(error) = queues.iter().find_map(|queue| queue.as_ref().err()) {
+            return Err(*error);
+        }
+        let queues = queues.map(Result::unwrap);
         transport.finish_init();
 
         Ok(VirtIOBlk {
             transport,
-            queue,
+            queues,
             capacity,
+            block_size,
             negotiated_features,
+            max_request_len: max_segment_len,
+            max_segment_len,
+            retry_policy: None,
+            tags: [0; QUEUE_SIZE as usize],
+            #[cfg(feature = "alloc")]
+            write_buffer: None,
+            topology,
+            discard_limits,
+            write_zeroes_limits,
         })
     }
 
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    ///
+    /// This is the capacity read when the device was initialised or by the last call to
+    /// [`handle_config_change`](Self::handle_config_change).
     pub fn capacity(&self) -> u64 {
         self.capacity
     }
 
+    /// Re-reads the capacity of the device after a configuration change, such as the host resizing
+    /// the disk, and returns the new capacity in 512 byte ([`SECTOR_SIZE`]) sectors.
+    ///
+    /// This should be called when [`ack_interrupt`](Self::ack_interrupt) reports
+    /// [`InterruptStatus::CONFIG_CHANGE`]. If the capacity can't be read consistently then the
+    /// cached capacity is left unchanged and an error is returned.
+    pub fn handle_config_change(&mut self) -> Result<u64> {
+        let capacity = read_capacity(&self.transport)?;
+        if capacity != self.capacity {
+            info!(
+                "block device resized from {}KB to {}KB",
+                self.capacity / 2,
+                capacity / 2
+            );
+            self.capacity = capacity;
+        }
+        Ok(capacity)
+    }
+
+    /// Returns the logical block size of the device in bytes.
+    ///
+    /// This is [`SECTOR_SIZE`] unless the device reports a larger block size, e.g. 4096 for a 4K
+    /// native disk. Block IDs passed to [`read_blocks`](Self::read_blocks),
+    /// [`write_blocks`](Self::write_blocks) and the other methods are always in units of 512 byte
+    /// sectors regardless of the block size, but they must be aligned to it, and buffer lengths
+    /// must be a multiple of it. So on a 4K device, block ID 8 refers to the second 4K block.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the device's optimal I/O alignment and sizes, or `None` if it doesn't support the
+    /// `VIRTIO_BLK_F_TOPOLOGY` feature.
+    pub fn topology(&self) -> Option<BlkTopology> {
+        self.topology
+    }
+
     /// Returns true if the block device is read-only, or false if it allows writes.
     pub fn readonly(&self) -> bool {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
+    /// Limits the number of bytes of data which [`read_blocks`](Self::read_blocks) and
+    /// [`write_blocks`](Self::write_blocks) will transfer in a single request to the device.
+    ///
+    /// Larger transfers are transparently split into several requests, each covering a whole
+    /// number of blocks. This bounds the size of any single buffer shared with the device, which
+    /// is useful when the HAL has to copy shared buffers to a separate bounce buffer.
+    ///
+    /// `max_len` is rounded down to a multiple of the [block size](Self::block_size). Returns
+    /// [`Error::InvalidParam`] if it is less than the block size. Pass `None` to remove the limit.
+    ///
+    /// If the device limits the size of a segment with `VIRTIO_BLK_F_SIZE_MAX` then the limit is
+    /// never set higher than that, and removing it falls back to the device's limit.
+    pub fn set_max_request_len(&mut self, max_len: Option<usize>) -> Result {
+        if let Some(max_len) = max_len {
+            if max_len < self.block_size {
+                return Err(Error::InvalidParam);
+            }
+            let max_len = max_len - max_len % self.block_size;
+            self.max_request_len = Some(match self.max_segment_len {
+                Some(max_segment_len) => max_len.min(max_segment_len),
+                None => max_len,
+            });
+        } else {
+            self.max_request_len = self.max_segment_len;
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of bytes of data which will be transferred in a single request,
+    /// if limited by [`set_max_request_len`](Self::set_max_request_len) or by the device.
+    pub fn max_request_len(&self) -> Option<usize> {
+        self.max_request_len
+    }
+
+    /// Enables or disables buffering of writes.
+    ///
+    /// While buffering is enabled, [`write_blocks`](Self::write_blocks) calls to contiguous
+    /// sectors are merged in memory rather than being sent to the device immediately. They are
+    /// submitted as a single request when [`flush_pending`](Self::flush_pending) is called, when
+    /// the merged write would exceed the [maximum request length](Self::max_request_len) (or
+    /// 64 KiB if there is none), when a write to a non-contiguous sector is made, or before any
+    /// other request is sent. Requests are therefore never reordered.
+    ///
+    /// Disabling buffering submits any pending writes first. Any writes still buffered when the
+    /// driver is dropped are discarded with a warning, as dropping must not block waiting for the
+    /// device, so call [`flush_pending`](Self::flush_pending) before dropping the driver.
+    #[cfg(feature = "alloc")]
+    pub fn set_write_buffering(&mut self, enabled: bool) -> Result {
+        if enabled {
+            if self.write_buffer.is_none() {
+                self.write_buffer = Some(WriteBuffer {
+                    sector: 0,
+                    data: Vec::new(),
+                });
+            }
+        } else {
+            self.submit_pending_write()?;
+            self.write_buffer = None;
+        }
+        Ok(())
+    }
+
+    /// Submits any writes which have been buffered, and waits for them to complete.
+    ///
+    /// If the write fails then the buffered data is discarded.
+    #[cfg(feature = "alloc")]
+    pub fn flush_pending(&mut self) -> Result {
+        self.submit_pending_write()
+    }
+
+    /// Submits any buffered writes to the device, and waits for them to complete.
+    fn submit_pending_write(&mut self) -> Result {
+        #[cfg(feature = "alloc")]
+        if let Some(write_buffer) = &mut self.write_buffer {
+            if !write_buffer.data.is_empty() {
+                let sector = write_buffer.sector;
+                let mut data = core::mem::take(&mut write_buffer.data);
+                let result = self.write_blocks_unbuffered(sector, &data);
+                // Keep the allocation to reuse for the next buffered write.
+                data.clear();
+                if let Some(write_buffer) = &mut self.write_buffer {
+                    write_buffer.data = data;
+                }
+                return result;
+            }
+        }
+        Ok(())
+    }
+
     /// Acknowledges a pending interrupt, if any.
     ///
-    /// Returns true if there was an interrupt to acknowledge.
-    pub fn ack_interrupt(&mut self) -> bool {
+    /// Returns which interrupts were pending.
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
     }
 
     /// Enables interrupts from the device.
     pub fn enable_interrupts(&mut self) {
-        self.queue.set_dev_notify(true);
+        for queue in &mut self.queues {
+            queue.set_dev_notify(true);
+        }
     }
 
     /// Disables interrupts from the device.
     pub fn disable_interrupts(&mut self) {
-        self.queue.set_dev_notify(false);
+        for queue in &mut self.queues {
+            queue.set_dev_notify(false);
+        }
+    }
+
+    /// Sets the policy for retrying requests which fail with a transient error, or `None` to return
+    /// all errors immediately.
+    ///
+    /// This only applies to blocking requests, such as [`read_blocks`](Self::read_blocks) and
+    /// [`write_blocks`](Self::write_blocks). Non-blocking requests are never retried.
+    pub fn set_retry_policy(&mut self, retry_policy: Option<RetryPolicy>) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Returns whether a request which completed with the given status should be retried according
+    /// to the retry policy, and if so calls its backoff hook.
+    fn should_retry(&self, status: RespStatus, attempts: &mut u32) -> bool {
+        match self.retry_policy {
+            Some(policy) if status == RespStatus::IO_ERR && *attempts < policy.max_retries => {
+                *attempts += 1;
+                (policy.backoff)(*attempts);
+                true
+            }
+            _ => false,
+        }
     }
 
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        self.request_on(0, request)
+    }
+
+    /// Sends the given request on the given queue and waits for a response, with no extra data.
+    fn request_on(&mut self, queue: usize, request: BlkReq) -> Result {
+        let mut attempts = 0;
+        loop {
+            let mut resp = BlkResp::default();
+            self.queues[queue].add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut [resp.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+            if !self.should_retry(resp.status, &mut attempts) {
+                return resp.status.into();
+            }
+        }
     }
 
     /// Sends the given request to the device and waits for a response, including the given data.
     fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [data, resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let mut attempts = 0;
+        loop {
+            let mut resp = BlkResp::default();
+            self.queues[0].add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut [data, resp.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+            if !self.should_retry(resp.status, &mut attempts) {
+                return resp.status.into();
+            }
+        }
     }
 
     /// Sends the given request and data to the device and waits for a response.
     fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes(), data],
-            &mut [resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let mut attempts = 0;
+        loop {
+            let mut resp = BlkResp::default();
+            self.queues[0].add_notify_wait_pop(
+                &[request.as_bytes(), data],
+                &mut [resp.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+            if !self.should_retry(resp.status, &mut attempts) {
+                return resp.status.into();
+            }
+        }
     }
 
-    /// Requests the device to flush any pending writes to storage.
+    /// Submits any buffered writes, then requests the device to flush any pending writes to
+    /// storage and waits for it to complete.
     ///
-    /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
+    /// Returns [`Error::Unsupported`] without doing anything if the device doesn't support the
+    /// `VIRTIO_BLK_F_FLUSH` feature.
     pub fn flush(&mut self) -> Result {
+        if self.negotiated_features.contains(BlkFeature::FLUSH) {
+            self.flush_if_supported()
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Submits any buffered writes, then sends a flush request on every request queue in turn,
+    /// waiting for each to complete.
+    ///
+    /// Use this rather than [`flush`](Self::flush) as a barrier when writes may have been
+    /// completed on queues other than queue 0, e.g. with
+    /// [`write_blocks_nb_on`](Self::write_blocks_nb_on).
+    ///
+    /// Returns [`Error::Unsupported`] without doing anything if the device doesn't support the
+    /// `VIRTIO_BLK_F_FLUSH` feature.
+    pub fn flush_all_queues(&mut self) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        self.submit_pending_write()?;
+        for queue in 0..NUM_QUEUES {
+            self.request_on(
+                queue,
+                BlkReq {
+                    type_: ReqType::Flush,
+                    ..Default::default()
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to flush pending writes to storage, but returns immediately without
+    /// waiting for it to complete.
+    ///
+    /// Any buffered writes are submitted first. Returns [`Error::Unsupported`] if the device
+    /// doesn't support the `VIRTIO_BLK_F_FLUSH` feature. Otherwise returns a token, which can be
+    /// used with [`peek_used`](Self::peek_used) and [`complete_flush`](Self::complete_flush) as
+    /// for [`read_blocks_nb`](Self::read_blocks_nb).
+    ///
+    /// # Safety
+    ///
+    /// `req` and `resp` are still borrowed by the underlying VirtIO block device even after this
+    /// method returns, so must not be accessed until the request is completed.
+    pub unsafe fn flush_nb(&mut self, req: &mut BlkReq, resp: &mut BlkResp) -> Result<u16> {
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        self.submit_pending_write()?;
+        *req = BlkReq {
+            type_: ReqType::Flush,
+            ..Default::default()
+        };
+        self.add_on(0, &[req.as_bytes()], &mut [resp.as_mut_bytes()])
+    }
+
+    /// Completes a flush which was started by [`flush_nb`](Self::flush_nb).
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `flush_nb` when it returned the
+    /// token.
+    pub unsafe fn complete_flush(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        resp: &mut BlkResp,
+    ) -> Result {
+        self.queues[0].pop_used(token, &[req.as_bytes()], &mut [resp.as_mut_bytes()])?;
+        resp.status.into()
+    }
+
+    /// Adds the given buffers to the given queue and notifies the device if necessary, returning
+    /// the token.
+    ///
+    /// # Safety
+    ///
+    /// As for `VirtQueue::add`.
+    unsafe fn add_on<'a, 'b>(
+        &mut self,
+        queue: usize,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        let token = self.queues[queue].add(inputs, outputs)?;
+        if self.queues[queue].should_notify() {
+            self.queues[queue].notify(&mut self.transport);
+        }
+        Ok(token)
+    }
+
+    /// Submits any buffered writes, then flushes them to storage if the device supports the
+    /// `VIRTIO_BLK_F_FLUSH` feature.
+    fn flush_if_supported(&mut self) -> Result {
+        self.submit_pending_write()?;
         if self.negotiated_features.contains(BlkFeature::FLUSH) {
             self.request(BlkReq {
                 type_: ReqType::Flush,
@@ -151,6 +560,106 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         }
     }
 
+    /// Returns the maximum number of sectors which may be discarded by a single range passed to
+    /// [`discard`](Self::discard), or `None` if the device doesn't support discard.
+    pub fn max_discard_sectors(&self) -> Option<u32> {
+        self.discard_limits.map(|limits| limits.max_sectors)
+    }
+
+    /// Returns the maximum number of ranges which may be passed to a single call to
+    /// [`discard`](Self::discard), or `None` if the device doesn't support discard.
+    pub fn max_discard_segments(&self) -> Option<u32> {
+        self.discard_limits.map(|limits| limits.max_segments)
+    }
+
+    /// Returns the maximum number of sectors which may be zeroed by a single range passed to
+    /// [`write_zeroes`](Self::write_zeroes), or `None` if the device doesn't support write zeroes.
+    pub fn max_write_zeroes_sectors(&self) -> Option<u32> {
+        self.write_zeroes_limits.map(|limits| limits.max_sectors)
+    }
+
+    /// Returns the maximum number of ranges which may be passed to a single call to
+    /// [`write_zeroes`](Self::write_zeroes), or `None` if the device doesn't support write zeroes.
+    pub fn max_write_zeroes_segments(&self) -> Option<u32> {
+        self.write_zeroes_limits.map(|limits| limits.max_segments)
+    }
+
+    /// Tells the device that the data in the given ranges of sectors is no longer needed, e.g. so
+    /// that it can deallocate the underlying storage.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_DISCARD`
+    /// feature, or [`Error::InvalidParam`] if no ranges are given, there are more than
+    /// [`max_discard_segments`](Self::max_discard_segments), or any is longer than
+    /// [`max_discard_sectors`](Self::max_discard_sectors).
+    pub fn discard(&mut self, ranges: &[BlkDiscardWriteZeroes]) -> Result {
+        self.request_ranges(ReqType::Discard, self.discard_limits, ranges)
+    }
+
+    /// Writes zeroes to the given ranges of sectors.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the
+    /// `VIRTIO_BLK_F_WRITE_ZEROES` feature, or [`Error::InvalidParam`] if no ranges are given,
+    /// there are more than [`max_write_zeroes_segments`](Self::max_write_zeroes_segments), or any
+    /// is longer than [`max_write_zeroes_sectors`](Self::max_write_zeroes_sectors).
+    pub fn write_zeroes(&mut self, ranges: &[BlkDiscardWriteZeroes]) -> Result {
+        self.request_ranges(ReqType::WriteZeroes, self.write_zeroes_limits, ranges)
+    }
+
+    /// Sends a discard or write zeroes request for the given ranges, after checking them against
+    /// the device's limits.
+    fn request_ranges(
+        &mut self,
+        type_: ReqType,
+        limits: Option<SegmentLimits>,
+        ranges: &[BlkDiscardWriteZeroes],
+    ) -> Result {
+        let limits = limits.ok_or(Error::Unsupported)?;
+        self.check_writable()?;
+        if ranges.is_empty()
+            || ranges.len() > limits.max_segments as usize
+            || ranges
+                .iter()
+                .any(|range| range.num_sectors > limits.max_sectors)
+        {
+            return Err(Error::InvalidParam);
+        }
+        self.submit_pending_write()?;
+        self.request_write(
+            BlkReq {
+                type_,
+                ..Default::default()
+            },
+            ranges.as_bytes(),
+        )
+    }
+
+    /// Writes the given pattern to a block or blocks, flushes it, then reads it back and returns
+    /// whether the data read matches the pattern.
+    ///
+    /// This is intended for testing storage integrity. As for [`write_blocks`](Self::write_blocks),
+    /// `block_id` and the pattern length must be aligned to the [block size](Self::block_size).
+    /// The data is read back 4 KiB at a time into a buffer on the stack, so no allocation is
+    /// needed.
+    ///
+    /// Returns [`Error::InvalidParam`] without writing anything if the block size is larger than
+    /// 4 KiB, as a block wouldn't fit in the buffer.
+    pub fn verify_blocks(&mut self, block_id: usize, pattern: &[u8]) -> Result<bool> {
+        if self.block_size > VERIFY_CHUNK_LEN {
+            return Err(Error::InvalidParam);
+        }
+        self.write_blocks(block_id, pattern)?;
+        self.flush_if_supported()?;
+        let mut buf = [0; VERIFY_CHUNK_LEN];
+        for (i, expected) in pattern.chunks(VERIFY_CHUNK_LEN).enumerate() {
+            let buf = &mut buf[..expected.len()];
+            self.read_blocks(block_id + i * VERIFY_CHUNK_LEN / SECTOR_SIZE, buf)?;
+            if buf != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
     /// Gets the device ID.
     ///
     /// The ID is written as ASCII into the given buffer, which must be 20 bytes long, and the used
@@ -168,22 +677,46 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         Ok(length)
     }
 
+    /// Gets the device ID, like [`device_id`](Self::device_id), but copies it into a buffer of any
+    /// length.
+    ///
+    /// If the buffer is shorter than the ID then the ID is truncated to fit. Returns the number of
+    /// bytes written.
+    pub fn get_id(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut id = [0; 20];
+        let length = self.device_id(&mut id)?.min(out.len());
+        out[..length].copy_from_slice(&id[..length]);
+        Ok(length)
+    }
+
     /// Reads one or more blocks into the given buffer.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// `block_id` is in units of [`SECTOR_SIZE`]. The buffer length must be a non-zero multiple of
+    /// [`SECTOR_SIZE`]. If it is longer than the limit set by
+    /// [`set_max_request_len`](Self::set_max_request_len) then it will be read with several
+    /// requests.
+    ///
+    /// Returns [`Error::InvalidParam`] if `block_id` or the buffer length isn't aligned to the
+    /// [block size](Self::block_size).
     ///
     /// Blocks until the read completes or there is an error.
     pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_read(
-            BlkReq {
-                type_: ReqType::In,
-                reserved: 0,
-                sector: block_id as u64,
-            },
-            buf,
-        )
+        self.check_block_alignment(block_id, buf.len())?;
+        self.submit_pending_write()?;
+        let chunk_len = self.max_request_len.unwrap_or(buf.len());
+        for (i, chunk) in buf.chunks_mut(chunk_len).enumerate() {
+            self.request_read(
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector: (block_id + i * chunk_len / SECTOR_SIZE) as u64,
+                },
+                chunk,
+            )?;
+        }
+        Ok(())
     }
 
     /// Submits a request to read one or more blocks, but returns immediately without waiting for
@@ -191,12 +724,14 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///
     /// # Arguments
     ///
-    /// * `block_id` - The identifier of the first block to read.
+    /// * `block_id` - The identifier of the first block to read, in units of [`SECTOR_SIZE`]. It
+    ///   must be aligned to the [block size](Self::block_size).
     /// * `req` - A buffer which the driver can use for the request to send to the device. The
     ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
     ///   it needs to be valid (and not otherwise used) until the corresponding
-    ///   `complete_read_blocks` call. Its length must be a non-zero multiple of [`SECTOR_SIZE`].
-    /// * `buf` - The buffer in memory into which the block should be read.
+    ///   `complete_read_blocks` call.
+    /// * `buf` - The buffer in memory into which the block should be read. Its length must be a
+    ///   non-zero multiple of the [block size](Self::block_size).
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
@@ -249,21 +784,41 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         req: &mut BlkReq,
         buf: &mut [u8],
         resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.read_blocks_nb_on(0, block_id, req, buf, resp)
+    }
+
+    /// Submits a request to read one or more blocks on the given queue, like
+    /// [`read_blocks_nb`](Self::read_blocks_nb).
+    ///
+    /// The request must be completed with [`complete_read_blocks_on`](Self::complete_read_blocks_on)
+    /// for the same queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than `NUM_QUEUES`.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn read_blocks_nb_on(
+        &mut self,
+        queue: usize,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_block_alignment(block_id, buf.len())?;
+        self.submit_pending_write()?;
         *req = BlkReq {
             type_: ReqType::In,
             reserved: 0,
             sector: block_id as u64,
         };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        self.add_on(queue, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])
     }
 
     /// Completes a read operation which was started by `read_blocks_nb`.
@@ -279,27 +834,110 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        self.complete_read_blocks_on(0, token, req, buf, resp)
+    }
+
+    /// Completes a read operation which was started on the given queue by
+    /// [`read_blocks_nb_on`](Self::read_blocks_nb_on).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than `NUM_QUEUES`.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb_on` when it
+    /// returned the token.
+    pub unsafe fn complete_read_blocks_on(
+        &mut self,
+        queue: usize,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queues[queue].pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
         resp.status.into()
     }
 
     /// Writes the contents of the given buffer to a block or blocks.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// `block_id` is in units of [`SECTOR_SIZE`]. The buffer length must be a non-zero multiple of
+    /// [`SECTOR_SIZE`]. If it is longer than the limit set by
+    /// [`set_max_request_len`](Self::set_max_request_len) then it will be written with several
+    /// requests.
+    ///
+    /// Returns [`Error::InvalidParam`] if `block_id` or the buffer length isn't aligned to the
+    /// [block size](Self::block_size), or [`Error::ReadOnly`] if the device is
+    /// [read-only](Self::readonly).
     ///
-    /// Blocks until the write is complete or there is an error.
+    /// Blocks until the write is complete or there is an error, unless write buffering has been
+    /// enabled with [`set_write_buffering`](Self::set_write_buffering).
     pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_write(
-            BlkReq {
-                type_: ReqType::Out,
-                sector: block_id as u64,
-                ..Default::default()
-            },
-            buf,
-        )
+        self.check_block_alignment(block_id, buf.len())?;
+        self.check_writable()?;
+
+        #[cfg(feature = "alloc")]
+        if self.write_buffer.is_some() {
+            let max_len = self.max_request_len.unwrap_or(DEFAULT_MAX_MERGED_WRITE_LEN);
+            if let Some(write_buffer) = &self.write_buffer {
+                let end = write_buffer.sector + write_buffer.data.len() / SECTOR_SIZE;
+                if !write_buffer.data.is_empty()
+                    && (block_id != end || write_buffer.data.len() + buf.len() > max_len)
+                {
+                    self.submit_pending_write()?;
+                }
+            }
+            if buf.len() <= max_len {
+                if let Some(write_buffer) = &mut self.write_buffer {
+                    if write_buffer.data.is_empty() {
+                        write_buffer.sector = block_id;
+                    }
+                    write_buffer.data.extend_from_slice(buf);
+                }
+                return Ok(());
+            }
+        }
+
+        self.write_blocks_unbuffered(block_id, buf)
+    }
+
+    /// Returns [`Error::InvalidParam`] if a request starting at the given sector with the given
+    /// length in bytes wouldn't be aligned to the block size.
+    fn check_block_alignment(&self, block_id: usize, len: usize) -> Result {
+        // The block size is always a power of two.
+        let mask = self.block_size - 1;
+        if (block_id * SECTOR_SIZE) & mask != 0 || len & mask != 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Returns [`Error::ReadOnly`] if the device is read-only.
+    fn check_writable(&self) -> Result {
+        if self.readonly() {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks, bypassing the write buffer.
+    fn write_blocks_unbuffered(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        let chunk_len = self.max_request_len.unwrap_or(buf.len());
+        for (i, chunk) in buf.chunks(chunk_len).enumerate() {
+            self.request_write(
+                BlkReq {
+                    type_: ReqType::Out,
+                    sector: (block_id + i * chunk_len / SECTOR_SIZE) as u64,
+                    ..Default::default()
+                },
+                chunk,
+            )?;
+        }
+        Ok(())
     }
 
     /// Submits a request to write one or more blocks, but returns immediately without waiting for
@@ -307,17 +945,21 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///
     /// # Arguments
     ///
-    /// * `block_id` - The identifier of the first block to write.
+    /// * `block_id` - The identifier of the first block to write, in units of [`SECTOR_SIZE`]. It
+    ///   must be aligned to the [block size](Self::block_size).
     /// * `req` - A buffer which the driver can use for the request to send to the device. The
     ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
     ///   it needs to be valid (and not otherwise used) until the corresponding
     ///   `complete_write_blocks` call.
     /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
-    ///   be a non-zero multiple of [`SECTOR_SIZE`].
+    ///   be a non-zero multiple of the [block size](Self::block_size).
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
     ///
+    /// Returns [`Error::ReadOnly`] without submitting anything if the device is
+    /// [read-only](Self::readonly).
+    ///
     /// # Usage
     ///
     /// See [VirtIOBlk::read_blocks_nb].
@@ -331,21 +973,42 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         req: &mut BlkReq,
         buf: &[u8],
         resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.write_blocks_nb_on(0, block_id, req, buf, resp)
+    }
+
+    /// Submits a request to write one or more blocks on the given queue, like
+    /// [`write_blocks_nb`](Self::write_blocks_nb).
+    ///
+    /// The request must be completed with
+    /// [`complete_write_blocks_on`](Self::complete_write_blocks_on) for the same queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than `NUM_QUEUES`.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb_on(
+        &mut self,
+        queue: usize,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_block_alignment(block_id, buf.len())?;
+        self.check_writable()?;
+        self.submit_pending_write()?;
         *req = BlkReq {
             type_: ReqType::Out,
             reserved: 0,
             sector: block_id as u64,
         };
-        let token = self
-            .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        self.add_on(queue, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])
     }
 
     /// Completes a write operation which was started by `write_blocks_nb`.
@@ -361,30 +1024,181 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        self.complete_write_blocks_on(0, token, req, buf, resp)
+    }
+
+    /// Completes a write operation which was started on the given queue by
+    /// [`write_blocks_nb_on`](Self::write_blocks_nb_on).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than `NUM_QUEUES`.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb_on` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks_on(
+        &mut self,
+        queue: usize,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queues[queue].pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
         resp.status.into()
     }
 
+    /// Submits a request to read one or more blocks like [`read_blocks_nb`](Self::read_blocks_nb),
+    /// associating the given tag with it so that it can be retrieved on completion with
+    /// [`peek_used_tagged`](Self::peek_used_tagged).
+    ///
+    /// Tagged requests are only supported on queue 0.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn read_blocks_nb_tagged(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+        tag: u64,
+    ) -> Result<u16> {
+        let token = self.read_blocks_nb(block_id, req, buf, resp)?;
+        self.tags[usize::from(token)] = tag;
+        Ok(token)
+    }
+
+    /// Submits a request to write one or more blocks like
+    /// [`write_blocks_nb`](Self::write_blocks_nb), associating the given tag with it so that it
+    /// can be retrieved on completion with [`peek_used_tagged`](Self::peek_used_tagged).
+    ///
+    /// Tagged requests are only supported on queue 0.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb_tagged(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+        tag: u64,
+    ) -> Result<u16> {
+        let token = self.write_blocks_nb(block_id, req, buf, resp)?;
+        self.tags[usize::from(token)] = tag;
+        Ok(token)
+    }
+
     /// Fetches the token of the next completed request from the used ring and returns it, without
     /// removing it from the used ring. If there are no pending completed requests returns `None`.
     pub fn peek_used(&mut self) -> Option<u16> {
-        self.queue.peek_used()
+        self.queues[0].peek_used()
+    }
+
+    /// Like [`peek_used`](Self::peek_used), but for requests submitted on the given queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than `NUM_QUEUES`.
+    pub fn peek_used_on(&mut self, queue: usize) -> Option<u16> {
+        self.queues[queue].peek_used()
+    }
+
+    /// Like [`peek_used`](Self::peek_used), but also returns the tag which was passed when the
+    /// request was submitted with [`read_blocks_nb_tagged`](Self::read_blocks_nb_tagged) or
+    /// [`write_blocks_nb_tagged`](Self::write_blocks_nb_tagged).
+    ///
+    /// Like `peek_used`, this only checks queue 0. The tag of a request submitted without one is
+    /// unspecified.
+    pub fn peek_used_tagged(&mut self) -> Option<(u16, u64)> {
+        let token = self.queues[0].peek_used()?;
+        Some((token, self.tags[usize::from(token)]))
+    }
+
+    /// Returns an iterator over the tokens of all requests which the device has completed, in the
+    /// order in which they must be completed.
+    ///
+    /// This doesn't complete the requests, so each must still be passed to
+    /// [`complete_read_blocks`](Self::complete_read_blocks) or
+    /// [`complete_write_blocks`](Self::complete_write_blocks) with its buffers. For example, the
+    /// tokens can be collected and then completed in a loop.
+    pub fn completed(&self) -> impl Iterator<Item = u16> + '_ {
+        self.queues[0].completed().map(|(token, _)| token)
+    }
+
+    /// Like [`completed`](Self::completed), but also returns the tag which was passed when each
+    /// request was submitted with [`read_blocks_nb_tagged`](Self::read_blocks_nb_tagged) or
+    /// [`write_blocks_nb_tagged`](Self::write_blocks_nb_tagged).
+    ///
+    /// Like `completed`, this only covers queue 0.
+    pub fn completed_tagged(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.completed()
+            .map(|token| (token, self.tags[usize::from(token)]))
     }
 
     /// Returns the size of the device's VirtQueue.
     ///
+    /// This is at most 16, but may be smaller if the device doesn't support queues that large.
     /// This can be used to tell the caller how many channels to monitor on.
     pub fn virt_queue_size(&self) -> u16 {
-        QUEUE_SIZE
+        self.queues[0].size()
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> VirtioDevice for VirtIOBlk<H, T, NUM_QUEUES> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            self.transport.queue_unset(index as u16);
+            // SAFETY: The device has been reset so won't access the queue again, and the buffers of
+            // any requests still in it must remain valid until they are popped.
+            unsafe { queue.reclaim_all() };
+        }
+    }
+
+    fn shutdown(&mut self) -> Result {
+        let result = self.flush_if_supported();
+        self.reset();
+        result
+    }
+}
+
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> Drop for VirtIOBlk<H, T, NUM_QUEUES> {
     fn drop(&mut self) {
+        // Submitting buffered writes would mean waiting for the device, which might never
+        // respond, so they are lost if `flush_pending` wasn't called.
+        #[cfg(feature = "alloc")]
+        if let Some(write_buffer) = &self.write_buffer {
+            if !write_buffer.data.is_empty() {
+                warn!(
+                    "Discarding {} bytes of buffered writes to sector {} on drop",
+                    write_buffer.data.len(),
+                    write_buffer.sector
+                );
+            }
+        }
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE);
+        for queue in 0..NUM_QUEUES {
+            self.transport.queue_unset(queue as u16);
+        }
     }
 }
 
@@ -404,9 +1218,28 @@ struct BlkConfig {
     alignment_offset: ReadOnly<u8>,
     min_io_size: ReadOnly<u16>,
     opt_io_size: ReadOnly<u32>,
+    writeback: ReadOnly<u8>,
+    _unused0: ReadOnly<u8>,
+    num_queues: ReadOnly<u16>,
+    max_discard_sectors: ReadOnly<u32>,
+    max_discard_seg: ReadOnly<u32>,
+    discard_sector_alignment: ReadOnly<u32>,
+    max_write_zeroes_sectors: ReadOnly<u32>,
+    max_write_zeroes_seg: ReadOnly<u32>,
+    write_zeroes_may_unmap: ReadOnly<u8>,
+    _unused1: [ReadOnly<u8>; 3],
     // ... ignored
 }
 
+/// The limits on a discard or write zeroes request.
+#[derive(Copy, Clone, Debug)]
+struct SegmentLimits {
+    /// The maximum number of sectors in a single segment.
+    max_sectors: u32,
+    /// The maximum number of segments in a single request.
+    max_segments: u32,
+}
+
 /// A VirtIO block device request.
 #[repr(C)]
 #[derive(Debug, Immutable, IntoBytes, KnownLayout)]
@@ -453,6 +1286,55 @@ enum ReqType {
     SecureErase = 14,
 }
 
+/// The optimal I/O alignment and sizes reported by a block device, as returned by
+/// [`VirtIOBlk::topology`].
+///
+/// All sizes are in units of logical blocks, as given by [`VirtIOBlk::block_size`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlkTopology {
+    /// The base 2 logarithm of the number of logical blocks per physical block.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size, in logical blocks.
+    pub min_io_size: u16,
+    /// The optimal (and maximum suggested) I/O size, in logical blocks.
+    pub opt_io_size: u32,
+}
+
+/// A range of sectors to discard or write zeroes to, as passed to [`VirtIOBlk::discard`] and
+/// [`VirtIOBlk::write_zeroes`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BlkDiscardWriteZeroes {
+    /// The first sector of the range, in units of [`SECTOR_SIZE`].
+    pub sector: u64,
+    /// The number of sectors in the range.
+    pub num_sectors: u32,
+    /// Flags for the range. Only [`FLAG_UNMAP`](Self::FLAG_UNMAP) is defined, and only for write
+    /// zeroes requests.
+    pub flags: u32,
+}
+
+impl BlkDiscardWriteZeroes {
+    /// For write zeroes requests, allows the device to deallocate the range rather than writing
+    /// zeroes to it, so long as it reads back as zeroes afterwards.
+    pub const FLAG_UNMAP: u32 = 1;
+}
+
+/// A policy for retrying block requests which fail with a transient error.
+///
+/// Only requests which the device completes with `VIRTIO_BLK_S_IOERR` are retried; other errors
+/// such as `VIRTIO_BLK_S_UNSUPP` are permanent and are returned immediately.
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of times to retry a failed request before returning the error.
+    pub max_retries: u32,
+    /// Called before each retry with the number of the retry, starting from 1. This may be used to
+    /// sleep or spin for some time before the request is resubmitted.
+    pub backoff: fn(u32),
+}
+
 /// Status of a VirtIOBlk request.
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
@@ -547,21 +1429,99 @@ bitflags! {
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
     }
-}
+}
+
+/// Reads the capacity of the device in sectors from its config space, making sure that both halves
+/// come from the same config generation.
+fn read_capacity(transport: &impl Transport) -> Result<u64> {
+    transport.read_consistent(|| {
+        Ok(read_config!(*transport, BlkConfig, capacity_low)? as u64
+            | (read_config!(*transport, BlkConfig, capacity_high)? as u64) << 32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{
+        future::Future,
+        mem::size_of,
+        pin::pin,
+        slice,
+        task::{Context, Poll, Waker},
+    };
+    use std::{sync::Mutex, thread, time::Duration};
+
+    /// Returns a fake transport for a block device with 66 sectors and the given features, and its
+    /// state.
+    fn fake_transport(
+        device_features: BlkFeature,
+    ) -> (FakeTransport<BlkConfig>, Arc<Mutex<State<BlkConfig>>>) {
+        fake_transport_with_queues(device_features, 1)
+    }
+
+    /// Returns a fake transport for a block device with 66 sectors, the given features and the given
+    /// number of request queues, and its state.
+    fn fake_transport_with_queues(
+        device_features: BlkFeature,
+        num_queues: u16,
+    ) -> (FakeTransport<BlkConfig>, Arc<Mutex<State<BlkConfig>>>) {
+        let config_space = BlkConfig {
+            capacity_low: ReadOnly::new(66),
+            capacity_high: ReadOnly::new(0),
+            size_max: ReadOnly::new(0),
+            seg_max: ReadOnly::new(0),
+            cylinders: ReadOnly::new(0),
+            heads: ReadOnly::new(0),
+            sectors: ReadOnly::new(0),
+            blk_size: ReadOnly::new(0),
+            physical_block_exp: ReadOnly::new(0),
+            alignment_offset: ReadOnly::new(0),
+            min_io_size: ReadOnly::new(0),
+            opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(num_queues),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            (0..num_queues).map(|_| QueueStatus::default()).collect(),
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: device_features.bits(),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        hal::fake::FakeHal,
-        transport::{
-            fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
-        },
-    };
-    use alloc::{sync::Arc, vec};
-    use core::mem::size_of;
-    use std::{sync::Mutex, thread};
+    /// Reverses the order of the first `count` elements of the used ring of the given queue, as if
+    /// the device had completed those requests in the opposite order.
+    fn reverse_used_ring(state: &Mutex<State<BlkConfig>>, queue: u16, count: usize) {
+        let device_area = state.lock().unwrap().queues[usize::from(queue)].device_area;
+        // SAFETY: The used ring elements start after the 16-bit flags and index, and are each a
+        // 32-bit ID followed by a 32-bit length. The driver doesn't access them during this call.
+        unsafe {
+            let elements = (device_area as *mut u8).add(4) as *mut [u32; 2];
+            slice::from_raw_parts_mut(elements, count).reverse();
+        }
+    }
 
     #[test]
     fn config() {
@@ -578,6 +1538,16 @@ mod tests {
             alignment_offset: ReadOnly::new(0),
             min_io_size: ReadOnly::new(0),
             opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(0),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
         };
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
@@ -589,10 +1559,124 @@ mod tests {
             device_features: BlkFeature::RO.bits(),
             state: state.clone(),
         };
+        assert_eq!(transport.read_config_struct(), Ok([0x42u32, 0x02]));
+        assert_eq!(
+            transport.read_config_struct::<[u32; 32]>(),
+            Err(Error::ConfigSpaceTooSmall)
+        );
         let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
         assert_eq!(blk.capacity(), 0x02_0000_0042);
         assert_eq!(blk.readonly(), true);
+        assert_eq!(blk.topology(), None);
+    }
+
+    #[test]
+    fn resize() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 66);
+
+        // The host grows the disk and signals a config change.
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.capacity_low = ReadOnly::new(0x10);
+            state.config_space.capacity_high = ReadOnly::new(0x01);
+            state.config_generation += 1;
+            state.interrupt_status = InterruptStatus::CONFIG_CHANGE;
+        }
+        assert_eq!(blk.capacity(), 66);
+        assert!(blk.ack_interrupt().contains(InterruptStatus::CONFIG_CHANGE));
+        assert_eq!(blk.handle_config_change(), Ok(0x01_0000_0010));
+        assert_eq!(blk.capacity(), 0x01_0000_0010);
+    }
+
+    #[test]
+    fn write_readonly() {
+        let (transport, state) = fake_transport(BlkFeature::RO);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Writes should be rejected without anything being sent to the device.
+        let buffer = [0; SECTOR_SIZE];
+        assert_eq!(blk.write_blocks(0, &buffer), Err(Error::ReadOnly));
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        assert_eq!(
+            unsafe { blk.write_blocks_nb(0, &mut request, &buffer, &mut response) },
+            Err(Error::ReadOnly)
+        );
+        assert!(!State::poll_queue_notified(&state, QUEUE));
+        assert_eq!(blk.peek_used(), None);
+    }
+
+    #[test]
+    fn block_size() {
+        let (transport, state) = fake_transport(BlkFeature::BLK_SIZE | BlkFeature::TOPOLOGY);
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.blk_size = ReadOnly::new(4096);
+            state.config_space.physical_block_exp = ReadOnly::new(3);
+            state.config_space.alignment_offset = ReadOnly::new(1);
+            state.config_space.min_io_size = ReadOnly::new(8);
+            state.config_space.opt_io_size = ReadOnly::new(64);
+        }
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.block_size(), 4096);
+        assert_eq!(
+            blk.topology(),
+            Some(BlkTopology {
+                physical_block_exp: 3,
+                alignment_offset: 1,
+                min_io_size: 8,
+                opt_io_size: 64,
+            })
+        );
+        // Block IDs are still in 512 byte sectors, so must be a multiple of 8.
+        let mut buffer = [0; 4096];
+        assert_eq!(blk.read_blocks(1, &mut buffer), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.write_blocks(0, &buffer[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.set_max_request_len(Some(SECTOR_SIZE)),
+            Err(Error::InvalidParam)
+        );
+        blk.set_max_request_len(Some(6000)).unwrap();
+        assert_eq!(blk.max_request_len(), Some(4096));
+    }
+
+    #[test]
+    fn shutdown() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a request which the device never completes.
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        let mut buffer = [0; 512];
+        unsafe { blk.read_blocks_nb(0, &mut request, &mut buffer, &mut response) }.unwrap();
+        assert!(blk.queues[0].available_desc() < usize::from(QUEUE_SIZE));
+
+        let device: &mut dyn VirtioDevice = &mut blk;
+        assert_eq!(device.device_type(), DeviceType::Block);
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+        // Without the flush feature there is nothing to complete before resetting.
+        device.shutdown().unwrap();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+
+        // Draining should also clear the queue and reclaim the request's buffers, and be safe to
+        // repeat.
+        device.drain();
+        device.drain();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(state.lock().unwrap().queues[0].descriptors, 0);
+        assert_eq!(blk.queues[0].available_desc(), usize::from(QUEUE_SIZE));
     }
 
     #[test]
@@ -610,6 +1694,16 @@ mod tests {
             alignment_offset: ReadOnly::new(0),
             min_io_size: ReadOnly::new(0),
             opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(0),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
         };
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
@@ -664,6 +1758,238 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn read_async() {
+        let (transport, state) = fake_transport(BlkFeature::RING_INDIRECT_DESC);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                }));
+        });
+
+        // Read a block from the device, polling the future until it completes.
+        let waker = BlkWaker::new();
+        let mut buffer = [0; 512];
+        {
+            // SAFETY: The future is pinned on the stack, so it is dropped at the end of the block.
+            let mut future = pin!(unsafe { blk.read_blocks_async(&waker, 42, &mut buffer) });
+            let mut context = Context::from_waker(Waker::noop());
+            let result = loop {
+                if let Poll::Ready(result) = future.as_mut().poll(&mut context) {
+                    break result;
+                }
+                waker.wake();
+                thread::yield_now();
+            };
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_async_needs_reset() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let waker = BlkWaker::new();
+        let mut context = Context::from_waker(Waker::noop());
+        let mut buffer = [0; 512];
+
+        // The future should resolve with an error rather than waiting forever.
+        {
+            // SAFETY: The future is pinned on the stack, so it is dropped at the end of the block.
+            let mut future = pin!(unsafe { blk.read_blocks_async(&waker, 0, &mut buffer) });
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+            state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+            assert_eq!(
+                future.as_mut().poll(&mut context),
+                Poll::Ready(Err(Error::DeviceNeedsReset))
+            );
+        }
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(blk.queues[0].available_desc(), usize::from(QUEUE_SIZE));
+
+        // Dropping a pending future shouldn't block forever either.
+        {
+            // SAFETY: The future is pinned on the stack, so it is dropped at the end of the block.
+            let mut future = pin!(unsafe { blk.read_blocks_async(&waker, 0, &mut buffer) });
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+            state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+        }
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(blk.queues[0].available_desc(), usize::from(QUEUE_SIZE));
+    }
+
+    #[test]
+    fn read_split() {
+        let (transport, state) = fake_transport(BlkFeature::RING_INDIRECT_DESC);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.set_max_request_len(Some(100)), Err(Error::InvalidParam));
+        blk.set_max_request_len(Some(SECTOR_SIZE + 100)).unwrap();
+        assert_eq!(blk.max_request_len(), Some(SECTOR_SIZE));
+
+        // Start a thread to simulate the device handling one read request per sector.
+        let handle = thread::spawn(move || {
+            for sector in 42..44 {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::In,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+
+                        let mut response = vec![sector as u8; SECTOR_SIZE];
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+
+                        response
+                    }));
+            }
+        });
+
+        // Read two blocks from the device, which should be split into two requests.
+        let mut buffer = [0; 2 * SECTOR_SIZE];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert!(buffer[..SECTOR_SIZE].iter().all(|&b| b == 42));
+        assert!(buffer[SECTOR_SIZE..].iter().all(|&b| b == 43));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_retry() {
+        let (transport, state) = fake_transport(BlkFeature::RING_INDIRECT_DESC);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        blk.set_retry_policy(Some(RetryPolicy {
+            max_retries: 1,
+            backoff: |attempt| assert_eq!(attempt, 1),
+        }));
+
+        // Start a thread to simulate the device failing the first read request and completing the
+        // second, then failing the next two.
+        let handle = thread::spawn(move || {
+            for status in [
+                RespStatus::IO_ERR,
+                RespStatus::OK,
+                RespStatus::IO_ERR,
+                RespStatus::IO_ERR,
+            ] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                        let mut response = vec![0; SECTOR_SIZE];
+                        response[0..9].copy_from_slice(b"Test data");
+                        response.extend_from_slice(BlkResp { status }.as_bytes());
+                        response
+                    }));
+            }
+        });
+
+        // The first read should succeed after one retry.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        // The second should fail once it runs out of retries.
+        assert_eq!(blk.read_blocks(42, &mut buffer), Err(Error::IoError));
+
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn write_buffered() {
+        let (transport, state) = fake_transport(BlkFeature::RING_INDIRECT_DESC);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        blk.set_write_buffering(true).unwrap();
+
+        // Start a thread to simulate the device waiting for write requests.
+        let handle = thread::spawn(move || {
+            for (sector, len) in [(42, 2 * SECTOR_SIZE), (10, SECTOR_SIZE)] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[0..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::Out,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        let data = &request[size_of::<BlkReq>()..];
+                        assert_eq!(data.len(), len);
+                        assert_eq!(data[0], sector as u8);
+                        assert_eq!(data[len - 1], sector as u8 + 1);
+
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    }));
+            }
+        });
+
+        // Two contiguous writes should be merged, and submitted when a non-contiguous write is
+        // made.
+        blk.write_blocks(42, &[42; SECTOR_SIZE]).unwrap();
+        blk.write_blocks(43, &[43; SECTOR_SIZE]).unwrap();
+        let mut buffer = [10; SECTOR_SIZE];
+        buffer[SECTOR_SIZE - 1] = 11;
+        blk.write_blocks(10, &buffer).unwrap();
+        blk.flush_pending().unwrap();
+
+        handle.join().unwrap();
+    }
+
     #[test]
     fn write() {
         let config_space = BlkConfig {
@@ -679,6 +2005,16 @@ mod tests {
             alignment_offset: ReadOnly::new(0),
             min_io_size: ReadOnly::new(0),
             opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(0),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
         };
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
@@ -732,8 +2068,8 @@ mod tests {
         buffer[0..9].copy_from_slice(b"Test data");
         blk.write_blocks(42, &mut buffer).unwrap();
 
-        // Request to flush should be ignored as the device doesn't support it.
-        blk.flush().unwrap();
+        // Request to flush should fail as the device doesn't support it.
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
 
         handle.join().unwrap();
     }
@@ -753,6 +2089,16 @@ mod tests {
             alignment_offset: ReadOnly::new(0),
             min_io_size: ReadOnly::new(0),
             opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(0),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
         };
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
@@ -767,6 +2113,97 @@ mod tests {
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
         // Start a thread to simulate the device waiting for a flush request.
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                println!("Device waiting for a request.");
+                State::wait_until_queue_notified(&state, QUEUE);
+                println!("Transmit queue was notified.");
+
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::Flush,
+                                reserved: 0,
+                                sector: 0,
+                            }
+                            .as_bytes()
+                        );
+
+                        let mut response = Vec::new();
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+
+                        response
+                    }));
+            }
+        });
+
+        // Request to flush.
+        blk.flush().unwrap();
+
+        // And again without blocking.
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        let token = unsafe { blk.flush_nb(&mut request, &mut response) }.unwrap();
+        while blk.peek_used() != Some(token) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        unsafe { blk.complete_flush(token, &request, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn discard() {
+        let (transport, state) =
+            fake_transport(BlkFeature::RING_INDIRECT_DESC | BlkFeature::DISCARD);
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.max_discard_sectors = ReadOnly::new(100);
+            state.config_space.max_discard_seg = ReadOnly::new(2);
+        }
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.max_discard_sectors(), Some(100));
+        assert_eq!(blk.max_discard_segments(), Some(2));
+        assert_eq!(blk.max_write_zeroes_sectors(), None);
+
+        let ranges = [
+            BlkDiscardWriteZeroes {
+                sector: 8,
+                num_sectors: 16,
+                flags: 0,
+            },
+            BlkDiscardWriteZeroes {
+                sector: 64,
+                num_sectors: 100,
+                flags: 0,
+            },
+        ];
+        assert_eq!(blk.write_zeroes(&ranges), Err(Error::Unsupported));
+        assert_eq!(blk.discard(&[]), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.discard(&[ranges[0], ranges[1], ranges[0]]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.discard(&[BlkDiscardWriteZeroes {
+                sector: 0,
+                num_sectors: 101,
+                flags: 0,
+            }]),
+            Err(Error::InvalidParam)
+        );
+
+        // Start a thread to simulate the device waiting for a discard request.
         let handle = thread::spawn(move || {
             println!("Device waiting for a request.");
             State::wait_until_queue_notified(&state, QUEUE);
@@ -776,15 +2213,17 @@ mod tests {
                 .lock()
                 .unwrap()
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let (request, segments) = request.split_at(size_of::<BlkReq>());
                     assert_eq!(
                         request,
                         BlkReq {
-                            type_: ReqType::Flush,
+                            type_: ReqType::Discard,
                             reserved: 0,
                             sector: 0,
                         }
                         .as_bytes()
                     );
+                    assert_eq!(segments, ranges.as_bytes());
 
                     let mut response = Vec::new();
                     response.extend_from_slice(
@@ -798,8 +2237,7 @@ mod tests {
                 }));
         });
 
-        // Request to flush.
-        blk.flush().unwrap();
+        blk.discard(&ranges).unwrap();
 
         handle.join().unwrap();
     }
@@ -819,6 +2257,16 @@ mod tests {
             alignment_offset: ReadOnly::new(0),
             min_io_size: ReadOnly::new(0),
             opt_io_size: ReadOnly::new(0),
+            writeback: ReadOnly::new(0),
+            _unused0: ReadOnly::new(0),
+            num_queues: ReadOnly::new(0),
+            max_discard_sectors: ReadOnly::new(0),
+            max_discard_seg: ReadOnly::new(0),
+            discard_sector_alignment: ReadOnly::new(0),
+            max_write_zeroes_sectors: ReadOnly::new(0),
+            max_write_zeroes_seg: ReadOnly::new(0),
+            write_zeroes_may_unmap: ReadOnly::new(0),
+            _unused1: Default::default(),
         };
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
@@ -832,43 +2280,414 @@ mod tests {
         };
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        // Start a thread to simulate the device waiting for a flush request.
+        // Start a thread to simulate the device waiting for two GET_ID requests.
         let handle = thread::spawn(move || {
-            println!("Device waiting for a request.");
-            State::wait_until_queue_notified(&state, QUEUE);
-            println!("Transmit queue was notified.");
+            for _ in 0..2 {
+                println!("Device waiting for a request.");
+                State::wait_until_queue_notified(&state, QUEUE);
+                println!("Transmit queue was notified.");
+
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::GetId,
+                                reserved: 0,
+                                sector: 0,
+                            }
+                            .as_bytes()
+                        );
+
+                        let mut response = Vec::new();
+                        response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+
+                        response
+                    }));
+            }
+        });
 
-            assert!(state
+        let mut id = [0; 20];
+        let length = blk.device_id(&mut id).unwrap();
+        assert_eq!(&id[0..length], b"device_id");
+
+        // The ID should be truncated to fit a shorter buffer.
+        let mut short_id = [0; 6];
+        assert_eq!(blk.get_id(&mut short_id), Ok(6));
+        assert_eq!(&short_id, b"device");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn multiqueue() {
+        // The device only supports two queues.
+        let (transport, _state) = fake_transport_with_queues(BlkFeature::MQ, 2);
+        assert_eq!(
+            VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>, 3>::new(transport).err(),
+            Some(Error::InvalidParam)
+        );
+        let (transport, state) = fake_transport_with_queues(BlkFeature::MQ, 2);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>, 2>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling a read on the second queue.
+        let device_state = state.clone();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&device_state, 1);
+            assert!(device_state
                 .lock()
                 .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(1, |request| {
                     assert_eq!(
                         request,
                         BlkReq {
-                            type_: ReqType::GetId,
+                            type_: ReqType::In,
                             reserved: 0,
-                            sector: 0,
+                            sector: 42,
                         }
                         .as_bytes()
                     );
 
-                    let mut response = Vec::new();
-                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
+                    let mut response = vec![7; SECTOR_SIZE];
                     response.extend_from_slice(
                         BlkResp {
                             status: RespStatus::OK,
                         }
                         .as_bytes(),
                     );
-
                     response
                 }));
         });
 
-        let mut id = [0; 20];
-        let length = blk.device_id(&mut id).unwrap();
-        assert_eq!(&id[0..length], b"device_id");
+        let mut request = BlkReq::default();
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        let token =
+            unsafe { blk.read_blocks_nb_on(1, 42, &mut request, &mut buffer, &mut response) }
+                .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(blk.peek_used(), None);
+        assert_eq!(blk.peek_used_on(1), Some(token));
+        unsafe {
+            blk.complete_read_blocks_on(1, token, &request, &mut buffer, &mut response)
+                .unwrap();
+        }
+        assert_eq!(buffer, [7; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn flush_all_queues() {
+        let (transport, state) = fake_transport_with_queues(BlkFeature::MQ | BlkFeature::FLUSH, 2);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>, 2>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling a flush on each queue in turn.
+        let handle = thread::spawn(move || {
+            for queue in 0..2 {
+                State::wait_until_queue_notified(&state, queue);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(queue, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::Flush,
+                                reserved: 0,
+                                sector: 0,
+                            }
+                            .as_bytes()
+                        );
+
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_owned()
+                    }));
+            }
+        });
+
+        blk.flush_all_queues().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn flush_all_queues_unsupported() {
+        let (transport, _state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.flush_all_queues(), Err(Error::Unsupported));
+    }
 
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn write_buffered_size_max() {
+        let (transport, state) = fake_transport(BlkFeature::SIZE_MAX);
+        state.lock().unwrap().config_space.size_max = ReadOnly::new(2 * SECTOR_SIZE as u32 + 100);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // The device's limit applies even if a larger one is set.
+        assert_eq!(blk.max_request_len(), Some(2 * SECTOR_SIZE));
+        blk.set_max_request_len(Some(8 * SECTOR_SIZE)).unwrap();
+        assert_eq!(blk.max_request_len(), Some(2 * SECTOR_SIZE));
+        blk.set_max_request_len(None).unwrap();
+        assert_eq!(blk.max_request_len(), Some(2 * SECTOR_SIZE));
+        blk.set_write_buffering(true).unwrap();
+
+        // Start a thread to simulate the device waiting for write requests.
+        let handle = thread::spawn(move || {
+            for (sector, len) in [(0, 2 * SECTOR_SIZE), (2, SECTOR_SIZE)] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        let (req, data) = request.split_at(size_of::<BlkReq>());
+                        assert_eq!(
+                            req,
+                            BlkReq {
+                                type_: ReqType::Out,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        assert_eq!(data.len(), len);
+                        assert_eq!(data[len - 1], (sector as usize * SECTOR_SIZE + len) as u8);
+
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    }));
+            }
+        });
+
+        // The third write would take the merged write over the device's segment size limit, so the
+        // first two are submitted.
+        for sector in 0..3 {
+            let mut buf = [0; SECTOR_SIZE];
+            buf[SECTOR_SIZE - 1] = ((sector + 1) * SECTOR_SIZE) as u8;
+            blk.write_blocks(sector, &buf).unwrap();
+        }
+        // The last write is still buffered until it is flushed.
+        blk.flush_pending().unwrap();
         handle.join().unwrap();
     }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn drop_discards_buffered_writes() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        blk.set_write_buffering(true).unwrap();
+        blk.write_blocks(0, &[42; SECTOR_SIZE]).unwrap();
+
+        // Dropping the driver shouldn't wait for the device to handle the buffered write.
+        drop(blk);
+        assert!(!State::poll_queue_notified(&state, QUEUE));
+    }
+
+    #[test]
+    fn verify_large_blocks() {
+        let (transport, state) = fake_transport(BlkFeature::BLK_SIZE);
+        state.lock().unwrap().config_space.blk_size = ReadOnly::new(8192);
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.block_size(), 8192);
+
+        // A block doesn't fit in the read back buffer, so nothing should be written.
+        assert_eq!(blk.verify_blocks(0, &[42; 8192]), Err(Error::InvalidParam));
+        assert!(!State::poll_queue_notified(&state, QUEUE));
+    }
+
+    #[test]
+    fn tagged_out_of_order() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let mut read_request = BlkReq::default();
+        let mut read_buffer = [0; SECTOR_SIZE];
+        let mut read_response = BlkResp::default();
+        let read_token = unsafe {
+            blk.read_blocks_nb_tagged(
+                1,
+                &mut read_request,
+                &mut read_buffer,
+                &mut read_response,
+                100,
+            )
+        }
+        .unwrap();
+        let mut write_request = BlkReq::default();
+        let write_buffer = [3; SECTOR_SIZE];
+        let mut write_response = BlkResp::default();
+        let write_token = unsafe {
+            blk.write_blocks_nb_tagged(
+                2,
+                &mut write_request,
+                &write_buffer,
+                &mut write_response,
+                200,
+            )
+        }
+        .unwrap();
+
+        // The device handles the read and then the write, but completes the write first.
+        {
+            let mut state = state.lock().unwrap();
+            assert!(
+                state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 1,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = vec![5; SECTOR_SIZE];
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                })
+            );
+            assert!(
+                state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let (req, data) = request.split_at(size_of::<BlkReq>());
+                    assert_eq!(
+                        req,
+                        BlkReq {
+                            type_: ReqType::Out,
+                            reserved: 0,
+                            sector: 2,
+                        }
+                        .as_bytes()
+                    );
+                    assert_eq!(data, [3; SECTOR_SIZE]);
+                    BlkResp {
+                        status: RespStatus::IO_ERR,
+                    }
+                    .as_bytes()
+                    .to_owned()
+                })
+            );
+        }
+        reverse_used_ring(&state, QUEUE, 2);
+
+        assert_eq!(blk.peek_used_tagged(), Some((write_token, 200)));
+        unsafe {
+            assert_eq!(
+                blk.complete_write_blocks(
+                    write_token,
+                    &write_request,
+                    &write_buffer,
+                    &mut write_response
+                ),
+                Err(Error::IoError)
+            );
+        }
+        assert_eq!(blk.peek_used_tagged(), Some((read_token, 100)));
+        unsafe {
+            blk.complete_read_blocks(
+                read_token,
+                &read_request,
+                &mut read_buffer,
+                &mut read_response,
+            )
+            .unwrap();
+        }
+        assert_eq!(read_buffer, [5; SECTOR_SIZE]);
+        assert_eq!(blk.peek_used_tagged(), None);
+    }
+
+    #[test]
+    fn completed_out_of_order() {
+        let (transport, state) = fake_transport(BlkFeature::empty());
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let mut requests: [BlkReq; 3] = Default::default();
+        let mut buffers = [[0; SECTOR_SIZE]; 3];
+        let mut responses: [BlkResp; 3] = Default::default();
+        let mut tokens = [0; 3];
+        for (i, ((request, buffer), response)) in requests
+            .iter_mut()
+            .zip(&mut buffers)
+            .zip(&mut responses)
+            .enumerate()
+        {
+            tokens[i] =
+                unsafe { blk.read_blocks_nb_tagged(i, request, buffer, response, 10 + i as u64) }
+                    .unwrap();
+        }
+        assert_eq!(blk.completed().count(), 0);
+
+        // The device handles the requests in order, failing the second, but completes them in
+        // reverse order.
+        {
+            let mut state = state.lock().unwrap();
+            for (i, status) in [RespStatus::OK, RespStatus::IO_ERR, RespStatus::OK]
+                .into_iter()
+                .enumerate()
+            {
+                assert!(
+                    state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::In,
+                                reserved: 0,
+                                sector: i as u64,
+                            }
+                            .as_bytes()
+                        );
+                        let mut response = vec![i as u8 + 1; SECTOR_SIZE];
+                        response.extend_from_slice(BlkResp { status }.as_bytes());
+                        response
+                    })
+                );
+            }
+        }
+        reverse_used_ring(&state, QUEUE, 3);
+
+        assert_eq!(
+            blk.completed().collect::<Vec<_>>(),
+            [tokens[2], tokens[1], tokens[0]]
+        );
+        assert_eq!(
+            blk.completed_tagged().collect::<Vec<_>>(),
+            [(tokens[2], 12), (tokens[1], 11), (tokens[0], 10)]
+        );
+
+        // Completing the requests in the order given should give each its own status and data.
+        let completed = blk.completed().collect::<Vec<_>>();
+        for token in completed {
+            let i = tokens.iter().position(|&t| t == token).unwrap();
+            let result = unsafe {
+                blk.complete_read_blocks(token, &requests[i], &mut buffers[i], &mut responses[i])
+            };
+            if i == 1 {
+                assert_eq!(result, Err(Error::IoError));
+                assert_eq!(responses[i].status(), RespStatus::IO_ERR);
+            } else {
+                assert_eq!(result, Ok(()));
+                assert_eq!(responses[i].status(), RespStatus::OK);
+                assert_eq!(buffers[i], [i as u8 + 1; SECTOR_SIZE]);
+            }
+        }
+        assert_eq!(blk.completed().count(), 0);
+    }
 }
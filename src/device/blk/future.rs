@@ -0,0 +1,332 @@
+//! Futures for awaiting the completion of block device requests, without needing an allocator.
+
+use super::{BlkReq, BlkResp, VirtIOBlk};
+use crate::device::VirtioDevice;
+use crate::hal::Hal;
+use crate::transport::{DeviceStatus, Transport};
+use crate::{Error, Result};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    hint::spin_loop,
+    marker::PhantomPinned,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// No task is registering or waking the waker.
+const WAITING: u8 = 0;
+/// A task is in the middle of registering a waker.
+const REGISTERING: u8 = 1;
+/// The interrupt handler is in the middle of waking the waker.
+const WAKING: u8 = 2;
+
+/// A slot holding the waker of a task which is waiting for a block device request to complete.
+///
+/// This is shared between the task polling a [`BlkFuture`] and the interrupt handler for the
+/// device, which should call [`wake`](Self::wake) when the device raises an interrupt. It never
+/// blocks, so it is safe to use from an interrupt handler which may have interrupted the task
+/// while it was registering its waker.
+pub struct BlkWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: Access to the waker is serialised by the state.
+unsafe impl Sync for BlkWaker {}
+
+impl BlkWaker {
+    /// Creates a new empty waker slot.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Registers the given waker to be woken by the next call to [`wake`](Self::wake), replacing
+    /// any waker which was previously registered.
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // SAFETY: We are in the `REGISTERING` state, so nothing else accesses the waker.
+                unsafe {
+                    match &mut *self.waker.get() {
+                        Some(old) if old.will_wake(waker) => {}
+                        slot => *slot = Some(waker.clone()),
+                    }
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` was called while we were registering, so it is up to us to wake the
+                    // waker.
+                    // SAFETY: `wake` doesn't access the waker while `REGISTERING` is set.
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(WAKING) => {
+                // The interrupt handler is waking the previous waker, so wake this one directly.
+                waker.wake_by_ref();
+            }
+            Err(_) => {
+                // Another task is registering at the same time; only one can be woken anyway.
+            }
+        }
+    }
+
+    /// Wakes the registered waker, if any, and removes it from the slot.
+    ///
+    /// This should be called by the interrupt handler for the device.
+    pub fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            // SAFETY: We have set `WAKING` while nothing was registering, so nothing else accesses
+            // the waker until we clear it.
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Default for BlkWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The buffer of a [`BlkFuture`], depending on the direction of the request.
+enum Buffer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// The progress of a [`BlkFuture`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FutureState {
+    NotSubmitted,
+    Submitted(u16),
+    Done,
+}
+
+/// A future for a read or write request to a block device, created by
+/// [`VirtIOBlk::read_blocks_async`] or [`VirtIOBlk::write_blocks_async`].
+///
+/// The request is submitted when the future is first polled, and the future resolves once the
+/// device has completed it. Whenever it returns `Poll::Pending` the task's waker is registered
+/// with the [`BlkWaker`] passed when creating the future, so the interrupt handler for the device
+/// must call [`BlkWaker::wake`] for the task to make progress. The future acknowledges the
+/// interrupt itself when it is polled, so an interrupt handler which can't access the device should
+/// mask the interrupt until the task runs.
+///
+/// The request header and response live in the future itself, so no allocation is needed. If the
+/// future is dropped after the request has been submitted but before it has completed, dropping
+/// it blocks until the device completes the request, as the device may still be accessing the
+/// buffer. If the device indicates that it needs to be reset while the request is outstanding then
+/// it is drained as for [`VirtioDevice::drain`] instead, and the future resolves to
+/// [`Error::DeviceNeedsReset`].
+///
+/// As the device completes requests in the order in which they appear in the used ring, any other
+/// requests submitted on queue 0 with the non-blocking methods must be completed before this one,
+/// otherwise the future will never resolve.
+#[must_use = "futures do nothing unless polled"]
+pub struct BlkFuture<'a, H: Hal, T: Transport, const NUM_QUEUES: usize> {
+    blk: &'a mut VirtIOBlk<H, T, NUM_QUEUES>,
+    waker: &'a BlkWaker,
+    block_id: usize,
+    buffer: Buffer<'a>,
+    req: BlkReq,
+    resp: BlkResp,
+    state: FutureState,
+    // The device holds the addresses of `req` and `resp` once the request has been submitted.
+    _pinned: PhantomPinned,
+}
+
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> VirtIOBlk<H, T, NUM_QUEUES> {
+    /// Returns a future which reads one or more blocks into the given buffer, like
+    /// [`read_blocks`](Self::read_blocks) but without blocking.
+    ///
+    /// See [`BlkFuture`] for how the future is woken.
+    ///
+    /// # Safety
+    ///
+    /// Once the future has been polled it must not be leaked, e.g. with `core::mem::forget`, but
+    /// must be dropped or run to completion. Otherwise the device may still write to `buf` after
+    /// the borrow of it ends.
+    pub unsafe fn read_blocks_async<'a>(
+        &'a mut self,
+        waker: &'a BlkWaker,
+        block_id: usize,
+        buf: &'a mut [u8],
+    ) -> BlkFuture<'a, H, T, NUM_QUEUES> {
+        BlkFuture::new(self, waker, block_id, Buffer::Read(buf))
+    }
+
+    /// Returns a future which writes the contents of the given buffer to one or more blocks, like
+    /// [`write_blocks`](Self::write_blocks) but without blocking.
+    ///
+    /// See [`BlkFuture`] for how the future is woken.
+    ///
+    /// # Safety
+    ///
+    /// Once the future has been polled it must not be leaked, e.g. with `core::mem::forget`, but
+    /// must be dropped or run to completion. Otherwise the device may still read from `buf` after
+    /// the borrow of it ends.
+    pub unsafe fn write_blocks_async<'a>(
+        &'a mut self,
+        waker: &'a BlkWaker,
+        block_id: usize,
+        buf: &'a [u8],
+    ) -> BlkFuture<'a, H, T, NUM_QUEUES> {
+        BlkFuture::new(self, waker, block_id, Buffer::Write(buf))
+    }
+}
+
+impl<'a, H: Hal, T: Transport, const NUM_QUEUES: usize> BlkFuture<'a, H, T, NUM_QUEUES> {
+    fn new(
+        blk: &'a mut VirtIOBlk<H, T, NUM_QUEUES>,
+        waker: &'a BlkWaker,
+        block_id: usize,
+        buffer: Buffer<'a>,
+    ) -> Self {
+        Self {
+            blk,
+            waker,
+            block_id,
+            buffer,
+            req: BlkReq::default(),
+            resp: BlkResp::default(),
+            state: FutureState::NotSubmitted,
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// Submits the request to the device.
+    ///
+    /// # Safety
+    ///
+    /// The future must be pinned, and must not be dropped until the request has been completed.
+    unsafe fn submit(&mut self) -> Result<u16> {
+        match &mut self.buffer {
+            Buffer::Read(buf) => {
+                self.blk
+                    .read_blocks_nb(self.block_id, &mut self.req, buf, &mut self.resp)
+            }
+            Buffer::Write(buf) => {
+                self.blk
+                    .write_blocks_nb(self.block_id, &mut self.req, buf, &mut self.resp)
+            }
+        }
+    }
+
+    /// Returns whether the device has indicated that it needs to be reset, in which case it may
+    /// never complete the request.
+    fn device_needs_reset(&self) -> bool {
+        self.blk
+            .transport
+            .get_status()
+            .contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
+    /// Stops the device from accessing the buffers of a request which it won't complete.
+    fn abandon(&mut self) {
+        self.state = FutureState::Done;
+        self.blk.drain();
+    }
+
+    /// Completes the request with the given token, which must be the next in the used ring.
+    ///
+    /// # Safety
+    ///
+    /// The request must have been submitted by `submit` and returned the given token.
+    unsafe fn complete(&mut self, token: u16) -> Result {
+        self.state = FutureState::Done;
+        match &mut self.buffer {
+            Buffer::Read(buf) => {
+                self.blk
+                    .complete_read_blocks(token, &self.req, buf, &mut self.resp)
+            }
+            Buffer::Write(buf) => {
+                self.blk
+                    .complete_write_blocks(token, &self.req, buf, &mut self.resp)
+            }
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> Future for BlkFuture<'_, H, T, NUM_QUEUES> {
+    type Output = Result;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        // SAFETY: Nothing is moved out of the future, and `Drop` makes sure the request is
+        // completed or the device drained before the future goes away, as our creator promised
+        // not to leak it.
+        let this = unsafe { self.get_unchecked_mut() };
+        // Register first so that an interrupt between checking the used ring and returning
+        // `Pending` isn't missed.
+        this.waker.register(cx.waker());
+        this.blk.ack_interrupt();
+
+        let token = match this.state {
+            FutureState::NotSubmitted => {
+                // SAFETY: The future is pinned, and completes the request or drains the device before
+                // being dropped.
+                match unsafe { this.submit() } {
+                    Ok(token) => {
+                        this.state = FutureState::Submitted(token);
+                        token
+                    }
+                    // Wait for another request to complete and free up some descriptors.
+                    Err(Error::QueueFull) => return Poll::Pending,
+                    Err(e) => {
+                        this.state = FutureState::Done;
+                        return Poll::Ready(Err(e));
+                    }
+                }
+            }
+            FutureState::Submitted(token) => token,
+            FutureState::Done => panic!("BlkFuture polled after completion"),
+        };
+
+        if this.blk.peek_used() == Some(token) {
+            // SAFETY: The token was returned by `submit` for the same buffers.
+            Poll::Ready(unsafe { this.complete(token) })
+        } else if this.device_needs_reset() {
+            this.abandon();
+            Poll::Ready(Err(Error::DeviceNeedsReset))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const NUM_QUEUES: usize> Drop for BlkFuture<'_, H, T, NUM_QUEUES> {
+    fn drop(&mut self) {
+        if let FutureState::Submitted(token) = self.state {
+            // The device may still be using the buffers, so wait for it to finish with them.
+            while self.blk.peek_used() != Some(token) {
+                if self.device_needs_reset() {
+                    self.abandon();
+                    return;
+                }
+                spin_loop();
+            }
+            // SAFETY: The token was returned by `submit` for the same buffers.
+            let _ = unsafe { self.complete(token) };
+        }
+    }
+}
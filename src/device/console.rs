@@ -5,26 +5,39 @@ mod embedded_io;
 
 use crate::config::{read_config, write_config, ReadOnly, WriteOnly};
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::queue::{Queue, VirtQueue};
 use crate::transport::Transport;
 use crate::{Error, Result, PAGE_SIZE};
-use alloc::boxed::Box;
+use alloc::{
+    boxed::Box,
+    collections::btree_map::{BTreeMap, Entry},
+    string::String,
+    vec::Vec,
+};
 use bitflags::bitflags;
 use core::fmt::{self, Display, Formatter, Write};
-use log::error;
-use zerocopy::{FromBytes, Immutable, IntoBytes};
+use core::mem::size_of;
+use log::{debug, error, warn};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
+const QUEUE_CONTROL_RECEIVEQ: u16 = 2;
+const QUEUE_CONTROL_TRANSMITQ: u16 = 3;
 const QUEUE_SIZE: usize = 2;
 const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
     .union(Features::RING_INDIRECT_DESC)
     .union(Features::SIZE)
-    .union(Features::EMERG_WRITE);
+    .union(Features::MULTIPORT)
+    .union(Features::EMERG_WRITE)
+    .union(Features::NOTIFICATION_DATA);
 
 /// Driver for a VirtIO console device.
 ///
-/// Only a single port is supported.
+/// Port 0 is always available through [`send`](Self::send), [`recv`](Self::recv) and friends. If
+/// the device supports `VIRTIO_CONSOLE_F_MULTIPORT` then it may also add further ports, which are
+/// discovered by [`poll_control`](Self::poll_control) and can be used with
+/// [`port_send`](Self::port_send) and [`port_recv`](Self::port_recv).
 ///
 /// # Example
 ///
@@ -49,15 +62,9 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
 pub struct VirtIOConsole<H: Hal, T: Transport> {
     transport: T,
     negotiated_features: Features,
-    receiveq: VirtQueue<H, QUEUE_SIZE>,
-    transmitq: VirtQueue<H, QUEUE_SIZE>,
-    queue_buf_rx: Box<[u8; PAGE_SIZE]>,
-    /// The index of the next byte in `queue_buf_rx` which `recv` should return.
-    cursor: usize,
-    /// The number of bytes read into `queue_buf_rx`.
-    pending_len: usize,
-    /// The token of the outstanding receive request, if there is one.
-    receive_token: Option<u16>,
+    port0: PortQueues<H>,
+    /// The control queues and additional ports, if `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    multiport: Option<Multiport<H>>,
 }
 
 // SAFETY: The config space can be accessed from any thread.
@@ -87,40 +94,54 @@ impl Display for Size {
     }
 }
 
+/// Information about a port which a multiport console device has added.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PortInfo {
+    /// The ID of the port.
+    pub id: u32,
+    /// The name of the port, if the device has given it one.
+    pub name: Option<String>,
+    /// Whether the device has marked the port as a console rather than a generic serial port.
+    pub console: bool,
+    /// Whether the host has the port open.
+    pub host_connected: bool,
+    /// Whether the guest has the port open.
+    pub guest_connected: bool,
+}
+
 impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// Creates a new VirtIO console driver.
+    ///
+    /// If the device supports multiple ports then this tells it that the driver is ready, but the
+    /// ports it adds in response are only discovered once [`poll_control`](Self::poll_control) is
+    /// called. Sending the ready message blocks until the device has taken it from the control
+    /// transmit queue, so this won't return until the device is processing control messages.
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let receiveq = VirtQueue::new(
-            &mut transport,
-            QUEUE_RECEIVEQ_PORT_0,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let transmitq = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMITQ_PORT_0,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-
-        // Safe because no alignment or initialisation is required for [u8], the DMA buffer is
-        // dereferenceable, and the lifetime of the reference matches the lifetime of the DMA buffer
-        // (which we don't otherwise access).
-        let queue_buf_rx = Box::new([0; PAGE_SIZE]);
-
+        let port0 = PortQueues::new(&mut transport, 0, negotiated_features)?;
+        let multiport = if negotiated_features.contains(Features::MULTIPORT) {
+            let max_nr_ports = read_config!(transport, Config, max_nr_ports)?;
+            Some(Multiport::new(
+                &mut transport,
+                max_nr_ports,
+                negotiated_features,
+            )?)
+        } else {
+            None
+        };
         transport.finish_init();
+
         let mut console = VirtIOConsole {
             transport,
             negotiated_features,
-            receiveq,
-            transmitq,
-            queue_buf_rx,
-            cursor: 0,
-            pending_len: 0,
-            receive_token: None,
+            port0,
+            multiport,
         };
-        console.poll_retrieve()?;
+        console.port0.poll_retrieve(&mut console.transport)?;
+        if let Some(multiport) = &mut console.multiport {
+            multiport.poll_retrieve(&mut console.transport)?;
+            multiport.send_control(&mut console.transport, 0, VIRTIO_CONSOLE_DEVICE_READY, 1)?;
+        }
         Ok(console)
     }
 
@@ -138,9 +159,239 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         }
     }
 
+    /// Acknowledges a pending interrupt, if any, handles any pending control messages and completes
+    /// the outstanding finished read request on port 0 if there is one.
+    ///
+    /// Returns true if new data has been received on port 0.
+    pub fn ack_interrupt(&mut self) -> Result<bool> {
+        if self.transport.ack_interrupt().is_empty() {
+            return Ok(false);
+        }
+
+        self.poll_control()?;
+        self.port0.finish_receive()
+    }
+
+    /// Returns the next available character from the console, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        self.port0.finish_receive()?;
+        let Some(&ch) = self.port0.pending().first() else {
+            return Ok(None);
+        };
+        if pop {
+            self.port0.cursor += 1;
+            self.port0.poll_retrieve(&mut self.transport)?;
+        }
+        Ok(Some(ch))
+    }
+
+    /// Copies as many received bytes as are available and fit into `buffer`, and returns the
+    /// number of bytes copied.
+    ///
+    /// Like [`recv`](Self::recv) this doesn't block, so returns `Ok(0)` if no data has been
+    /// received.
+    pub fn recv_bytes(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.port0.recv_bytes(&mut self.transport, buffer)
+    }
+
+    /// Sends a character to the console.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.send_bytes(&[chr])
+    }
+
+    /// Sends one or more bytes to the console.
+    pub fn send_bytes(&mut self, buffer: &[u8]) -> Result {
+        self.port0.send_bytes(&mut self.transport, buffer)
+    }
+
+    /// Blocks until at least one character is available to read.
+    fn wait_for_receive(&mut self) -> Result {
+        self.port0.poll_retrieve(&mut self.transport)?;
+        while self.port0.pending().is_empty() {
+            self.port0.finish_receive()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a character to the console using the emergency write feature.
+    ///
+    /// Returns an error if the device doesn't support emergency write.
+    pub fn emergency_write(&mut self, chr: u8) -> Result<()> {
+        if self.negotiated_features.contains(Features::EMERG_WRITE) {
+            write_config!(self.transport, Config, emerg_wr, chr.into())?;
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Handles any control messages which the device has sent, such as adding or naming ports.
+    ///
+    /// Returns true if any messages were handled. This is also done by
+    /// [`ack_interrupt`](Self::ack_interrupt), so only needs to be called directly by a driver
+    /// which polls rather than handling interrupts.
+    pub fn poll_control(&mut self) -> Result<bool> {
+        match &mut self.multiport {
+            Some(multiport) => multiport.process_control(&mut self.transport),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the ports which the device has added so far.
+    ///
+    /// This is always empty if the device doesn't support multiple ports.
+    pub fn ports(&self) -> &[PortInfo] {
+        match &self.multiport {
+            Some(multiport) => &multiport.port_info,
+            None => &[],
+        }
+    }
+
+    /// Returns the ID of the port with the given name, if the device has added one.
+    pub fn find_port(&self, name: &str) -> Option<u32> {
+        self.ports()
+            .iter()
+            .find(|port| port.name.as_deref() == Some(name))
+            .map(|port| port.id)
+    }
+
+    /// Tells the device that the guest has opened the port with the given ID.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support multiple ports, or
+    /// [`Error::InvalidParam`] if it hasn't added the given port.
+    pub fn port_open(&mut self, id: u32) -> Result {
+        self.set_port_open(id, true)
+    }
+
+    /// Tells the device that the guest has closed the port with the given ID.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support multiple ports, or
+    /// [`Error::InvalidParam`] if it hasn't added the given port.
+    pub fn port_close(&mut self, id: u32) -> Result {
+        self.set_port_open(id, false)
+    }
+
+    fn set_port_open(&mut self, id: u32, open: bool) -> Result {
+        let multiport = self.multiport.as_mut().ok_or(Error::Unsupported)?;
+        multiport.port_info(id).ok_or(Error::InvalidParam)?;
+        multiport.send_control(
+            &mut self.transport,
+            id,
+            VIRTIO_CONSOLE_PORT_OPEN,
+            open.into(),
+        )?;
+        multiport.port_info(id).unwrap().guest_connected = open;
+        Ok(())
+    }
+
+    /// Copies as many bytes received on the given port as are available and fit into `buffer`,
+    /// and returns the number of bytes copied.
+    ///
+    /// This doesn't block, so returns `Ok(0)` if no data has been received. Port 0 is the same port
+    /// as used by [`recv_bytes`](Self::recv_bytes).
+    pub fn port_recv(&mut self, id: u32, buffer: &mut [u8]) -> Result<usize> {
+        let queues = match &mut self.multiport {
+            _ if id == 0 => &mut self.port0,
+            Some(multiport) => multiport.port_queues(id)?,
+            None => return Err(Error::Unsupported),
+        };
+        queues.recv_bytes(&mut self.transport, buffer)
+    }
+
+    /// Sends one or more bytes on the given port.
+    ///
+    /// Port 0 is the same port as used by [`send_bytes`](Self::send_bytes).
+    pub fn port_send(&mut self, id: u32, buffer: &[u8]) -> Result {
+        let queues = match &mut self.multiport {
+            _ if id == 0 => &mut self.port0,
+            Some(multiport) => multiport.port_queues(id)?,
+            None => return Err(Error::Unsupported),
+        };
+        queues.send_bytes(&mut self.transport, buffer)
+    }
+}
+
+impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.send_bytes(s.as_bytes()).map_err(|e| {
+            error!("Error writing to conosel: {}", e);
+            fmt::Error
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.port0.unset(&mut self.transport);
+        if let Some(multiport) = &self.multiport {
+            self.transport.queue_unset(QUEUE_CONTROL_RECEIVEQ);
+            self.transport.queue_unset(QUEUE_CONTROL_TRANSMITQ);
+            for queues in multiport.ports.values() {
+                queues.unset(&mut self.transport);
+            }
+        }
+    }
+}
+
+/// The receive and transmit queues of a single port, and the buffer used to receive data on it.
+struct PortQueues<H: Hal> {
+    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    queue_buf_rx: Box<[u8; PAGE_SIZE]>,
+    /// The index of the next byte in `queue_buf_rx` which `recv` should return.
+    cursor: usize,
+    /// The number of bytes read into `queue_buf_rx`.
+    pending_len: usize,
+    /// The token of the outstanding receive request, if there is one.
+    receive_token: Option<u16>,
+}
+
+impl<H: Hal> PortQueues<H> {
+    /// Sets up the queues for the given port.
+    ///
+    /// Port 0 uses queues 0 and 1, and port `n` uses queues `2n + 2` and `2n + 3`, as queues 2 and
+    /// 3 are the control queues.
+    fn new(transport: &mut impl Transport, id: u32, negotiated_features: Features) -> Result<Self> {
+        let (receiveq_index, transmitq_index) = if id == 0 {
+            (QUEUE_RECEIVEQ_PORT_0, QUEUE_TRANSMITQ_PORT_0)
+        } else {
+            let receiveq_index = u16::try_from(id * 2 + 2).map_err(|_| Error::InvalidParam)?;
+            (receiveq_index, receiveq_index + 1)
+        };
+        let receiveq = VirtQueue::new(
+            transport,
+            receiveq_index,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        let transmitq = VirtQueue::new(
+            transport,
+            transmitq_index,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        Ok(Self {
+            receiveq,
+            transmitq,
+            queue_buf_rx: Box::new([0; PAGE_SIZE]),
+            cursor: 0,
+            pending_len: 0,
+            receive_token: None,
+        })
+    }
+
+    /// Returns the data which has been received but not yet returned.
+    fn pending(&self) -> &[u8] {
+        &self.queue_buf_rx[self.cursor..self.pending_len]
+    }
+
     /// Makes a request to the device to receive data, if there is not already an outstanding
     /// receive request or some data already received and not yet returned.
-    fn poll_retrieve(&mut self) -> Result<()> {
+    fn poll_retrieve(&mut self, transport: &mut impl Transport) -> Result<()> {
         if self.receive_token.is_none() && self.cursor == self.pending_len {
             // Safe because the buffer lasts at least as long as the queue, and there are no other
             // outstanding requests using the buffer.
@@ -149,24 +400,12 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                     .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
             }?);
             if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
+                self.receiveq.notify(transport);
             }
         }
         Ok(())
     }
 
-    /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
-    /// request if there is one.
-    ///
-    /// Returns true if new data has been received.
-    pub fn ack_interrupt(&mut self) -> Result<bool> {
-        if !self.transport.ack_interrupt() {
-            return Ok(false);
-        }
-
-        self.finish_receive()
-    }
-
     /// If there is an outstanding receive request and it has finished, completes it.
     ///
     /// Returns true if new data has been received.
@@ -195,74 +434,218 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         Ok(flag)
     }
 
-    /// Returns the next available character from the console, if any.
-    ///
-    /// If no data has been received this will not block but immediately return `Ok<None>`.
-    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+    /// Copies as much received data as fits into `buffer`, and returns its length.
+    fn recv_bytes(&mut self, transport: &mut impl Transport, buffer: &mut [u8]) -> Result<usize> {
         self.finish_receive()?;
-        if self.cursor == self.pending_len {
-            return Ok(None);
-        }
-        let ch = self.queue_buf_rx[self.cursor];
-        if pop {
-            self.cursor += 1;
-            self.poll_retrieve()?;
-        }
-        Ok(Some(ch))
+        let len = buffer.len().min(self.pending().len());
+        buffer[..len].copy_from_slice(&self.pending()[..len]);
+        self.cursor += len;
+        self.poll_retrieve(transport)?;
+        Ok(len)
     }
 
-    /// Sends a character to the console.
-    pub fn send(&mut self, chr: u8) -> Result<()> {
-        let buf: [u8; 1] = [chr];
+    fn send_bytes(&mut self, transport: &mut impl Transport, buffer: &[u8]) -> Result {
         self.transmitq
-            .add_notify_wait_pop(&[&buf], &mut [], &mut self.transport)?;
+            .add_notify_wait_pop(&[buffer], &mut [], transport)?;
         Ok(())
     }
 
-    /// Sends one or more bytes to the console.
-    pub fn send_bytes(&mut self, buffer: &[u8]) -> Result {
-        self.transmitq
-            .add_notify_wait_pop(&[buffer], &mut [], &mut self.transport)?;
-        Ok(())
+    fn unset(&self, transport: &mut impl Transport) {
+        transport.queue_unset(self.receiveq.queue_idx());
+        transport.queue_unset(self.transmitq.queue_idx());
     }
+}
 
-    /// Blocks until at least one character is available to read.
-    fn wait_for_receive(&mut self) -> Result {
-        self.poll_retrieve()?;
-        while self.cursor == self.pending_len {
-            self.finish_receive()?;
-        }
-        Ok(())
+/// The control queues and additional ports of a device with `VIRTIO_CONSOLE_F_MULTIPORT`.
+struct Multiport<H: Hal> {
+    control_receiveq: VirtQueue<H, QUEUE_SIZE>,
+    control_transmitq: VirtQueue<H, QUEUE_SIZE>,
+    control_buf_rx: Box<[u8; PAGE_SIZE]>,
+    /// The token of the outstanding control receive request, if there is one.
+    control_receive_token: Option<u16>,
+    /// The maximum number of ports the device supports, including port 0.
+    max_nr_ports: u32,
+    negotiated_features: Features,
+    /// The queues for ports other than port 0, set up when the device first adds each port.
+    ports: BTreeMap<u32, PortQueues<H>>,
+    /// The ports which the device has added, in the order it added them.
+    port_info: Vec<PortInfo>,
+}
+
+impl<H: Hal> Multiport<H> {
+    fn new(
+        transport: &mut impl Transport,
+        max_nr_ports: u32,
+        negotiated_features: Features,
+    ) -> Result<Self> {
+        let control_receiveq = VirtQueue::new(
+            transport,
+            QUEUE_CONTROL_RECEIVEQ,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        let control_transmitq = VirtQueue::new(
+            transport,
+            QUEUE_CONTROL_TRANSMITQ,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        Ok(Self {
+            control_receiveq,
+            control_transmitq,
+            control_buf_rx: Box::new([0; PAGE_SIZE]),
+            control_receive_token: None,
+            max_nr_ports,
+            negotiated_features,
+            ports: BTreeMap::new(),
+            port_info: Vec::new(),
+        })
     }
 
-    /// Sends a character to the console using the emergency write feature.
-    ///
-    /// Returns an error if the device doesn't support emergency write.
-    pub fn emergency_write(&mut self, chr: u8) -> Result<()> {
-        if self.negotiated_features.contains(Features::EMERG_WRITE) {
-            write_config!(self.transport, Config, emerg_wr, chr.into())?;
-            Ok(())
+    /// Returns the queues for the given port, other than port 0, if the device has added it.
+    fn port_queues(&mut self, id: u32) -> Result<&mut PortQueues<H>> {
+        if self.port_info.iter().any(|port| port.id == id) {
+            self.ports.get_mut(&id).ok_or(Error::InvalidParam)
         } else {
-            Err(Error::Unsupported)
+            Err(Error::InvalidParam)
         }
     }
-}
 
-impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.send_bytes(s.as_bytes()).map_err(|e| {
-            error!("Error writing to conosel: {}", e);
-            fmt::Error
-        })
+    /// Sets up the queues for the given port if this is the first time the device has added it,
+    /// and starts receiving on it.
+    fn add_port_queues(&mut self, transport: &mut impl Transport, id: u32) -> Result {
+        let queues = match self.ports.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(PortQueues::new(transport, id, self.negotiated_features)?)
+            }
+        };
+        queues.poll_retrieve(transport)
     }
-}
 
-impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
-        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+    fn port_info(&mut self, id: u32) -> Option<&mut PortInfo> {
+        self.port_info.iter_mut().find(|port| port.id == id)
+    }
+
+    /// Makes a request to the device to receive a control message, if there is not already one
+    /// outstanding.
+    fn poll_retrieve(&mut self, transport: &mut impl Transport) -> Result<()> {
+        if self.control_receive_token.is_none() {
+            // Safe because the buffer lasts at least as long as the queue, and there are no other
+            // outstanding requests using the buffer.
+            self.control_receive_token = Some(unsafe {
+                self.control_receiveq
+                    .add(&[], &mut [self.control_buf_rx.as_mut_slice()])
+            }?);
+            if self.control_receiveq.should_notify() {
+                self.control_receiveq.notify(transport);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_control(
+        &mut self,
+        transport: &mut impl Transport,
+        id: u32,
+        event: u16,
+        value: u16,
+    ) -> Result {
+        let message = ControlMessage { id, event, value };
+        self.control_transmitq
+            .add_notify_wait_pop(&[message.as_bytes()], &mut [], transport)?;
+        Ok(())
+    }
+
+    /// Handles all control messages which the device has sent so far.
+    ///
+    /// Returns true if there were any.
+    fn process_control(&mut self, transport: &mut impl Transport) -> Result<bool> {
+        let mut processed = false;
+        while let Some(token) = self.control_receive_token {
+            if self.control_receiveq.peek_used() != Some(token) {
+                break;
+            }
+            // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
+            // `poll_retrieve` and it is still valid.
+            let len = unsafe {
+                self.control_receiveq.pop_used(
+                    token,
+                    &[],
+                    &mut [self.control_buf_rx.as_mut_slice()],
+                )?
+            } as usize;
+            self.control_receive_token = None;
+            let received = &self.control_buf_rx[..len.min(PAGE_SIZE)];
+            let message = ControlMessage::read_from_prefix(received)
+                .map_err(|_| Error::IoError)?
+                .0;
+            // Copy the name out before the buffer is reused for the next message.
+            let name = (message.event == VIRTIO_CONSOLE_PORT_NAME).then(|| {
+                let name = &received[size_of::<ControlMessage>()..];
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                String::from_utf8_lossy(name).into_owned()
+            });
+            self.poll_retrieve(transport)?;
+            self.handle_control(transport, message, name)?;
+            processed = true;
+        }
+        Ok(processed)
+    }
+
+    fn handle_control(
+        &mut self,
+        transport: &mut impl Transport,
+        message: ControlMessage,
+        name: Option<String>,
+    ) -> Result {
+        let id = message.id;
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                let mut ready = id == 0 || id < self.max_nr_ports;
+                if !ready {
+                    warn!("Device added port {} beyond max_nr_ports", id);
+                } else if self.port_info(id).is_none() {
+                    // Port 0 is always set up to receive, by `VirtIOConsole::new`.
+                    if id != 0 {
+                        if let Err(e) = self.add_port_queues(transport, id) {
+                            warn!("Failed to set up queues for port {}: {}", id, e);
+                            ready = false;
+                        }
+                    }
+                    if ready {
+                        self.port_info.push(PortInfo {
+                            id,
+                            ..Default::default()
+                        });
+                    }
+                }
+                self.send_control(transport, id, VIRTIO_CONSOLE_PORT_READY, ready.into())?;
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => self.port_info.retain(|port| port.id != id),
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                if let Some(port) = self.port_info(id) {
+                    port.console = true;
+                    // Console ports are opened straight away, as there is no separate
+                    // application to open them.
+                    self.send_control(transport, id, VIRTIO_CONSOLE_PORT_OPEN, 1)?;
+                    self.port_info(id).unwrap().guest_connected = true;
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.port_info(id) {
+                    port.host_connected = message.value != 0;
+                }
+            }
+            VIRTIO_CONSOLE_PORT_NAME => {
+                if let Some(port) = self.port_info(id) {
+                    port.name = name;
+                }
+            }
+            VIRTIO_CONSOLE_RESIZE => debug!("Ignoring resize of port {}", id),
+            event => warn!("Unknown console control event {} for port {}", event, id),
+        }
+        Ok(())
     }
 }
 
@@ -300,6 +683,26 @@ bitflags! {
     }
 }
 
+/// A message sent on one of the control queues.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
+#[repr(C)]
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -307,7 +710,7 @@ mod tests {
         hal::fake::FakeHal,
         transport::{
             fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
+            DeviceType, InterruptStatus,
         },
     };
     use alloc::{sync::Arc, vec};
@@ -351,7 +754,7 @@ mod tests {
         let transport = FakeTransport {
             device_type: DeviceType::Console,
             max_queue_size: 2,
-            device_features: 0x07,
+            device_features: (Features::SIZE | Features::EMERG_WRITE).bits(),
             state: state.clone(),
         };
         let console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
@@ -380,7 +783,7 @@ mod tests {
         let transport = FakeTransport {
             device_type: DeviceType::Console,
             max_queue_size: 2,
-            device_features: 0x07,
+            device_features: (Features::SIZE | Features::EMERG_WRITE).bits(),
             state: state.clone(),
         };
         let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
@@ -422,10 +825,13 @@ mod tests {
             let mut state = state.lock().unwrap();
             state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
 
-            state.interrupt_pending = true;
+            state.interrupt_status = InterruptStatus::USED_BUFFER;
         }
         assert_eq!(console.ack_interrupt(), Ok(true));
-        assert_eq!(state.lock().unwrap().interrupt_pending, false);
+        assert_eq!(
+            state.lock().unwrap().interrupt_status,
+            InterruptStatus::empty()
+        );
 
         // Receive the character. If we don't pop it it is still there to read again.
         assert_eq!(console.recv(false).unwrap(), Some(42));
@@ -470,4 +876,183 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    #[test]
+    fn echo_bytes() {
+        let config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let mut buffer = [0; 2];
+        assert_eq!(console.recv_bytes(&mut buffer), Ok(0));
+
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"abc");
+
+        // The received bytes are returned across as many calls as needed.
+        assert_eq!(console.recv_bytes(&mut buffer), Ok(2));
+        assert_eq!(&buffer, b"ab");
+        assert_eq!(console.recv_bytes(&mut buffer), Ok(1));
+        assert_eq!(&buffer[..1], b"c");
+        assert_eq!(console.recv_bytes(&mut buffer), Ok(0));
+
+        // Echo them back.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+            let data = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+            assert_eq!(data, b"abc");
+        });
+        assert_eq!(console.send_bytes(b"abc"), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn multiport() {
+        let config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(2),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            (0..6).map(|_| QueueStatus::default()).collect(),
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: Features::MULTIPORT.bits(),
+            state: state.clone(),
+        };
+
+        /// Spawns a thread to simulate the device receiving the given control message.
+        fn expect_control(
+            state: &Arc<Mutex<State<Config>>>,
+            id: u32,
+            event: u16,
+            value: u16,
+        ) -> thread::JoinHandle<()> {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE_CONTROL_TRANSMITQ);
+                let message = state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(QUEUE_CONTROL_TRANSMITQ);
+                assert_eq!(message, ControlMessage { id, event, value }.as_bytes());
+            })
+        }
+
+        let handle = expect_control(&state, 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        handle.join().unwrap();
+        assert_eq!(console.poll_control(), Ok(false));
+        assert_eq!(console.ports(), &[]);
+        // Port 1's queues aren't set up until the device adds it.
+        assert_eq!(state.lock().unwrap().queues[4].descriptors, 0);
+        assert_eq!(state.lock().unwrap().queues[5].descriptors, 0);
+
+        // The device adds port 1, which the driver acknowledges.
+        let handle = expect_control(&state, 1, VIRTIO_CONSOLE_PORT_READY, 1);
+        let message = ControlMessage {
+            id: 1,
+            event: VIRTIO_CONSOLE_DEVICE_ADD,
+            value: 0,
+        };
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_CONTROL_RECEIVEQ, message.as_bytes());
+        assert_eq!(console.poll_control(), Ok(true));
+        handle.join().unwrap();
+        assert_ne!(state.lock().unwrap().queues[4].descriptors, 0);
+        assert_ne!(state.lock().unwrap().queues[5].descriptors, 0);
+
+        // It then names the port and opens it on the host side.
+        let mut message = ControlMessage {
+            id: 1,
+            event: VIRTIO_CONSOLE_PORT_NAME,
+            value: 1,
+        }
+        .as_bytes()
+        .to_vec();
+        message.extend_from_slice(b"agent\0");
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_CONTROL_RECEIVEQ, &message);
+        assert_eq!(console.poll_control(), Ok(true));
+        let message = ControlMessage {
+            id: 1,
+            event: VIRTIO_CONSOLE_PORT_OPEN,
+            value: 1,
+        };
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_CONTROL_RECEIVEQ, message.as_bytes());
+        assert_eq!(console.poll_control(), Ok(true));
+        assert_eq!(console.find_port("agent"), Some(1));
+        assert_eq!(console.find_port("other"), None);
+        assert_eq!(
+            console.ports(),
+            &[PortInfo {
+                id: 1,
+                name: Some("agent".into()),
+                console: false,
+                host_connected: true,
+                guest_connected: false,
+            }]
+        );
+
+        let handle = expect_control(&state, 1, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        assert_eq!(console.port_open(1), Ok(()));
+        handle.join().unwrap();
+        assert!(console.ports()[0].guest_connected);
+        assert_eq!(console.port_open(2), Err(Error::InvalidParam));
+
+        // Data on port 1 uses its own queues.
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(4, b"hi");
+        let mut buffer = [0; 4];
+        assert_eq!(console.port_recv(1, &mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], b"hi");
+        assert_eq!(console.recv_bytes(&mut buffer), Ok(0));
+
+        let handle = thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 5);
+                assert_eq!(
+                    state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(5),
+                    b"yo"
+                );
+            }
+        });
+        assert_eq!(console.port_send(1, b"yo"), Ok(()));
+        handle.join().unwrap();
+
+        let handle = expect_control(&state, 1, VIRTIO_CONSOLE_PORT_OPEN, 0);
+        assert_eq!(console.port_close(1), Ok(()));
+        handle.join().unwrap();
+        assert!(!console.ports()[0].guest_connected);
+    }
 }
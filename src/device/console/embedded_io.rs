@@ -27,8 +27,8 @@ impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
 
 impl<H: Hal, T: Transport> ReadReady for VirtIOConsole<H, T> {
     fn read_ready(&mut self) -> Result<bool, Self::Error> {
-        self.finish_receive()?;
-        Ok(self.cursor != self.pending_len)
+        self.port0.finish_receive()?;
+        Ok(!self.port0.pending().is_empty())
     }
 }
 
@@ -38,9 +38,8 @@ impl<H: Hal, T: Transport> Read for VirtIOConsole<H, T> {
             Ok(0)
         } else {
             self.wait_for_receive()?;
-            let read_length = min(buf.len(), self.pending_len - self.cursor);
-            buf[..read_length]
-                .copy_from_slice(&self.queue_buf_rx[self.cursor..self.cursor + read_length]);
+            let read_length = min(buf.len(), self.port0.pending().len());
+            buf[..read_length].copy_from_slice(&self.port0.pending()[..read_length]);
             Ok(read_length)
         }
     }
@@ -49,11 +48,11 @@ impl<H: Hal, T: Transport> Read for VirtIOConsole<H, T> {
 impl<H: Hal, T: Transport> BufRead for VirtIOConsole<H, T> {
     fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
         self.wait_for_receive()?;
-        Ok(&self.queue_buf_rx[self.cursor..self.pending_len])
+        Ok(self.port0.pending())
     }
 
     fn consume(&mut self, amt: usize) {
-        assert!(self.cursor + amt <= self.pending_len);
-        self.cursor += amt;
+        assert!(amt <= self.port0.pending().len());
+        self.port0.cursor += amt;
     }
 }
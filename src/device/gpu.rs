@@ -1,17 +1,26 @@
 //! Driver for VirtIO GPU devices.
 
-use crate::config::{read_config, ReadOnly, WriteOnly};
+#[cfg(feature = "edid")]
+pub mod edid;
+
+use super::VirtioDevice;
+use crate::config::{read_config, write_config, ReadOnly, WriteOnly};
 use crate::hal::{BufferDirection, Dma, Hal};
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::{pages, Error, Result, PAGE_SIZE};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use bitflags::bitflags;
+use core::{array, mem::size_of};
 use log::info;
 use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
+/// The size of each queue, which is just enough for a single command and its response buffer.
 const QUEUE_SIZE: u16 = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX.union(Features::RING_INDIRECT_DESC);
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_INDIRECT_DESC)
+    .union(Features::EDID)
+    .union(Features::NOTIFICATION_DATA);
 
 /// A virtio based graphics adapter.
 ///
@@ -20,13 +29,20 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX.union(Features::RI
 /// a gpu with 3D support on the host machine.
 /// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
 /// and multiple scanouts (aka heads).
+///
+/// Control commands are sent one at a time, with each waiting for its response before the next is
+/// sent, so there is a single command and response buffer shared by all commands and no way to
+/// have several commands in flight.
 pub struct VirtIOGpu<H: Hal, T: Transport> {
     transport: T,
-    rect: Option<Rect>,
-    /// DMA area of frame buffer.
-    frame_buffer_dma: Option<Dma<H>>,
+    /// The framebuffer set up for each scanout, if any.
+    framebuffers: [Option<Framebuffer<H>>; MAX_SCANOUTS],
+    /// The pair of framebuffers set up for the first scanout by `setup_double_buffer`, if any.
+    double_buffer: Option<DoubleBuffer<H>>,
     /// DMA area of cursor image buffer.
     cursor_buffer_dma: Option<Dma<H>>,
+    /// The position of the cursor last set by `move_cursor`.
+    cursor_pos: (u32, u32),
     /// Queue for sending control commands.
     control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     /// Queue for sending cursor commands.
@@ -35,6 +51,14 @@ pub struct VirtIOGpu<H: Hal, T: Transport> {
     queue_buf_send: Box<[u8]>,
     /// Recv buffer for queue.
     queue_buf_recv: Box<[u8]>,
+    /// The maximum number of scanouts supported by the device.
+    num_scanouts: u32,
+    /// The maximum number of capability sets supported by the device.
+    num_capsets: u32,
+    /// The length the device reported writing for the response to the last control command.
+    last_response_len: u32,
+    /// The features negotiated with the device.
+    negotiated_features: Features,
 }
 
 impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
@@ -45,9 +69,10 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         // read configuration space
         let events_read = read_config!(transport, Config, events_read)?;
         let num_scanouts = read_config!(transport, Config, num_scanouts)?;
+        let num_capsets = read_config!(transport, Config, num_capsets)?;
         info!(
-            "events_read: {:#x}, num_scanouts: {:#x}",
-            events_read, num_scanouts
+            "events_read: {:#x}, num_scanouts: {:#x}, num_capsets: {:#x}",
+            events_read, num_scanouts, num_capsets
         );
 
         let control_queue = VirtQueue::new(
@@ -70,86 +95,477 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
 
         Ok(VirtIOGpu {
             transport,
-            frame_buffer_dma: None,
+            framebuffers: array::from_fn(|_| None),
+            double_buffer: None,
             cursor_buffer_dma: None,
-            rect: None,
+            cursor_pos: (0, 0),
             control_queue,
             cursor_queue,
             queue_buf_send,
             queue_buf_recv,
+            num_scanouts,
+            num_capsets,
+            last_response_len: 0,
+            negotiated_features,
         })
     }
 
     /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> bool {
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
     }
 
-    /// Get the resolution (width, height).
+    /// Reads the pending events from the config space and clears them.
+    ///
+    /// Only the events which were read are cleared, so an event which arrives
+    /// after the read remains pending to be returned by the next call rather
+    /// than being lost.
+    pub fn take_config_events(&mut self) -> Result<GpuEvents> {
+        let events = read_config!(self.transport, Config, events_read)?;
+        if events != 0 {
+            write_config!(self.transport, Config, events_clear, events)?;
+        }
+        Ok(GpuEvents::from_bits_retain(events))
+    }
+
+    /// Returns the maximum number of scanouts (aka heads) supported by the device, as read from
+    /// its config space.
+    pub fn num_scanouts(&self) -> u32 {
+        self.num_scanouts
+    }
+
+    /// Returns the maximum number of capability sets supported by the device, as read from its
+    /// config space.
+    ///
+    /// This will be 0 if the device doesn't support 3D (virgl) mode.
+    pub fn num_capsets(&self) -> u32 {
+        self.num_capsets
+    }
+
+    /// Returns the length in bytes which the device reported writing for the response to the most
+    /// recent control command, such as a transfer or flush.
+    ///
+    /// This is mostly useful for debugging; a response too short to contain a header is already
+    /// reported as [`Error::IoError`].
+    pub fn last_response_len(&self) -> u32 {
+        self.last_response_len
+    }
+
+    /// Get the resolution (width, height) of the first scanout.
     pub fn resolution(&mut self) -> Result<(u32, u32)> {
         let display_info = self.get_display_info()?;
-        Ok((display_info.rect.width, display_info.rect.height))
+        let rect = display_info.pmodes[SCANOUT_ID as usize].rect;
+        Ok((rect.width, rect.height))
     }
 
-    /// Setup framebuffer
+    /// Returns the geometry and connection state of each scanout supported by the device, as
+    /// reported by `VIRTIO_GPU_CMD_GET_DISPLAY_INFO`.
+    ///
+    /// The index of each entry is the scanout index to pass to
+    /// [`setup_framebuffer_on`](Self::setup_framebuffer_on) and [`flush_on`](Self::flush_on).
+    pub fn scanouts(&mut self) -> Result<Vec<ScanoutInfo>> {
+        let display_info = self.get_display_info()?;
+        Ok(display_info.pmodes[..self.scanout_count()]
+            .iter()
+            .map(|pmode| ScanoutInfo {
+                x: pmode.rect.x,
+                y: pmode.rect.y,
+                width: pmode.rect.width,
+                height: pmode.rect.height,
+                enabled: pmode.enabled != 0,
+            })
+            .collect())
+    }
+
+    /// Returns the raw EDID data of the display connected to the given scanout, of at most 1024
+    /// bytes.
+    ///
+    /// With the `edid` feature, the modes it lists can be found with `edid::parse_modes`.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support EDID, or
+    /// [`Error::InvalidParam`] if the scanout index is not less than the number of scanouts.
+    pub fn get_edid(&mut self, scanout: u32) -> Result<Vec<u8>> {
+        if !self.negotiated_features.contains(Features::EDID) {
+            return Err(Error::Unsupported);
+        }
+        if scanout as usize >= self.scanout_count() {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespEdid = self.request(GetEdid {
+            header: CtrlHeader::with_type(Command::GET_EDID),
+            scanout,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_EDID)?;
+        let size = (rsp.size as usize).min(rsp.edid.len());
+        Ok(rsp.edid[..size].to_vec())
+    }
+
+    /// Setup framebuffer on the first scanout.
     pub fn setup_framebuffer(&mut self) -> Result<&mut [u8]> {
+        self.setup_framebuffer_on(SCANOUT_ID as usize)
+    }
+
+    /// Sets up a framebuffer for the given scanout, with the size which the device reports for it.
+    ///
+    /// Returns [`Error::InvalidParam`] if the scanout index is not less than the number of
+    /// scanouts supported by the device.
+    pub fn setup_framebuffer_on(&mut self, scanout: usize) -> Result<&mut [u8]> {
+        if scanout >= self.scanout_count() {
+            return Err(Error::InvalidParam);
+        }
         // get display info
         let display_info = self.get_display_info()?;
         info!("=> {:?}", display_info);
-        self.rect = Some(display_info.rect);
+        self.create_framebuffer(scanout, display_info.pmodes[scanout].rect)
+    }
+
+    /// Changes the resolution of the first scanout, replacing its framebuffer with a new one of
+    /// the given size.
+    ///
+    /// See [`set_resolution_on`](Self::set_resolution_on).
+    pub fn set_resolution(&mut self, width: u32, height: u32) -> Result<&mut [u8]> {
+        self.set_resolution_on(SCANOUT_ID as usize, width, height)
+    }
+
+    /// Changes the resolution of the given scanout, replacing its framebuffer with a new one of the
+    /// given size, and returns the new framebuffer.
+    ///
+    /// Any existing framebuffer for the scanout is detached from it and destroyed first, so the
+    /// slice previously returned for it must no longer be used. The size doesn't need to match
+    /// the one reported by [`scanouts`](Self::scanouts); if it is larger then the host may scale
+    /// or crop the display.
+    ///
+    /// Returns [`Error::InvalidParam`] if the scanout index is not less than the number of
+    /// scanouts, or if the width or height is 0 or too large for the framebuffer size to fit in a
+    /// `u32`.
+    pub fn set_resolution_on(
+        &mut self,
+        scanout: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<&mut [u8]> {
+        if scanout >= self.scanout_count()
+            || width == 0
+            || height == 0
+            || width
+                .checked_mul(height)
+                .and_then(|pixels| pixels.checked_mul(4))
+                .is_none()
+        {
+            return Err(Error::InvalidParam);
+        }
+        self.destroy_framebuffer(scanout)?;
+        self.create_framebuffer(
+            scanout,
+            Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+        )
+    }
+
+    /// Creates a framebuffer of the size of the given rectangle and attaches it to the given
+    /// scanout.
+    fn create_framebuffer(&mut self, scanout: usize, rect: Rect) -> Result<&mut [u8]> {
+        // The position of the scanout in the host's display layout doesn't matter here, as
+        // rectangles within the resource are relative to its top left corner.
+        let rect = Rect { x: 0, y: 0, ..rect };
+        let resource_id = framebuffer_resource_id(scanout);
+        let dma = self.create_backed_resource(resource_id, rect)?;
+
+        // map frame buffer to screen
+        self.set_scanout(rect, scanout as u32, resource_id)?;
+
+        let size = framebuffer_size(rect);
+        let buf = unsafe { &mut dma.raw_slice().as_mut()[..size] };
+        self.framebuffers[scanout] = Some(Framebuffer { rect, dma });
+        Ok(buf)
+    }
 
+    /// Creates a 2D resource of the size of the given rectangle, with a newly allocated DMA area
+    /// attached as its backing.
+    fn create_backed_resource(&mut self, resource_id: u32, rect: Rect) -> Result<Dma<H>> {
         // create resource 2d
-        self.resource_create_2d(
-            RESOURCE_ID_FB,
-            display_info.rect.width,
-            display_info.rect.height,
-        )?;
+        self.resource_create_2d(resource_id, rect.width, rect.height)?;
 
         // alloc continuous pages for the frame buffer
-        let size = display_info.rect.width * display_info.rect.height * 4;
-        let frame_buffer_dma = Dma::new(pages(size as usize), BufferDirection::DriverToDevice)?;
+        let size = framebuffer_size(rect);
+        let dma = Dma::new(pages(size), BufferDirection::DriverToDevice)?;
 
         // resource_attach_backing
-        self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_dma.paddr() as u64, size)?;
+        self.resource_attach_backing(resource_id, dma.paddr() as u64, size as u32)?;
+        Ok(dma)
+    }
 
-        // map frame buffer to screen
-        self.set_scanout(display_info.rect, SCANOUT_ID, RESOURCE_ID_FB)?;
+    /// Detaches the framebuffer of the given scanout, if any, and destroys its resource. This
+    /// includes the double buffer, for the first scanout.
+    ///
+    /// The DMA area is only freed once the device has stopped using it.
+    fn destroy_framebuffer(&mut self, scanout: usize) -> Result {
+        if scanout == SCANOUT_ID as usize && self.double_buffer.is_some() {
+            self.set_scanout(Rect::default(), SCANOUT_ID, 0)?;
+            for resource_id in DOUBLE_BUFFER_RESOURCE_IDS {
+                self.resource_detach_backing(resource_id)?;
+            }
+            self.double_buffer = None;
+            for resource_id in DOUBLE_BUFFER_RESOURCE_IDS {
+                self.resource_unref(resource_id)?;
+            }
+        }
+        if self.framebuffers[scanout].is_none() {
+            return Ok(());
+        }
+        let resource_id = framebuffer_resource_id(scanout);
+        // A resource ID of 0 disables the scanout.
+        self.set_scanout(Rect::default(), scanout as u32, 0)?;
+        self.resource_detach_backing(resource_id)?;
+        self.framebuffers[scanout] = None;
+        self.resource_unref(resource_id)
+    }
 
-        let buf = unsafe { frame_buffer_dma.raw_slice().as_mut() };
-        self.frame_buffer_dma = Some(frame_buffer_dma);
-        Ok(buf)
+    /// Sets up a pair of framebuffers for the first scanout, with the size which the device
+    /// reports for it, to draw into alternately without tearing.
+    ///
+    /// The first buffer is initially displayed, so the second one should be drawn into first.
+    /// [`flip`](Self::flip) then displays it, and the first one can be drawn into next. Any
+    /// framebuffer previously set up for the first scanout is destroyed first, so the slices
+    /// previously returned for it must no longer be used.
+    pub fn setup_double_buffer(&mut self) -> Result<(&mut [u8], &mut [u8])> {
+        let display_info = self.get_display_info()?;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            ..display_info.pmodes[SCANOUT_ID as usize].rect
+        };
+        self.destroy_framebuffer(SCANOUT_ID as usize)?;
+
+        let first = self.create_backed_resource(DOUBLE_BUFFER_RESOURCE_IDS[0], rect)?;
+        let second = self.create_backed_resource(DOUBLE_BUFFER_RESOURCE_IDS[1], rect)?;
+        self.set_scanout(rect, SCANOUT_ID, DOUBLE_BUFFER_RESOURCE_IDS[0])?;
+
+        let size = framebuffer_size(rect);
+        // SAFETY: The two DMA areas are distinct, and the device only reads them.
+        let bufs = unsafe {
+            (
+                &mut first.raw_slice().as_mut()[..size],
+                &mut second.raw_slice().as_mut()[..size],
+            )
+        };
+        self.double_buffer = Some(DoubleBuffer {
+            rect,
+            _dma: [first, second],
+            front: 0,
+        });
+        Ok(bufs)
     }
 
-    /// Flush framebuffer to screen.
+    /// Displays the back buffer of the pair set up by
+    /// [`setup_double_buffer`](Self::setup_double_buffer), so that it becomes the front buffer.
+    ///
+    /// Only the back buffer is transferred to the host, and the scanout is then pointed at it.
+    /// Returns the index of the new back buffer, i.e. 0 for the first slice returned by
+    /// `setup_double_buffer` or 1 for the second, which should be drawn into next.
+    ///
+    /// Returns [`Error::NotReady`] if no double buffer has been set up.
+    pub fn flip(&mut self) -> Result<usize> {
+        let double_buffer = self.double_buffer.as_ref().ok_or(Error::NotReady)?;
+        let rect = double_buffer.rect;
+        let back = 1 - double_buffer.front;
+        let resource_id = DOUBLE_BUFFER_RESOURCE_IDS[back];
+        self.transfer_to_host_2d(rect, 0, resource_id)?;
+        self.set_scanout(rect, SCANOUT_ID, resource_id)?;
+        self.resource_flush(rect, resource_id)?;
+        self.double_buffer.as_mut().unwrap().front = back;
+        Ok(1 - back)
+    }
+
+    /// Flush framebuffer of the first scanout to screen.
+    ///
+    /// Returns [`Error::ResourceLost`] if the device no longer knows about the framebuffer, e.g.
+    /// because the host restarted. In that case [`setup_framebuffer`](Self::setup_framebuffer)
+    /// should be called again to recreate it.
     pub fn flush(&mut self) -> Result {
-        let rect = self.rect.ok_or(Error::NotReady)?;
+        self.flush_on(SCANOUT_ID as usize)
+    }
+
+    /// Flushes the framebuffer of the given scanout to screen.
+    ///
+    /// Returns [`Error::NotReady`] if no framebuffer has been set up for the scanout, or
+    /// [`Error::ResourceLost`] as for [`flush`](Self::flush).
+    pub fn flush_on(&mut self, scanout: usize) -> Result {
+        let rect = self.framebuffer_rect(scanout)?;
+        self.flush_rect_on(scanout, rect.x, rect.y, rect.width, rect.height)
+    }
+
+    /// Flushes only the given rectangle of the framebuffer of the first scanout to screen, in
+    /// pixels from the top left corner.
+    ///
+    /// See [`flush_rect_on`](Self::flush_rect_on).
+    pub fn flush_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result {
+        self.flush_rect_on(SCANOUT_ID as usize, x, y, width, height)
+    }
+
+    /// Flushes only the given rectangle of the framebuffer of the given scanout to screen, in
+    /// pixels from the top left corner.
+    ///
+    /// This only copies the changed part of the framebuffer to the host, so is much cheaper than
+    /// [`flush_on`](Self::flush_on) for small updates.
+    ///
+    /// Returns [`Error::InvalidParam`] if the rectangle doesn't fit within the framebuffer,
+    /// [`Error::NotReady`] if no framebuffer has been set up for the scanout, or
+    /// [`Error::ResourceLost`] as for [`flush`](Self::flush).
+    pub fn flush_rect_on(
+        &mut self,
+        scanout: usize,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result {
+        let framebuffer_rect = self.framebuffer_rect(scanout)?;
+        if x.checked_add(width)
+            .is_none_or(|right| right > framebuffer_rect.width)
+            || y.checked_add(height)
+                .is_none_or(|bottom| bottom > framebuffer_rect.height)
+        {
+            return Err(Error::InvalidParam);
+        }
+        let rect = Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        // The offset of the first pixel of the rectangle within the backing memory.
+        let offset = (u64::from(y) * u64::from(framebuffer_rect.width) + u64::from(x)) * 4;
+        let resource_id = framebuffer_resource_id(scanout);
         // copy data from guest to host
-        self.transfer_to_host_2d(rect, 0, RESOURCE_ID_FB)?;
+        self.transfer_to_host_2d(rect, offset, resource_id)?;
         // flush data to screen
-        self.resource_flush(rect, RESOURCE_ID_FB)?;
+        self.resource_flush(rect, resource_id)?;
         Ok(())
     }
 
-    /// Set the pointer shape and position.
+    /// Returns the rectangle covered by the framebuffer of the given scanout.
+    fn framebuffer_rect(&self, scanout: usize) -> Result<Rect> {
+        Ok(self
+            .framebuffers
+            .get(scanout)
+            .and_then(Option::as_ref)
+            .ok_or(Error::NotReady)?
+            .rect)
+    }
+
+    /// Returns the number of scanouts which can be used, which is the number reported by the
+    /// device but at most the number for which the display info has room.
+    fn scanout_count(&self) -> usize {
+        (self.num_scanouts as usize).min(MAX_SCANOUTS)
+    }
+
+    /// Fills the whole framebuffer with the given colour, given as `0xRRGGBBAA`.
+    ///
+    /// As with writes to the framebuffer slice, [`flush`](Self::flush) must be called afterwards
+    /// to display the change.
+    pub fn clear_framebuffer(&mut self, rgba: u32) -> Result {
+        let pixel = pixel_bytes(rgba);
+        let (buf, _) = self.framebuffer()?;
+        for dst in buf.chunks_exact_mut(pixel.len()) {
+            dst.copy_from_slice(&pixel);
+        }
+        Ok(())
+    }
+
+    /// Moves the contents of the framebuffer up by the given number of rows, filling the rows
+    /// uncovered at the bottom with the given colour, given as `0xRRGGBBAA`.
+    ///
+    /// If `rows` is at least the height of the framebuffer then the whole framebuffer is filled. As
+    /// with writes to the framebuffer slice, [`flush`](Self::flush) must be called afterwards to
+    /// display the change.
+    pub fn scroll_framebuffer_up(&mut self, rows: u32, fill_rgba: u32) -> Result {
+        let pixel = pixel_bytes(fill_rgba);
+        let (buf, stride) = self.framebuffer()?;
+        let offset = (rows as usize).saturating_mul(stride).min(buf.len());
+        buf.copy_within(offset.., 0);
+        let remaining = buf.len() - offset;
+        for dst in buf[remaining..].chunks_exact_mut(pixel.len()) {
+            dst.copy_from_slice(&pixel);
+        }
+        Ok(())
+    }
+
+    /// Returns the part of the framebuffer which is displayed, and the length in bytes of each row.
+    fn framebuffer(&mut self) -> Result<(&mut [u8], usize)> {
+        let framebuffer = self.framebuffers[SCANOUT_ID as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let rect = framebuffer.rect;
+        let stride = rect.width as usize * 4;
+        // SAFETY: The framebuffer is only otherwise accessed through the slice returned by
+        // `setup_framebuffer`, which the caller can't be using while they call a `&mut self`
+        // method.
+        let buf = unsafe { framebuffer.dma.raw_slice().as_mut() };
+        Ok((&mut buf[..stride * rect.height as usize], stride))
+    }
+
+    /// Sets the shape of the hardware cursor, and shows it at the position last set by
+    /// [`move_cursor`](Self::move_cursor).
+    ///
+    /// The image is `width` by `height` pixels in the same format as the framebuffer, with no
+    /// padding between rows. The cursor is at most 64 by 64 pixels, and smaller images are padded
+    /// with transparent pixels. `hot_x` and `hot_y` give the pixel of the image which is placed at
+    /// the cursor position.
+    ///
+    /// The cursor resource is created the first time this is called, and reused afterwards.
+    ///
+    /// Returns [`Error::InvalidParam`] if the image is larger than 64 by 64 pixels, its length
+    /// doesn't match `width` and `height`, or the hotspot is outside it.
     pub fn setup_cursor(
         &mut self,
-        cursor_image: &[u8],
-        pos_x: u32,
-        pos_y: u32,
+        image: &[u8],
+        width: u32,
+        height: u32,
         hot_x: u32,
         hot_y: u32,
     ) -> Result {
-        let size = CURSOR_RECT.width * CURSOR_RECT.height * 4;
-        if cursor_image.len() != size as usize {
+        if width > CURSOR_RECT.width
+            || height > CURSOR_RECT.height
+            || hot_x >= width
+            || hot_y >= height
+            || image.len() != (width * height * 4) as usize
+        {
             return Err(Error::InvalidParam);
         }
-        let cursor_buffer_dma = Dma::new(pages(size as usize), BufferDirection::DriverToDevice)?;
+
+        if self.cursor_buffer_dma.is_none() {
+            let size = CURSOR_RECT.width * CURSOR_RECT.height * 4;
+            let cursor_buffer_dma =
+                Dma::new(pages(size as usize), BufferDirection::DriverToDevice)?;
+            self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_RECT.width, CURSOR_RECT.height)?;
+            self.resource_attach_backing(
+                RESOURCE_ID_CURSOR,
+                cursor_buffer_dma.paddr() as u64,
+                size,
+            )?;
+            self.cursor_buffer_dma = Some(cursor_buffer_dma);
+        }
+        let cursor_buffer_dma = self.cursor_buffer_dma.as_ref().unwrap();
+        // SAFETY: The device only reads the cursor buffer during `transfer_to_host_2d`, and the
+        // buffer is not otherwise accessed by the driver.
         let buf = unsafe { cursor_buffer_dma.raw_slice().as_mut() };
-        buf.copy_from_slice(cursor_image);
+        buf.fill(0);
+        let row_len = width as usize * 4;
+        for (src, dst) in image
+            .chunks_exact(row_len)
+            .zip(buf.chunks_exact_mut(CURSOR_RECT.width as usize * 4))
+        {
+            dst[..row_len].copy_from_slice(src);
+        }
 
-        self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_RECT.width, CURSOR_RECT.height)?;
-        self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_dma.paddr() as u64, size)?;
         self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
+        let (pos_x, pos_y) = self.cursor_pos;
         self.update_cursor(
             RESOURCE_ID_CURSOR,
             SCANOUT_ID,
@@ -158,13 +574,12 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
             hot_x,
             hot_y,
             false,
-        )?;
-        self.cursor_buffer_dma = Some(cursor_buffer_dma);
-        Ok(())
+        )
     }
 
     /// Move the pointer without updating the shape.
     pub fn move_cursor(&mut self, pos_x: u32, pos_y: u32) -> Result {
+        self.cursor_pos = (pos_x, pos_y);
         self.update_cursor(RESOURCE_ID_CURSOR, SCANOUT_ID, pos_x, pos_y, 0, 0, true)?;
         Ok(())
     }
@@ -172,11 +587,14 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     /// Send a request to the device and block for a response.
     fn request<Req: IntoBytes + Immutable, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
         req.write_to_prefix(&mut self.queue_buf_send).unwrap();
-        self.control_queue.add_notify_wait_pop(
+        self.last_response_len = self.control_queue.add_notify_wait_pop(
             &[&self.queue_buf_send],
             &mut [&mut self.queue_buf_recv],
             &mut self.transport,
         )?;
+        if (self.last_response_len as usize) < size_of::<CtrlHeader>() {
+            return Err(Error::IoError);
+        }
         Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap().0)
     }
 
@@ -209,6 +627,15 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         rsp.check_type(Command::OK_NODATA)
     }
 
+    fn resource_unref(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceUnref {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNREF),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
     fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
         let rsp: CtrlHeader = self.request(SetScanout {
             header: CtrlHeader::with_type(Command::SET_SCANOUT),
@@ -252,6 +679,15 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         rsp.check_type(Command::OK_NODATA)
     }
 
+    fn resource_detach_backing(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceDetachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_DETACH_BACKING),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
     fn update_cursor(
         &mut self,
         resource_id: u32,
@@ -282,6 +718,32 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     }
 }
 
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOGpu<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.transport.queue_unset(QUEUE_CURSOR);
+        // SAFETY: The device has been reset so won't access the queues again, and the buffers in
+        // them are owned by the driver.
+        unsafe {
+            self.control_queue.reclaim_all();
+            self.cursor_queue.reclaim_all();
+        }
+    }
+}
+
 impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
@@ -291,6 +753,40 @@ impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
     }
 }
 
+/// A framebuffer which has been set up for a scanout.
+struct Framebuffer<H: Hal> {
+    /// The size of the framebuffer, at position 0, 0.
+    rect: Rect,
+    /// DMA area of the framebuffer.
+    dma: Dma<H>,
+}
+
+/// A pair of framebuffers set up for the first scanout, to be displayed alternately.
+struct DoubleBuffer<H: Hal> {
+    /// The size of both framebuffers, at position 0, 0.
+    rect: Rect,
+    /// DMA areas of the two framebuffers, backing the resources in `DOUBLE_BUFFER_RESOURCE_IDS`.
+    _dma: [Dma<H>; 2],
+    /// The index of the framebuffer which is currently displayed.
+    front: usize,
+}
+
+/// The geometry and connection state of a scanout, as reported by the device.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScanoutInfo {
+    /// The horizontal position of the scanout in the host's display layout, in pixels.
+    pub x: u32,
+    /// The vertical position of the scanout in the host's display layout, in pixels.
+    pub y: u32,
+    /// The preferred width of the scanout in pixels.
+    pub width: u32,
+    /// The preferred height of the scanout in pixels.
+    pub height: u32,
+    /// Whether a display is connected to the scanout and enabled by the user.
+    pub enabled: bool,
+}
+
+#[derive(FromBytes, Immutable, IntoBytes)]
 #[repr(C)]
 struct Config {
     /// Signals pending events to the driver。
@@ -303,10 +799,20 @@ struct Config {
     ///
     /// Minimum value is 1, maximum value is 16.
     num_scanouts: ReadOnly<u32>,
+
+    /// Specifies the maximum number of capability sets supported by the device.
+    num_capsets: ReadOnly<u32>,
 }
 
-/// Display configuration has changed.
-const EVENT_DISPLAY: u32 = 1 << 0;
+bitflags! {
+    /// Events signalled by a GPU device through its config space.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct GpuEvents: u32 {
+        /// Display configuration has changed. The new configuration can be
+        /// fetched with `VIRTIO_GPU_CMD_GET_DISPLAY_INFO`.
+        const DISPLAY = 1 << 0;
+    }
+}
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -363,10 +869,17 @@ impl Command {
     const ERR_UNSPEC: Command = Command(0x1200);
     const ERR_OUT_OF_MEMORY: Command = Command(0x1201);
     const ERR_INVALID_SCANOUT_ID: Command = Command(0x1202);
+    const ERR_INVALID_RESOURCE_ID: Command = Command(0x1203);
 }
 
 const GPU_FLAG_FENCE: u32 = 1 << 0;
 
+/// Converts a colour given as `0xRRGGBBAA` to the bytes of a pixel in the framebuffer format.
+fn pixel_bytes(rgba: u32) -> [u8; 4] {
+    let [red, green, blue, alpha] = rgba.to_be_bytes();
+    [blue, green, red, alpha]
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, FromBytes, Immutable, IntoBytes, KnownLayout)]
 struct CtrlHeader {
@@ -389,9 +902,14 @@ impl CtrlHeader {
     }
 
     /// Return error if the type is not same as expected.
+    ///
+    /// An invalid resource ID response is reported as [`Error::ResourceLost`], as the driver only
+    /// uses resources which it has created.
     fn check_type(&self, expected: Command) -> Result {
         if self.hdr_type == expected {
             Ok(())
+        } else if self.hdr_type == Command::ERR_INVALID_RESOURCE_ID {
+            Err(Error::ResourceLost)
         } else {
             Err(Error::IoError)
         }
@@ -408,14 +926,37 @@ struct Rect {
 }
 
 #[repr(C)]
-#[derive(Debug, FromBytes, Immutable, KnownLayout)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
 struct RespDisplayInfo {
     header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct DisplayOne {
     rect: Rect,
     enabled: u32,
     flags: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct GetEdid {
+    header: CtrlHeader,
+    scanout: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct RespEdid {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+    edid: [u8; 1024],
+}
+
 #[repr(C)]
 #[derive(Debug, Immutable, IntoBytes, KnownLayout)]
 struct ResourceCreate2D {
@@ -443,6 +984,22 @@ struct ResourceAttachBacking {
     _padding: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct ResourceDetachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct ResourceUnref {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
 #[repr(C)]
 #[derive(Debug, Immutable, IntoBytes, KnownLayout)]
 struct SetScanout {
@@ -494,9 +1051,23 @@ struct UpdateCursor {
 const QUEUE_TRANSMIT: u16 = 0;
 const QUEUE_CURSOR: u16 = 1;
 
+/// The maximum number of scanouts, and so the number of entries in the display info response.
+const MAX_SCANOUTS: usize = 16;
+
 const SCANOUT_ID: u32 = 0;
 const RESOURCE_ID_FB: u32 = 0xbabe;
 const RESOURCE_ID_CURSOR: u32 = 0xdade;
+const DOUBLE_BUFFER_RESOURCE_IDS: [u32; 2] = [0xcafe, 0xcaff];
+
+/// Returns the size in bytes of a framebuffer covering the given rectangle.
+fn framebuffer_size(rect: Rect) -> usize {
+    rect.width as usize * rect.height as usize * 4
+}
+
+/// Returns the ID of the resource used for the framebuffer of the given scanout.
+fn framebuffer_resource_id(scanout: usize) -> u32 {
+    RESOURCE_ID_FB + scanout as u32
+}
 
 const CURSOR_RECT: Rect = Rect {
     x: 0,
@@ -504,3 +1075,323 @@ const CURSOR_RECT: Rect = Rect {
     width: 64,
     height: 64,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, QueueStatus, State},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::convert::TryInto;
+    use std::{sync::Mutex, thread};
+
+    type FakeGpu = VirtIOGpu<FakeHal, FakeTransport<Config>>;
+
+    /// Returns a fake GPU device with two scanouts and the given features, and its state.
+    fn fake_gpu(device_features: Features) -> (FakeGpu, Arc<Mutex<State<Config>>>) {
+        let config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: ReadOnly::new(2),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: device_features.bits(),
+            state: state.clone(),
+        };
+        (VirtIOGpu::new(transport).unwrap(), state)
+    }
+
+    /// Waits for a request on the given queue, checks that it has the given command type and
+    /// returns it, responding with `OK_NODATA` on the control queue.
+    fn expect_request(state: &Mutex<State<Config>>, queue: u16, command: Command) -> Vec<u8> {
+        State::wait_until_queue_notified(state, queue);
+        let mut received = Vec::new();
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(queue, |request| {
+                assert_eq!(
+                    CtrlHeader::read_from_prefix(&request).unwrap().0.hdr_type,
+                    command
+                );
+                received = request;
+                if queue == QUEUE_CURSOR {
+                    // Cursor commands have no response.
+                    vec![]
+                } else {
+                    CtrlHeader::with_type(Command::OK_NODATA)
+                        .as_bytes()
+                        .to_vec()
+                }
+            }));
+        received
+    }
+
+    /// Returns the little-endian `u32` at the given offset of a request.
+    fn field(request: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(request[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn one_command_in_flight() {
+        let (_gpu, state) = fake_gpu(Features::empty());
+        let state = state.lock().unwrap();
+
+        // Each queue only has room for one command and its response.
+        assert_eq!(state.queues[usize::from(QUEUE_TRANSMIT)].size, 2);
+        assert_eq!(state.queues[usize::from(QUEUE_CURSOR)].size, 2);
+    }
+
+    #[test]
+    fn scanouts() {
+        let (mut gpu, state) = fake_gpu(Features::empty());
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    assert_eq!(
+                        CtrlHeader::read_from_prefix(&request).unwrap().0.hdr_type,
+                        Command::GET_DISPLAY_INFO
+                    );
+                    let mut response = RespDisplayInfo::new_zeroed();
+                    response.header = CtrlHeader::with_type(Command::OK_DISPLAY_INFO);
+                    response.pmodes[0] = DisplayOne {
+                        rect: Rect {
+                            x: 0,
+                            y: 0,
+                            width: 1280,
+                            height: 800,
+                        },
+                        enabled: 1,
+                        flags: 0,
+                    };
+                    response.pmodes[1].rect = Rect {
+                        x: 1280,
+                        y: 0,
+                        width: 1024,
+                        height: 768,
+                    };
+                    // Entries beyond the number of scanouts should be ignored.
+                    response.pmodes[2].enabled = 1;
+                    response.as_bytes().to_vec()
+                }));
+        });
+
+        assert_eq!(
+            gpu.scanouts().unwrap(),
+            vec![
+                ScanoutInfo {
+                    x: 0,
+                    y: 0,
+                    width: 1280,
+                    height: 800,
+                    enabled: true,
+                },
+                ScanoutInfo {
+                    x: 1280,
+                    y: 0,
+                    width: 1024,
+                    height: 768,
+                    enabled: false,
+                },
+            ]
+        );
+        handle.join().unwrap();
+
+        assert_eq!(gpu.setup_framebuffer_on(2), Err(Error::InvalidParam));
+        assert_eq!(gpu.flush_on(1), Err(Error::NotReady));
+    }
+
+    #[test]
+    fn cursor() {
+        let (mut gpu, state) = fake_gpu(Features::empty());
+        assert_eq!(
+            gpu.setup_cursor(&[0; 65 * 4], 65, 1, 0, 0),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            gpu.setup_cursor(&[0; 16 * 16 * 4], 16, 16, 16, 0),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || {
+            let create = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_CREATE_2D);
+            assert_eq!(field(&create, 24), RESOURCE_ID_CURSOR);
+            assert_eq!(field(&create, 32), 64);
+            assert_eq!(field(&create, 36), 64);
+            let attach = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_ATTACH_BACKING);
+            assert_eq!(field(&attach, 24), RESOURCE_ID_CURSOR);
+            assert_eq!(field(&attach, 28), 1);
+            assert_eq!(field(&attach, 40), 64 * 64 * 4);
+            let transfer = expect_request(&state, QUEUE_TRANSMIT, Command::TRANSFER_TO_HOST_2D);
+            assert_eq!(field(&transfer, 48), RESOURCE_ID_CURSOR);
+            let update = expect_request(&state, QUEUE_CURSOR, Command::UPDATE_CURSOR);
+            assert_eq!(field(&update, 40), RESOURCE_ID_CURSOR);
+            assert_eq!(field(&update, 44), 3);
+            assert_eq!(field(&update, 48), 4);
+
+            let update = expect_request(&state, QUEUE_CURSOR, Command::MOVE_CURSOR);
+            assert_eq!(field(&update, 28), 100);
+            assert_eq!(field(&update, 32), 200);
+        });
+
+        assert_eq!(gpu.setup_cursor(&[0xff; 16 * 16 * 4], 16, 16, 3, 4), Ok(()));
+        assert_eq!(gpu.move_cursor(100, 200), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn get_edid() {
+        let (mut gpu, _) = fake_gpu(Features::empty());
+        assert_eq!(gpu.get_edid(0), Err(Error::Unsupported));
+
+        let (mut gpu, state) = fake_gpu(Features::EDID);
+        assert_eq!(gpu.get_edid(2), Err(Error::InvalidParam));
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    assert_eq!(
+                        request[..size_of::<GetEdid>()],
+                        *GetEdid {
+                            header: CtrlHeader::with_type(Command::GET_EDID),
+                            scanout: 1,
+                            _padding: 0,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = RespEdid::new_zeroed();
+                    response.header = CtrlHeader::with_type(Command::OK_EDID);
+                    response.size = 3;
+                    response.edid[..4].copy_from_slice(&[1, 2, 3, 4]);
+                    response.as_bytes().to_vec()
+                }));
+        });
+        assert_eq!(gpu.get_edid(1), Ok(vec![1, 2, 3]));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn set_resolution() {
+        let (mut gpu, state) = fake_gpu(Features::empty());
+        assert_eq!(gpu.set_resolution(0, 600), Err(Error::InvalidParam));
+        assert_eq!(
+            gpu.set_resolution(0x10000, 0x10000),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || {
+            for (width, height) in [(640, 480), (800, 600)] {
+                let create = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_CREATE_2D);
+                assert_eq!(field(&create, 24), RESOURCE_ID_FB);
+                assert_eq!(field(&create, 32), width);
+                assert_eq!(field(&create, 36), height);
+                expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_ATTACH_BACKING);
+                let set_scanout = expect_request(&state, QUEUE_TRANSMIT, Command::SET_SCANOUT);
+                assert_eq!(field(&set_scanout, 32), width);
+                assert_eq!(field(&set_scanout, 44), RESOURCE_ID_FB);
+                if width == 640 {
+                    // The old framebuffer is torn down before the new one is created.
+                    let set_scanout = expect_request(&state, QUEUE_TRANSMIT, Command::SET_SCANOUT);
+                    assert_eq!(field(&set_scanout, 44), 0);
+                    expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_DETACH_BACKING);
+                    let unref = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_UNREF);
+                    assert_eq!(field(&unref, 24), RESOURCE_ID_FB);
+                }
+            }
+        });
+
+        assert_eq!(gpu.set_resolution(640, 480).unwrap().len(), 640 * 480 * 4);
+        assert_eq!(gpu.set_resolution(800, 600).unwrap().len(), 800 * 600 * 4);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn flush_rect() {
+        let (mut gpu, state) = fake_gpu(Features::empty());
+        assert_eq!(gpu.flush_rect(0, 0, 1, 1), Err(Error::NotReady));
+
+        let handle = thread::spawn(move || {
+            expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_CREATE_2D);
+            expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_ATTACH_BACKING);
+            expect_request(&state, QUEUE_TRANSMIT, Command::SET_SCANOUT);
+            let transfer = expect_request(&state, QUEUE_TRANSMIT, Command::TRANSFER_TO_HOST_2D);
+            let rect = [10, 20, 30, 40];
+            for (i, value) in rect.into_iter().enumerate() {
+                assert_eq!(field(&transfer, 24 + i * 4), value);
+            }
+            // The offset is (20 * 640 + 10) * 4.
+            assert_eq!(field(&transfer, 40), 51240);
+            let flush = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_FLUSH);
+            for (i, value) in rect.into_iter().enumerate() {
+                assert_eq!(field(&flush, 24 + i * 4), value);
+            }
+        });
+
+        gpu.set_resolution(640, 480).unwrap();
+        assert_eq!(gpu.flush_rect(600, 0, 41, 1), Err(Error::InvalidParam));
+        assert_eq!(gpu.flush_rect(0, 1, 1, u32::MAX), Err(Error::InvalidParam));
+        assert_eq!(gpu.flush_rect(10, 20, 30, 40), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn double_buffer() {
+        let (mut gpu, state) = fake_gpu(Features::empty());
+        assert_eq!(gpu.flip(), Err(Error::NotReady));
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |_| {
+                    let mut response = RespDisplayInfo::new_zeroed();
+                    response.header = CtrlHeader::with_type(Command::OK_DISPLAY_INFO);
+                    response.pmodes[0].rect.width = 320;
+                    response.pmodes[0].rect.height = 200;
+                    response.as_bytes().to_vec()
+                }));
+            for resource_id in DOUBLE_BUFFER_RESOURCE_IDS {
+                let create = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_CREATE_2D);
+                assert_eq!(field(&create, 24), resource_id);
+                let attach =
+                    expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_ATTACH_BACKING);
+                assert_eq!(field(&attach, 24), resource_id);
+            }
+            let set_scanout = expect_request(&state, QUEUE_TRANSMIT, Command::SET_SCANOUT);
+            assert_eq!(field(&set_scanout, 44), DOUBLE_BUFFER_RESOURCE_IDS[0]);
+
+            // Each flip transfers and displays the back buffer.
+            for resource_id in [DOUBLE_BUFFER_RESOURCE_IDS[1], DOUBLE_BUFFER_RESOURCE_IDS[0]] {
+                let transfer = expect_request(&state, QUEUE_TRANSMIT, Command::TRANSFER_TO_HOST_2D);
+                assert_eq!(field(&transfer, 48), resource_id);
+                let set_scanout = expect_request(&state, QUEUE_TRANSMIT, Command::SET_SCANOUT);
+                assert_eq!(field(&set_scanout, 44), resource_id);
+                let flush = expect_request(&state, QUEUE_TRANSMIT, Command::RESOURCE_FLUSH);
+                assert_eq!(field(&flush, 40), resource_id);
+            }
+        });
+
+        let (first, second) = gpu.setup_double_buffer().unwrap();
+        assert_eq!(first.len(), 320 * 200 * 4);
+        assert_eq!(second.len(), 320 * 200 * 4);
+        assert_eq!(gpu.flip(), Ok(0));
+        assert_eq!(gpu.flip(), Ok(1));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Parser for EDID (Extended Display Identification Data), to find the display modes supported by
+//! a scanout.
+
+use crate::{Error, Result};
+use alloc::vec::Vec;
+
+/// The length in bytes of the EDID base block and of each extension block.
+const BLOCK_LEN: usize = 128;
+/// The fixed header at the start of the EDID base block.
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+/// The offset of the established timings bitmap in the base block.
+const ESTABLISHED_TIMINGS_OFFSET: usize = 35;
+/// The offset of the standard timings in the base block.
+const STANDARD_TIMINGS_OFFSET: usize = 38;
+/// The number of standard timings in the base block.
+const STANDARD_TIMINGS_COUNT: usize = 8;
+/// The offset of the first detailed timing descriptor in the base block.
+const DESCRIPTORS_OFFSET: usize = 54;
+/// The length in bytes of a detailed timing descriptor.
+const DESCRIPTOR_LEN: usize = 18;
+/// The offset of the extension block count in the base block.
+const EXTENSION_COUNT_OFFSET: usize = 126;
+/// The tag of a CTA-861 extension block.
+const CTA_EXTENSION_TAG: u8 = 0x02;
+
+/// The modes corresponding to each bit of the established timings bitmap, starting with the most
+/// significant bit of the first byte.
+const ESTABLISHED_TIMINGS: [Option<Mode>; 17] = [
+    Some(Mode::new(720, 400, 70)),
+    Some(Mode::new(720, 400, 88)),
+    Some(Mode::new(640, 480, 60)),
+    Some(Mode::new(640, 480, 67)),
+    Some(Mode::new(640, 480, 72)),
+    Some(Mode::new(640, 480, 75)),
+    Some(Mode::new(800, 600, 56)),
+    Some(Mode::new(800, 600, 60)),
+    Some(Mode::new(800, 600, 72)),
+    Some(Mode::new(800, 600, 75)),
+    Some(Mode::new(832, 624, 75)),
+    // 1024x768 at 87 Hz is interlaced, so not included.
+    None,
+    Some(Mode::new(1024, 768, 60)),
+    Some(Mode::new(1024, 768, 70)),
+    Some(Mode::new(1024, 768, 75)),
+    Some(Mode::new(1280, 1024, 75)),
+    Some(Mode::new(1152, 870, 75)),
+];
+
+/// A display mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Mode {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The refresh rate in Hz, rounded to the nearest integer.
+    pub refresh: u32,
+}
+
+impl Mode {
+    const fn new(width: u32, height: u32, refresh: u32) -> Self {
+        Self {
+            width,
+            height,
+            refresh,
+        }
+    }
+}
+
+/// Parses the given EDID data, consisting of a base block optionally followed by extension
+/// blocks, and returns the display modes it lists.
+///
+/// Modes from detailed timing descriptors are listed first, as the first of these is the
+/// display's preferred mode. Duplicate modes are omitted. Extension blocks other than CTA-861
+/// blocks are ignored, as are any extension blocks which are missing from the data.
+///
+/// Returns [`Error::InvalidParam`] if the base block is too short, doesn't start with the EDID
+/// header, or has an invalid checksum.
+pub fn parse_modes(edid: &[u8]) -> Result<Vec<Mode>> {
+    let base = edid.get(..BLOCK_LEN).ok_or(Error::InvalidParam)?;
+    if base[..HEADER.len()] != HEADER || !checksum_valid(base) {
+        return Err(Error::InvalidParam);
+    }
+
+    let mut modes = Vec::new();
+    for descriptor in base[DESCRIPTORS_OFFSET..EXTENSION_COUNT_OFFSET].chunks_exact(DESCRIPTOR_LEN)
+    {
+        if let Some(mode) = parse_detailed_timing(descriptor) {
+            push_unique(&mut modes, mode);
+        }
+    }
+    for extension in edid[BLOCK_LEN..]
+        .chunks_exact(BLOCK_LEN)
+        .take(base[EXTENSION_COUNT_OFFSET].into())
+    {
+        // An offset of 0 in byte 2 means the block has no detailed timings or data blocks.
+        if extension[0] == CTA_EXTENSION_TAG && extension[2] != 0 && checksum_valid(extension) {
+            // Detailed timing descriptors start at the offset given in byte 2, and continue until
+            // padding or the checksum at the end of the block. A malformed offset past the
+            // checksum just means there are none.
+            let start = usize::from(extension[2]).clamp(4, BLOCK_LEN - 1);
+            for descriptor in extension[start..BLOCK_LEN - 1].chunks_exact(DESCRIPTOR_LEN) {
+                if let Some(mode) = parse_detailed_timing(descriptor) {
+                    push_unique(&mut modes, mode);
+                }
+            }
+        }
+    }
+    for timing in base[STANDARD_TIMINGS_OFFSET..]
+        .chunks_exact(2)
+        .take(STANDARD_TIMINGS_COUNT)
+    {
+        if let Some(mode) = parse_standard_timing(timing[0], timing[1]) {
+            push_unique(&mut modes, mode);
+        }
+    }
+    for (i, mode) in ESTABLISHED_TIMINGS.iter().enumerate() {
+        let byte = base[ESTABLISHED_TIMINGS_OFFSET + i / 8];
+        if let Some(mode) = mode {
+            if byte & (0x80 >> (i % 8)) != 0 {
+                push_unique(&mut modes, *mode);
+            }
+        }
+    }
+    Ok(modes)
+}
+
+/// Returns whether the bytes of the given block sum to 0.
+fn checksum_valid(block: &[u8]) -> bool {
+    block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Adds the given mode to the list, if it is not already present.
+fn push_unique(modes: &mut Vec<Mode>, mode: Mode) {
+    if !modes.contains(&mode) {
+        modes.push(mode);
+    }
+}
+
+/// Parses an 18 byte detailed timing descriptor, returning `None` if it is some other kind of
+/// descriptor or describes an interlaced mode.
+fn parse_detailed_timing(descriptor: &[u8]) -> Option<Mode> {
+    // The pixel clock is in units of 10 kHz; 0 indicates a display descriptor.
+    let pixel_clock = u32::from(u16::from_le_bytes([descriptor[0], descriptor[1]])) * 10_000;
+    if pixel_clock == 0 || descriptor[17] & 0x80 != 0 {
+        return None;
+    }
+    let width = u32::from(descriptor[2]) | u32::from(descriptor[4] & 0xf0) << 4;
+    let h_blank = u32::from(descriptor[3]) | u32::from(descriptor[4] & 0x0f) << 8;
+    let height = u32::from(descriptor[5]) | u32::from(descriptor[7] & 0xf0) << 4;
+    let v_blank = u32::from(descriptor[6]) | u32::from(descriptor[7] & 0x0f) << 8;
+    let total = (width + h_blank) * (height + v_blank);
+    if total == 0 {
+        return None;
+    }
+    Some(Mode::new(width, height, (pixel_clock + total / 2) / total))
+}
+
+/// Parses a 2 byte standard timing, returning `None` if it is unused.
+fn parse_standard_timing(first: u8, second: u8) -> Option<Mode> {
+    if first == 0x00 || (first == 0x01 && second == 0x01) {
+        return None;
+    }
+    let width = (u32::from(first) + 31) * 8;
+    let height = match second >> 6 {
+        0b00 => width * 10 / 16,
+        0b01 => width * 3 / 4,
+        0b10 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+    Some(Mode::new(width, height, u32::from(second & 0x3f) + 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Returns a base block with a 1920x1080 detailed timing, a 1280x1024 standard timing and the
+    /// 640x480 established timing.
+    fn base_block() -> [u8; BLOCK_LEN] {
+        let mut block = [0; BLOCK_LEN];
+        block[..HEADER.len()].copy_from_slice(&HEADER);
+        block[ESTABLISHED_TIMINGS_OFFSET] = 0x20;
+        for timing in block[STANDARD_TIMINGS_OFFSET..DESCRIPTORS_OFFSET].chunks_exact_mut(2) {
+            timing.copy_from_slice(&[0x01, 0x01]);
+        }
+        block[STANDARD_TIMINGS_OFFSET..STANDARD_TIMINGS_OFFSET + 2].copy_from_slice(&[0x81, 0x80]);
+        block[DESCRIPTORS_OFFSET..DESCRIPTORS_OFFSET + 8]
+            .copy_from_slice(&[0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40]);
+        set_checksum(&mut block);
+        block
+    }
+
+    fn set_checksum(block: &mut [u8; BLOCK_LEN]) {
+        block[BLOCK_LEN - 1] = 0;
+        let sum = block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        block[BLOCK_LEN - 1] = sum.wrapping_neg();
+    }
+
+    #[test]
+    fn base_block_modes() {
+        assert_eq!(
+            parse_modes(&base_block()).unwrap(),
+            vec![
+                Mode::new(1920, 1080, 60),
+                Mode::new(1280, 1024, 60),
+                Mode::new(640, 480, 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse_modes(&[0; 64]), Err(Error::InvalidParam));
+        let mut block = base_block();
+        block[20] ^= 1;
+        assert_eq!(parse_modes(&block), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn extensions() {
+        let mut base = base_block();
+        base[EXTENSION_COUNT_OFFSET] = 2;
+        set_checksum(&mut base);
+
+        // An unknown extension, which should be ignored.
+        let mut unknown = [0xab; BLOCK_LEN];
+        set_checksum(&mut unknown);
+
+        // A CTA-861 extension with a 1280x720 detailed timing.
+        let mut cta = [0; BLOCK_LEN];
+        cta[0] = CTA_EXTENSION_TAG;
+        cta[1] = 3;
+        cta[2] = 4;
+        cta[4..12].copy_from_slice(&[0x01, 0x1d, 0x00, 0x72, 0x51, 0xd0, 0x1e, 0x20]);
+        set_checksum(&mut cta);
+
+        let edid = [base, unknown, cta].concat();
+        assert_eq!(
+            parse_modes(&edid).unwrap(),
+            vec![
+                Mode::new(1920, 1080, 60),
+                Mode::new(1280, 720, 60),
+                Mode::new(1280, 1024, 60),
+                Mode::new(640, 480, 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_cta_extension() {
+        let mut base = base_block();
+        base[EXTENSION_COUNT_OFFSET] = 1;
+        set_checksum(&mut base);
+
+        // A CTA-861 extension claiming its detailed timings start past the end of the block.
+        let mut cta = [0; BLOCK_LEN];
+        cta[0] = CTA_EXTENSION_TAG;
+        cta[1] = 3;
+        cta[2] = 0xff;
+        set_checksum(&mut cta);
+
+        let edid = [base, cta].concat();
+        assert_eq!(
+            parse_modes(&edid).unwrap(),
+            vec![
+                Mode::new(1920, 1080, 60),
+                Mode::new(1280, 1024, 60),
+                Mode::new(640, 480, 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_cta_extension() {
+        let mut base = base_block();
+        base[EXTENSION_COUNT_OFFSET] = 1;
+        set_checksum(&mut base);
+
+        // A CTA-861 extension with no detailed timings, but what would be one at offset 4.
+        let mut cta = [0; BLOCK_LEN];
+        cta[0] = CTA_EXTENSION_TAG;
+        cta[1] = 3;
+        cta[2] = 0;
+        cta[4..12].copy_from_slice(&[0x01, 0x1d, 0x00, 0x72, 0x51, 0xd0, 0x1e, 0x20]);
+        set_checksum(&mut cta);
+
+        let edid = [base, cta].concat();
+        assert_eq!(
+            parse_modes(&edid).unwrap(),
+            vec![
+                Mode::new(1920, 1080, 60),
+                Mode::new(1280, 1024, 60),
+                Mode::new(640, 480, 60),
+            ]
+        );
+    }
+}
@@ -1,13 +1,15 @@
 //! Driver for VirtIO input devices.
 
 use super::common::Feature;
+use super::VirtioDevice;
 use crate::config::{read_config, write_config, ReadOnly, WriteOnly};
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
+use crate::queue::{Queue, VirtQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::Error;
 use alloc::{boxed::Box, string::String};
 use core::cmp::min;
+use core::hint::spin_loop;
 use core::mem::{offset_of, size_of};
 use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
@@ -16,17 +18,36 @@ use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 /// An instance of the virtio device represents one such input device.
 /// Device behavior mirrors that of the evdev layer in Linux,
 /// making pass-through implementations on top of evdev easy.
-pub struct VirtIOInput<H: Hal, T: Transport> {
+///
+/// The driver takes ownership of the transport, so it is the only consumer of the device's events
+/// for as long as it exists, much like an exclusive grab of an evdev device. A second transport for
+/// the same device can only be created with `unsafe`, and the caller of the transport constructor
+/// is responsible for not doing so. Creating the driver resets the device first, so a device left
+/// running by an earlier driver, e.g. before a reboot, can still be used. Dropping the driver
+/// resets the device too.
+///
+/// `EVENT_QUEUE_SIZE` is the number of event buffers posted to the device, and so the number of
+/// events which can be buffered between calls to
+/// [`pop_pending_event`](Self::pop_pending_event). It must be a power of two, and no more than
+/// the maximum queue size supported by the device.
+pub struct VirtIOInput<H: Hal, T: Transport, const EVENT_QUEUE_SIZE: usize = { QUEUE_SIZE }> {
     transport: T,
-    event_queue: VirtQueue<H, QUEUE_SIZE>,
-    status_queue: VirtQueue<H, QUEUE_SIZE>,
-    event_buf: Box<[InputEvent; 32]>,
+    event_queue: VirtQueue<H, EVENT_QUEUE_SIZE>,
+    /// The queue for sending status events such as LED changes to the device, or `None` if the
+    /// device doesn't provide one.
+    status_queue: Option<VirtQueue<H, QUEUE_SIZE>>,
+    event_buf: Box<[InputEvent; EVENT_QUEUE_SIZE]>,
+    /// The state of the device accumulated from the events received so far.
+    state: InputState,
 }
 
-impl<H: Hal, T: Transport> VirtIOInput<H, T> {
+impl<H: Hal, T: Transport, const EVENT_QUEUE_SIZE: usize> VirtIOInput<H, T, EVENT_QUEUE_SIZE> {
     /// Create a new VirtIO-Input driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if the device doesn't support an event queue of size
+    /// `EVENT_QUEUE_SIZE`.
     pub fn new(mut transport: T) -> Result<Self, Error> {
-        let mut event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
+        let mut event_buf = Box::new([InputEvent::default(); EVENT_QUEUE_SIZE]);
 
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
@@ -36,19 +57,23 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             negotiated_features.contains(Feature::RING_INDIRECT_DESC),
             negotiated_features.contains(Feature::RING_EVENT_IDX),
         )?;
-        let status_queue = VirtQueue::new(
+        let status_queue = match VirtQueue::new_clamped(
             &mut transport,
             QUEUE_STATUS,
             negotiated_features.contains(Feature::RING_INDIRECT_DESC),
             negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
+        ) {
+            Ok(status_queue) => Some(status_queue),
+            Err(Error::NoUsableQueue) => None,
+            Err(e) => return Err(e),
+        };
         for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
             // Safe because the buffer lasts as long as the queue.
             let token = unsafe { event_queue.add(&[], &mut [event.as_mut_bytes()])? };
             assert_eq!(token, i as u16);
         }
         if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT);
+            event_queue.notify(&mut transport);
         }
 
         transport.finish_init();
@@ -58,41 +83,138 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             event_queue,
             status_queue,
             event_buf,
+            state: InputState::default(),
         })
     }
 
     /// Acknowledge interrupt and process events.
-    pub fn ack_interrupt(&mut self) -> bool {
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
     }
 
     /// Pop the pending event.
+    ///
+    /// This is the same as [`poll_event`](Self::poll_event), except that errors are treated as
+    /// there being no pending event.
     pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
-        if let Some(token) = self.event_queue.peek_used() {
+        self.poll_event().ok().flatten()
+    }
+
+    /// Returns the next event sent by the device, or `None` if there are no pending events.
+    ///
+    /// This doesn't block. The buffer which held the event is returned to the device before this
+    /// returns, so the device never runs out of buffers for new events.
+    pub fn poll_event(&mut self) -> Result<Option<InputEvent>, Error> {
+        let Some(token) = self.event_queue.peek_used() else {
+            return Ok(None);
+        };
+        let event = &mut self.event_buf[token as usize];
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
+        // is still valid.
+        unsafe {
+            self.event_queue
+                .pop_used(token, &[], &mut [event.as_mut_bytes()])?;
+        }
+        let event_saved = *event;
+        self.state.update(&event_saved);
+        // requeue
+        // Safe because buffer lasts as long as the queue.
+        let new_token = unsafe { self.event_queue.add(&[], &mut [event.as_mut_bytes()]) }?;
+        // This only works because nothing happen between `pop_used` and `add` that affects
+        // the list of free descriptors in the queue, so `add` reuses the descriptor which
+        // was just freed by `pop_used`.
+        assert_eq!(new_token, token);
+        if self.event_queue.should_notify() {
+            self.event_queue.notify(&mut self.transport);
+        }
+        Ok(Some(event_saved))
+    }
+
+    /// Waits until the device sends an event, and returns it.
+    ///
+    /// This busy-waits, acknowledging any interrupts from the device while it waits. As for
+    /// [`poll_event`](Self::poll_event), the buffer is returned to the device automatically.
+    pub fn next_event(&mut self) -> Result<InputEvent, Error> {
+        loop {
+            self.transport.ack_interrupt();
+            if let Some(event) = self.poll_event()? {
+                return Ok(event);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Acknowledges any pending interrupt, then calls `f` for each pending event.
+    ///
+    /// The event buffers are only returned to the device after all the callbacks have been called,
+    /// so they can't be overwritten while being processed. This doesn't allocate, so may be called
+    /// from an interrupt handler.
+    ///
+    /// Returns an error if a used event buffer can't be popped from the queue or returned to the
+    /// device, after returning the buffers which were popped.
+    pub fn handle_interrupt(&mut self, mut f: impl FnMut(InputEvent)) -> Result<(), Error> {
+        self.transport.ack_interrupt();
+
+        let mut tokens = [0; EVENT_QUEUE_SIZE];
+        let mut count = 0;
+        let mut result = Ok(());
+        while let Some(token) = self.event_queue.peek_used() {
             let event = &mut self.event_buf[token as usize];
             // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
             // is still valid.
-            unsafe {
+            if let Err(e) = unsafe {
                 self.event_queue
                     .pop_used(token, &[], &mut [event.as_mut_bytes()])
-                    .ok()?;
+            } {
+                result = Err(e);
+                break;
             }
-            let event_saved = *event;
-            // requeue
+            self.state.update(event);
+            f(*event);
+            tokens[count] = token;
+            count += 1;
+        }
+
+        // Requeue the buffers in the reverse order to which they were popped, so that each `add`
+        // reuses the descriptor which was most recently freed by `pop_used`, which is the one
+        // which previously held the same buffer.
+        for &token in tokens[..count].iter().rev() {
+            let event = &mut self.event_buf[token as usize];
             // Safe because buffer lasts as long as the queue.
-            if let Ok(new_token) = unsafe { self.event_queue.add(&[], &mut [event.as_mut_bytes()]) }
-            {
-                // This only works because nothing happen between `pop_used` and `add` that affects
-                // the list of free descriptors in the queue, so `add` reuses the descriptor which
-                // was just freed by `pop_used`.
-                assert_eq!(new_token, token);
-                if self.event_queue.should_notify() {
-                    self.transport.notify(QUEUE_EVENT);
-                }
-                return Some(event_saved);
-            }
+            let new_token = unsafe { self.event_queue.add(&[], &mut [event.as_mut_bytes()]) }?;
+            assert_eq!(new_token, token);
+        }
+        if count > 0 && self.event_queue.should_notify() {
+            self.event_queue.notify(&mut self.transport);
         }
-        None
+        result
+    }
+
+    /// Sends a status event to the device, and waits for the device to consume it.
+    ///
+    /// This is used to update the state of outputs of the device, for example to turn on the
+    /// caps lock LED of a keyboard with an `EV_LED` event, or to play force feedback effects with
+    /// `EV_FF` events. The event type, code and value are as for the evdev layer in Linux.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't provide a status queue.
+    pub fn send_status(&mut self, event_type: u16, code: u16, value: u32) -> Result<(), Error> {
+        let status_queue = self.status_queue.as_mut().ok_or(Error::Unsupported)?;
+        let event = InputEvent {
+            event_type,
+            code,
+            value,
+        };
+        status_queue.add_notify_wait_pop(&[event.as_bytes()], &mut [], &mut self.transport)?;
+        Ok(())
+    }
+
+    /// Returns a snapshot of which keys are currently pressed and the last known value of each
+    /// absolute axis, accumulated from the events the driver has received so far.
+    ///
+    /// The device doesn't report its initial state, so this only reflects events since the driver
+    /// was created.
+    pub fn state_snapshot(&self) -> InputState {
+        self.state.clone()
     }
 
     /// Query a specific piece of information by `select` and `subsel`, and write
@@ -186,6 +308,32 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         self.query_config_select_alloc(InputConfigSelect::EvBits, event_type)
     }
 
+    /// Queries and returns the set of event types supported by the device, indexed by evdev event
+    /// type such as `EV_KEY` or `EV_ABS`.
+    ///
+    /// This can be used to tell what kind of device it is, e.g. a keyboard supports `EV_KEY` but
+    /// not `EV_REL` or `EV_ABS`, while a tablet supports `EV_ABS`.
+    pub fn supported_events(&mut self) -> Result<EvBitmap, Error> {
+        let mut bitmap = [0; EV_COUNT / 8];
+        for event_type in 0..EV_COUNT as u8 {
+            // The device returns an empty bitmap for unsupported event types.
+            let mut code_bits = [0; 1];
+            if self.query_config_select(InputConfigSelect::EvBits, event_type, &mut code_bits)? != 0
+            {
+                bitmap[usize::from(event_type) / 8] |= 1 << (event_type % 8);
+            }
+        }
+        Ok(EvBitmap(Box::new(bitmap)))
+    }
+
+    /// Queries and returns the set of event codes supported for the given event type, such as the
+    /// keys for `EV_KEY` or the axes for `EV_ABS`.
+    ///
+    /// The set is empty if the event type is not supported.
+    pub fn event_codes(&mut self, event_type: u8) -> Result<EvBitmap, Error> {
+        Ok(EvBitmap(self.ev_bits(event_type)?))
+    }
+
     /// Queries and returns information about the given axis of the device.
     pub fn abs_info(&mut self, axis: u8) -> Result<AbsInfo, Error> {
         let mut info = AbsInfo::default();
@@ -197,22 +345,82 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             Err(Error::IoError)
         }
     }
+
+    /// Returns the latest absolute pointer position reported by the device, such as a tablet or
+    /// touchscreen, scaled from the range of each axis into `0..=max_x` and `0..=max_y`, e.g. to
+    /// map it to screen coordinates.
+    ///
+    /// The range of the `ABS_X` and `ABS_Y` axes is queried with [`abs_info`](Self::abs_info).
+    /// Returns `None` if no value has been received for either axis yet, as for
+    /// [`InputState::abs_value`].
+    pub fn absolute_position(
+        &mut self,
+        max_x: u32,
+        max_y: u32,
+    ) -> Result<Option<(u32, u32)>, Error> {
+        let (Some(x), Some(y)) = (self.state.abs_value(ABS_X), self.state.abs_value(ABS_Y)) else {
+            return Ok(None);
+        };
+        let x = self.abs_info(ABS_X)?.scale(x, max_x);
+        let y = self.abs_info(ABS_Y)?.scale(y, max_y);
+        Ok(Some((x, y)))
+    }
 }
 
 // SAFETY: The config space can be accessed from any thread.
-unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOInput<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Send
+unsafe impl<H: Hal, T: Transport + Send, const EVENT_QUEUE_SIZE: usize> Send
+    for VirtIOInput<H, T, EVENT_QUEUE_SIZE>
+where
+    VirtQueue<H, EVENT_QUEUE_SIZE>: Send,
+    VirtQueue<H, QUEUE_SIZE>: Send,
 {
 }
 
 // SAFETY: An '&VirtIOInput` can't do anything, all methods take `&mut self`.
-unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOInput<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Sync
+unsafe impl<H: Hal, T: Transport + Sync, const EVENT_QUEUE_SIZE: usize> Sync
+    for VirtIOInput<H, T, EVENT_QUEUE_SIZE>
+where
+    VirtQueue<H, EVENT_QUEUE_SIZE>: Sync,
+    VirtQueue<H, QUEUE_SIZE>: Sync,
 {
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
+impl<H: Hal, T: Transport, const EVENT_QUEUE_SIZE: usize> VirtioDevice
+    for VirtIOInput<H, T, EVENT_QUEUE_SIZE>
+{
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.transport.queue_unset(QUEUE_EVENT);
+        self.transport.queue_unset(QUEUE_STATUS);
+        // SAFETY: The device has been reset so won't access the queues again, and the buffers in
+        // them are owned by the driver.
+        unsafe {
+            self.event_queue.reclaim_all();
+            if let Some(status_queue) = &mut self.status_queue {
+                status_queue.reclaim_all();
+            }
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const EVENT_QUEUE_SIZE: usize> Drop
+    for VirtIOInput<H, T, EVENT_QUEUE_SIZE>
+{
     fn drop(&mut self) {
+        // Reset the device so that it no longer looks to be in use by a driver.
+        self.transport.reset();
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
         self.transport.queue_unset(QUEUE_EVENT);
@@ -273,6 +481,44 @@ pub struct AbsInfo {
     pub res: u32,
 }
 
+impl AbsInfo {
+    /// Scales the given value of the axis from `min..=max` into `0..=target_max`, clamping it to
+    /// the range of the axis first.
+    pub fn scale(&self, value: u32, target_max: u32) -> u32 {
+        if self.max <= self.min {
+            return 0;
+        }
+        let offset = value.clamp(self.min, self.max) - self.min;
+        (u64::from(offset) * u64::from(target_max) / u64::from(self.max - self.min)) as u32
+    }
+}
+
+/// A bitmap returned by an input device, such as of the event types or event codes which it
+/// supports.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EvBitmap(Box<[u8]>);
+
+impl EvBitmap {
+    /// Returns whether the given bit is set, e.g. whether the event type or code with the given
+    /// number is supported.
+    pub fn contains(&self, bit: u16) -> bool {
+        let bit = usize::from(bit);
+        self.0
+            .get(bit / 8)
+            .is_some_and(|&byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Returns an iterator over the numbers of the bits which are set.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..(self.0.len() * 8) as u16).filter(|&bit| self.contains(bit))
+    }
+
+    /// Returns the raw bitmap, with bit 0 being the least significant bit of the first byte.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 /// The identifiers of a VirtIO input device.
 #[repr(C)]
 #[derive(Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
@@ -290,7 +536,9 @@ pub struct DevIDs {
 /// Both queues use the same `virtio_input_event` struct. `type`, `code` and `value`
 /// are filled according to the Linux input layer (evdev) interface.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[derive(
+    Clone, Copy, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
 pub struct InputEvent {
     /// Event type.
     pub event_type: u16,
@@ -300,9 +548,92 @@ pub struct InputEvent {
     pub value: u32,
 }
 
+/// The evdev event type for key and button state changes.
+const EV_KEY: u16 = 0x01;
+/// The evdev event type for absolute axis value changes.
+const EV_ABS: u16 = 0x03;
+/// The evdev code for the X axis.
+const ABS_X: u8 = 0x00;
+/// The evdev code for the Y axis.
+const ABS_Y: u8 = 0x01;
+/// The number of evdev event types.
+const EV_COUNT: usize = 0x20;
+/// The number of evdev key codes.
+const KEY_COUNT: usize = 0x300;
+/// The number of evdev absolute axes.
+const ABS_COUNT: usize = 0x40;
+
+/// The state of an input device, accumulated from the events it has sent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InputState {
+    /// Bitmap of the keys which are currently pressed, indexed by key code.
+    keys: [u8; KEY_COUNT / 8],
+    /// The last known value of each absolute axis.
+    abs_values: [u32; ABS_COUNT],
+    /// Bitmap of the absolute axes for which a value has been received.
+    abs_known: u64,
+}
+
+impl InputState {
+    /// Returns whether the key or button with the given evdev code is currently pressed.
+    pub fn key_pressed(&self, code: u16) -> bool {
+        let code = usize::from(code);
+        code < KEY_COUNT && self.keys[code / 8] & (1 << (code % 8)) != 0
+    }
+
+    /// Returns an iterator over the evdev codes of all keys and buttons which are currently
+    /// pressed.
+    pub fn pressed_keys(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..KEY_COUNT as u16).filter(|&code| self.key_pressed(code))
+    }
+
+    /// Returns the last known value of the given absolute axis, or `None` if no value has been
+    /// received for it.
+    pub fn abs_value(&self, axis: u8) -> Option<u32> {
+        let axis = usize::from(axis);
+        if axis < ABS_COUNT && self.abs_known & (1 << axis) != 0 {
+            Some(self.abs_values[axis])
+        } else {
+            None
+        }
+    }
+
+    /// Updates the state according to the given event.
+    fn update(&mut self, event: &InputEvent) {
+        let code = usize::from(event.code);
+        match event.event_type {
+            EV_KEY if code < KEY_COUNT => {
+                // A value of 0 is a release, 1 is a press and 2 is an autorepeat.
+                if event.value == 0 {
+                    self.keys[code / 8] &= !(1 << (code % 8));
+                } else {
+                    self.keys[code / 8] |= 1 << (code % 8);
+                }
+            }
+            EV_ABS if code < ABS_COUNT => {
+                self.abs_values[code] = event.value;
+                self.abs_known |= 1 << code;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        Self {
+            keys: [0; KEY_COUNT / 8],
+            abs_values: [0; ABS_COUNT],
+            abs_known: 0,
+        }
+    }
+}
+
 const QUEUE_EVENT: u16 = 0;
 const QUEUE_STATUS: u16 = 1;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX.union(Feature::RING_INDIRECT_DESC);
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_INDIRECT_DESC)
+    .union(Feature::NOTIFICATION_DATA);
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
@@ -314,15 +645,15 @@ mod tests {
         hal::fake::FakeHal,
         transport::{
             fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
+            DeviceStatus, DeviceType,
         },
     };
-    use alloc::{sync::Arc, vec};
-    use core::convert::TryInto;
-    use std::sync::Mutex;
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{convert::TryInto, time::Duration};
+    use std::{sync::Mutex, thread};
 
-    #[test]
-    fn config() {
+    /// Returns a fake input device with an event queue and a status queue, and its state.
+    fn fake_transport() -> (FakeTransport<Config>, Arc<Mutex<State<Config>>>) {
         const DEFAULT_DATA: ReadOnly<u8> = ReadOnly::new(0);
         let config_space = Config {
             select: WriteOnly::default(),
@@ -336,11 +667,55 @@ mod tests {
             config_space,
         )));
         let transport = FakeTransport {
-            device_type: DeviceType::Block,
+            device_type: DeviceType::Input,
             max_queue_size: QUEUE_SIZE.try_into().unwrap(),
             device_features: 0,
             state: state.clone(),
         };
+        (transport, state)
+    }
+
+    #[test]
+    fn event_queue_size() {
+        // The device doesn't support a queue this big.
+        let (transport, _) = fake_transport();
+        assert_eq!(
+            VirtIOInput::<FakeHal, FakeTransport<Config>, 64>::new(transport).err(),
+            Some(Error::InvalidParam)
+        );
+
+        let (transport, state) = fake_transport();
+        let input = VirtIOInput::<FakeHal, FakeTransport<Config>, 8>::new(transport).unwrap();
+        assert_eq!(state.lock().unwrap().queues[QUEUE_EVENT as usize].size, 8);
+
+        // Dropping the driver resets the device.
+        drop(input);
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+    }
+
+    #[test]
+    fn new_resets_running_device() {
+        // A device left running by an earlier driver is reset and set up again.
+        let (transport, state) = fake_transport();
+        state.lock().unwrap().status = DeviceStatus::ACKNOWLEDGE
+            | DeviceStatus::DRIVER
+            | DeviceStatus::FEATURES_OK
+            | DeviceStatus::DRIVER_OK;
+        let _input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+        assert_ne!(
+            state.lock().unwrap().queues[QUEUE_EVENT as usize].descriptors,
+            0
+        );
+    }
+
+    #[test]
+    fn config() {
+        let (transport, state) = fake_transport();
         let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
 
         set_data(
@@ -411,6 +786,180 @@ mod tests {
         assert_eq!(state.lock().unwrap().config_space.subsel.0, 5);
     }
 
+    #[test]
+    fn supported_events() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // The fake device returns the same data for every event type.
+        assert_eq!(input.supported_events().unwrap().iter().count(), 0);
+        set_data(&mut state.lock().unwrap().config_space, &[0x06, 0x01]);
+        let events = input.supported_events().unwrap();
+        assert_eq!(events.iter().count(), EV_COUNT);
+        assert!(events.contains(EV_ABS));
+        assert!(!events.contains(EV_COUNT as u16));
+
+        let codes = input.event_codes(EV_KEY as u8).unwrap();
+        assert_eq!(codes.iter().collect::<Vec<_>>(), vec![1, 2, 8]);
+        assert_eq!(codes.as_bytes(), &[0x06, 0x01]);
+        assert_eq!(state.lock().unwrap().config_space.subsel.0, EV_KEY as u8);
+    }
+
+    #[test]
+    fn absolute_position() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        // The fake device returns the same range for both axes.
+        let abs_info = AbsInfo {
+            min: 0,
+            max: 0x7fff,
+            fuzz: 0,
+            flat: 0,
+            res: 0,
+        };
+        set_data(&mut state.lock().unwrap().config_space, abs_info.as_bytes());
+
+        assert_eq!(input.absolute_position(1920, 1080), Ok(None));
+        for (code, value) in [(ABS_X, 0x7fff), (ABS_Y, 0x4000)] {
+            let event = InputEvent {
+                event_type: EV_ABS,
+                code: code.into(),
+                value,
+            };
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        }
+        while input.poll_event().unwrap().is_some() {}
+        assert_eq!(input.absolute_position(1920, 1080), Ok(Some((1920, 540))));
+    }
+
+    #[test]
+    fn send_status() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Turn on the caps lock LED.
+        let event = InputEvent {
+            event_type: 0x11,
+            code: 0x01,
+            value: 1,
+        };
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_STATUS);
+            assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                QUEUE_STATUS,
+                |request| {
+                    assert_eq!(request, event.as_bytes());
+                    vec![]
+                }
+            ));
+        });
+        assert_eq!(input.send_status(0x11, 0x01, 1), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn handle_interrupt() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Send more events than there are buffers in total, in batches, to check that the buffers
+        // are requeued after each batch.
+        for batch in 0..3 {
+            let events: Vec<_> = (0..QUEUE_SIZE as u32 - 1)
+                .map(|i| InputEvent {
+                    event_type: 1,
+                    code: batch,
+                    value: i,
+                })
+                .collect();
+            for event in &events {
+                state
+                    .lock()
+                    .unwrap()
+                    .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+            }
+            state.lock().unwrap().interrupt_status = InterruptStatus::USED_BUFFER;
+
+            let mut received = Vec::new();
+            input
+                .handle_interrupt(|event| received.push(event))
+                .unwrap();
+            assert_eq!(received, events);
+            assert!(state.lock().unwrap().interrupt_status.is_empty());
+        }
+    }
+
+    #[test]
+    fn poll_and_next_event() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(input.poll_event(), Ok(None));
+
+        // Send more events than there are buffers, to check that each buffer is requeued.
+        for i in 0..QUEUE_SIZE as u32 * 2 {
+            let event = InputEvent {
+                event_type: 2,
+                code: 0,
+                value: i,
+            };
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+            assert_eq!(input.poll_event(), Ok(Some(event)));
+        }
+        assert_eq!(input.poll_event(), Ok(None));
+
+        let event = InputEvent {
+            event_type: 1,
+            code: 42,
+            value: 1,
+        };
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        });
+        assert_eq!(input.next_event(), Ok(event));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn state_snapshot() {
+        let (transport, state) = fake_transport();
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(input.state_snapshot(), InputState::default());
+
+        for (event_type, code, value) in [
+            (EV_KEY, 30, 1),
+            (EV_KEY, 31, 1),
+            (EV_ABS, 1, 42),
+            (EV_KEY, 30, 0),
+        ] {
+            let event = InputEvent {
+                event_type,
+                code,
+                value,
+            };
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        }
+        while input.pop_pending_event().is_some() {}
+
+        let snapshot = input.state_snapshot();
+        assert_eq!(snapshot.pressed_keys().collect::<Vec<_>>(), vec![31]);
+        assert!(!snapshot.key_pressed(30));
+        assert_eq!(snapshot.abs_value(0), None);
+        assert_eq!(snapshot.abs_value(1), Some(42));
+    }
+
     fn set_data(config_space: &mut Config, value: &[u8]) {
         config_space.size.0 = value.len().try_into().unwrap();
         for (i, &byte) in value.into_iter().enumerate() {
@@ -1,5 +1,7 @@
 //! Drivers for specific VirtIO devices.
 
+#[cfg(feature = "alloc")]
+pub mod balloon;
 pub mod blk;
 #[cfg(feature = "alloc")]
 pub mod console;
@@ -9,9 +11,51 @@ pub mod gpu;
 pub mod input;
 
 pub mod net;
+pub mod rng;
 
 pub mod socket;
 #[cfg(feature = "alloc")]
 pub mod sound;
 
 pub(crate) mod common;
+
+use crate::transport::{DeviceType, InterruptStatus};
+use crate::Result;
+
+/// Operations common to the VirtIO device drivers, so that code managing several devices can treat
+/// them uniformly, e.g. as `Box<dyn VirtioDevice>`.
+pub trait VirtioDevice {
+    /// Returns the type of the device being driven.
+    fn device_type(&self) -> DeviceType;
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns which interrupts were pending.
+    fn ack_interrupt(&mut self) -> InterruptStatus;
+
+    /// Resets the device immediately, discarding any requests in flight.
+    ///
+    /// The driver can't be used after this, and should be dropped and created again to use the
+    /// device.
+    fn reset(&mut self);
+
+    /// Completes any writes the driver has buffered, then resets the device.
+    ///
+    /// The device is reset even if completing the writes fails. As with [`reset`](Self::reset),
+    /// the driver can't be used afterwards.
+    fn shutdown(&mut self) -> Result {
+        self.reset();
+        Ok(())
+    }
+
+    /// Resets the device and its queues, waiting until the device has stopped accessing them.
+    ///
+    /// Once this returns the device won't access any queue or buffer given to it by the driver, and
+    /// any buffers still in the queues have been unshared with [`Hal::unshare`](crate::Hal::unshare),
+    /// so it is safe to free or unmap the DMA memory backing them, including buffers passed to
+    /// non-blocking requests which never completed. Calling this more than once has no further
+    /// effect. As with [`reset`](Self::reset), the driver can't be used afterwards.
+    fn drain(&mut self) {
+        self.reset();
+    }
+}
@@ -0,0 +1,91 @@
+//! Helpers for calculating internet checksums in software, for when checksum offload is not
+//! negotiated with the device.
+//!
+//! To fill in the checksum of a TCP or UDP segment sent over IPv4, zero its checksum field, then
+//! calculate:
+//!
+//! ```
+//! # use virtio_drivers::device::net::checksum::{ipv4_pseudo_header_checksum, ones_complement_sum};
+//! # let (source, destination, protocol) = ([10, 0, 0, 1], [10, 0, 0, 2], 17);
+//! # let segment = [0x12, 0x34, 0x56, 0x78, 0x00, 0x0a, 0x00, 0x00, 0x42, 0x43];
+//! let pseudo_header =
+//!     ipv4_pseudo_header_checksum(source, destination, protocol, segment.len() as u16);
+//! let checksum = !ones_complement_sum(pseudo_header, &segment);
+//! ```
+//!
+//! A received segment can be verified by calculating the same sum over it including its checksum
+//! field, which should give `0xffff`.
+
+/// Adds the given data to a running 16-bit ones' complement sum, treating it as a sequence of
+/// big-endian 16-bit words.
+///
+/// If the data has an odd length it is padded with a zero byte. The result is not complemented,
+/// so it may be passed back in to continue the sum over more data.
+pub fn ones_complement_sum(initial: u16, data: &[u8]) -> u16 {
+    let mut sum = u32::from(initial);
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(u16::from_be_bytes([*last, 0]));
+    }
+    fold(sum)
+}
+
+/// Calculates the ones' complement sum of the IPv4 pseudo-header used for TCP and UDP checksums.
+///
+/// `length` is the length in bytes of the TCP or UDP header and payload. The result is not
+/// complemented, so it may be used both as the initial value for [`ones_complement_sum`] over the
+/// segment, and as the partial checksum the device expects when `VIRTIO_NET_HDR_F_NEEDS_CSUM` is
+/// used.
+pub fn ipv4_pseudo_header_checksum(
+    source: [u8; 4],
+    destination: [u8; 4],
+    protocol: u8,
+    length: u16,
+) -> u16 {
+    let sum = ones_complement_sum(0, &source);
+    let sum = ones_complement_sum(sum, &destination);
+    let [length_high, length_low] = length.to_be_bytes();
+    ones_complement_sum(sum, &[0, protocol, length_high, length_low])
+}
+
+/// Folds the carries of a 32-bit sum back into the low 16 bits.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc1071_example() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(ones_complement_sum(0, &data), 0xddf2);
+        // Splitting the data shouldn't change the result.
+        assert_eq!(
+            ones_complement_sum(ones_complement_sum(0, &data[..4]), &data[4..]),
+            0xddf2
+        );
+    }
+
+    #[test]
+    fn udp_checksum_verifies() {
+        let source = [192, 168, 0, 1];
+        let destination = [192, 168, 0, 2];
+        let mut segment = [
+            0x04, 0xd2, 0x16, 0x2e, 0x00, 0x0b, 0x00, 0x00, 0x61, 0x62, 0x63,
+        ];
+        let pseudo_header =
+            ipv4_pseudo_header_checksum(source, destination, 17, segment.len() as u16);
+        let checksum = !ones_complement_sum(pseudo_header, &segment);
+        segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+        assert_eq!(ones_complement_sum(pseudo_header, &segment), 0xffff);
+    }
+}
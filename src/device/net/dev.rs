@@ -1,8 +1,14 @@
 use alloc::vec;
+use core::array;
 
 use super::net_buf::{RxBuffer, TxBuffer};
-use super::{EthernetAddress, VirtIONetRaw};
-use crate::{hal::Hal, transport::Transport, Error, Result};
+use super::{EthernetAddress, NetLayout, RssLimits, TsoType, VirtIONetRaw};
+use crate::device::VirtioDevice;
+use crate::{
+    hal::Hal,
+    transport::{DeviceType, InterruptStatus, Transport},
+    Error, Result,
+};
 
 /// Driver for a VirtIO network device.
 ///
@@ -17,34 +23,122 @@ use crate::{hal::Hal, transport::Transport, Error, Result};
 /// Empty buffers are placed in one virtqueue for receiving packets, and
 /// outgoing packets are enqueued into another for transmission in that order.
 /// A third command queue is used to control advanced filtering features.
-pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
-    inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
-    rx_buffers: [Option<RxBuffer>; QUEUE_SIZE],
+///
+/// `NUM_PAIRS` is the number of receive and transmit queue pairs to use, as
+/// for [`VirtIONetRaw`]. Each pair has its own receive buffers.
+pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize = 1> {
+    inner: VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>,
+    rx_buffers: [[Option<RxBuffer>; QUEUE_SIZE]; NUM_PAIRS],
+    /// The length in bytes of each receive buffer.
+    rx_buf_len: usize,
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize>
+    VirtIONet<H, T, QUEUE_SIZE, NUM_PAIRS>
+{
     /// Create a new VirtIO-Net driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if `buf_len` is less than
+    /// [`min_rx_buffer_len`](Self::min_rx_buffer_len), e.g. if it is too small
+    /// for the MTU reported by the device.
     pub fn new(transport: T, buf_len: usize) -> Result<Self> {
-        let mut inner = VirtIONetRaw::new(transport)?;
-
-        const NONE_BUF: Option<RxBuffer> = None;
-        let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
-        for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = RxBuffer::new(i, buf_len);
-            // Safe because the buffer lives as long as the queue.
-            let token = unsafe { inner.receive_begin(rx_buf.as_bytes_mut())? };
-            assert_eq!(token, i as u16);
-            *rx_buf_place = Some(rx_buf);
+        Self::with_raw(VirtIONetRaw::new(transport)?, buf_len, false)
+    }
+
+    /// Creates a new VirtIO-Net driver whose receive buffers each give the
+    /// device the [`VirtioNetHdr`](super::VirtioNetHdr) in a separate
+    /// descriptor from the packet, as for
+    /// [`VirtIONetRaw::receive_begin_split`], so each received packet starts at
+    /// the beginning of its buffer's allocation.
+    ///
+    /// `packet_buf_len` is the length of the packet part of each receive
+    /// buffer, not including the header. As each buffer takes two
+    /// descriptors, only `QUEUE_SIZE / 2` buffers are given to the device.
+    ///
+    /// Returns [`Error::Unsupported`] if `NUM_PAIRS` is more than 1 or the
+    /// hash report feature was negotiated, or [`Error::InvalidParam`] if
+    /// `packet_buf_len` is too small for the MTU reported by the device.
+    pub fn new_split(transport: T, packet_buf_len: usize) -> Result<Self> {
+        if NUM_PAIRS != 1 {
+            return Err(Error::Unsupported);
+        }
+        Self::with_raw(VirtIONetRaw::new(transport)?, packet_buf_len, true)
+    }
+
+    /// Creates a new VirtIO-Net driver, with receive buffers of the minimum
+    /// length needed for the MTU reported by the device.
+    pub fn new_auto_buffer(transport: T) -> Result<Self> {
+        let inner = VirtIONetRaw::new(transport)?;
+        let buf_len = inner.min_rx_buffer_len();
+        Self::with_raw(inner, buf_len, false)
+    }
+
+    /// Creates a new driver wrapping the given raw driver, and fills its
+    /// receive queue with buffers of the given length, split as for
+    /// [`new_split`](Self::new_split) if `split` is true.
+    fn with_raw(
+        mut inner: VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>,
+        buf_len: usize,
+        split: bool,
+    ) -> Result<Self> {
+        let mut rx_buffers = array::from_fn(|_| array::from_fn(|_| None));
+        // Split buffers take two descriptors each, so only half as many fit.
+        let step = if split { 2 } else { 1 };
+        for (pair, pair_buffers) in rx_buffers.iter_mut().enumerate() {
+            for (i, rx_buf_place) in pair_buffers.iter_mut().enumerate().step_by(step) {
+                let mut rx_buf = if split {
+                    RxBuffer::new_split(i, buf_len)
+                } else {
+                    RxBuffer::new(i, pair, buf_len, inner.hdr_len())
+                };
+                // Safe because the buffer lives as long as the queue.
+                let token = unsafe { Self::receive_begin(&mut inner, &mut rx_buf)? };
+                assert_eq!(token, i as u16);
+                *rx_buf_place = Some(rx_buf);
+            }
         }
 
-        Ok(VirtIONet { inner, rx_buffers })
+        Ok(VirtIONet {
+            inner,
+            rx_buffers,
+            rx_buf_len: buf_len,
+        })
     }
 
     /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> bool {
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.inner.ack_interrupt()
     }
 
+    /// Re-reads the link status from the device's config space.
+    ///
+    /// See [`VirtIONetRaw::refresh_status`].
+    pub fn refresh_status(&mut self) {
+        self.inner.refresh_status()
+    }
+
+    /// Returns whether the link is up.
+    ///
+    /// See [`VirtIONetRaw::link_up`].
+    pub fn link_up(&self) -> bool {
+        self.inner.link_up()
+    }
+
+    /// Returns whether the device has asked the driver to send gratuitous packets announcing its
+    /// addresses.
+    ///
+    /// See [`VirtIONetRaw::announce_requested`].
+    pub fn announce_requested(&self) -> bool {
+        self.inner.announce_requested()
+    }
+
+    /// Tells the device that the driver has sent the announcements it asked for.
+    ///
+    /// See [`VirtIONetRaw::ack_announce`].
+    pub fn ack_announce(&mut self) -> Result {
+        self.inner.ack_announce()
+    }
+
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
         self.inner.disable_interrupts()
@@ -60,6 +154,70 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.mac_address()
     }
 
+    /// Changes the device's MAC address.
+    ///
+    /// See [`VirtIONetRaw::set_mac_address`].
+    pub fn set_mac_address(&mut self, mac: EthernetAddress) -> Result {
+        self.inner.set_mac_address(mac)
+    }
+
+    /// Returns the device's limits for RSS (receive-side scaling), or `None` if
+    /// the RSS feature was not negotiated.
+    pub fn rss_limits(&self) -> Option<RssLimits> {
+        self.inner.rss_limits()
+    }
+
+    /// Configures RSS (receive-side scaling) to steer received packets to
+    /// receive queues according to a hash of their flow.
+    ///
+    /// See [`VirtIONetRaw::configure_rss`] for details.
+    pub fn configure_rss(
+        &mut self,
+        key: &[u8],
+        indirection_table: &[u16],
+        hash_types: u32,
+    ) -> Result {
+        self.inner.configure_rss(key, indirection_table, hash_types)
+    }
+
+    /// Enables or disables promiscuous mode.
+    ///
+    /// See [`VirtIONetRaw::set_promiscuous`].
+    pub fn set_promiscuous(&mut self, on: bool) -> Result {
+        self.inner.set_promiscuous(on)
+    }
+
+    /// Enables or disables receiving all multicast packets.
+    ///
+    /// See [`VirtIONetRaw::set_all_multicast`].
+    pub fn set_all_multicast(&mut self, on: bool) -> Result {
+        self.inner.set_all_multicast(on)
+    }
+
+    /// Returns the maximum MTU reported by the device, or 1500 if the MTU
+    /// feature was not negotiated.
+    ///
+    /// See [`VirtIONetRaw::mtu`].
+    pub fn mtu(&self) -> u16 {
+        self.inner.mtu()
+    }
+
+    /// Returns the minimum length of a receive buffer, including the header,
+    /// which can hold any packet the device may send.
+    pub fn min_rx_buffer_len(&self) -> usize {
+        self.inner.min_rx_buffer_len()
+    }
+
+    /// Returns the layout of the buffers used by the driver.
+    pub fn layout(&self) -> NetLayout {
+        NetLayout {
+            hdr_len: self.inner.hdr_len(),
+            rx_buffers: QUEUE_SIZE * NUM_PAIRS,
+            tx_buffers: QUEUE_SIZE * NUM_PAIRS,
+            rx_buffer_len: self.rx_buf_len,
+        }
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
         self.inner.can_send()
@@ -67,7 +225,16 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
 
     /// Whether can receive packet.
     pub fn can_recv(&self) -> bool {
-        self.inner.poll_receive().is_some()
+        self.can_recv_on(0)
+    }
+
+    /// Whether a packet can be received on the given queue pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn can_recv_on(&self, pair: usize) -> bool {
+        self.inner.poll_receive_on(pair).is_some()
     }
 
     /// Receives a [`RxBuffer`] from network. If currently no data, returns an
@@ -76,8 +243,18 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     /// It will try to pop a buffer that completed data reception in the
     /// NIC queue.
     pub fn receive(&mut self) -> Result<RxBuffer> {
-        if let Some(token) = self.inner.poll_receive() {
-            let mut rx_buf = self.rx_buffers[token as usize]
+        self.receive_on(0)
+    }
+
+    /// Like [`receive`](Self::receive), but receives from the given queue
+    /// pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn receive_on(&mut self, pair: usize) -> Result<RxBuffer> {
+        if let Some(token) = self.inner.poll_receive_on(pair) {
+            let mut rx_buf = self.rx_buffers[pair][token as usize]
                 .take()
                 .ok_or(Error::WrongToken)?;
             if token != rx_buf.idx {
@@ -86,8 +263,16 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
 
             // Safe because `token` == `rx_buf.idx`, we are passing the same
             // buffer as we passed to `VirtQueue::add` and it is still valid.
-            let (_hdr_len, pkt_len) =
-                unsafe { self.inner.receive_complete(token, rx_buf.as_bytes_mut())? };
+            let pkt_len = unsafe {
+                if rx_buf.is_split() {
+                    let (hdr, payload) = rx_buf.split_mut();
+                    self.inner.receive_complete_split(token, hdr, payload)?
+                } else {
+                    self.inner
+                        .receive_complete_on(pair, token, rx_buf.as_bytes_mut())?
+                        .1
+                }
+            };
             rx_buf.set_packet_len(pkt_len);
             Ok(rx_buf)
         } else {
@@ -97,21 +282,56 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
 
     /// Gives back the ownership of `rx_buf`, and recycles it for next use.
     ///
-    /// It will add the buffer back to the NIC queue.
+    /// It will add the buffer back to the NIC queue it was received from.
     pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+        let pair = rx_buf.pair;
         // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
         // it lives as long as the queue.
-        let new_token = unsafe { self.inner.receive_begin(rx_buf.as_bytes_mut()) }?;
+        let new_token = unsafe { Self::receive_begin(&mut self.inner, &mut rx_buf) }?;
         // `rx_buffers[new_token]` is expected to be `None` since it was taken
         // away at `Self::receive()` and has not been added back.
-        if self.rx_buffers[new_token as usize].is_some() {
+        if self.rx_buffers[pair][new_token as usize].is_some() {
             return Err(Error::WrongToken);
         }
         rx_buf.idx = new_token;
-        self.rx_buffers[new_token as usize] = Some(rx_buf);
+        self.rx_buffers[pair][new_token as usize] = Some(rx_buf);
         Ok(())
     }
 
+    /// Gives `rx_buf` to the device to receive a packet on its queue pair.
+    ///
+    /// # Safety
+    ///
+    /// `rx_buf` must not be accessed or dropped until the device has finished
+    /// with it.
+    unsafe fn receive_begin(
+        inner: &mut VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>,
+        rx_buf: &mut RxBuffer,
+    ) -> Result<u16> {
+        if rx_buf.is_split() {
+            let (hdr, payload) = rx_buf.split_mut();
+            inner.receive_begin_split(hdr, payload)
+        } else {
+            inner.receive_begin_on(rx_buf.pair, rx_buf.as_bytes_mut())
+        }
+    }
+
+    /// Discards all packets which have been received but not yet returned by
+    /// [`receive`](Self::receive), and gives their buffers back to the device.
+    ///
+    /// Returns the number of packets discarded, across all queue pairs.
+    pub fn flush_rx(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for pair in 0..NUM_PAIRS {
+            while self.inner.poll_receive_on(pair).is_some() {
+                let rx_buf = self.receive_on(pair)?;
+                self.recycle_rx_buffer(rx_buf)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     /// Allocate a new buffer for transmitting.
     pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer {
         TxBuffer(vec![0; buf_len])
@@ -122,4 +342,63 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
         self.inner.send(tx_buf.packet())
     }
+
+    /// Like [`send`](Self::send), but sends on the given queue pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn send_on(&mut self, pair: usize, tx_buf: TxBuffer) -> Result {
+        self.inner.send_on(pair, tx_buf.packet())
+    }
+
+    /// Sends a [`TxBuffer`] to the network like [`send`](Self::send), but asks
+    /// the device to calculate its TCP or UDP checksum.
+    ///
+    /// See [`VirtIONetRaw::send_with_csum`].
+    pub fn send_with_csum(
+        &mut self,
+        tx_buf: TxBuffer,
+        csum_start: u16,
+        csum_offset: u16,
+    ) -> Result {
+        self.inner
+            .send_with_csum(tx_buf.packet(), csum_start, csum_offset)
+    }
+
+    /// Sends a [`TxBuffer`] containing a TCP packet larger than the MTU, and
+    /// asks the device to segment it.
+    ///
+    /// See [`VirtIONetRaw::send_gso`].
+    pub fn send_gso(
+        &mut self,
+        tx_buf: TxBuffer,
+        tso_type: TsoType,
+        gso_size: u16,
+        hdr_len: u16,
+        csum_start: u16,
+    ) -> Result {
+        self.inner
+            .send_gso(tx_buf.packet(), tso_type, gso_size, hdr_len, csum_start)
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize> VirtioDevice
+    for VirtIONet<H, T, QUEUE_SIZE, NUM_PAIRS>
+{
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.inner.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn drain(&mut self) {
+        self.inner.drain()
+    }
 }
@@ -1,13 +1,31 @@
-use super::{Config, EthernetAddress, Features, VirtioNetHdr};
-use super::{MIN_BUFFER_LEN, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES};
+use super::CTRL_MQ_VQ_PAIRS_SET;
+use super::{Config, CtrlHeader, EthernetAddress, Features, RssConfigHeader, RssLimits, RxHash};
+use super::{
+    Flags, GsoType, TsoType, CTRL_CLASS_MAC, CTRL_MAC_ADDR_SET, DEFAULT_MTU, MIN_BUFFER_LEN,
+};
+use super::{Status, VirtioNetHdr, CTRL_ANNOUNCE_ACK, CTRL_CLASS_ANNOUNCE, CTRL_CLASS_MQ};
+use super::{
+    VirtioNetHdrHash, ETHERNET_HEADER_LEN, NET_HDR_HASH_SIZE, NET_HDR_SIZE, QUEUE_CONTROL,
+    QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES,
+};
+use super::{CTRL_CLASS_RX, CTRL_MQ_RSS_CONFIG, CTRL_OK, CTRL_RX_ALLMULTI, CTRL_RX_PROMISC};
 use crate::config::read_config;
+use crate::device::VirtioDevice;
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
+use crate::queue::{Queue, VirtQueue};
+use crate::transport::{DeviceStatus, DeviceType, InterruptStatus, Transport};
 use crate::{Error, Result};
+use core::array;
 use log::{debug, info, warn};
 use zerocopy::IntoBytes;
 
+/// The maximum number of parts of the command specific data passed to
+/// `send_ctrl_command`.
+const MAX_CTRL_DATA_PARTS: usize = 4;
+
+/// The offset of the checksum field within a TCP header.
+const TCP_CHECKSUM_OFFSET: u16 = 16;
+
 /// Raw driver for a VirtIO network device.
 ///
 /// This is a raw version of the VirtIONet driver. It provides non-blocking
@@ -15,63 +33,239 @@ use zerocopy::IntoBytes;
 /// management. For more higher-level functions such as receive buffer backing,
 /// see [`VirtIONet`].
 ///
+/// `NUM_PAIRS` is the number of receive and transmit queue pairs to use, which
+/// requires the `VIRTIO_NET_F_MQ` feature if it is more than 1. Methods without
+/// a `_on` suffix use the first pair.
+///
 /// [`VirtIONet`]: super::VirtIONet
-pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize = 1> {
     transport: T,
+    negotiated_features: Features,
     mac: EthernetAddress,
-    recv_queue: VirtQueue<H, QUEUE_SIZE>,
-    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The status most recently read from the config space, if the status feature was negotiated.
+    status: Option<Status>,
+    /// The maximum MTU reported by the device, if the MTU feature was negotiated.
+    mtu: Option<u16>,
+    rss_limits: Option<RssLimits>,
+    /// The length of the header which precedes each packet, which depends on
+    /// the negotiated features.
+    hdr_len: usize,
+    recv_queues: [VirtQueue<H, QUEUE_SIZE>; NUM_PAIRS],
+    send_queues: [VirtQueue<H, QUEUE_SIZE>; NUM_PAIRS],
+    /// The control queue, if the `VIRTIO_NET_F_CTRL_VQ` feature was negotiated.
+    ctrl_queue: Option<VirtQueue<H, QUEUE_SIZE>>,
+    /// The index of the control queue, which follows all the queue pairs the
+    /// device supports.
+    ctrl_queue_idx: u16,
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize>
+    VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>
+{
     /// Create a new VirtIO-Net driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if `NUM_PAIRS` is 0 or more than the
+    /// device supports.
     pub fn new(mut transport: T) -> Result<Self> {
+        if NUM_PAIRS == 0 {
+            return Err(Error::InvalidParam);
+        }
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
         info!("negotiated_features {:?}", negotiated_features);
+        let device_pairs = if negotiated_features.intersects(Features::MQ | Features::RSS) {
+            read_config!(transport, Config, max_virtqueue_pairs)?
+        } else {
+            1
+        };
+        if NUM_PAIRS > usize::from(device_pairs)
+            || (NUM_PAIRS > 1 && !negotiated_features.contains(Features::MQ))
+        {
+            return Err(Error::InvalidParam);
+        }
 
         // Read configuration space.
         let mac = transport.read_consistent(|| read_config!(transport, Config, mac))?;
-        let status = read_config!(transport, Config, status)?;
+        let status = if negotiated_features.contains(Features::STATUS) {
+            Some(read_config!(transport, Config, status)?)
+        } else {
+            None
+        };
         debug!("Got MAC={:02x?}, status={:?}", mac, status);
+        let mtu = if negotiated_features.contains(Features::MTU) {
+            Some(read_config!(transport, Config, mtu)?)
+        } else {
+            None
+        };
+        let rss_limits = if negotiated_features.contains(Features::RSS) {
+            Some(transport.read_consistent(|| {
+                Ok(RssLimits {
+                    max_key_size: read_config!(transport, Config, rss_max_key_size)?,
+                    max_indirection_table_length: read_config!(
+                        transport,
+                        Config,
+                        rss_max_indirection_table_length
+                    )?,
+                    supported_hash_types: read_config!(transport, Config, supported_hash_types)?,
+                })
+            })?)
+        } else {
+            None
+        };
+        let hdr_len = if negotiated_features.contains(Features::HASH_REPORT) {
+            NET_HDR_HASH_SIZE
+        } else {
+            NET_HDR_SIZE
+        };
 
-        let send_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMIT,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let recv_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_RECEIVE,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+        let send_queues = array::from_fn(|pair| {
+            VirtQueue::new(
+                &mut transport,
+                transmit_queue_idx(pair),
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )
+        });
+        let recv_queues = array::from_fn(|pair| {
+            VirtQueue::new(
+                &mut transport,
+                receive_queue_idx(pair),
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )
+        });
+        let ctrl_queue_idx = QUEUE_CONTROL * device_pairs;
+        let ctrl_queue = negotiated_features.contains(Features::CTRL_VQ).then(|| {
+            VirtQueue::new(
+                &mut transport,
+                ctrl_queue_idx,
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )
+        });
+        if let Some(&error) = send_queues
+            .iter()
+            .chain(&recv_queues)
+            .chain(&ctrl_queue)
+            .find_map(|queue| queue.as_ref().err())
+        {
+            // Disable the queues which were set up, so the device doesn't access them after they
+            // are freed.
+            for pair in 0..NUM_PAIRS {
+                if send_queues[pair].is_ok() {
+                    transport.queue_unset(transmit_queue_idx(pair));
+                }
+                if recv_queues[pair].is_ok() {
+                    transport.queue_unset(receive_queue_idx(pair));
+                }
+            }
+            if let Some(Ok(_)) = ctrl_queue {
+                transport.queue_unset(ctrl_queue_idx);
+            }
+            return Err(error);
+        }
+        let send_queues = send_queues.map(Result::unwrap);
+        let recv_queues = recv_queues.map(Result::unwrap);
+        let ctrl_queue = ctrl_queue.map(Result::unwrap);
 
         transport.finish_init();
 
-        Ok(VirtIONetRaw {
+        let mut net = VirtIONetRaw {
             transport,
+            negotiated_features,
             mac,
-            recv_queue,
-            send_queue,
-        })
+            status,
+            mtu,
+            rss_limits,
+            hdr_len,
+            recv_queues,
+            send_queues,
+            ctrl_queue,
+            ctrl_queue_idx,
+        };
+        if NUM_PAIRS > 1 {
+            // The device only uses the first pair until told otherwise.
+            net.send_ctrl_command(
+                CTRL_CLASS_MQ,
+                CTRL_MQ_VQ_PAIRS_SET,
+                &[&(NUM_PAIRS as u16).to_le_bytes()],
+            )?;
+        }
+        Ok(net)
     }
 
     /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+    ///
+    /// This also refreshes the link status reported by [`link_up`](Self::link_up) and
+    /// [`announce_requested`](Self::announce_requested) if the device raised a configuration change
+    /// interrupt, as it does when they change.
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
+        let status = self.transport.ack_interrupt();
+        if status.contains(InterruptStatus::CONFIG_CHANGE) {
+            self.refresh_status();
+        }
+        status
+    }
+
+    /// Re-reads the link status from the device's config space.
+    ///
+    /// This is done automatically by [`ack_interrupt`](Self::ack_interrupt), but may be called
+    /// directly by a driver which polls rather than handling interrupts.
+    pub fn refresh_status(&mut self) {
+        if self.status.is_some() {
+            match read_config!(self.transport, Config, status) {
+                Ok(status) => self.status = Some(status),
+                Err(e) => warn!("Failed to read net status: {:?}", e),
+            }
+        }
+    }
+
+    /// Returns whether the link is up.
+    ///
+    /// If the `VIRTIO_NET_F_STATUS` feature was not negotiated then the link is always assumed to
+    /// be up.
+    pub fn link_up(&self) -> bool {
+        self.status
+            .is_none_or(|status| status.contains(Status::LINK_UP))
+    }
+
+    /// Returns whether the device has asked the driver to send gratuitous packets announcing its
+    /// addresses, e.g. after live migration.
+    ///
+    /// Once the packets have been sent the driver should call
+    /// [`ack_announce`](Self::ack_announce).
+    pub fn announce_requested(&self) -> bool {
+        self.negotiated_features.contains(Features::GUEST_ANNOUNCE)
+            && self
+                .status
+                .is_some_and(|status| status.contains(Status::ANNOUNCE))
+    }
+
+    /// Tells the device that the driver has sent the announcements it asked for, which clears the
+    /// request reported by [`announce_requested`](Self::announce_requested).
+    ///
+    /// Returns [`Error::Unsupported`] if the `VIRTIO_NET_F_GUEST_ANNOUNCE` feature was not
+    /// negotiated.
+    pub fn ack_announce(&mut self) -> Result {
+        if !self.negotiated_features.contains(Features::GUEST_ANNOUNCE) {
+            return Err(Error::Unsupported);
+        }
+        self.send_ctrl_command(CTRL_CLASS_ANNOUNCE, CTRL_ANNOUNCE_ACK, &[])?;
+        self.refresh_status();
+        Ok(())
     }
 
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
-        self.send_queue.set_dev_notify(false);
-        self.recv_queue.set_dev_notify(false);
+        for queue in self.send_queues.iter_mut().chain(&mut self.recv_queues) {
+            queue.set_dev_notify(false);
+        }
     }
 
     /// Enable interrupts.
     pub fn enable_interrupts(&mut self) {
-        self.send_queue.set_dev_notify(true);
-        self.recv_queue.set_dev_notify(true);
+        for queue in self.send_queues.iter_mut().chain(&mut self.recv_queues) {
+            queue.set_dev_notify(true);
+        }
     }
 
     /// Get MAC address.
@@ -79,14 +273,216 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         self.mac
     }
 
+    /// Changes the device's MAC address, which is then returned by
+    /// [`mac_address`](Self::mac_address).
+    ///
+    /// Returns [`Error::Unsupported`] if the `VIRTIO_NET_F_CTRL_MAC_ADDR`
+    /// feature was not negotiated.
+    pub fn set_mac_address(&mut self, mac: EthernetAddress) -> Result {
+        if !self.negotiated_features.contains(Features::CTL_MAC_ADDR) {
+            return Err(Error::Unsupported);
+        }
+        self.send_ctrl_command(CTRL_CLASS_MAC, CTRL_MAC_ADDR_SET, &[&mac])?;
+        self.mac = mac;
+        Ok(())
+    }
+
+    /// Returns the device's limits for RSS (receive-side scaling), or `None` if
+    /// the RSS feature was not negotiated.
+    pub fn rss_limits(&self) -> Option<RssLimits> {
+        self.rss_limits
+    }
+
+    /// Configures RSS (receive-side scaling) to steer received packets to
+    /// receive queues according to a hash of their flow.
+    ///
+    /// `hash_types` is the bitmask of hash types to calculate, `key` is the
+    /// Toeplitz hash key, and `indirection_table` maps the low bits of the hash
+    /// to receive queue indices. Its length must be a power of two.
+    ///
+    /// Returns [`Error::Unsupported`] if RSS was not negotiated, or
+    /// [`Error::InvalidParam`] if the parameters exceed the limits reported by
+    /// [`rss_limits`](Self::rss_limits).
+    pub fn configure_rss(
+        &mut self,
+        key: &[u8],
+        indirection_table: &[u16],
+        hash_types: u32,
+    ) -> Result {
+        let limits = self.rss_limits.ok_or(Error::Unsupported)?;
+        let receive_queues = NUM_PAIRS as u16;
+        if key.len() > usize::from(limits.max_key_size)
+            || indirection_table.len() > usize::from(limits.max_indirection_table_length)
+            || !indirection_table.len().is_power_of_two()
+            || indirection_table
+                .iter()
+                .any(|&queue| queue >= receive_queues)
+            || hash_types & !limits.supported_hash_types != 0
+        {
+            return Err(Error::InvalidParam);
+        }
+
+        let rss_header = RssConfigHeader {
+            hash_types,
+            indirection_table_mask: (indirection_table.len() - 1) as u16,
+            unclassified_queue: 0,
+        };
+        let [max_tx_vq_low, max_tx_vq_high] = receive_queues.to_le_bytes();
+        let rss_footer = [max_tx_vq_low, max_tx_vq_high, key.len() as u8];
+        self.send_ctrl_command(
+            CTRL_CLASS_MQ,
+            CTRL_MQ_RSS_CONFIG,
+            &[
+                rss_header.as_bytes(),
+                indirection_table.as_bytes(),
+                &rss_footer,
+                key,
+            ],
+        )
+    }
+
+    /// Enables or disables promiscuous mode, in which the device passes all
+    /// received packets to the driver regardless of their destination address.
+    ///
+    /// Returns [`Error::Unsupported`] if the `VIRTIO_NET_F_CTRL_RX` feature was
+    /// not negotiated.
+    pub fn set_promiscuous(&mut self, on: bool) -> Result {
+        self.rx_mode_command(CTRL_RX_PROMISC, on)
+    }
+
+    /// Enables or disables receiving all multicast packets, rather than only
+    /// those for addresses in the device's multicast filter.
+    ///
+    /// Returns [`Error::Unsupported`] if the `VIRTIO_NET_F_CTRL_RX` feature was
+    /// not negotiated.
+    pub fn set_all_multicast(&mut self, on: bool) -> Result {
+        self.rx_mode_command(CTRL_RX_ALLMULTI, on)
+    }
+
+    /// Sends the given command in the receive mode class, to turn the mode on
+    /// or off.
+    fn rx_mode_command(&mut self, command: u8, on: bool) -> Result {
+        if !self.negotiated_features.contains(Features::CTRL_RX) {
+            return Err(Error::Unsupported);
+        }
+        self.send_ctrl_command(CTRL_CLASS_RX, command, &[&[on.into()]])
+    }
+
+    /// Sends the given command on the control queue and waits for the device
+    /// to acknowledge it.
+    ///
+    /// `data` is the command specific data which follows the [`CtrlHeader`],
+    /// split into up to [`MAX_CTRL_DATA_PARTS`] parts. Empty parts are skipped.
+    ///
+    /// Returns [`Error::Unsupported`] if the control queue was not negotiated,
+    /// or [`Error::IoError`] if the device doesn't acknowledge the command.
+    fn send_ctrl_command(&mut self, class: u8, command: u8, data: &[&[u8]]) -> Result {
+        let ctrl_queue = self.ctrl_queue.as_mut().ok_or(Error::Unsupported)?;
+        let header = CtrlHeader { class, command };
+        let mut inputs: [&[u8]; MAX_CTRL_DATA_PARTS + 1] = [&[]; MAX_CTRL_DATA_PARTS + 1];
+        inputs[0] = header.as_bytes();
+        let mut len = 1;
+        for part in data.iter().filter(|part| !part.is_empty()) {
+            *inputs.get_mut(len).ok_or(Error::InvalidParam)? = part;
+            len += 1;
+        }
+        let mut ack = [0xff];
+        ctrl_queue.add_notify_wait_pop(&inputs[..len], &mut [&mut ack], &mut self.transport)?;
+        if ack[0] == CTRL_OK {
+            Ok(())
+        } else {
+            warn!("Control command failed with status {}", ack[0]);
+            Err(Error::IoError)
+        }
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
-        self.send_queue.available_desc() >= 2
+        self.send_queues[0].available_desc() >= 2
+    }
+
+    /// Returns [`Error::DeviceNeedsReset`] if the device has indicated that it
+    /// needs to be reset.
+    fn check_device_status(&self) -> Result<()> {
+        if self
+            .transport
+            .get_status()
+            .contains(DeviceStatus::DEVICE_NEEDS_RESET)
+        {
+            warn!("Device needs reset");
+            Err(Error::DeviceNeedsReset)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Adds the given buffers to the queue and returns the token, or if the
+    /// queue is full returns [`Error::DeviceNeedsReset`] if that is the reason.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`VirtQueue::add`].
+    unsafe fn add_checked<'a, 'b>(
+        queue: &mut VirtQueue<H, QUEUE_SIZE>,
+        transport: &T,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        match queue.add(inputs, outputs) {
+            Err(Error::QueueFull)
+                if transport
+                    .get_status()
+                    .contains(DeviceStatus::DEVICE_NEEDS_RESET) =>
+            {
+                Err(Error::DeviceNeedsReset)
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the maximum MTU reported by the device, or 1500 if the MTU
+    /// feature was not negotiated.
+    ///
+    /// If the MTU feature was negotiated then the send and transmit methods
+    /// reject frames with more than this many bytes after the Ethernet header,
+    /// unless the device is asked to segment them.
+    pub fn mtu(&self) -> u16 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    /// Returns the length in bytes of the header which precedes each packet.
+    ///
+    /// This is the size of [`VirtioNetHdr`], unless the hash report feature
+    /// was negotiated, in which case it is larger to make space for the hash.
+    pub fn hdr_len(&self) -> usize {
+        self.hdr_len
+    }
+
+    /// Returns the flow hash which the device reported for the packet in a
+    /// completed receive buffer, or `None` if the hash report feature was not
+    /// negotiated.
+    pub fn rx_hash(&self, rx_buf: &[u8]) -> Option<RxHash> {
+        if self.hdr_len == NET_HDR_HASH_SIZE {
+            RxHash::from_header(rx_buf)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the minimum length of a receive buffer, including the header,
+    /// which can hold any packet the device may send.
+    ///
+    /// This is based on the MTU if it was negotiated.
+    pub fn min_rx_buffer_len(&self) -> usize {
+        match self.mtu {
+            Some(mtu) => MIN_BUFFER_LEN.max(self.hdr_len + ETHERNET_HEADER_LEN + usize::from(mtu)),
+            None => MIN_BUFFER_LEN,
+        }
     }
 
     /// Whether the length of the receive buffer is valid.
-    fn check_rx_buf_len(rx_buf: &[u8]) -> Result<()> {
-        if rx_buf.len() < MIN_BUFFER_LEN {
+    fn check_rx_buf_len(&self, rx_buf: &[u8]) -> Result<()> {
+        if rx_buf.len() < self.min_rx_buffer_len() {
             warn!("Receive buffer len {} is too small", rx_buf.len());
             Err(Error::InvalidParam)
         } else {
@@ -94,26 +490,108 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         }
     }
 
+    /// Whether the length of a receive payload buffer, excluding the header, is valid.
+    fn check_rx_payload_len(&self, payload: &[u8]) -> Result<()> {
+        if payload.len() < self.min_rx_buffer_len() - self.hdr_len {
+            warn!("Receive payload buffer len {} is too small", payload.len());
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Whether the length of the transmit buffer is valid.
-    fn check_tx_buf_len(tx_buf: &[u8]) -> Result<()> {
-        if tx_buf.len() < NET_HDR_SIZE {
+    fn check_tx_buf_len(&self, tx_buf: &[u8]) -> Result<()> {
+        if tx_buf.len() < self.hdr_len {
             warn!("Transmit buffer len {} is too small", tx_buf.len());
             Err(Error::InvalidParam)
         } else {
-            Ok(())
+            self.check_frame_len(GsoType(tx_buf[1]), tx_buf.len() - self.hdr_len)
         }
     }
 
-    /// Fill the header of the `buffer` with [`VirtioNetHdr`].
+    /// Checks that a frame of the given length, excluding the header, is
+    /// within the MTU if one was negotiated, unless the header has the given
+    /// GSO type asking the device to segment it.
+    fn check_frame_len(&self, gso_type: GsoType, frame_len: usize) -> Result<()> {
+        match self.mtu {
+            Some(mtu)
+                if gso_type == GsoType::NONE
+                    && frame_len > ETHERNET_HEADER_LEN + usize::from(mtu) =>
+            {
+                warn!("Frame len {} is larger than the MTU {}", frame_len, mtu);
+                Err(Error::InvalidParam)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fill the header of the `buffer` with [`VirtioNetHdr`], and returns the
+    /// length of the header.
     ///
     /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`].
     pub fn fill_buffer_header(&self, buffer: &mut [u8]) -> Result<usize> {
-        if buffer.len() < NET_HDR_SIZE {
+        if buffer.len() < self.hdr_len {
             return Err(Error::InvalidParam);
         }
-        let header = VirtioNetHdr::default();
-        buffer[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
-        Ok(NET_HDR_SIZE)
+        let header = VirtioNetHdrHash::default();
+        buffer[..self.hdr_len].copy_from_slice(&header.as_bytes()[..self.hdr_len]);
+        Ok(self.hdr_len)
+    }
+
+    /// Fills the header of the `buffer` like
+    /// [`fill_buffer_header`](Self::fill_buffer_header), but asks the device to
+    /// calculate the TCP or UDP checksum of the packet which follows it.
+    ///
+    /// The device calculates the checksum from `csum_start` to the end of the
+    /// packet, adds it to the partial checksum already in the packet, and
+    /// stores the result at `csum_start + csum_offset`. Both offsets are
+    /// relative to the start of the packet, after the header. The partial
+    /// checksum is typically the sum of the pseudo-header, e.g. from
+    /// [`ipv4_pseudo_header_checksum`](super::checksum::ipv4_pseudo_header_checksum).
+    ///
+    /// Returns [`Error::Unsupported`] if the `VIRTIO_NET_F_CSUM` feature was
+    /// not negotiated, or [`Error::InvalidParam`] if the `buffer` is not large
+    /// enough for the header or the checksum doesn't fit within the packet.
+    pub fn fill_buffer_header_csum(
+        &self,
+        buffer: &mut [u8],
+        csum_start: u16,
+        csum_offset: u16,
+    ) -> Result<usize> {
+        let packet_len = buffer
+            .len()
+            .checked_sub(self.hdr_len)
+            .ok_or(Error::InvalidParam)?;
+        let header = self.csum_header(packet_len, csum_start, csum_offset)?;
+        buffer[..self.hdr_len].copy_from_slice(&header.as_bytes()[..self.hdr_len]);
+        Ok(self.hdr_len)
+    }
+
+    /// Returns a header asking the device to calculate the checksum of a
+    /// packet of the given length, after checking that the offsets are valid.
+    fn csum_header(
+        &self,
+        packet_len: usize,
+        csum_start: u16,
+        csum_offset: u16,
+    ) -> Result<VirtioNetHdrHash> {
+        if !self.negotiated_features.contains(Features::CSUM) {
+            return Err(Error::Unsupported);
+        }
+        // The checksum field itself is 2 bytes long, and must be within the packet.
+        if usize::from(csum_start) + usize::from(csum_offset) + 2 > packet_len {
+            warn!(
+                "Checksum at {}+{} is outside packet of length {}",
+                csum_start, csum_offset, packet_len
+            );
+            return Err(Error::InvalidParam);
+        }
+        let mut header = VirtioNetHdrHash::default();
+        header.hdr.flags = Flags::NEEDS_CSUM;
+        header.hdr.csum_start = csum_start;
+        header.hdr.csum_offset = csum_offset;
+        Ok(header)
     }
 
     /// Submits a request to transmit a buffer immediately without waiting for
@@ -141,19 +619,114 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_transmit`]: Self::poll_transmit
     /// [`transmit_complete`]: Self::transmit_complete
     pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
-        Self::check_tx_buf_len(tx_buf)?;
-        let token = self.send_queue.add(&[tx_buf], &mut [])?;
-        if self.send_queue.should_notify() {
-            self.transport.notify(QUEUE_TRANSMIT);
+        self.transmit_begin_on(0, tx_buf)
+    }
+
+    /// Like [`transmit_begin`](Self::transmit_begin), but transmits on the
+    /// given queue pair.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`transmit_begin`](Self::transmit_begin).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub unsafe fn transmit_begin_on(&mut self, pair: usize, tx_buf: &[u8]) -> Result<u16> {
+        self.check_tx_buf_len(tx_buf)?;
+        let token = Self::add_checked(
+            &mut self.send_queues[pair],
+            &self.transport,
+            &[tx_buf],
+            &mut [],
+        )?;
+        if self.send_queues[pair].should_notify() {
+            self.send_queues[pair].notify(&mut self.transport);
+        }
+        Ok(token)
+    }
+
+    /// Submits a request to transmit a packet gathered from several buffers,
+    /// like [`transmit_begin`], without copying them into a single buffer.
+    ///
+    /// The buffers are sent in order, and together must start with the header,
+    /// e.g. filled in with [`fill_buffer_header`]. If indirect descriptors were
+    /// negotiated then all the buffers only take up a single descriptor in the
+    /// transmit queue, subject to
+    /// [`set_indirect_threshold`](Self::set_indirect_threshold).
+    ///
+    /// The request must be completed with [`transmit_complete_sg`] with the
+    /// same buffers.
+    ///
+    /// # Safety
+    ///
+    /// The buffers are still borrowed by the underlying VirtIO net device even
+    /// after this method returns, as for [`transmit_begin`].
+    ///
+    /// [`fill_buffer_header`]: Self::fill_buffer_header
+    /// [`transmit_begin`]: Self::transmit_begin
+    /// [`transmit_complete_sg`]: Self::transmit_complete_sg
+    pub unsafe fn transmit_begin_sg(&mut self, tx_bufs: &[&[u8]]) -> Result<u16> {
+        let len = tx_bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if len < self.hdr_len {
+            warn!("Transmit buffers len {} is too small", len);
+            return Err(Error::InvalidParam);
+        }
+        // The header may be split across buffers, so only check the GSO type if the first buffer
+        // contains it.
+        let gso_type = tx_bufs[0]
+            .get(1)
+            .map_or(GsoType::NONE, |&gso_type| GsoType(gso_type));
+        self.check_frame_len(gso_type, len - self.hdr_len)?;
+        let token = Self::add_checked(&mut self.send_queues[0], &self.transport, tx_bufs, &mut [])?;
+        if self.send_queues[0].should_notify() {
+            self.send_queues[0].notify(&mut self.transport);
         }
         Ok(token)
     }
 
+    /// Completes a transmission operation which was started by
+    /// [`transmit_begin_sg`]. Returns number of bytes transmitted.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to
+    /// [`transmit_begin_sg`] when it returned the token.
+    ///
+    /// [`transmit_begin_sg`]: Self::transmit_begin_sg
+    pub unsafe fn transmit_complete_sg(&mut self, token: u16, tx_bufs: &[&[u8]]) -> Result<usize> {
+        let len = self.send_queues[0].pop_used(token, tx_bufs, &mut [])?;
+        Ok(len as usize)
+    }
+
+    /// Sets the number of buffers above which a request uses an indirect
+    /// descriptor table rather than a descriptor for each buffer, on both the
+    /// transmit and receive queues.
+    ///
+    /// The default is 1. This has no effect unless the device supports indirect
+    /// descriptors.
+    #[cfg(feature = "alloc")]
+    pub fn set_indirect_threshold(&mut self, threshold: usize) {
+        for queue in self.send_queues.iter_mut().chain(&mut self.recv_queues) {
+            queue.set_indirect_threshold(threshold);
+        }
+    }
+
     /// Fetches the token of the next completed transmission request from the
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
     pub fn poll_transmit(&mut self) -> Option<u16> {
-        self.send_queue.peek_used()
+        self.poll_transmit_on(0)
+    }
+
+    /// Like [`poll_transmit`](Self::poll_transmit), but for the given queue
+    /// pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn poll_transmit_on(&mut self, pair: usize) -> Option<u16> {
+        self.send_queues[pair].peek_used()
     }
 
     /// Completes a transmission operation which was started by [`transmit_begin`].
@@ -166,7 +739,27 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     ///
     /// [`transmit_begin`]: Self::transmit_begin
     pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result<usize> {
-        let len = self.send_queue.pop_used(token, &[tx_buf], &mut [])?;
+        self.transmit_complete_on(0, token, tx_buf)
+    }
+
+    /// Like [`transmit_complete`](Self::transmit_complete), but for a request
+    /// started on the given queue pair with
+    /// [`transmit_begin_on`](Self::transmit_begin_on).
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`transmit_complete`](Self::transmit_complete).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub unsafe fn transmit_complete_on(
+        &mut self,
+        pair: usize,
+        token: u16,
+        tx_buf: &[u8],
+    ) -> Result<usize> {
+        let len = self.send_queues[pair].pop_used(token, &[tx_buf], &mut [])?;
         Ok(len as usize)
     }
 
@@ -193,10 +786,29 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_receive`]: Self::poll_receive
     /// [`receive_complete`]: Self::receive_complete
     pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
-        Self::check_rx_buf_len(rx_buf)?;
-        let token = self.recv_queue.add(&[], &mut [rx_buf])?;
-        if self.recv_queue.should_notify() {
-            self.transport.notify(QUEUE_RECEIVE);
+        self.receive_begin_on(0, rx_buf)
+    }
+
+    /// Like [`receive_begin`](Self::receive_begin), but receives on the given
+    /// queue pair.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`receive_begin`](Self::receive_begin).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub unsafe fn receive_begin_on(&mut self, pair: usize, rx_buf: &mut [u8]) -> Result<u16> {
+        self.check_rx_buf_len(rx_buf)?;
+        let token = Self::add_checked(
+            &mut self.recv_queues[pair],
+            &self.transport,
+            &[],
+            &mut [rx_buf],
+        )?;
+        if self.recv_queues[pair].should_notify() {
+            self.recv_queues[pair].notify(&mut self.transport);
         }
         Ok(token)
     }
@@ -205,7 +817,16 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
     pub fn poll_receive(&self) -> Option<u16> {
-        self.recv_queue.peek_used()
+        self.poll_receive_on(0)
+    }
+
+    /// Like [`poll_receive`](Self::poll_receive), but for the given queue pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn poll_receive_on(&self, pair: usize) -> Option<u16> {
+        self.recv_queues[pair].peek_used()
     }
 
     /// Completes a transmission operation which was started by [`receive_begin`].
@@ -225,51 +846,975 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         token: u16,
         rx_buf: &mut [u8],
     ) -> Result<(usize, usize)> {
-        let len = self.recv_queue.pop_used(token, &[], &mut [rx_buf])? as usize;
-        let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?;
-        Ok((NET_HDR_SIZE, packet_len))
+        self.receive_complete_on(0, token, rx_buf)
+    }
+
+    /// Like [`receive_complete`](Self::receive_complete), but for a request
+    /// started on the given queue pair with
+    /// [`receive_begin_on`](Self::receive_begin_on).
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`receive_complete`](Self::receive_complete).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub unsafe fn receive_complete_on(
+        &mut self,
+        pair: usize,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        let len = self.recv_queues[pair].pop_used(token, &[], &mut [rx_buf])? as usize;
+        let packet_len = len.checked_sub(self.hdr_len).ok_or(Error::IoError)?;
+        Ok((self.hdr_len, packet_len))
+    }
+
+    /// Submits a request to receive a packet into separate header and payload
+    /// buffers, without waiting for the reception to complete.
+    ///
+    /// This is like [`receive_begin`], except that the [`VirtioNetHdr`] is
+    /// placed in its own descriptor, so the packet starts at the beginning of
+    /// `payload` rather than being offset by the header. This allows the
+    /// payload buffer to be page-aligned, for example.
+    ///
+    /// The caller can then call [`poll_receive`] with the returned token to
+    /// check whether the device has finished handling the request. Once it has,
+    /// the caller must call [`receive_complete_split`] with the same buffers
+    /// before reading the response.
+    ///
+    /// # Safety
+    ///
+    /// `hdr` and `payload` are still borrowed by the underlying VirtIO net
+    /// device even after this method returns. Thus, it is the caller's
+    /// responsibility to guarantee that they are not accessed before the
+    /// request is completed in order to avoid data races.
+    ///
+    /// Returns [`Error::Unsupported`] if the hash report feature was
+    /// negotiated, as the header is then larger than [`VirtioNetHdr`].
+    ///
+    /// [`receive_begin`]: Self::receive_begin
+    /// [`poll_receive`]: Self::poll_receive
+    /// [`receive_complete_split`]: Self::receive_complete_split
+    pub unsafe fn receive_begin_split(
+        &mut self,
+        hdr: &mut VirtioNetHdr,
+        payload: &mut [u8],
+    ) -> Result<u16> {
+        if self.hdr_len != NET_HDR_SIZE {
+            return Err(Error::Unsupported);
+        }
+        self.check_rx_payload_len(payload)?;
+        let token = Self::add_checked(
+            &mut self.recv_queues[0],
+            &self.transport,
+            &[],
+            &mut [hdr.as_mut_bytes(), payload],
+        )?;
+        if self.recv_queues[0].should_notify() {
+            self.recv_queues[0].notify(&mut self.transport);
+        }
+        Ok(token)
+    }
+
+    /// Completes a reception operation which was started by
+    /// [`receive_begin_split`].
+    ///
+    /// After completion, `hdr` will contain the header and `payload` will
+    /// contain the received packet. It returns the length of the packet.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to
+    /// [`receive_begin_split`] when it returned the token.
+    ///
+    /// [`receive_begin_split`]: Self::receive_begin_split
+    pub unsafe fn receive_complete_split(
+        &mut self,
+        token: u16,
+        hdr: &mut VirtioNetHdr,
+        payload: &mut [u8],
+    ) -> Result<usize> {
+        let len =
+            self.recv_queues[0].pop_used(token, &[], &mut [hdr.as_mut_bytes(), payload])? as usize;
+        len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)
     }
 
     /// Sends a packet to the network, and blocks until the request completed.
+    ///
+    /// Returns [`Error::DeviceNeedsReset`] rather than waiting forever if the
+    /// device indicates that it needs to be reset. In that case the device is
+    /// drained as for [`VirtioDevice::drain`] before returning, so that it
+    /// can't access `tx_buf` afterwards, and the driver can't be used again.
     pub fn send(&mut self, tx_buf: &[u8]) -> Result {
-        let header = VirtioNetHdr::default();
-        if tx_buf.is_empty() {
-            // Special case sending an empty packet, to avoid adding an empty buffer to the
-            // virtqueue.
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes()],
-                &mut [],
-                &mut self.transport,
-            )?;
+        self.send_on(0, tx_buf)
+    }
+
+    /// Like [`send`](Self::send), but sends on the given queue pair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pair` is not less than `NUM_PAIRS`.
+    pub fn send_on(&mut self, pair: usize, tx_buf: &[u8]) -> Result {
+        self.send_with_header(pair, &VirtioNetHdrHash::default(), tx_buf)
+    }
+
+    /// Sends a packet to the network like [`send`](Self::send), but asks the
+    /// device to calculate its TCP or UDP checksum.
+    ///
+    /// See [`fill_buffer_header_csum`](Self::fill_buffer_header_csum) for the
+    /// meaning of `csum_start` and `csum_offset`, and the errors returned if
+    /// they are invalid.
+    pub fn send_with_csum(&mut self, tx_buf: &[u8], csum_start: u16, csum_offset: u16) -> Result {
+        let header = self.csum_header(tx_buf.len(), csum_start, csum_offset)?;
+        self.send_with_header(0, &header, tx_buf)
+    }
+
+    /// Sends a TCP packet larger than the MTU to the network, and asks the
+    /// device to split it into segments of at most `gso_size` bytes of TCP
+    /// payload each. It blocks until the request completed.
+    ///
+    /// `hdr_len` is the length of the Ethernet, IP and TCP headers at the start
+    /// of the packet, which the device copies to each segment, and
+    /// `csum_start` is the offset of the TCP header. The device calculates the
+    /// TCP checksum of each segment, so the checksum field in the packet should
+    /// hold the sum of the pseudo-header as for
+    /// [`send_with_csum`](Self::send_with_csum).
+    ///
+    /// Returns [`Error::Unsupported`] if the feature needed for `tso_type` or
+    /// the `VIRTIO_NET_F_CSUM` feature was not negotiated, or
+    /// [`Error::InvalidParam`] if `gso_size` is 0 or the headers don't fit
+    /// within the packet.
+    pub fn send_gso(
+        &mut self,
+        tx_buf: &[u8],
+        tso_type: TsoType,
+        gso_size: u16,
+        hdr_len: u16,
+        csum_start: u16,
+    ) -> Result {
+        if !self.negotiated_features.contains(tso_type.feature()) {
+            return Err(Error::Unsupported);
+        }
+        if gso_size == 0 || usize::from(hdr_len) > tx_buf.len() || csum_start >= hdr_len {
+            return Err(Error::InvalidParam);
+        }
+        let mut header = self.csum_header(tx_buf.len(), csum_start, TCP_CHECKSUM_OFFSET)?;
+        header.hdr.gso_type = tso_type.gso_type();
+        header.hdr.gso_size = gso_size;
+        header.hdr.hdr_len = hdr_len;
+        self.send_with_header(0, &header, tx_buf)
+    }
+
+    /// Sends a packet preceded by the given header on the given queue pair,
+    /// and blocks until the request completed.
+    fn send_with_header(
+        &mut self,
+        pair: usize,
+        header: &VirtioNetHdrHash,
+        tx_buf: &[u8],
+    ) -> Result {
+        self.check_frame_len(header.hdr.gso_type, tx_buf.len())?;
+        let header = &header.as_bytes()[..self.hdr_len];
+        // Special case sending an empty packet, to avoid adding an empty buffer to the virtqueue.
+        let inputs: &[&[u8]] = if tx_buf.is_empty() {
+            &[header]
         } else {
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes(), tx_buf],
+            &[header, tx_buf]
+        };
+        // Safe because we don't return until the same token has been popped, or the device has
+        // been drained and so won't access the buffers again.
+        let token = unsafe {
+            Self::add_checked(
+                &mut self.send_queues[pair],
+                &self.transport,
+                inputs,
                 &mut [],
-                &mut self.transport,
-            )?;
+            )?
+        };
+        if self.send_queues[pair].should_notify() {
+            self.send_queues[pair].notify(&mut self.transport);
         }
+        while !self.send_queues[pair].can_pop() {
+            self.check_device_status().map_err(|e| self.fail(e))?;
+            core::hint::spin_loop();
+        }
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.send_queues[pair].pop_used(token, inputs, &mut []) }
+            .map_err(|e| self.fail(e))?;
         Ok(())
     }
 
+    /// Drains the device, then returns the given error.
+    ///
+    /// This is for when a blocking request fails while its buffers are still
+    /// in a queue, as they mustn't be left shared with the device once the
+    /// caller's borrow of them ends.
+    fn fail(&mut self, error: Error) -> Error {
+        self.drain();
+        error
+    }
+
+    /// Sends several packets to the network, notifying the device only once,
+    /// and blocks until they have all been sent.
+    ///
+    /// If there is not enough space in the transmit queue for all the packets
+    /// then as many as fit are sent. Returns the number of packets sent, which
+    /// are always the first ones in `frames`, or 0 if the queue is full.
+    ///
+    /// Returns [`Error::InvalidParam`] without sending any of the packets if
+    /// any of them is larger than the MTU allows. If a packet can't be added
+    /// to the queue for some other reason then the packets before it are
+    /// still sent before the error is returned. If the device needs to be
+    /// reset, or uses a buffer which wasn't part of the batch, then it is
+    /// drained as for [`send`](Self::send) before returning the error, so
+    /// that it can't access `frames` afterwards.
+    pub fn send_many(&mut self, frames: &[&[u8]]) -> Result<usize> {
+        // Check all the frames before adding any of them to the queue, so that an invalid one
+        // doesn't leave the earlier ones behind.
+        for frame in frames {
+            self.check_frame_len(GsoType::NONE, frame.len())?;
+        }
+        let header = VirtioNetHdrHash::default();
+        let header = &header.as_bytes()[..self.hdr_len];
+        let header_only: &[&[u8]] = &[header];
+        let mut inputs: [[&[u8]; 2]; QUEUE_SIZE] = [[&[]; 2]; QUEUE_SIZE];
+        let mut tokens = [0; QUEUE_SIZE];
+        let mut count = 0;
+        let mut result = Ok(());
+        for frame in frames.iter().take(QUEUE_SIZE) {
+            inputs[count] = [header, frame];
+            // Special case sending an empty packet, to avoid adding an empty buffer to the
+            // virtqueue.
+            let frame_inputs = if frame.is_empty() {
+                header_only
+            } else {
+                &inputs[count]
+            };
+            // Safe because we don't return until all the tokens have been popped, or the device
+            // has been drained and so won't access the buffers again.
+            match unsafe {
+                Self::add_checked(
+                    &mut self.send_queues[0],
+                    &self.transport,
+                    frame_inputs,
+                    &mut [],
+                )
+            } {
+                Ok(token) => tokens[count] = token,
+                Err(Error::QueueFull) => break,
+                Err(e) => {
+                    // Still wait for the frames which were already added.
+                    result = Err(e);
+                    break;
+                }
+            }
+            count += 1;
+        }
+        if count > 0 && self.send_queues[0].should_notify() {
+            self.send_queues[0].notify(&mut self.transport);
+        }
+
+        // The device may complete the requests in any order, so find the
+        // buffers for each token as it is used.
+        for _ in 0..count {
+            let token = loop {
+                if let Some(token) = self.send_queues[0].peek_used() {
+                    break token;
+                }
+                self.check_device_status().map_err(|e| self.fail(e))?;
+                core::hint::spin_loop();
+            };
+            let Some(i) = tokens[..count].iter().position(|&t| t == token) else {
+                return Err(self.fail(Error::WrongToken));
+            };
+            let frame_inputs = if frames[i].is_empty() {
+                header_only
+            } else {
+                &inputs[i]
+            };
+            // Safe because these are the same buffers as we passed to `add` above and they are
+            // still valid.
+            unsafe { self.send_queues[0].pop_used(token, frame_inputs, &mut []) }
+                .map_err(|e| self.fail(e))?;
+        }
+        result.map(|()| count)
+    }
+
+    /// Disables all the queues which the driver set up.
+    fn unset_queues(&mut self) {
+        for pair in 0..NUM_PAIRS {
+            self.transport.queue_unset(receive_queue_idx(pair));
+            self.transport.queue_unset(transmit_queue_idx(pair));
+        }
+        if self.ctrl_queue.is_some() {
+            self.transport.queue_unset(self.ctrl_queue_idx);
+        }
+    }
+
     /// Blocks and waits for a packet to be received.
     ///
     /// After completion, the `rx_buf` will contain a header followed by the
     /// received packet. It returns the length of the header and the length of
     /// the packet.
+    ///
+    /// Returns [`Error::DeviceNeedsReset`] rather than waiting forever if the
+    /// device indicates that it needs to be reset. In that case the device is
+    /// drained as for [`VirtioDevice::drain`] before returning, so that it
+    /// can't access `rx_buf` afterwards, and the driver can't be used again.
     pub fn receive_wait(&mut self, rx_buf: &mut [u8]) -> Result<(usize, usize)> {
+        // Safe because we don't return until the same token has been popped, or the device has
+        // been drained and so won't access the buffer again.
         let token = unsafe { self.receive_begin(rx_buf)? };
         while self.poll_receive().is_none() {
+            self.check_device_status().map_err(|e| self.fail(e))?;
             core::hint::spin_loop();
         }
-        unsafe { self.receive_complete(token, rx_buf) }
+        match unsafe { self.receive_complete(token, rx_buf) } {
+            // The chain has been popped, even if the packet it holds was invalid.
+            Err(Error::WrongToken) => Err(self.fail(Error::WrongToken)),
+            result => result,
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize> VirtioDevice
+    for VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>
+{
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.unset_queues();
+        // SAFETY: The device has been reset so won't access the queues again, and the buffers of
+        // any requests still in them must remain valid until they are popped.
+        unsafe {
+            for queue in self.recv_queues.iter_mut().chain(&mut self.send_queues) {
+                queue.reclaim_all();
+            }
+            if let Some(ctrl_queue) = &mut self.ctrl_queue {
+                ctrl_queue.reclaim_all();
+            }
+        }
     }
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const NUM_PAIRS: usize> Drop
+    for VirtIONetRaw<H, T, QUEUE_SIZE, NUM_PAIRS>
+{
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVE);
-        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.unset_queues();
+    }
+}
+
+/// Returns the index of the receive queue of the given queue pair.
+fn receive_queue_idx(pair: usize) -> u16 {
+    QUEUE_RECEIVE + 2 * pair as u16
+}
+
+/// Returns the index of the transmit queue of the given queue pair.
+fn transmit_queue_idx(pair: usize) -> u16 {
+    QUEUE_TRANSMIT + 2 * pair as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::device::net::VirtIONet;
+    use crate::{
+        config::ReadOnly,
+        device::net::Flags,
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            InterruptStatus,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use std::{
+        sync::{atomic::Ordering, Mutex},
+        thread,
+    };
+
+    const QUEUE_SIZE: usize = 4;
+
+    fn fake_transport(
+        device_features: Features,
+    ) -> (FakeTransport<Config>, Arc<Mutex<State<Config>>>) {
+        let config_space = Config {
+            mac: ReadOnly::new([0x02, 0, 0, 0, 0, 0x01]),
+            status: ReadOnly::new(Status::LINK_UP),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(1500),
+            speed: ReadOnly::new(0),
+            duplex: ReadOnly::new(0),
+            rss_max_key_size: ReadOnly::new(0),
+            rss_max_indirection_table_length: ReadOnly::new(0),
+            supported_hash_types: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: device_features.bits(),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    #[test]
+    fn ctrl_command_without_ctrl_vq() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(state.lock().unwrap().queues[2].descriptors, 0);
+        assert_eq!(
+            net.send_ctrl_command(CTRL_CLASS_ANNOUNCE, CTRL_ANNOUNCE_ACK, &[]),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn ctrl_command() {
+        let (transport, state) = fake_transport(Features::MAC | Features::CTRL_VQ);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        // Simulate the device acknowledging one command and rejecting the next.
+        let handle = thread::spawn(move || {
+            for ack in [CTRL_OK, 1] {
+                State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+                assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                    QUEUE_CONTROL,
+                    |request| {
+                        assert_eq!(request, vec![5, 6, 1, 2, 3]);
+                        vec![ack]
+                    }
+                ));
+            }
+        });
+
+        assert_eq!(net.send_ctrl_command(5, 6, &[&[1, 2], &[], &[3]]), Ok(()));
+        assert_eq!(
+            net.send_ctrl_command(5, 6, &[&[1], &[2, 3]]),
+            Err(Error::IoError)
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn rx_modes() {
+        let (transport, _) = fake_transport(Features::MAC | Features::CTRL_VQ);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(net.set_promiscuous(true), Err(Error::Unsupported));
+
+        let (transport, state) =
+            fake_transport(Features::MAC | Features::CTRL_VQ | Features::CTRL_RX);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        let handle = thread::spawn(move || {
+            for expected in [
+                [CTRL_CLASS_RX, CTRL_RX_PROMISC, 1],
+                [CTRL_CLASS_RX, CTRL_RX_ALLMULTI, 0],
+            ] {
+                State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+                assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                    QUEUE_CONTROL,
+                    |request| {
+                        assert_eq!(request, expected);
+                        vec![CTRL_OK]
+                    }
+                ));
+            }
+        });
+
+        assert_eq!(net.set_promiscuous(true), Ok(()));
+        assert_eq!(net.set_all_multicast(false), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn set_mac_address() {
+        let (transport, state) =
+            fake_transport(Features::MAC | Features::CTRL_VQ | Features::CTL_MAC_ADDR);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        let mac = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                QUEUE_CONTROL,
+                |request| {
+                    assert_eq!(
+                        request,
+                        [&[CTRL_CLASS_MAC, CTRL_MAC_ADDR_SET][..], &mac].concat()
+                    );
+                    vec![CTRL_OK]
+                }
+            ));
+        });
+
+        assert_eq!(net.set_mac_address(mac), Ok(()));
+        assert_eq!(net.mac_address(), mac);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn send_with_csum() {
+        let (transport, _) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(
+            net.send_with_csum(&[0; 40], 20, 16),
+            Err(Error::Unsupported)
+        );
+
+        let (transport, state) = fake_transport(Features::MAC | Features::CSUM);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        // The checksum field would extend past the end of the packet.
+        assert_eq!(
+            net.send_with_csum(&[0; 37], 20, 16),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                QUEUE_TRANSMIT,
+                |request| {
+                    assert_eq!(request[..NET_HDR_SIZE], [1, 0, 0, 0, 0, 0, 20, 0, 16, 0]);
+                    assert_eq!(request.len(), NET_HDR_SIZE + 38);
+                    vec![]
+                }
+            ));
+        });
+        assert_eq!(net.send_with_csum(&[0; 38], 20, 16), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn send_gso() {
+        let (transport, state) =
+            fake_transport(Features::MAC | Features::CSUM | Features::HOST_TSO4);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        let packet = vec![0; 4000];
+        assert_eq!(
+            net.send_gso(&packet, TsoType::TcpV6, 1448, 54, 34),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            net.send_gso(&packet, TsoType::TcpV4, 0, 54, 34),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                QUEUE_TRANSMIT,
+                |request| {
+                    // NEEDS_CSUM, TCPV4, hdr_len 54, gso_size 1448, csum at 34+16.
+                    assert_eq!(
+                        request[..NET_HDR_SIZE],
+                        [1, 1, 54, 0, 0xa8, 0x05, 34, 0, 16, 0]
+                    );
+                    assert_eq!(request.len(), NET_HDR_SIZE + 4000);
+                    vec![]
+                }
+            ));
+        });
+        assert_eq!(net.send_gso(&packet, TsoType::TcpV4, 1448, 54, 34), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn multiqueue() {
+        let (transport, _) = fake_transport(Features::MAC | Features::CTRL_VQ | Features::MQ);
+        // More pairs than the device supports.
+        assert_eq!(
+            VirtIONetRaw::<FakeHal, _, QUEUE_SIZE, 2>::new(transport).err(),
+            Some(Error::InvalidParam)
+        );
+
+        let (transport, state) = fake_transport(Features::MAC | Features::CTRL_VQ | Features::MQ);
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.max_virtqueue_pairs = ReadOnly::new(3);
+            state.queues = (0..7).map(|_| QueueStatus::default()).collect();
+        }
+        let handle = thread::spawn(move || {
+            // The control queue follows all 3 pairs supported by the device.
+            State::wait_until_queue_notified(&state, 6);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<QUEUE_SIZE>(6, |request| {
+                    assert_eq!(request, [CTRL_CLASS_MQ, CTRL_MQ_VQ_PAIRS_SET, 2, 0]);
+                    vec![CTRL_OK]
+                }));
+
+            // The transmit queue of the second pair.
+            State::wait_until_queue_notified(&state, 3);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<QUEUE_SIZE>(3, |request| {
+                    assert_eq!(request[NET_HDR_SIZE..], [42]);
+                    vec![]
+                }));
+        });
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE, 2>::new(transport).unwrap();
+        assert_eq!(net.send_on(1, &[42]), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn queue_setup_failure() {
+        let (transport, state) = fake_transport(Features::MAC | Features::CTRL_VQ | Features::MQ);
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.max_virtqueue_pairs = ReadOnly::new(2);
+            state.queues = (0..5).map(|_| QueueStatus::default()).collect();
+            // The receive queue of the second pair is already in use.
+            state.queues[2].descriptors = 0x1000;
+        }
+        assert_eq!(
+            VirtIONetRaw::<FakeHal, _, QUEUE_SIZE, 2>::new(transport).err(),
+            Some(Error::AlreadyUsed)
+        );
+
+        // The queues which were set up have been disabled again, and the one in use left alone.
+        let state = state.lock().unwrap();
+        for (index, queue) in state.queues.iter().enumerate() {
+            let expected = if index == 2 { 0x1000 } else { 0 };
+            assert_eq!(queue.descriptors, expected);
+        }
+    }
+
+    #[test]
+    fn configure_rss() {
+        let (transport, _) = fake_transport(Features::MAC | Features::CTRL_VQ);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(net.rss_limits(), None);
+        assert_eq!(net.configure_rss(&[1], &[0], 1), Err(Error::Unsupported));
+
+        // The RSS command needs more descriptors than the other tests' queues have.
+        const RSS_QUEUE_SIZE: usize = 8;
+        let (mut transport, state) =
+            fake_transport(Features::MAC | Features::CTRL_VQ | Features::MQ | Features::RSS);
+        transport.max_queue_size = RSS_QUEUE_SIZE as u32;
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.max_virtqueue_pairs = ReadOnly::new(2);
+            state.config_space.rss_max_key_size = ReadOnly::new(4);
+            state.config_space.rss_max_indirection_table_length = ReadOnly::new(4);
+            state.config_space.supported_hash_types = ReadOnly::new(0b11);
+            state.queues = (0..5).map(|_| QueueStatus::default()).collect();
+        }
+        let handle = thread::spawn(move || {
+            // The control queue follows both pairs.
+            State::wait_until_queue_notified(&state, 4);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<RSS_QUEUE_SIZE>(4, |request| {
+                    assert_eq!(request, [CTRL_CLASS_MQ, CTRL_MQ_VQ_PAIRS_SET, 2, 0]);
+                    vec![CTRL_OK]
+                }));
+
+            State::wait_until_queue_notified(&state, 4);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<RSS_QUEUE_SIZE>(4, |request| {
+                    assert_eq!(
+                        request,
+                        [
+                            CTRL_CLASS_MQ,
+                            CTRL_MQ_RSS_CONFIG,
+                            // Hash types.
+                            0b11,
+                            0,
+                            0,
+                            0,
+                            // Indirection table mask and unclassified queue.
+                            3,
+                            0,
+                            0,
+                            0,
+                            // Indirection table.
+                            1,
+                            0,
+                            0,
+                            0,
+                            1,
+                            0,
+                            0,
+                            0,
+                            // Maximum transmit queue pairs and key length.
+                            2,
+                            0,
+                            4,
+                            // Key.
+                            0xa1,
+                            0xb2,
+                            0xc3,
+                            0xd4,
+                        ]
+                    );
+                    vec![CTRL_OK]
+                }));
+        });
+        let mut net = VirtIONetRaw::<FakeHal, _, RSS_QUEUE_SIZE, 2>::new(transport).unwrap();
+        assert_eq!(
+            net.rss_limits(),
+            Some(RssLimits {
+                max_key_size: 4,
+                max_indirection_table_length: 4,
+                supported_hash_types: 0b11,
+            })
+        );
+
+        // Key too long, table not a power of two, queue out of range, unsupported hash type.
+        let key = [0xa1, 0xb2, 0xc3, 0xd4];
+        for (key, table, hash_types) in [
+            (&[0; 5][..], &[0, 1][..], 0b01),
+            (&key[..], &[0, 1, 0][..], 0b01),
+            (&key[..], &[0, 2][..], 0b01),
+            (&key[..], &[0, 1][..], 0b100),
+        ] {
+            assert_eq!(
+                net.configure_rss(key, table, hash_types),
+                Err(Error::InvalidParam)
+            );
+        }
+
+        assert_eq!(net.configure_rss(&key, &[1, 0, 1, 0], 0b11), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn mtu() {
+        let (transport, _) = fake_transport(Features::MAC);
+        let net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(net.mtu(), 1500);
+
+        let (transport, _) = fake_transport(Features::MAC | Features::MTU);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert_eq!(net.mtu(), 1500);
+        assert_eq!(
+            net.send(&[0; ETHERNET_HEADER_LEN + 1501]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn send_many_needs_reset() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+
+        // The third frame doesn't fit because the device has stopped using the queue, and then the
+        // first two are never completed.
+        assert_eq!(
+            net.send_many(&[&[1], &[2], &[3]]),
+            Err(Error::DeviceNeedsReset)
+        );
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(state.lock().unwrap().queues[1].descriptors, 0);
+        assert_eq!(net.send_queues[0].available_desc(), QUEUE_SIZE);
+    }
+
+    #[test]
+    fn send_many_wrong_token() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        // The device completes a transmission from outside the batch, which send_many doesn't have
+        // the buffers for.
+        let mut tx_buf = [0; NET_HDR_SIZE + 1];
+        net.fill_buffer_header(&mut tx_buf).unwrap();
+        unsafe { net.transmit_begin(&tx_buf) }.unwrap();
+        state
+            .lock()
+            .unwrap()
+            .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMIT);
+
+        assert_eq!(net.send_many(&[&[1]]), Err(Error::WrongToken));
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(net.send_queues[0].available_desc(), QUEUE_SIZE);
+    }
+
+    #[test]
+    fn send_many_oversized() {
+        let (transport, state) = fake_transport(Features::MAC | Features::MTU);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        // An oversized frame in the middle of the batch should stop any of it being sent.
+        let oversized = [0; ETHERNET_HEADER_LEN + 1501];
+        assert_eq!(
+            net.send_many(&[&[1, 2], &oversized, &[3]]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(net.send_queues[0].available_desc(), QUEUE_SIZE);
+        assert!(!State::poll_queue_notified(&state, QUEUE_TRANSMIT));
+    }
+
+    #[test]
+    fn config_change_interrupt() {
+        let (transport, state) = fake_transport(Features::MAC | Features::STATUS);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        assert!(net.link_up());
+
+        state.lock().unwrap().config_space.status = ReadOnly::new(Status::empty());
+
+        // A used buffer notification alone doesn't re-read the config space.
+        state.lock().unwrap().interrupt_status = InterruptStatus::USED_BUFFER;
+        assert_eq!(net.ack_interrupt(), InterruptStatus::USED_BUFFER);
+        assert!(net.link_up());
+
+        state.lock().unwrap().interrupt_status = InterruptStatus::CONFIG_CHANGE;
+        assert_eq!(net.ack_interrupt(), InterruptStatus::CONFIG_CHANGE);
+        assert!(!net.link_up());
+        assert_eq!(net.ack_interrupt(), InterruptStatus::empty());
+    }
+
+    /// Tests that blocking requests drain the device rather than leaving their buffers shared with
+    /// it if it needs to be reset.
+    #[test]
+    fn needs_reset() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+        state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+
+        assert_eq!(net.send(&[1, 2, 3]), Err(Error::DeviceNeedsReset));
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.status, DeviceStatus::empty());
+            assert!(state.queues.iter().all(|queue| queue.descriptors == 0));
+        }
+        assert_eq!(net.send_queues[0].available_desc(), QUEUE_SIZE);
+
+        state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+        let mut rx_buf = [0; 1526];
+        assert_eq!(net.receive_wait(&mut rx_buf), Err(Error::DeviceNeedsReset));
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(net.recv_queues[0].available_desc(), QUEUE_SIZE);
+    }
+
+    #[test]
+    fn notification_data() {
+        let (transport, state) = fake_transport(Features::MAC | Features::NOTIFICATION_DATA);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        let mut tx_buf = [0; NET_HDR_SIZE + 1];
+        net.fill_buffer_header(&mut tx_buf).unwrap();
+        unsafe { net.transmit_begin(&tx_buf) }.unwrap();
+
+        // The queue index in bits 0-15 and the next available index in bits 16-31.
+        let state = state.lock().unwrap();
+        assert!(state.queues[usize::from(QUEUE_TRANSMIT)]
+            .notified
+            .load(Ordering::SeqCst));
+        assert_eq!(
+            state.queues[usize::from(QUEUE_TRANSMIT)]
+                .notification_data
+                .load(Ordering::SeqCst),
+            0x0001_0000 | u32::from(QUEUE_TRANSMIT)
+        );
+    }
+
+    #[test]
+    fn receive_split() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        let mut hdr = VirtioNetHdr::default();
+        let mut payload = vec![0; net.min_rx_buffer_len() - NET_HDR_SIZE];
+        let token = unsafe { net.receive_begin_split(&mut hdr, &mut payload) }.unwrap();
+        assert_eq!(net.poll_receive(), None);
+
+        // Simulate the device writing a header and packet across both descriptors.
+        let mut response = VirtioNetHdr {
+            flags: Flags::DATA_VALID,
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec();
+        response.extend_from_slice(&[1, 2, 3, 4, 5]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &response);
+
+        assert_eq!(net.poll_receive(), Some(token));
+        let len = unsafe { net.receive_complete_split(token, &mut hdr, &mut payload) }.unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(hdr.flags, Flags::DATA_VALID);
+        assert_eq!(&payload[..len], &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn receive_split_hash_report() {
+        let (transport, _) = fake_transport(Features::MAC | Features::HASH_REPORT);
+        let mut net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport).unwrap();
+
+        let mut hdr = VirtioNetHdr::default();
+        let mut payload = vec![0; net.min_rx_buffer_len()];
+        assert_eq!(
+            unsafe { net.receive_begin_split(&mut hdr, &mut payload) },
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn virtio_net_receive_split() {
+        let (transport, state) = fake_transport(Features::MAC);
+        let mut net = VirtIONet::<FakeHal, _, QUEUE_SIZE>::new_split(transport, 1514).unwrap();
+
+        let mut response = VirtioNetHdr {
+            flags: Flags::DATA_VALID,
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec();
+        response.extend_from_slice(&[1, 2, 3, 4, 5]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &response);
+
+        // The packet should be at the start of the buffer, with the header after it.
+        let rx_buf = net.receive().unwrap();
+        assert_eq!(rx_buf.packet(), &[1, 2, 3, 4, 5]);
+        assert_eq!(rx_buf.as_bytes()[..5], [1, 2, 3, 4, 5]);
+        assert_eq!(rx_buf.header().flags, Flags::DATA_VALID);
+        assert_eq!(rx_buf.hash(), None);
+        net.recycle_rx_buffer(rx_buf).unwrap();
+        assert_eq!(net.receive().err(), Some(Error::NotReady));
+
+        // Split buffers are only supported on a single queue pair.
+        let (transport, _) = fake_transport(Features::MAC);
+        assert_eq!(
+            VirtIONet::<FakeHal, _, QUEUE_SIZE, 2>::new_split(transport, 1514).err(),
+            Some(Error::Unsupported)
+        );
     }
 }
@@ -1,5 +1,6 @@
 //! Driver for VirtIO network devices.
 
+pub mod checksum;
 #[cfg(feature = "alloc")]
 mod dev;
 mod dev_raw;
@@ -17,6 +18,12 @@ use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 const MAX_BUFFER_LEN: usize = 65535;
 const MIN_BUFFER_LEN: usize = 1526;
 const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHdr>();
+/// The size of the header when `VIRTIO_NET_F_HASH_REPORT` is negotiated.
+const NET_HDR_HASH_SIZE: usize = core::mem::size_of::<VirtioNetHdrHash>();
+/// The MTU assumed when the device doesn't report one.
+const DEFAULT_MTU: u16 = 1500;
+/// The length of an Ethernet header, without a VLAN tag.
+const ETHERNET_HEADER_LEN: usize = 14;
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -78,10 +85,19 @@ bitflags! {
         const RING_INDIRECT_DESC = 1 << 28;
         const RING_EVENT_IDX = 1 << 29;
         const VERSION_1 = 1 << 32; // legacy
+        const NOTIFICATION_DATA = 1 << 38;
+
+        /// Device can report the hash it calculated for each received packet.
+        const HASH_REPORT = 1 << 57;
+        /// Device supports RSS (receive-side scaling) with Toeplitz hash
+        /// calculation and configurable hash parameters for receive steering.
+        const RSS = 1 << 60;
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, KnownLayout, PartialEq)]
+#[derive(
+    Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
 #[repr(transparent)]
 struct Status(u16);
 
@@ -92,24 +108,76 @@ bitflags! {
     }
 }
 
-bitflags! {
-    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-    struct InterruptStatus : u32 {
-        const USED_RING_UPDATE = 1 << 0;
-        const CONFIGURATION_CHANGE = 1 << 1;
-    }
-}
-
+#[derive(FromBytes, Immutable, IntoBytes)]
 #[repr(C)]
 struct Config {
     mac: ReadOnly<EthernetAddress>,
     status: ReadOnly<Status>,
     max_virtqueue_pairs: ReadOnly<u16>,
     mtu: ReadOnly<u16>,
+    speed: ReadOnly<u32>,
+    duplex: ReadOnly<u8>,
+    rss_max_key_size: ReadOnly<u8>,
+    rss_max_indirection_table_length: ReadOnly<u16>,
+    supported_hash_types: ReadOnly<u32>,
 }
 
 type EthernetAddress = [u8; 6];
 
+/// The layout of the buffers used by a VirtIO network driver.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NetLayout {
+    /// The length in bytes of the [`VirtioNetHdr`] which precedes each packet.
+    pub hdr_len: usize,
+    /// The number of receive buffers posted to the device.
+    pub rx_buffers: usize,
+    /// The maximum number of transmit buffers which may be in flight at once.
+    pub tx_buffers: usize,
+    /// The length in bytes of each receive buffer, including the header.
+    pub rx_buffer_len: usize,
+}
+
+/// The limits of the device's support for RSS (receive-side scaling), as read
+/// from its config space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RssLimits {
+    /// The maximum supported length of the RSS key, in bytes.
+    pub max_key_size: u8,
+    /// The maximum number of entries in the RSS indirection table.
+    pub max_indirection_table_length: u16,
+    /// The bitmask of supported hash types.
+    pub supported_hash_types: u32,
+}
+
+/// The kind of TCP segmentation offload to request from the device, with
+/// [`VirtIONetRaw::send_gso`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TsoType {
+    /// Segment a TCP packet over IPv4, requiring `VIRTIO_NET_F_HOST_TSO4`.
+    TcpV4,
+    /// Segment a TCP packet over IPv6, requiring `VIRTIO_NET_F_HOST_TSO6`.
+    TcpV6,
+}
+
+impl TsoType {
+    /// Returns the feature which must be negotiated to use this kind of
+    /// segmentation offload.
+    fn feature(self) -> Features {
+        match self {
+            Self::TcpV4 => Features::HOST_TSO4,
+            Self::TcpV6 => Features::HOST_TSO6,
+        }
+    }
+
+    /// Returns the corresponding value for the `gso_type` field of the header.
+    fn gso_type(self) -> GsoType {
+        match self {
+            Self::TcpV4 => GsoType::TCPV4,
+            Self::TcpV6 => GsoType::TCPV6,
+        }
+    }
+}
+
 /// VirtIO 5.1.6 Device Operation:
 ///
 /// Packets are transmitted by placing them in the transmitq1. . .transmitqN,
@@ -128,6 +196,42 @@ pub struct VirtioNetHdr {
     // payload starts from here
 }
 
+/// The header used instead of [`VirtioNetHdr`] when `VIRTIO_NET_F_HASH_REPORT`
+/// is negotiated, for both transmitted and received packets.
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct VirtioNetHdrHash {
+    hdr: VirtioNetHdr,
+    num_buffers: u16,
+    hash_value: u32,
+    hash_report: u16,
+    _padding: u16,
+}
+
+/// The flow hash which the device calculated for a received packet, when
+/// `VIRTIO_NET_F_HASH_REPORT` is negotiated.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RxHash {
+    /// The hash value.
+    pub value: u32,
+    /// The type of hash which was calculated, one of the
+    /// `VIRTIO_NET_HASH_REPORT_*` values from the VirtIO specification, or 0
+    /// if no hash was calculated.
+    pub report: u16,
+}
+
+impl RxHash {
+    /// Reads the hash from the header at the start of a received buffer, if
+    /// the buffer is long enough to contain it.
+    fn from_header(buf: &[u8]) -> Option<Self> {
+        let (header, _) = VirtioNetHdrHash::read_from_prefix(buf).ok()?;
+        Some(Self {
+            value: header.hash_value,
+            report: header.hash_report,
+        })
+    }
+}
+
 #[derive(
     IntoBytes, Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, KnownLayout, PartialEq,
 )]
@@ -156,9 +260,69 @@ impl GsoType {
     const ECN: GsoType = GsoType(0x80);
 }
 
+/// The header of a command sent on the control queue.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct CtrlHeader {
+    class: u8,
+    command: u8,
+}
+
+/// The command class for receive filtering modes.
+const CTRL_CLASS_RX: u8 = 0;
+/// The command to enable or disable promiscuous mode, within [`CTRL_CLASS_RX`].
+const CTRL_RX_PROMISC: u8 = 0;
+/// The command to enable or disable receiving all multicast packets, within [`CTRL_CLASS_RX`].
+const CTRL_RX_ALLMULTI: u8 = 1;
+
+/// The command class for MAC address filtering and configuration.
+const CTRL_CLASS_MAC: u8 = 1;
+/// The command to set the device's MAC address, within [`CTRL_CLASS_MAC`].
+const CTRL_MAC_ADDR_SET: u8 = 1;
+
+/// The command class for gratuitous packet announcements.
+const CTRL_CLASS_ANNOUNCE: u8 = 3;
+/// The command to acknowledge an announcement request, within [`CTRL_CLASS_ANNOUNCE`].
+const CTRL_ANNOUNCE_ACK: u8 = 0;
+
+/// The command class for multiqueue and RSS configuration.
+const CTRL_CLASS_MQ: u8 = 4;
+/// The command to set the number of queue pairs in use, within [`CTRL_CLASS_MQ`].
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+/// The command to set the RSS configuration, within [`CTRL_CLASS_MQ`].
+const CTRL_MQ_RSS_CONFIG: u8 = 1;
+
+/// The ack status returned by the device for a successful control command.
+const CTRL_OK: u8 = 0;
+
+/// The fixed-size fields at the start of the `virtio_net_rss_config` structure,
+/// before the indirection table.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct RssConfigHeader {
+    hash_types: u32,
+    indirection_table_mask: u16,
+    unclassified_queue: u16,
+}
+
 const QUEUE_RECEIVE: u16 = 0;
 const QUEUE_TRANSMIT: u16 = 1;
+/// The index of the control queue when multiqueue is not negotiated, or the
+/// number to multiply the maximum number of queue pairs by when it is.
+const QUEUE_CONTROL: u16 = 2;
 const SUPPORTED_FEATURES: Features = Features::MAC
+    .union(Features::CSUM)
+    .union(Features::HOST_TSO4)
+    .union(Features::HOST_TSO6)
+    .union(Features::MTU)
     .union(Features::STATUS)
+    .union(Features::CTRL_VQ)
+    .union(Features::CTRL_RX)
+    .union(Features::CTL_MAC_ADDR)
+    .union(Features::MQ)
+    .union(Features::GUEST_ANNOUNCE)
+    .union(Features::RSS)
+    .union(Features::HASH_REPORT)
     .union(Features::RING_EVENT_IDX)
-    .union(Features::RING_INDIRECT_DESC);
+    .union(Features::RING_INDIRECT_DESC)
+    .union(Features::NOTIFICATION_DATA);
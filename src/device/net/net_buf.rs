@@ -1,7 +1,7 @@
-use super::{VirtioNetHdr, NET_HDR_SIZE};
+use super::{RxHash, VirtioNetHdr, NET_HDR_HASH_SIZE, NET_HDR_SIZE};
 use alloc::{vec, vec::Vec};
 use core::{convert::TryInto, mem::size_of};
-use zerocopy::IntoBytes;
+use zerocopy::{FromBytes, IntoBytes};
 
 /// A buffer used for transmitting.
 pub struct TxBuffer(pub(crate) Vec<u8>);
@@ -10,7 +10,15 @@ pub struct TxBuffer(pub(crate) Vec<u8>);
 pub struct RxBuffer {
     pub(crate) buf: Vec<usize>, // for alignment
     pub(crate) packet_len: usize,
+    /// The length of the header which precedes the packet.
+    pub(crate) hdr_len: usize,
+    /// The offset of the header within `buf`.
+    pub(crate) hdr_offset: usize,
+    /// The offset of the packet within `buf`.
+    pub(crate) packet_offset: usize,
     pub(crate) idx: u16,
+    /// The queue pair on which the buffer is used.
+    pub(crate) pair: usize,
 }
 
 impl TxBuffer {
@@ -36,15 +44,48 @@ impl TxBuffer {
 }
 
 impl RxBuffer {
-    /// Allocates a new buffer with length `buf_len`.
-    pub(crate) fn new(idx: usize, buf_len: usize) -> Self {
+    /// Allocates a new buffer with length `buf_len`, for packets preceded by a
+    /// header of length `hdr_len`, to be used on the given queue pair.
+    pub(crate) fn new(idx: usize, pair: usize, buf_len: usize, hdr_len: usize) -> Self {
         Self {
             buf: vec![0; buf_len / size_of::<usize>()],
             packet_len: 0,
+            hdr_len,
+            hdr_offset: 0,
+            packet_offset: hdr_len,
             idx: idx.try_into().unwrap(),
+            pair,
         }
     }
 
+    /// Allocates a new buffer for packets of up to `packet_buf_len` bytes with
+    /// the [`VirtioNetHdr`] after them, to be given to the device as separate
+    /// descriptors on queue pair 0.
+    pub(crate) fn new_split(idx: usize, packet_buf_len: usize) -> Self {
+        let hdr_offset = packet_buf_len.next_multiple_of(size_of::<usize>());
+        Self {
+            buf: vec![0; (hdr_offset + NET_HDR_SIZE).div_ceil(size_of::<usize>())],
+            packet_len: 0,
+            hdr_len: NET_HDR_SIZE,
+            hdr_offset,
+            packet_offset: 0,
+            idx: idx.try_into().unwrap(),
+            pair: 0,
+        }
+    }
+
+    /// Returns whether the buffer was allocated by [`new_split`](Self::new_split).
+    pub(crate) fn is_split(&self) -> bool {
+        self.hdr_offset != 0
+    }
+
+    /// Returns the header and packet parts of a buffer allocated by
+    /// [`new_split`](Self::new_split).
+    pub(crate) fn split_mut(&mut self) -> (&mut VirtioNetHdr, &mut [u8]) {
+        let (packet, hdr) = self.buf.as_mut_bytes().split_at_mut(self.hdr_offset);
+        (VirtioNetHdr::mut_from_prefix(hdr).unwrap().0, packet)
+    }
+
     /// Set the network packet length.
     pub(crate) fn set_packet_len(&mut self, packet_len: usize) {
         self.packet_len = packet_len
@@ -68,16 +109,26 @@ impl RxBuffer {
 
     /// Returns the reference of the header.
     pub fn header(&self) -> &VirtioNetHdr {
-        unsafe { &*(self.buf.as_ptr() as *const VirtioNetHdr) }
+        unsafe { &*(self.buf.as_bytes()[self.hdr_offset..].as_ptr() as *const VirtioNetHdr) }
+    }
+
+    /// Returns the flow hash which the device reported for the packet, or
+    /// `None` if the hash report feature was not negotiated.
+    pub fn hash(&self) -> Option<RxHash> {
+        if self.hdr_len == NET_HDR_HASH_SIZE {
+            RxHash::from_header(self.as_bytes())
+        } else {
+            None
+        }
     }
 
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        &self.buf.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &self.buf.as_bytes()[self.packet_offset..self.packet_offset + self.packet_len]
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        &mut self.buf.as_mut_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &mut self.buf.as_mut_bytes()[self.packet_offset..self.packet_offset + self.packet_len]
     }
 }
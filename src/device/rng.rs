@@ -0,0 +1,191 @@
+//! Driver for VirtIO entropy source devices.
+
+use super::common::Feature;
+use super::VirtioDevice;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::Result;
+
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: usize = 4;
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC
+    .union(Feature::RING_EVENT_IDX)
+    .union(Feature::NOTIFICATION_DATA);
+
+/// Driver for a VirtIO entropy source device, which provides random bytes from the host.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::rng::VirtIORng;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut rng = VirtIORng::<HalImpl, _>::new(transport)?;
+///
+/// let mut seed = [0; 32];
+/// let mut filled = 0;
+/// while filled < seed.len() {
+///     filled += rng.request_entropy(&mut seed[filled..])?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIORng<H: Hal, T: Transport> {
+    transport: T,
+    request_queue: VirtQueue<H, QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport> VirtIORng<H, T> {
+    /// Creates a new VirtIO entropy source driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let request_queue = VirtQueue::new(
+            &mut transport,
+            QUEUE_REQUEST,
+            negotiated_features.contains(Feature::RING_INDIRECT_DESC),
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+        transport.finish_init();
+        Ok(Self {
+            transport,
+            request_queue,
+        })
+    }
+
+    /// Asks the device to fill the given buffer with random bytes, blocking until it has done so.
+    ///
+    /// Returns the number of bytes which the device filled, starting from the beginning of the
+    /// buffer. This may be less than the length of the buffer, so callers needing a certain
+    /// amount of entropy should call it again for the rest. It is never more than the length of
+    /// the buffer, even if the device claims to have written more.
+    pub fn request_entropy(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = self
+            .request_queue
+            .add_notify_wait_pop(&[], &mut [buf], &mut self.transport)?;
+        Ok((len as usize).min(buf.len()))
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns which interrupts were pending.
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIORng<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.transport.queue_unset(QUEUE_REQUEST);
+        // SAFETY: The device has been reset so won't access the queue again, and nothing is left in
+        // it between requests.
+        unsafe { self.request_queue.reclaim_all() };
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIORng<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, QueueStatus, State},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{
+        ptr,
+        sync::atomic::{AtomicU16, Ordering},
+    };
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn request_entropy() {
+        let state = Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ())));
+        let transport = FakeTransport {
+            device_type: DeviceType::EntropySource,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut rng = VirtIORng::<FakeHal, FakeTransport<()>>::new(transport).unwrap();
+
+        // Simulate the device only filling part of the buffer.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            assert!(state.lock().unwrap().read_write_queue::<QUEUE_SIZE>(
+                QUEUE_REQUEST,
+                |request| {
+                    assert_eq!(request, vec![]);
+                    vec![1, 2, 3]
+                }
+            ));
+        });
+
+        let mut buf = [0; 8];
+        assert_eq!(rng.request_entropy(&mut buf), Ok(3));
+        assert_eq!(buf, [1, 2, 3, 0, 0, 0, 0, 0]);
+        assert_eq!(rng.request_entropy(&mut []), Ok(0));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn request_entropy_over_reported() {
+        let state = Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ())));
+        let transport = FakeTransport {
+            device_type: DeviceType::EntropySource,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut rng = VirtIORng::<FakeHal, FakeTransport<()>>::new(transport).unwrap();
+
+        // Simulate a device which fills the buffer but claims to have written more than that.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            let state = state.lock().unwrap();
+            let queue = &state.queues[usize::from(QUEUE_REQUEST)];
+            // SAFETY: The driver set up the split queue at these addresses, and won't touch the
+            // buffer again until the used index changes.
+            unsafe {
+                // The first entry of the available ring follows its flags and index.
+                let head = *((queue.driver_area + 4) as *const u16);
+                let descriptor = (queue.descriptors + 16 * usize::from(head)) as *const u64;
+                let len = *(descriptor.add(1) as *const u32);
+                ptr::write_bytes(*descriptor as *mut u8, 0x42, len as usize);
+                // The first element of the used ring follows its flags and index.
+                let used = (queue.device_area + 4) as *mut u32;
+                used.write_volatile(head.into());
+                used.add(1).write_volatile(len + 100);
+                (*((queue.device_area + 2) as *const AtomicU16)).store(1, Ordering::Release);
+            }
+        });
+
+        let mut buf = [0; 8];
+        assert_eq!(rng.request_entropy(&mut buf), Ok(8));
+        assert_eq!(buf, [0x42; 8]);
+        handle.join().unwrap();
+    }
+}
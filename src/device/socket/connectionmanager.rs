@@ -1,6 +1,8 @@
 use super::{
-    protocol::VsockAddr, vsock::ConnectionInfo, DisconnectReason, SocketError, VirtIOSocket,
-    VsockEvent, VsockEventType, DEFAULT_RX_BUFFER_SIZE,
+    protocol::VsockAddr,
+    vsock::{ConnectionInfo, VsockBufferStatus},
+    DisconnectReason, SocketError, VirtIOSocket, VsockEvent, VsockEventType,
+    DEFAULT_RX_BUFFER_SIZE,
 };
 use crate::{transport::Transport, Hal, Result};
 use alloc::{boxed::Box, vec::Vec};
@@ -52,6 +54,9 @@ pub struct VsockConnectionManager<
     per_connection_buffer_capacity: u32,
     connections: Vec<Connection>,
     listening_ports: Vec<u32>,
+    /// Disconnection events for connections which were closed by a transport reset, which haven't
+    /// yet been returned by `poll`.
+    reset_events: Vec<VsockEvent>,
 }
 
 #[derive(Debug)]
@@ -61,6 +66,9 @@ struct Connection {
     /// The peer sent a SHUTDOWN request, but we haven't yet responded with a RST because there is
     /// still data in the buffer.
     peer_requested_shutdown: bool,
+    /// The peer connected to one of our listening ports, and the connection hasn't yet been
+    /// returned by `accept`.
+    pending_accept: bool,
 }
 
 impl Connection {
@@ -71,6 +79,7 @@ impl Connection {
             info,
             buffer: RingBuffer::new(buffer_capacity.try_into().unwrap()),
             peer_requested_shutdown: false,
+            pending_accept: false,
         }
     }
 }
@@ -93,6 +102,7 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize>
             driver,
             connections: Vec::new(),
             listening_ports: Vec::new(),
+            reset_events: Vec::new(),
             per_connection_buffer_capacity,
         }
     }
@@ -103,6 +113,9 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize>
     }
 
     /// Allows incoming connections on the given port number.
+    ///
+    /// Connection requests to the port are accepted automatically by [`poll`](Self::poll), and can
+    /// then be taken with [`accept`](Self::accept).
     pub fn listen(&mut self, port: u32) {
         if !self.listening_ports.contains(&port) {
             self.listening_ports.push(port);
@@ -143,7 +156,39 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize>
     }
 
     /// Polls the vsock device to receive data or other updates.
+    ///
+    /// If the device reports a transport reset then all connections are forgotten, as the device
+    /// has already closed them, so any further operations on them will return
+    /// [`SocketError::NotConnected`]. A `VsockEventType::Disconnected` event with
+    /// [`DisconnectReason::Reset`] is returned for each of them, one per call, before any other
+    /// events. Any data received on them which hadn't yet been read is discarded.
     pub fn poll(&mut self) -> Result<Option<VsockEvent>> {
+        let old_guest_cid = self.driver.guest_cid();
+        if self.driver.poll_transport_reset()? {
+            debug!(
+                "Transport reset, dropping {} connections",
+                self.connections.len()
+            );
+            self.reset_events
+                .extend(self.connections.drain(..).map(|connection| VsockEvent {
+                    source: connection.info.dst,
+                    destination: VsockAddr {
+                        cid: old_guest_cid,
+                        port: connection.info.src_port,
+                    },
+                    buffer_status: VsockBufferStatus {
+                        buffer_allocation: 0,
+                        forward_count: 0,
+                    },
+                    event_type: VsockEventType::Disconnected {
+                        reason: DisconnectReason::Reset,
+                    },
+                }));
+        }
+        if !self.reset_events.is_empty() {
+            return Ok(Some(self.reset_events.remove(0)));
+        }
+
         let guest_cid = self.driver.guest_cid();
         let connections = &mut self.connections;
         let per_connection_buffer_capacity = self.per_connection_buffer_capacity;
@@ -197,6 +242,7 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize>
             VsockEventType::ConnectionRequest => {
                 if self.listening_ports.contains(&event.destination.port) {
                     self.driver.accept(&connection.info)?;
+                    connection.pending_accept = true;
                 } else {
                     // Reject the connection request and remove it from our list.
                     self.driver.force_close(&connection.info)?;
@@ -233,6 +279,22 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize>
         Ok(Some(event))
     }
 
+    /// Returns the peer address and local port of an incoming connection which [`poll`](Self::poll)
+    /// has accepted on one of the listening ports, if there are any which haven't already been
+    /// returned.
+    ///
+    /// This doesn't poll the device itself, so `poll` or
+    /// [`wait_for_event`](Self::wait_for_event) must be called to receive connection requests.
+    /// Connections which are closed before being accepted are not returned.
+    pub fn accept(&mut self) -> Option<(VsockAddr, u32)> {
+        let connection = self
+            .connections
+            .iter_mut()
+            .find(|connection| connection.pending_accept)?;
+        connection.pending_accept = false;
+        Some((connection.info.dst, connection.info.src_port))
+    }
+
     /// Reads data received from the given connection.
     pub fn recv(&mut self, peer: VsockAddr, src_port: u32, buffer: &mut [u8]) -> Result<usize> {
         let (connection_index, connection) = get_connection(&mut self.connections, peer, src_port)?;
@@ -420,7 +482,10 @@ mod tests {
             protocol::{
                 SocketType, StreamShutdown, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp,
             },
-            vsock::{VsockBufferStatus, QUEUE_SIZE, RX_QUEUE_IDX, TX_QUEUE_IDX},
+            vsock::{
+                VsockBufferStatus, EVENT_QUEUE_IDX, QUEUE_SIZE, RX_QUEUE_IDX, TX_QUEUE_IDX,
+                VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+            },
         },
         hal::fake::FakeHal,
         transport::{
@@ -781,6 +846,7 @@ mod tests {
 
         // Expect an incoming connection.
         println!("Guest expecting incoming connection.");
+        assert_eq!(socket.accept(), None);
         assert_eq!(
             socket.wait_for_event().unwrap(),
             VsockEvent {
@@ -796,7 +862,86 @@ mod tests {
                 },
             }
         );
+        assert_eq!(socket.accept(), Some((host_address, guest_port)));
+        assert_eq!(socket.accept(), None);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn transport_reset() {
+        let host_address = VsockAddr { cid: 2, port: 1234 };
+        let guest_ports = [4321, 4322];
+
+        let config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut socket = VsockConnectionManager::new(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+        );
+
+        // Start a thread to simulate the device.
+        let handle = thread::spawn(move || {
+            // Wait for both connection requests.
+            for _ in guest_ports {
+                State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+            }
 
+            // The guest is migrated and gets a new CID.
+            let mut state = state.lock().unwrap();
+            state.config_space.guest_cid_low = ReadOnly::new(67);
+            state.write_to_queue::<QUEUE_SIZE>(
+                EVENT_QUEUE_IDX,
+                &VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes(),
+            );
+        });
+
+        for port in guest_ports {
+            socket.connect(host_address, port).unwrap();
+        }
         handle.join().unwrap();
+
+        // Each connection should be reported as reset, on the old CID.
+        for port in guest_ports {
+            assert_eq!(
+                socket.poll().unwrap(),
+                Some(VsockEvent {
+                    source: host_address,
+                    destination: VsockAddr { cid: 66, port },
+                    event_type: VsockEventType::Disconnected {
+                        reason: DisconnectReason::Reset,
+                    },
+                    buffer_status: VsockBufferStatus {
+                        buffer_allocation: 0,
+                        forward_count: 0,
+                    },
+                })
+            );
+        }
+        assert_eq!(socket.poll().unwrap(), None);
+        assert_eq!(socket.guest_cid(), 67);
+        assert_eq!(
+            socket.send(host_address, guest_ports[0], &[1]),
+            Err(SocketError::NotConnected.into())
+        );
     }
 }
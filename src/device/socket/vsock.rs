@@ -3,7 +3,8 @@
 
 use super::error::SocketError;
 use super::protocol::{
-    Feature, StreamShutdown, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr,
+    Feature, StreamShutdown, VirtioVsockConfig, VirtioVsockEvent, VirtioVsockHdr, VirtioVsockOp,
+    VsockAddr,
 };
 use super::DEFAULT_RX_BUFFER_SIZE;
 use crate::config::read_config;
@@ -17,10 +18,15 @@ use zerocopy::{FromBytes, IntoBytes};
 
 pub(crate) const RX_QUEUE_IDX: u16 = 0;
 pub(crate) const TX_QUEUE_IDX: u16 = 1;
-const EVENT_QUEUE_IDX: u16 = 2;
+pub(crate) const EVENT_QUEUE_IDX: u16 = 2;
 
 pub(crate) const QUEUE_SIZE: usize = 8;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX.union(Feature::RING_INDIRECT_DESC);
+const EVENT_BUFFER_SIZE: usize = size_of::<VirtioVsockEvent>();
+/// The event sent by the device when it has been reset, e.g. after the guest was migrated.
+pub(crate) const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_INDIRECT_DESC)
+    .union(Feature::NOTIFICATION_DATA);
 
 /// Information about a particular vsock connection.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -223,7 +229,7 @@ pub struct VirtIOSocket<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize = DEFA
     rx: OwningQueue<H, QUEUE_SIZE, RX_BUFFER_SIZE>,
     tx: VirtQueue<H, { QUEUE_SIZE }>,
     /// Virtqueue to receive events from the device.
-    event: VirtQueue<H, { QUEUE_SIZE }>,
+    event: OwningQueue<H, QUEUE_SIZE, EVENT_BUFFER_SIZE>,
     /// The guest_cid field contains the guest’s context ID, which uniquely identifies
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
@@ -248,12 +254,7 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize> VirtIOSocket<H, T, RX_BU
 
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let guest_cid = transport.read_consistent(|| {
-            Ok(
-                read_config!(transport, VirtioVsockConfig, guest_cid_low)? as u64
-                    | (read_config!(transport, VirtioVsockConfig, guest_cid_high)? as u64) << 32,
-            )
-        })?;
+        let guest_cid = read_guest_cid(&transport)?;
         debug!("guest cid: {guest_cid:?}");
 
         let rx = VirtQueue::new(
@@ -276,10 +277,14 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize> VirtIOSocket<H, T, RX_BU
         )?;
 
         let rx = OwningQueue::new(rx)?;
+        let event = OwningQueue::new(event)?;
 
         transport.finish_init();
         if rx.should_notify() {
-            transport.notify(RX_QUEUE_IDX);
+            rx.notify(&mut transport);
+        }
+        if event.should_notify() {
+            event.notify(&mut transport);
         }
 
         Ok(Self {
@@ -296,6 +301,31 @@ impl<H: Hal, T: Transport, const RX_BUFFER_SIZE: usize> VirtIOSocket<H, T, RX_BU
         self.guest_cid
     }
 
+    /// Handles any events which the device has sent on the event queue.
+    ///
+    /// Returns true if the device has reported a transport reset, e.g. because the guest was
+    /// migrated to another host. In that case all existing connections have been closed by the
+    /// device, and the guest CID is re-read as it may have changed.
+    pub fn poll_transport_reset(&mut self) -> Result<bool> {
+        let mut reset = false;
+        while let Some(id) = self.event.poll(&mut self.transport, |buffer| {
+            let event = VirtioVsockEvent::read_from_bytes(buffer)
+                .map_err(|_| SocketError::BufferTooShort)?;
+            Ok(Some(event.id.get()))
+        })? {
+            if id == VIRTIO_VSOCK_EVENT_TRANSPORT_RESET {
+                reset = true;
+            } else {
+                debug!("Ignoring unknown vsock event {id}");
+            }
+        }
+        if reset {
+            self.guest_cid = read_guest_cid(&self.transport)?;
+            debug!("transport reset, guest cid: {:?}", self.guest_cid);
+        }
+        Ok(reset)
+    }
+
     /// Sends a request to connect to the given destination.
     ///
     /// This returns as soon as the request is sent; you should wait until `poll` returns a
@@ -458,6 +488,16 @@ fn read_header_and_body(buffer: &[u8]) -> Result<(VirtioVsockHdr, &[u8])> {
     Ok((header, data))
 }
 
+/// Reads the guest CID from the device's config space.
+fn read_guest_cid(transport: &impl Transport) -> Result<u64> {
+    transport.read_consistent(|| {
+        Ok(
+            read_config!(*transport, VirtioVsockConfig, guest_cid_low)? as u64
+                | (read_config!(*transport, VirtioVsockConfig, guest_cid_high)? as u64) << 32,
+        )
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -496,4 +536,43 @@ mod tests {
             VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
         assert_eq!(socket.guest_cid(), 0x00_0000_0042);
     }
+
+    #[test]
+    fn transport_reset() {
+        let config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut socket =
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
+        assert_eq!(socket.poll_transport_reset(), Ok(false));
+
+        // The guest is migrated and gets a new CID.
+        {
+            let mut state = state.lock().unwrap();
+            state.config_space.guest_cid_low = ReadOnly::new(67);
+            state.write_to_queue::<QUEUE_SIZE>(
+                EVENT_QUEUE_IDX,
+                &VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes(),
+            );
+        }
+        assert_eq!(socket.guest_cid(), 66);
+        assert_eq!(socket.poll_transport_reset(), Ok(true));
+        assert_eq!(socket.guest_cid(), 67);
+        assert_eq!(socket.poll_transport_reset(), Ok(false));
+    }
 }
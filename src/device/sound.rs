@@ -4,10 +4,11 @@
 mod fake;
 
 use super::common::Feature;
+use super::VirtioDevice;
 use crate::{
     config::{read_config, ReadOnly},
-    queue::{owning::OwningQueue, VirtQueue},
-    transport::Transport,
+    queue::{owning::OwningQueue, Queue, VirtQueue},
+    transport::{DeviceType, InterruptStatus, Transport},
     Error, Hal, Result, PAGE_SIZE,
 };
 use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
@@ -25,9 +26,8 @@ use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
 /// Audio driver based on virtio v1.2.
 ///
-/// Supports synchronous blocking and asynchronous non-blocking audio playback.
-///
-/// Currently, only audio playback functionality has been implemented.
+/// Supports synchronous blocking and asynchronous non-blocking audio playback, and synchronous
+/// blocking audio capture.
 pub struct VirtIOSound<H: Hal, T: Transport> {
     transport: T,
 
@@ -48,6 +48,7 @@ pub struct VirtIOSound<H: Hal, T: Transport> {
 
     // pcm params
     pcm_parameters: Vec<PcmParameters>,
+    pcm_positions: Vec<PcmPosition>,
 
     queue_buf_send: Box<[u8]>,
     queue_buf_recv: Box<[u8]>,
@@ -112,11 +113,12 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         for _ in 0..streams {
             pcm_parameters.push(PcmParameters::default());
         }
+        let pcm_positions = vec![PcmPosition::default(); streams as usize];
 
         transport.finish_init();
 
         if event_queue.should_notify() {
-            transport.notify(EVENT_QUEUE_IDX);
+            event_queue.notify(&mut transport);
         }
 
         Ok(VirtIOSound {
@@ -135,6 +137,7 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
             queue_buf_send,
             queue_buf_recv,
             pcm_parameters,
+            pcm_positions,
             set_up: false,
             token_rsp: BTreeMap::new(),
             pcm_states: vec![],
@@ -158,7 +161,7 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
     }
 
     /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> bool {
+    pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
     }
 
@@ -315,6 +318,24 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         Ok(chmap_infos)
     }
 
+    /// Queries the device for the current information about all jacks, including whether each one
+    /// is connected.
+    ///
+    /// Returns [`Error::InvalidParam`] if the device has no jacks.
+    pub fn jack_infos(&mut self) -> Result<Vec<VirtIOSndJackInfo>> {
+        if !self.set_up {
+            self.set_up()?;
+            self.set_up = true;
+        }
+        if self.jacks == 0 {
+            error!("[sound device] There is no available jacks!");
+            return Err(Error::InvalidParam);
+        }
+        let jack_infos = self.jack_info(0, self.jacks)?;
+        self.jack_infos = Some(jack_infos.clone());
+        Ok(jack_infos)
+    }
+
     /// If the VIRTIO_SND_JACK_F_REMAP feature bit is set in the jack information, then the driver can send a
     /// control request to change the association and/or sequence number for the specified jack ID.
     /// # Arguments
@@ -423,6 +444,9 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         })?;
         // rsp is just a header, so it can be compared with VirtIOSndHdr
         if rsp == VirtIOSndHdr::from(RequestStatusCode::Ok) {
+            if let Some(position) = self.pcm_positions.get_mut(stream_id as usize) {
+                *position = PcmPosition::default();
+            }
             Ok(())
         } else {
             Err(Error::IoError)
@@ -488,24 +512,75 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
 
     /// Transfer PCM frame to device, based on the stream type(OUTPUT/INPUT).
     ///
-    /// Currently supports only output stream.
+    /// Only supports output streams; use [`pcm_capture`](Self::pcm_capture) for input streams.
     ///
     /// This is a blocking method that will not return until the audio playback is complete.
     pub fn pcm_xfer(&mut self, stream_id: u32, frames: &[u8]) -> Result {
-        const U32_SIZE: usize = size_of::<u32>();
         if !self.set_up {
             self.set_up()?;
             self.set_up = true;
         }
+        if self.is_input_stream(stream_id)? {
+            return Err(Error::InvalidParam);
+        }
         if !self.pcm_parameters[stream_id as usize].setup {
             warn!("Please set parameters for a stream before using it!");
             return Err(Error::IoError);
         }
 
-        let stream_id_bytes = stream_id.to_le_bytes();
         let period_size = self.pcm_parameters[stream_id as usize].period_bytes as usize;
+        self.pcm_xfer_chunks(stream_id, frames, period_size, usize::from(QUEUE_SIZE))
+    }
+
+    /// Transfers PCM frames to an output stream in chunks of `period_bytes`, keeping no more
+    /// chunks in flight than fit in the buffer set for the stream with
+    /// [`pcm_set_params`](Self::pcm_set_params), and waiting for each to complete before sending
+    /// the next.
+    ///
+    /// This streams the data the way the device consumes it, rather than filling the TX queue
+    /// with as much as possible. `period_bytes` would normally be the period size set for the
+    /// stream.
+    ///
+    /// This is a blocking method that will not return until the audio playback is complete.
+    /// Returns [`Error::InvalidParam`] if `period_bytes` is 0.
+    pub fn pcm_xfer_periodic(
+        &mut self,
+        stream_id: u32,
+        frames: &[u8],
+        period_bytes: usize,
+    ) -> Result {
+        if period_bytes == 0 {
+            return Err(Error::InvalidParam);
+        }
+        if !self.set_up {
+            self.set_up()?;
+            self.set_up = true;
+        }
+        if self.is_input_stream(stream_id)? {
+            return Err(Error::InvalidParam);
+        }
+        let parameters = &self.pcm_parameters[stream_id as usize];
+        if !parameters.setup {
+            warn!("Please set parameters for a stream before using it!");
+            return Err(Error::IoError);
+        }
 
-        let mut remaining_buffers = frames.chunks(period_size);
+        let max_in_flight = (parameters.buffer_bytes as usize / period_bytes).max(1);
+        self.pcm_xfer_chunks(stream_id, frames, period_bytes, max_in_flight)
+    }
+
+    /// Transfers the given PCM frames to the device in chunks of `chunk_size` bytes, with at most
+    /// `max_in_flight` chunks on the TX queue at once, and waits for them all to complete.
+    fn pcm_xfer_chunks(
+        &mut self,
+        stream_id: u32,
+        frames: &[u8],
+        chunk_size: usize,
+        max_in_flight: usize,
+    ) -> Result {
+        const U32_SIZE: usize = size_of::<u32>();
+        let stream_id_bytes = stream_id.to_le_bytes();
+        let mut remaining_buffers = frames.chunks(chunk_size);
         let mut buffers: [Option<&[u8]>; QUEUE_SIZE as usize] = [None; QUEUE_SIZE as usize];
         let mut statuses: [VirtIOSndPcmStatus; QUEUE_SIZE as usize] =
             array::from_fn(|_| Default::default());
@@ -514,11 +589,13 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         let mut head = 0;
         // The next element of `status` and `tokens` to use for popping the queue.
         let mut tail = 0;
+        // The number of buffers currently on the queue.
+        let mut in_flight = 0;
 
         loop {
             // Add as buffers to the TX queue if possible. 3 descriptors are required for the 2
             // input buffers and 1 output buffer.
-            if self.tx_queue.available_desc() >= 3 {
+            if self.tx_queue.available_desc() >= 3 && in_flight < max_in_flight {
                 if let Some(buffer) = remaining_buffers.next() {
                     tokens[head] = unsafe {
                         self.tx_queue.add(
@@ -527,14 +604,15 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
                         )?
                     };
                     if self.tx_queue.should_notify() {
-                        self.transport.notify(TX_QUEUE_IDX);
+                        self.tx_queue.notify(&mut self.transport);
                     }
                     buffers[head] = Some(buffer);
+                    in_flight += 1;
                     head += 1;
                     if head >= usize::from(QUEUE_SIZE) {
                         head = 0;
                     }
-                } else if head == tail {
+                } else if in_flight == 0 {
                     break;
                 }
             }
@@ -549,6 +627,9 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
                 if statuses[tail].status != CommandCode::SOk.into() {
                     return Err(Error::IoError);
                 }
+                self.pcm_positions[stream_id as usize]
+                    .complete(buffers[tail].unwrap().len(), &statuses[tail]);
+                in_flight -= 1;
                 tail += 1;
                 if tail >= usize::from(QUEUE_SIZE) {
                     tail = 0;
@@ -562,36 +643,64 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
 
     /// Transfer PCM frame to device, based on the stream type(OUTPUT/INPUT).
     ///
-    /// Currently supports only output stream.
+    /// Only supports output streams.
     ///
-    /// This is a non-blocking method that returns a token.
+    /// This is a non-blocking method that returns a token. Once the device has played the frames,
+    /// the buffer must be reclaimed by passing the token to
+    /// [`poll_xfer_complete`](Self::poll_xfer_complete) or [`pcm_xfer_ok`](Self::pcm_xfer_ok).
+    /// Several transfers may be in flight at once, to keep playback gapless.
     ///
-    /// The length of the `frames` must be equal to the buffer size set for the stream corresponding to the `stream_id`.
+    /// The length of the `frames` must be equal to the period size set for the stream corresponding
+    /// to the `stream_id`, otherwise [`Error::InvalidParam`] is returned.
     pub fn pcm_xfer_nb(&mut self, stream_id: u32, frames: &[u8]) -> Result<u16> {
         if !self.set_up {
             self.set_up()?;
             self.set_up = true;
         }
+        if self.is_input_stream(stream_id)? {
+            return Err(Error::InvalidParam);
+        }
         if !self.pcm_parameters[stream_id as usize].setup {
             warn!("Please set parameters for a stream before using it!");
             return Err(Error::IoError);
         }
         const U32_SIZE: usize = size_of::<u32>();
         let period_size: usize = self.pcm_parameters[stream_id as usize].period_bytes as usize;
-        assert_eq!(period_size, frames.len());
+        if frames.len() != period_size {
+            return Err(Error::InvalidParam);
+        }
         let mut buf = vec![0; U32_SIZE + period_size];
         buf[..U32_SIZE].copy_from_slice(&stream_id.to_le_bytes());
         buf[U32_SIZE..U32_SIZE + period_size].copy_from_slice(frames);
         let mut rsp = VirtIOSndPcmStatus::new_box_zeroed().unwrap();
         let token = unsafe { self.tx_queue.add(&[&buf], &mut [rsp.as_mut_bytes()])? };
         if self.tx_queue.should_notify() {
-            self.transport.notify(TX_QUEUE_IDX);
+            self.tx_queue.notify(&mut self.transport);
         }
         self.token_buf.insert(token, buf);
         self.token_rsp.insert(token, rsp);
         Ok(token)
     }
 
+    /// Checks whether the transfer with the given token, returned by
+    /// [`pcm_xfer_nb`](Self::pcm_xfer_nb), has been completed by the device, and if so reclaims
+    /// its buffer as for [`pcm_xfer_ok`](Self::pcm_xfer_ok) and returns true.
+    ///
+    /// This doesn't block. The device completes transfers in order, so this only returns true once
+    /// all transfers submitted before this one have been reclaimed.
+    ///
+    /// Returns [`Error::WrongToken`] if there is no transfer in flight with the given token.
+    pub fn poll_xfer_complete(&mut self, token: u16) -> Result<bool> {
+        if !self.token_buf.contains_key(&token) {
+            return Err(Error::WrongToken);
+        }
+        if self.tx_queue.peek_used() != Some(token) {
+            return Ok(false);
+        }
+        self.pcm_xfer_ok(token)?;
+        Ok(true)
+    }
+
     /// The PCM frame transmission corresponding to the given token has been completed.
     pub fn pcm_xfer_ok(&mut self, token: u16) -> Result {
         assert!(self.token_buf.contains_key(&token));
@@ -604,11 +713,156 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
             )?;
         }
 
-        self.token_buf.remove(&token);
-        self.token_rsp.remove(&token);
+        let buf = self.token_buf.remove(&token).unwrap();
+        let rsp = self.token_rsp.remove(&token).unwrap();
+        let (stream_id, frames) = buf.split_at(size_of::<u32>());
+        let stream_id = u32::from_le_bytes(stream_id.try_into().unwrap());
+        if let Some(position) = self.pcm_positions.get_mut(stream_id as usize) {
+            position.complete(frames.len(), &rsp);
+        }
         Ok(())
     }
 
+    /// Captures PCM frames from an input stream into the given buffer.
+    ///
+    /// The buffer is filled in chunks of the period size set for the stream with
+    /// [`pcm_set_params`](Self::pcm_set_params), so its length must be a multiple of the period
+    /// size. The stream should have been prepared and started with
+    /// [`pcm_prepare`](Self::pcm_prepare) and [`pcm_start`](Self::pcm_start) first.
+    ///
+    /// This is a blocking method that will not return until the buffer has been filled.
+    /// Returns [`Error::InvalidParam`] if the stream is not an input stream or the length of the
+    /// buffer is not a multiple of the period size. If the device fails any period with
+    /// [`Error::IoError`], the periods already queued are still waited for before returning.
+    pub fn pcm_capture(&mut self, stream_id: u32, buf: &mut [u8]) -> Result {
+        if !self.set_up {
+            self.set_up()?;
+            self.set_up = true;
+        }
+        if !self.is_input_stream(stream_id)? {
+            return Err(Error::InvalidParam);
+        }
+        let parameters = &self.pcm_parameters[stream_id as usize];
+        if !parameters.setup {
+            warn!("Please set parameters for a stream before using it!");
+            return Err(Error::IoError);
+        }
+        let period_bytes = parameters.period_bytes as usize;
+        if !buf.len().is_multiple_of(period_bytes) {
+            return Err(Error::InvalidParam);
+        }
+
+        let stream_id_bytes = stream_id.to_le_bytes();
+        let mut remaining_buffers = buf.chunks_mut(period_bytes);
+        let mut buffers: [Option<&mut [u8]>; QUEUE_SIZE as usize] = array::from_fn(|_| None);
+        let mut statuses: [VirtIOSndPcmStatus; QUEUE_SIZE as usize] =
+            array::from_fn(|_| Default::default());
+        let mut tokens = [0; QUEUE_SIZE as usize];
+        // The next element of `buffers`, `statuses` and `tokens` to use for adding to the queue.
+        let mut head = 0;
+        // The next element of `buffers`, `statuses` and `tokens` to use for popping the queue.
+        let mut tail = 0;
+        // The number of buffers currently on the queue.
+        let mut in_flight = 0;
+        // The first error encountered. Once this is set no more buffers are added, but those
+        // already on the queue must still be popped as they point into `buf` and `statuses`.
+        let mut result = Ok(());
+
+        loop {
+            if result.is_err() && in_flight == 0 {
+                break;
+            }
+            // Add buffers to the RX queue if possible. 3 descriptors are required for the 1 input
+            // buffer and 2 output buffers.
+            if result.is_ok() && self.rx_queue.available_desc() >= 3 {
+                if let Some(buffer) = remaining_buffers.next() {
+                    let added = unsafe {
+                        self.rx_queue.add(
+                            &[&stream_id_bytes],
+                            &mut [&mut *buffer, statuses[head].as_mut_bytes()],
+                        )
+                    };
+                    tokens[head] = match added {
+                        Ok(token) => token,
+                        Err(e) => {
+                            result = Err(e);
+                            continue;
+                        }
+                    };
+                    if self.rx_queue.should_notify() {
+                        self.rx_queue.notify(&mut self.transport);
+                    }
+                    buffers[head] = Some(buffer);
+                    in_flight += 1;
+                    head += 1;
+                    if head >= usize::from(QUEUE_SIZE) {
+                        head = 0;
+                    }
+                } else if in_flight == 0 {
+                    break;
+                }
+            }
+            if self.rx_queue.can_pop() {
+                let buffer = buffers[tail].take().unwrap();
+                let popped = unsafe {
+                    self.rx_queue.pop_used(
+                        tokens[tail],
+                        &[&stream_id_bytes],
+                        &mut [&mut *buffer, statuses[tail].as_mut_bytes()],
+                    )
+                };
+                if let Err(e) = popped {
+                    // The used ring doesn't match what we added, so the remaining buffers can't be
+                    // reclaimed in order. Reset the device so it no longer accesses them.
+                    self.drain();
+                    return Err(e);
+                }
+                if statuses[tail].status != CommandCode::SOk.into() {
+                    result = result.and(Err(Error::IoError));
+                } else if result.is_ok() {
+                    self.pcm_positions[stream_id as usize].complete(buffer.len(), &statuses[tail]);
+                }
+                in_flight -= 1;
+                tail += 1;
+                if tail >= usize::from(QUEUE_SIZE) {
+                    tail = 0;
+                }
+            }
+            spin_loop();
+        }
+
+        result
+    }
+
+    /// Returns whether the given stream is an input stream, or [`Error::InvalidParam`] if there is
+    /// no such stream.
+    fn is_input_stream(&self, stream_id: u32) -> Result<bool> {
+        let info = self
+            .pcm_infos
+            .as_ref()
+            .unwrap()
+            .get(stream_id as usize)
+            .ok_or(Error::InvalidParam)?;
+        Ok(info.direction == VIRTIO_SND_D_INPUT)
+    }
+
+    /// Returns the approximate playback position of the given stream, as the number of bytes
+    /// which the device has played since the stream was last prepared.
+    ///
+    /// The device doesn't report its position directly, so this is calculated from the length of
+    /// the transfers it has completed, less the latency it reported for the most recent one. The
+    /// count wraps on overflow.
+    ///
+    /// Returns `None` if there is no stream with the given ID.
+    pub fn pcm_position(&self, stream_id: u32) -> Option<u32> {
+        let position = self.pcm_positions.get(stream_id as usize)?;
+        Some(
+            position
+                .consumed_bytes
+                .saturating_sub(position.latency_bytes),
+        )
+    }
+
     /// Get all output streams.
     pub fn output_streams(&mut self) -> Result<Vec<u32>> {
         if !self.set_up {
@@ -715,6 +969,36 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
     }
 }
 
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOSound<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+    }
+
+    fn drain(&mut self) {
+        self.reset();
+        self.transport.queue_unset(CONTROL_QUEUE_IDX);
+        self.transport.queue_unset(EVENT_QUEUE_IDX);
+        self.transport.queue_unset(TX_QUEUE_IDX);
+        self.transport.queue_unset(RX_QUEUE_IDX);
+        // SAFETY: The device has been reset so won't access the queues again, and the buffers of
+        // any requests still in them must remain valid until they are popped.
+        unsafe {
+            self.control_queue.reclaim_all();
+            self.event_queue.reclaim_all();
+            self.tx_queue.reclaim_all();
+            self.rx_queue.reclaim_all();
+        }
+    }
+}
+
 /// The status of the PCM stream.
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 enum PCMState {
@@ -732,7 +1016,9 @@ const EVENT_QUEUE_IDX: u16 = 1;
 const TX_QUEUE_IDX: u16 = 2;
 const RX_QUEUE_IDX: u16 = 3;
 
-const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC.union(Feature::RING_EVENT_IDX);
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC
+    .union(Feature::RING_EVENT_IDX)
+    .union(Feature::NOTIFICATION_DATA);
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -1239,6 +1525,28 @@ pub struct VirtIOSndJackInfo {
     _padding: [u8; 7],
 }
 
+impl VirtIOSndJackInfo {
+    /// Returns the HDA pin default configuration value of the jack.
+    pub fn hda_reg_defconf(&self) -> u32 {
+        self.hda_reg_defconf
+    }
+
+    /// Returns the HDA pin capabilities value of the jack.
+    pub fn hda_reg_caps(&self) -> u32 {
+        self.hda_reg_caps
+    }
+
+    /// Returns whether the jack is currently connected.
+    pub fn connected(&self) -> bool {
+        self.connected == 1
+    }
+
+    /// Returns whether the jack can be remapped with [`VirtIOSound::jack_remap`].
+    pub fn supports_remap(&self) -> bool {
+        JackFeatures::from_bits_retain(self.features).contains(JackFeatures::REMAP)
+    }
+}
+
 impl Debug for VirtIOSndJackInfo {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("VirtIOSndJackInfo")
@@ -1408,6 +1716,23 @@ struct PcmParameters {
     rate: PcmRate,
 }
 
+/// Accounting of how much of a PCM stream the device has consumed.
+#[derive(Clone, Debug, Default)]
+struct PcmPosition {
+    /// The total length of the transfers which the device has completed, wrapping on overflow.
+    consumed_bytes: u32,
+    /// The latency reported by the device for the most recently completed transfer.
+    latency_bytes: u32,
+}
+
+impl PcmPosition {
+    /// Records that the device has completed a transfer of the given length with the given status.
+    fn complete(&mut self, len: usize, status: &VirtIOSndPcmStatus) {
+        self.consumed_bytes = self.consumed_bytes.wrapping_add(len as u32);
+        self.latency_bytes = status.latency_bytes;
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
 struct VirtIOSndPcmSetParams {
@@ -1567,8 +1892,9 @@ mod tests {
         },
     };
     use alloc::{sync::Arc, vec};
+    use core::time::Duration;
     use fake::FakeSoundDevice;
-    use std::sync::Mutex;
+    use std::{sync::Mutex, thread};
 
     #[test]
     fn config() {
@@ -1611,6 +1937,53 @@ mod tests {
         assert_eq!(sound.chmaps(), 0);
         assert_eq!(sound.output_streams().unwrap(), vec![]);
         assert_eq!(sound.input_streams().unwrap(), vec![]);
+        assert_eq!(sound.jack_infos(), Err(Error::InvalidParam));
+        assert_eq!(sound.jack_remap(0, 0, 0), Err(Error::InvalidParam));
+
+        fake.terminate();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn jacks() {
+        let (fake, transport) = FakeSoundDevice::new(
+            vec![
+                VirtIOSndJackInfo {
+                    hdr: VirtIOSndInfo { hda_fn_nid: 0 },
+                    features: JackFeatures::REMAP.bits(),
+                    hda_reg_defconf: 0x1234,
+                    hda_reg_caps: 0x5678,
+                    connected: 1,
+                    _padding: Default::default(),
+                },
+                VirtIOSndJackInfo {
+                    hdr: VirtIOSndInfo { hda_fn_nid: 0 },
+                    features: 0,
+                    hda_reg_defconf: 0,
+                    hda_reg_caps: 0,
+                    connected: 0,
+                    _padding: Default::default(),
+                },
+            ],
+            vec![],
+            vec![],
+        );
+        let mut sound =
+            VirtIOSound::<FakeHal, FakeTransport<VirtIOSoundConfig>>::new(transport).unwrap();
+        let handle = fake.spawn();
+
+        let jack_infos = sound.jack_infos().unwrap();
+        assert_eq!(jack_infos.len(), 2);
+        assert_eq!(jack_infos[0].hda_reg_defconf(), 0x1234);
+        assert_eq!(jack_infos[0].hda_reg_caps(), 0x5678);
+        assert!(jack_infos[0].connected());
+        assert!(jack_infos[0].supports_remap());
+        assert!(!jack_infos[1].connected());
+        assert!(!jack_infos[1].supports_remap());
+
+        assert_eq!(sound.jack_remap(0, 1, 2), Ok(()));
+        assert_eq!(sound.jack_remap(1, 1, 2), Err(Error::Unsupported));
+        assert_eq!(sound.jack_remap(2, 1, 2), Err(Error::InvalidParam));
 
         fake.terminate();
         handle.join().unwrap();
@@ -1737,6 +2110,8 @@ mod tests {
 
         sound.pcm_prepare(0).unwrap();
         sound.pcm_start(0).unwrap();
+        assert_eq!(sound.pcm_position(0), Some(0));
+        assert_eq!(sound.pcm_position(1), None);
 
         let mut expected_sound = vec![];
 
@@ -1762,6 +2137,7 @@ mod tests {
         sound.pcm_xfer(0, &[55; 50]).unwrap();
         expected_sound.extend([55; 50]);
         assert_eq!(fake.played_bytes.lock().unwrap()[0], expected_sound);
+        assert_eq!(sound.pcm_position(0), Some(350));
 
         // Send enough that the queue will fill up.
         println!("Playing 5000");
@@ -1769,10 +2145,190 @@ mod tests {
         expected_sound.extend([12; 5000]);
         assert_eq!(fake.played_bytes.lock().unwrap()[0], expected_sound);
 
+        // Send in chunks smaller than the period, with the last one partial.
+        println!("Playing 250 periodically");
+        assert_eq!(
+            sound.pcm_xfer_periodic(0, &[7; 250], 0),
+            Err(Error::InvalidParam)
+        );
+        sound.pcm_xfer_periodic(0, &[7; 250], 40).unwrap();
+        expected_sound.extend([7; 250]);
+        assert_eq!(fake.played_bytes.lock().unwrap()[0], expected_sound);
+
+        sound.pcm_stop(0).unwrap();
+        sound.pcm_release(0).unwrap();
+
+        fake.terminate();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn capture() {
+        let (fake, transport) = FakeSoundDevice::new(
+            vec![],
+            vec![VirtIOSndPcmInfo {
+                hdr: VirtIOSndInfo { hda_fn_nid: 0 },
+                features: 0,
+                formats: PcmFormats::U8.bits(),
+                rates: PcmRates::RATE_8000.bits(),
+                direction: VIRTIO_SND_D_INPUT,
+                channels_min: 1,
+                channels_max: 1,
+                _padding: Default::default(),
+            }],
+            vec![],
+        );
+        let mut sound =
+            VirtIOSound::<FakeHal, FakeTransport<VirtIOSoundConfig>>::new(transport).unwrap();
+        let handle = fake.spawn();
+
+        assert_eq!(sound.output_streams().unwrap(), vec![]);
+        assert_eq!(sound.input_streams().unwrap(), vec![0]);
+        // Input streams can't be played to.
+        assert_eq!(sound.pcm_xfer(0, &[0; 10]), Err(Error::InvalidParam));
+
+        sound
+            .pcm_set_params(
+                0,
+                200,
+                50,
+                PcmFeatures::empty(),
+                1,
+                PcmFormat::U8,
+                PcmRate::Rate8000,
+            )
+            .unwrap();
+        sound.pcm_prepare(0).unwrap();
+        sound.pcm_start(0).unwrap();
+
+        let recorded: Vec<u8> = (0..150).collect();
+        fake.captured_bytes.lock().unwrap()[0].extend(&recorded);
+        let mut buf = [0xff; 200];
+        assert_eq!(
+            sound.pcm_capture(0, &mut buf[..120]),
+            Err(Error::InvalidParam)
+        );
+        sound.pcm_capture(0, &mut buf).unwrap();
+        // The device ran out of recorded data part way through the last period.
+        assert_eq!(buf[..150], recorded);
+        assert_eq!(buf[150..], [0; 50]);
+        assert_eq!(sound.pcm_position(0), Some(200));
+
         sound.pcm_stop(0).unwrap();
         sound.pcm_release(0).unwrap();
 
         fake.terminate();
         handle.join().unwrap();
     }
+
+    #[test]
+    fn play_nonblocking() {
+        let (fake, transport) = FakeSoundDevice::new(
+            vec![],
+            vec![VirtIOSndPcmInfo {
+                hdr: VirtIOSndInfo { hda_fn_nid: 0 },
+                features: 0,
+                formats: PcmFormats::U8.bits(),
+                rates: PcmRates::RATE_8000.bits(),
+                direction: VIRTIO_SND_D_OUTPUT,
+                channels_min: 1,
+                channels_max: 1,
+                _padding: Default::default(),
+            }],
+            vec![],
+        );
+        let mut sound =
+            VirtIOSound::<FakeHal, FakeTransport<VirtIOSoundConfig>>::new(transport).unwrap();
+        let handle = fake.spawn();
+
+        sound
+            .pcm_set_params(
+                0,
+                200,
+                100,
+                PcmFeatures::empty(),
+                1,
+                PcmFormat::U8,
+                PcmRate::Rate8000,
+            )
+            .unwrap();
+        sound.pcm_prepare(0).unwrap();
+        sound.pcm_start(0).unwrap();
+
+        assert_eq!(sound.pcm_xfer_nb(0, &[1; 50]), Err(Error::InvalidParam));
+        assert_eq!(sound.poll_xfer_complete(0), Err(Error::WrongToken));
+
+        // Keep two periods in flight.
+        let tokens = [
+            sound.pcm_xfer_nb(0, &[1; 100]).unwrap(),
+            sound.pcm_xfer_nb(0, &[2; 100]).unwrap(),
+        ];
+        for token in tokens {
+            while !sound.poll_xfer_complete(token).unwrap() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(
+            fake.played_bytes.lock().unwrap()[0],
+            [[1; 100], [2; 100]].concat()
+        );
+        assert_eq!(sound.pcm_position(0), Some(200));
+        assert_eq!(sound.poll_xfer_complete(tokens[0]), Err(Error::WrongToken));
+
+        fake.terminate();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn capture_failed_period() {
+        let (fake, transport) = FakeSoundDevice::new(
+            vec![],
+            vec![VirtIOSndPcmInfo {
+                hdr: VirtIOSndInfo { hda_fn_nid: 0 },
+                features: 0,
+                formats: PcmFormats::U8.bits(),
+                rates: PcmRates::RATE_8000.bits(),
+                direction: VIRTIO_SND_D_INPUT,
+                channels_min: 1,
+                channels_max: 1,
+                _padding: Default::default(),
+            }],
+            vec![],
+        );
+        let mut sound =
+            VirtIOSound::<FakeHal, FakeTransport<VirtIOSoundConfig>>::new(transport).unwrap();
+        let handle = fake.spawn();
+
+        sound
+            .pcm_set_params(
+                0,
+                200,
+                50,
+                PcmFeatures::empty(),
+                1,
+                PcmFormat::U8,
+                PcmRate::Rate8000,
+            )
+            .unwrap();
+        sound.pcm_prepare(0).unwrap();
+        sound.pcm_start(0).unwrap();
+
+        // Fail the second of four periods.
+        fake.rx_statuses
+            .lock()
+            .unwrap()
+            .extend([CommandCode::SOk, CommandCode::SIoErr]);
+        let mut buf = [0xff; 200];
+        assert_eq!(sound.pcm_capture(0, &mut buf), Err(Error::IoError));
+        // All the periods after the failed one were still reclaimed from the queue.
+        assert_eq!(sound.rx_queue.available_desc(), usize::from(QUEUE_SIZE));
+        assert_eq!(sound.pcm_position(0), Some(50));
+
+        // So capturing again works.
+        sound.pcm_capture(0, &mut buf).unwrap();
+        assert_eq!(sound.pcm_position(0), Some(250));
+
+        fake.terminate();
+        handle.join().unwrap();
+    }
 }
@@ -3,17 +3,17 @@
 use super::{
     CommandCode, VirtIOSndChmapInfo, VirtIOSndHdr, VirtIOSndJackInfo, VirtIOSndPcmInfo,
     VirtIOSndPcmStatus, VirtIOSndPcmXfer, VirtIOSndQueryInfo, VirtIOSoundConfig, CONTROL_QUEUE_IDX,
-    QUEUE_SIZE, TX_QUEUE_IDX,
+    QUEUE_SIZE, RX_QUEUE_IDX, TX_QUEUE_IDX,
 };
 use crate::{
     config::ReadOnly,
-    device::sound::{VirtIOSndPcmHdr, VirtIOSndPcmSetParams},
+    device::sound::{VirtIOSndJackRemap, VirtIOSndPcmHdr, VirtIOSndPcmSetParams},
     transport::{
         fake::{FakeTransport, QueueStatus, State},
         DeviceType,
     },
 };
-use alloc::{sync::Arc, vec};
+use alloc::{collections::VecDeque, sync::Arc, vec};
 use core::{
     convert::{TryFrom, TryInto},
     mem::size_of,
@@ -37,6 +37,10 @@ pub struct FakeSoundDevice {
     pub params: Arc<Mutex<Vec<Option<VirtIOSndPcmSetParams>>>>,
     /// The bytes send on the TX queue for each channel.
     pub played_bytes: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The bytes to return on the RX queue for each channel.
+    pub captured_bytes: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The statuses to return for the next RX periods. Once this is empty, periods succeed.
+    pub rx_statuses: Arc<Mutex<VecDeque<CommandCode>>>,
     pub jack_infos: Vec<VirtIOSndJackInfo>,
     pub pcm_infos: Vec<VirtIOSndPcmInfo>,
     pub chmap_infos: Vec<VirtIOSndChmapInfo>,
@@ -70,6 +74,7 @@ impl FakeSoundDevice {
         };
         let params = repeat_with(|| None).take(pcm_infos.len()).collect();
         let played_bytes = vec![vec![]; pcm_infos.len()];
+        let captured_bytes = vec![vec![]; pcm_infos.len()];
 
         (
             Self {
@@ -77,6 +82,8 @@ impl FakeSoundDevice {
                 terminate: Arc::new(AtomicBool::new(false)),
                 params: Arc::new(Mutex::new(params)),
                 played_bytes: Arc::new(Mutex::new(played_bytes)),
+                captured_bytes: Arc::new(Mutex::new(captured_bytes)),
+                rx_statuses: Default::default(),
                 jack_infos,
                 pcm_infos,
                 chmap_infos,
@@ -119,6 +126,16 @@ impl FakeSoundDevice {
                         self.handle_tx(&request)
                     })
                 {}
+            } else if State::poll_queue_notified(&self.state, RX_QUEUE_IDX) {
+                println!("RX queue was notified");
+                while self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(RX_QUEUE_IDX, |request| {
+                        self.handle_rx(&request)
+                    })
+                {}
             } else {
                 thread::sleep(Duration::from_millis(10));
             }
@@ -140,6 +157,34 @@ impl FakeSoundDevice {
         .to_owned()
     }
 
+    /// Returns a period of the captured bytes for the stream, followed by the status.
+    fn handle_rx(&self, request: &[u8]) -> Vec<u8> {
+        let header = VirtIOSndPcmXfer::read_from_bytes(request).expect("RX request wrong size");
+        let stream_id = usize::try_from(header.stream_id).unwrap();
+        let period_bytes = self.params.lock().unwrap()[stream_id]
+            .as_ref()
+            .expect("RX before parameters set")
+            .period_bytes as usize;
+        let mut captured_bytes = self.captured_bytes.lock().unwrap();
+        let available = period_bytes.min(captured_bytes[stream_id].len());
+        let mut response: Vec<u8> = captured_bytes[stream_id].drain(..available).collect();
+        response.resize(period_bytes, 0);
+        let status = self
+            .rx_statuses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(CommandCode::SOk);
+        response.extend_from_slice(
+            VirtIOSndPcmStatus {
+                status: status.into(),
+                latency_bytes: 0,
+            }
+            .as_bytes(),
+        );
+        response
+    }
+
     fn handle_control_request(&self, request: &[u8]) -> Vec<u8> {
         {
             let header = VirtIOSndHdr::read_from_prefix(&request)
@@ -147,6 +192,7 @@ impl FakeSoundDevice {
                 .0;
             let mut response = Vec::new();
             const R_JACK_INFO: u32 = CommandCode::RJackInfo as u32;
+            const R_JACK_REMAP: u32 = CommandCode::RJackRemap as u32;
             const R_PCM_INFO: u32 = CommandCode::RPcmInfo as u32;
             const R_CHMAP_INFO: u32 = CommandCode::RChmapInfo as u32;
             const R_PCM_SET_PARAMS: u32 = CommandCode::RPcmSetParams as u32;
@@ -174,6 +220,16 @@ impl FakeSoundDevice {
                         response.extend_from_slice(jack_info.as_bytes());
                     }
                 }
+                R_JACK_REMAP => {
+                    let _request = VirtIOSndJackRemap::read_from_bytes(request)
+                        .expect("R_JACK_REMAP control request wrong length");
+                    response.extend_from_slice(
+                        VirtIOSndHdr {
+                            command_code: CommandCode::SOk.into(),
+                        }
+                        .as_bytes(),
+                    );
+                }
                 R_PCM_INFO => {
                     let request = VirtIOSndQueryInfo::read_from_bytes(&request)
                         .expect("R_PCM_INFO control request wrong length");
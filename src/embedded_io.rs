@@ -9,6 +9,7 @@ impl embedded_io::Error for Error {
             Error::InvalidParam => ErrorKind::InvalidInput,
             Error::DmaError => ErrorKind::OutOfMemory,
             Error::Unsupported => ErrorKind::Unsupported,
+            Error::ReadOnly => ErrorKind::PermissionDenied,
             Error::SocketDeviceError(e) => match e {
                 &SocketError::ConnectionExists => ErrorKind::AddrInUse,
                 SocketError::NotConnected => ErrorKind::NotConnected,
@@ -27,9 +28,14 @@ impl embedded_io::Error for Error {
             | Error::NotReady
             | Error::WrongToken
             | Error::AlreadyUsed
+            | Error::NoUsableQueue
             | Error::IoError
             | Error::ConfigSpaceTooSmall
-            | Error::ConfigSpaceMissing => ErrorKind::Other,
+            | Error::ConfigUnstable
+            | Error::ConfigSpaceMissing
+            | Error::DeviceNeedsReset
+            | Error::DmaAddressOutOfRange
+            | Error::ResourceLost => ErrorKind::Other,
         }
     }
 }
@@ -33,6 +33,14 @@ impl<H: Hal> Dma<H> {
         if paddr == 0 {
             return Err(Error::DmaError);
         }
+        if !dma_addressable::<H>(paddr as u64, pages * PAGE_SIZE) {
+            // SAFETY: The memory was just allocated by `dma_alloc` with these values, and nothing
+            // else has a reference to it.
+            unsafe {
+                H::dma_dealloc(paddr, vaddr, pages);
+            }
+            return Err(Error::DmaAddressOutOfRange);
+        }
         Ok(Self {
             paddr,
             vaddr,
@@ -123,6 +131,12 @@ pub unsafe trait Hal {
     /// This may involve mapping the buffer into an IOMMU, giving the host permission to access the
     /// memory, or copying it to a special region where it can be accessed.
     ///
+    /// Drivers don't allocate DMA memory for individual requests: `dma_alloc` is only used for
+    /// long-lived regions such as the virtqueues, and the buffers of each request are passed
+    /// through `share` and `unshare` instead. So this is called for every buffer of every request,
+    /// and implementations which copy buffers to a special region should keep a pool of bounce
+    /// buffers rather than allocating one each time.
+    ///
     /// # Safety
     ///
     /// The buffer must be a valid pointer to a non-empty memory range which will not be accessed by
@@ -138,6 +152,97 @@ pub unsafe trait Hal {
     /// any other thread for the duration of this method call. The `paddr` must be the value
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
+
+    /// Makes the contents of a shared buffer visible to the device, before ownership of it passes
+    /// to the device.
+    ///
+    /// This is called by the virtqueues after [`share`](Self::share) for every buffer added to
+    /// them, with the physical address which `share` returned. On platforms where DMA isn't
+    /// coherent with the CPU caches this should clean the caches for the range, or for
+    /// [`BufferDirection::DeviceToDriver`] buffers make sure no dirty cache lines can later be
+    /// written back over data written by the device. The default implementation does nothing, which
+    /// is correct for cache-coherent platforms.
+    ///
+    /// Memory allocated by [`dma_alloc`](Self::dma_alloc), such as the virtqueue rings themselves,
+    /// is accessed by both sides concurrently so must be mapped coherently; it is never synced.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` and `len` must describe a buffer previously returned by `share` which hasn't yet
+    /// been unshared.
+    unsafe fn dma_sync_for_device(_paddr: PhysAddr, _len: usize, _direction: BufferDirection) {}
+
+    /// Makes anything which the device wrote to a shared buffer visible to the CPU, once ownership
+    /// of it has passed back from the device.
+    ///
+    /// This is called by the virtqueues for every buffer popped from them, before
+    /// [`unshare`](Self::unshare). On platforms where DMA isn't coherent with the CPU caches this
+    /// should invalidate the caches for the range of [`BufferDirection::DeviceToDriver`] buffers.
+    /// The default implementation does nothing, which is correct for cache-coherent platforms.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` and `len` must describe a buffer previously returned by `share` which hasn't yet
+    /// been unshared, and which the device has finished accessing.
+    unsafe fn dma_sync_for_cpu(_paddr: PhysAddr, _len: usize, _direction: BufferDirection) {}
+
+    /// Returns the number of bits of physical address which devices can use for DMA.
+    ///
+    /// Drivers check the addresses returned by `dma_alloc` and `share` against this, and fail
+    /// with [`Error::DmaAddressOutOfRange`] rather than passing the device an address it can't
+    /// access. The default of 64 disables the check.
+    fn dma_address_bits() -> u8 {
+        64
+    }
+}
+
+/// Shares the given buffer with the device and then syncs it for the device, as the virtqueues do
+/// for each buffer added to them.
+///
+/// # Safety
+///
+/// As for [`Hal::share`].
+pub(crate) unsafe fn share_for_device<H: Hal>(
+    buffer: NonNull<[u8]>,
+    direction: BufferDirection,
+) -> PhysAddr {
+    // SAFETY: Our caller promises the buffer is valid, and it has just been shared at `paddr`.
+    unsafe {
+        let paddr = H::share(buffer, direction);
+        H::dma_sync_for_device(paddr, buffer.len(), direction);
+        paddr
+    }
+}
+
+/// Syncs the given buffer for the CPU and then unshares it, as the virtqueues do for each buffer
+/// popped from them.
+///
+/// # Safety
+///
+/// As for [`Hal::unshare`], and the device must have finished accessing the buffer.
+pub(crate) unsafe fn unshare_from_device<H: Hal>(
+    paddr: PhysAddr,
+    buffer: NonNull<[u8]>,
+    direction: BufferDirection,
+) {
+    // SAFETY: Our caller promises the buffer is valid, shared at `paddr` and no longer in use by
+    // the device.
+    unsafe {
+        H::dma_sync_for_cpu(paddr, buffer.len(), direction);
+        H::unshare(paddr, buffer, direction);
+    }
+}
+
+/// Returns whether the given range of physical addresses can be accessed by devices, according to
+/// [`Hal::dma_address_bits`].
+pub(crate) fn dma_addressable<H: Hal>(paddr: u64, len: usize) -> bool {
+    let bits = H::dma_address_bits();
+    if bits >= 64 {
+        return true;
+    }
+    paddr
+        .checked_add(len as u64)
+        .is_some_and(|end| end <= 1 << bits)
 }
 
 /// The direction in which a buffer is passed.
@@ -88,21 +88,44 @@ pub enum Error {
     /// Invalid parameter.
     #[error("Invalid parameter")]
     InvalidParam,
+    /// The device reports a maximum size of 0 for a queue which the driver needs, so the queue
+    /// can't be used.
+    #[error("Device has no usable queue")]
+    NoUsableQueue,
     /// Failed to allocate DMA memory.
     #[error("Failed to allocate DMA memory")]
     DmaError,
+    /// A DMA buffer has a physical address which is too high for the device to access, according
+    /// to `Hal::dma_address_bits`.
+    #[error("DMA address out of range for device")]
+    DmaAddressOutOfRange,
     /// I/O error
     #[error("I/O error")]
     IoError,
     /// The request was not supported by the device.
     #[error("Request not supported by device")]
     Unsupported,
+    /// The request would modify a device which is read-only.
+    #[error("Device is read-only")]
+    ReadOnly,
     /// The config space advertised by the device is smaller than the driver expected.
     #[error("Config space advertised by the device is smaller than expected")]
     ConfigSpaceTooSmall,
+    /// The config generation kept changing while the driver was trying to read the config space,
+    /// so a consistent value couldn't be read.
+    #[error("Config space kept changing while being read")]
+    ConfigUnstable,
     /// The device doesn't have any config space, but the driver expects some.
     #[error("The device doesn't have any config space, but the driver expects some")]
     ConfigSpaceMissing,
+    /// The device has set the `DEVICE_NEEDS_RESET` status bit, so it must be reset before it can
+    /// be used again.
+    #[error("Device needs reset")]
+    DeviceNeedsReset,
+    /// The device doesn't recognise a resource which the driver previously created, e.g. because
+    /// the host side of a GPU device was restarted. The resource must be created again.
+    #[error("Device resource lost")]
+    ResourceLost,
     /// Error from the socket device.
     #[error("Error from the socket device: {0}")]
     SocketDeviceError(#[from] SocketError),
@@ -2,12 +2,16 @@
 
 #[cfg(feature = "alloc")]
 pub mod owning;
+pub mod packed;
 
-use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
+use crate::device::common::Feature;
+use crate::hal::{
+    dma_addressable, share_for_device, unshare_from_device, BufferDirection, Dma, Hal, PhysAddr,
+};
 use crate::transport::Transport;
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use bitflags::bitflags;
 #[cfg(test)]
 use core::cmp::min;
@@ -16,16 +20,132 @@ use core::hint::spin_loop;
 use core::mem::{size_of, take};
 #[cfg(test)]
 use core::ptr;
-use core::ptr::NonNull;
+use core::ptr::{addr_of_mut, NonNull};
 use core::sync::atomic::{fence, AtomicU16, Ordering};
+use packed::PackedQueue;
 use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
+/// The input and output buffers of a single request passed to [`VirtQueue::add_batch`].
+pub type BatchRequest<'a, 'b> = (&'a [&'b [u8]], &'a mut [&'b mut [u8]]);
+
+/// The buffers which the descriptors of an indirect descriptor list point to.
+#[cfg(feature = "alloc")]
+type IndirectBuffers = Box<[NonNull<[u8]>]>;
+
+/// The operations common to split and packed virtqueues, so that drivers can use either layout.
+pub trait Queue {
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16>;
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32>;
+
+    /// Returns the index of the queue on the device.
+    fn queue_idx(&self) -> u16;
+
+    /// Advise the device whether used buffer notifications are needed.
+    fn set_dev_notify(&mut self, enable: bool);
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    fn should_notify(&self) -> bool;
+
+    /// Returns whether there is a used element that can be popped.
+    fn can_pop(&self) -> bool;
+
+    /// Returns the notification data to send to the device when `VIRTIO_F_NOTIFICATION_DATA` has
+    /// been negotiated, with the queue index in the low 16 bits and the position of the next
+    /// available buffer above it.
+    fn notification_data(&self) -> u32;
+
+    /// Notifies the device about new available buffers, including the notification data if
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    fn notify(&self, transport: &mut impl Transport) {
+        if transport.negotiated_features() & Feature::NOTIFICATION_DATA.bits() != 0 {
+            transport.notify_with_data(self.notification_data());
+        } else {
+            transport.notify(self.queue_idx());
+        }
+    }
+
+    /// Returns the token of the next used element and the length which the device wrote to it,
+    /// without popping it, or `None` if there is none.
+    fn peek_used_with_len(&self) -> Option<(u16, u32)>;
+
+    /// Returns an iterator over the tokens and used lengths of all elements currently used by the
+    /// device, in the order in which they must be popped, without popping them.
+    fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_;
+
+    /// Returns the number of free descriptors.
+    fn available_desc(&self) -> usize;
+
+    /// Returns the token of the next used element without popping it, or `None` if there is none.
+    fn peek_used(&self) -> Option<u16> {
+        self.peek_used_with_len().map(|(token, _)| token)
+    }
+
+    /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    ///
+    /// This assumes that the device isn't processing any other buffers at the same time.
+    ///
+    /// The buffers must not be empty.
+    fn add_notify_wait_pop<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        // Safe because we don't return until the same token has been popped, so the buffers remain
+        // valid and are not otherwise accessed until then.
+        let token = unsafe { self.add(inputs, outputs) }?;
+
+        // Notify the queue.
+        if self.should_notify() {
+            self.notify(transport);
+        }
+
+        // Wait until there is at least one element in the used ring.
+        while !self.can_pop() {
+            spin_loop();
+        }
+
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
+    }
+}
+
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
 ///
 /// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings. It must be a power of 2 and fit in a [`u16`].
+///   in the available and used rings. It must be a power of 2 and fit in a [`u16`]. If the queue is
+///   created with [`new_clamped`](Self::new_clamped) then this is only the maximum size, and the
+///   actual size may be smaller.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
@@ -47,6 +167,8 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
 
     /// The index of queue
     queue_idx: u16,
+    /// The actual size of the queue, which is a power of 2 no greater than `SIZE`.
+    size: u16,
     /// The number of descriptors currently in use.
     num_used: u16,
     /// The head desc index of the free list.
@@ -58,10 +180,21 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
     last_used_idx: u16,
     /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
     event_idx: bool,
+    /// Whether the driver wants used buffer notifications, as set by `set_dev_notify`.
+    dev_notify: bool,
     #[cfg(feature = "alloc")]
     indirect: bool,
+    /// Chains with more than this many descriptors use an indirect descriptor table.
+    #[cfg(feature = "alloc")]
+    indirect_threshold: usize,
     #[cfg(feature = "alloc")]
     indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    /// For each descriptor in use which isn't an indirect descriptor, the buffer it points to, so
+    /// that `reclaim_all` can unshare it without the caller passing the buffers back.
+    buffers: [Option<NonNull<[u8]>>; SIZE],
+    /// For each indirect descriptor list, the buffers its descriptors point to.
+    #[cfg(feature = "alloc")]
+    indirect_buffers: [Option<IndirectBuffers>; SIZE],
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
@@ -74,11 +207,40 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// * `event_idx`: Whether to use the `used_event` and `avail_event` fields for notification
     ///   suppression. This should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated
     ///   with the device.
+    ///
+    /// Returns [`Error::NoUsableQueue`] if the device reports a maximum size of 0 for the queue,
+    /// or [`Error::InvalidParam`] if the maximum size is smaller than `SIZE`.
     pub fn new<T: Transport>(
         transport: &mut T,
         idx: u16,
         indirect: bool,
         event_idx: bool,
+    ) -> Result<Self> {
+        Self::new_with_size(transport, idx, indirect, event_idx, false)
+    }
+
+    /// Creates a new VirtQueue like [`new`](Self::new), but if the device's maximum size for the
+    /// queue is smaller than `SIZE` then uses the largest power of 2 which the device supports
+    /// rather than failing.
+    ///
+    /// The size chosen can be found with [`size`](Self::size).
+    ///
+    /// Returns [`Error::NoUsableQueue`] if the device reports a maximum size of 0 for the queue.
+    pub fn new_clamped<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        Self::new_with_size(transport, idx, indirect, event_idx, true)
+    }
+
+    fn new_with_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+        clamp: bool,
     ) -> Result<Self> {
         #[allow(clippy::let_unit_value)]
         let _ = Self::SIZE_OK;
@@ -86,15 +248,23 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         if transport.queue_used(idx) {
             return Err(Error::AlreadyUsed);
         }
-        if transport.max_queue_size(idx) < SIZE as u32 {
-            return Err(Error::InvalidParam);
+        let max_queue_size = transport.max_queue_size(idx);
+        if max_queue_size == 0 {
+            return Err(Error::NoUsableQueue);
         }
-        let size = SIZE as u16;
+        let size = if max_queue_size >= SIZE as u32 {
+            SIZE as u16
+        } else if clamp {
+            // Round down to a power of 2, as split queues require.
+            (1 << max_queue_size.ilog2()) as u16
+        } else {
+            return Err(Error::InvalidParam);
+        };
 
         let layout = if transport.requires_legacy_layout() {
-            VirtQueueLayout::allocate_legacy(size)?
+            VirtQueueLayout::allocate_legacy(size, SIZE as u16)?
         } else {
-            VirtQueueLayout::allocate_flexible(size)?
+            VirtQueueLayout::allocate_flexible(size, SIZE as u16)?
         };
 
         transport.queue_set(
@@ -105,8 +275,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             layout.device_area_paddr(),
         );
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
         let avail = layout.avail_vaddr().cast();
         let used = layout.used_vaddr().cast();
 
@@ -123,28 +295,62 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         #[cfg(feature = "alloc")]
         const NONE: Option<NonNull<[Descriptor]>> = None;
+        #[cfg(feature = "alloc")]
+        const NO_BUFFERS: Option<IndirectBuffers> = None;
         Ok(VirtQueue {
             layout,
             desc,
             avail,
             used,
             queue_idx: idx,
+            size,
             num_used: 0,
             free_head: 0,
             desc_shadow,
             avail_idx: 0,
             last_used_idx: 0,
             event_idx,
+            dev_notify: true,
             #[cfg(feature = "alloc")]
             indirect,
             #[cfg(feature = "alloc")]
+            indirect_threshold: 1,
+            #[cfg(feature = "alloc")]
             indirect_lists: [NONE; SIZE],
+            buffers: [None; SIZE],
+            #[cfg(feature = "alloc")]
+            indirect_buffers: [NO_BUFFERS; SIZE],
         })
     }
 
+    /// Sets the number of buffers above which a chain uses an indirect descriptor table, rather
+    /// than a descriptor in the ring for each buffer. The default is 1, so every chain of more than
+    /// one buffer is indirect.
+    ///
+    /// An indirect chain only uses a single descriptor in the ring, so a higher threshold trades
+    /// queue depth for avoiding an allocation for short chains. This has no effect unless indirect
+    /// descriptors were enabled when the queue was created.
+    #[cfg(feature = "alloc")]
+    pub fn set_indirect_threshold(&mut self, threshold: usize) {
+        self.indirect_threshold = threshold.max(1);
+    }
+
+    /// Returns whether a chain of the given number of descriptors should use an indirect
+    /// descriptor table.
+    #[cfg(feature = "alloc")]
+    fn use_indirect(&self, descriptors_needed: usize) -> bool {
+        self.indirect && descriptors_needed > self.indirect_threshold
+    }
+
     /// Add buffers to the virtqueue, return a token.
     ///
-    /// The buffers must not be empty.
+    /// The buffers must not be empty. Returns [`Error::InvalidParam`] if any of them are, without
+    /// allocating any descriptors, so a failed call leaves the queue as it was.
+    ///
+    /// Returns [`Error::QueueFull`] if there aren't enough free descriptors. Descriptors are only
+    /// freed by [`pop_used`](Self::pop_used), which needs the original buffers, so the queue can't
+    /// reclaim chains the device has finished with by itself, and waiting here would never make
+    /// progress. Callers must pop their completed requests before trying again.
     ///
     /// Ref: linux virtio_ring.c virtqueue_add
     ///
@@ -156,27 +362,100 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: Our caller promises that the buffers remain valid until they are popped.
+        let head = unsafe { self.add_chain(inputs, outputs, 0) }?;
+        self.publish_avail(1);
+        Ok(head)
+    }
+
+    /// Adds several requests to the virtqueue at once, each with its own input and output buffers
+    /// as for [`add`](Self::add), and writes the token for each request to the corresponding
+    /// element of `tokens`.
+    ///
+    /// The available index is only updated once, after all the requests have been added, so the
+    /// device sees them all together and can then be notified once for the whole batch rather than
+    /// once per request.
+    ///
+    /// Returns [`Error::InvalidParam`] if `tokens` isn't the same length as `requests`. If any
+    /// request can't be added then the ones before it are removed again and the error is returned,
+    /// so a failed call leaves the queue as it was.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers of each request must remain valid and not be accessed until a
+    /// call to `pop_used` with the corresponding token succeeds.
+    pub unsafe fn add_batch<'a, 'b>(
+        &mut self,
+        requests: &mut [BatchRequest<'a, 'b>],
+        tokens: &mut [u16],
+    ) -> Result {
+        if tokens.len() != requests.len() {
+            return Err(Error::InvalidParam);
+        }
+        for i in 0..requests.len() {
+            let (inputs, outputs) = &mut requests[i];
+            // SAFETY: Our caller promises that the buffers remain valid until they are popped.
+            match unsafe { self.add_chain(inputs, outputs, i as u16) } {
+                Ok(head) => tokens[i] = head,
+                Err(e) => {
+                    for (&head, (inputs, outputs)) in tokens[..i].iter().zip(requests).rev() {
+                        // SAFETY: None of the chains have been made available to the device, and
+                        // the buffers are the ones they were just built from.
+                        unsafe {
+                            self.recycle_descriptors(head, inputs, outputs);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.publish_avail(requests.len() as u16);
+        Ok(())
+    }
+
+    /// Builds a descriptor chain for the given buffers and puts it in the available ring `pending`
+    /// slots after the current available index, without making it available to the device yet.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    unsafe fn add_chain<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        pending: u16,
     ) -> Result<u16> {
         if inputs.is_empty() && outputs.is_empty() {
             return Err(Error::InvalidParam);
         }
         let descriptors_needed = inputs.len() + outputs.len();
+        let size = usize::from(self.size);
         // Only consider indirect descriptors if the alloc feature is enabled, as they require
         // allocation.
         #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
+        if self.num_used as usize + 1 > size
+            || descriptors_needed > size
+            || (!self.use_indirect(descriptors_needed)
+                && self.num_used as usize + descriptors_needed > size)
         {
             return Err(Error::QueueFull);
         }
         #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
+        if self.num_used as usize + descriptors_needed > size {
             return Err(Error::QueueFull);
         }
+        // Check all the buffers before starting to build the chain, so that it never needs to be
+        // unwound part way through.
+        if inputs.iter().any(|input| input.is_empty())
+            || outputs.iter().any(|output| output.is_empty())
+        {
+            return Err(Error::InvalidParam);
+        }
 
         #[cfg(feature = "alloc")]
-        let head = if self.indirect && descriptors_needed > 1 {
+        let head = if self.use_indirect(descriptors_needed) {
             self.add_indirect(inputs, outputs)
         } else {
             self.add_direct(inputs, outputs)
@@ -184,26 +463,38 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         #[cfg(not(feature = "alloc"))]
         let head = self.add_direct(inputs, outputs);
 
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
+        if !self.chain_addressable(head) {
+            // SAFETY: The chain hasn't been made available to the device, and the buffers are the
+            // ones it was just built from.
+            unsafe {
+                self.recycle_descriptors(head, inputs, outputs);
+            }
+            return Err(Error::DmaAddressOutOfRange);
+        }
+
+        let avail_slot = self.avail_idx.wrapping_add(pending) & (self.size - 1);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
             (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
         }
 
+        Ok(head)
+    }
+
+    /// Makes the next `count` chains in the available ring available to the device.
+    fn publish_avail(&mut self, count: u16) {
         // Write barrier so that device sees changes to descriptor table and available ring before
         // change to available index.
         fence(Ordering::SeqCst);
 
         // increase head of avail ring
-        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.avail_idx = self.avail_idx.wrapping_add(count);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
             (*self.avail.as_ptr())
                 .idx
                 .store(self.avail_idx, Ordering::Release);
         }
-
-        Ok(head)
     }
 
     fn add_direct<'a, 'b>(
@@ -227,6 +518,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             }
             last = self.free_head;
             self.free_head = desc.next;
+            self.buffers[usize::from(last)] = Some(buffer);
 
             self.write_desc(last);
         }
@@ -253,6 +545,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         // Allocate and fill in indirect descriptor list.
         let mut indirect_list =
             <[Descriptor]>::new_box_zeroed_with_elems(inputs.len() + outputs.len()).unwrap();
+        let mut buffers = Vec::with_capacity(indirect_list.len());
         for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
             let desc = &mut indirect_list[i];
             // Safe because our caller promises that the buffers live at least until `pop_used`
@@ -261,6 +554,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
             }
             desc.next = (i + 1) as u16;
+            buffers.push(buffer);
         }
         indirect_list
             .last_mut()
@@ -272,6 +566,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         // the physical DMA address which might be different.
         assert!(self.indirect_lists[usize::from(head)].is_none());
         self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
+        self.indirect_buffers[usize::from(head)] = Some(buffers.into_boxed_slice());
 
         // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
         // indirect list from being freed when this function returns; recycle_descriptors is instead
@@ -291,6 +586,35 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         head
     }
 
+    /// Returns whether all the buffers in the descriptor chain starting at `head` can be accessed
+    /// by the device, according to `Hal::dma_address_bits`.
+    fn chain_addressable(&self, head: u16) -> bool {
+        if H::dma_address_bits() >= 64 {
+            return true;
+        }
+        let mut next = Some(head);
+        while let Some(index) = next {
+            let desc = &self.desc_shadow[usize::from(index)];
+            if !dma_addressable::<H>(desc.addr, desc.len as usize) {
+                return false;
+            }
+            #[cfg(feature = "alloc")]
+            if let Some(indirect_list) = self.indirect_lists[usize::from(index)] {
+                // SAFETY: The indirect list was allocated by `add_indirect` and won't be freed until
+                // the chain is recycled.
+                let indirect_list = unsafe { indirect_list.as_ref() };
+                if !indirect_list
+                    .iter()
+                    .all(|desc| dma_addressable::<H>(desc.addr, desc.len as usize))
+                {
+                    return false;
+                }
+            }
+            next = desc.next();
+        }
+        true
+    }
+
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
     /// them, then pops them.
     ///
@@ -303,31 +627,22 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         outputs: &'a mut [&'a mut [u8]],
         transport: &mut impl Transport,
     ) -> Result<u32> {
-        // Safe because we don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
-        let token = unsafe { self.add(inputs, outputs) }?;
-
-        // Notify the queue.
-        if self.should_notify() {
-            transport.notify(self.queue_idx);
-        }
-
-        // Wait until there is at least one element in the used ring.
-        while !self.can_pop() {
-            spin_loop();
-        }
-
-        // Safe because these are the same buffers as we passed to `add` above and they are still
-        // valid.
-        unsafe { self.pop_used(token, inputs, outputs) }
+        Queue::add_notify_wait_pop(self, inputs, outputs, transport)
     }
 
     /// Advise the device whether used buffer notifications are needed.
     ///
+    /// If `VIRTIO_F_EVENT_IDX` has been negotiated then the avail ring's flags must be left as 0,
+    /// so instead notifications are disabled by moving `used_event` to just behind the next used
+    /// element, which the device won't reach until the used index wraps all the way around.
+    ///
     /// See Virtio v1.1 2.6.7 Used Buffer Notification Suppression
     pub fn set_dev_notify(&mut self, enable: bool) {
-        let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
-        if !self.event_idx {
+        self.dev_notify = enable;
+        if self.event_idx {
+            self.write_used_event();
+        } else {
+            let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
             // Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
             // instance of AvailRing.
             unsafe {
@@ -338,16 +653,60 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Writes `used_event` to ask for a notification when the device uses the next element, or
+    /// to suppress notifications if they have been disabled with `set_dev_notify`.
+    fn write_used_event(&mut self) {
+        let used_event = if self.dev_notify {
+            self.last_used_idx
+        } else {
+            self.last_used_idx.wrapping_sub(1)
+        };
+        self.used_event().store(used_event, Ordering::Release);
+    }
+
+    /// Returns the `used_event` field of the available ring, which follows the last slot in use
+    /// rather than the last slot of `AvailRing<SIZE>` if the queue is smaller than `SIZE`.
+    fn used_event(&self) -> &AtomicU16 {
+        // Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of AvailRing, which has `self.size` slots before `used_event`.
+        unsafe {
+            &*addr_of_mut!((*self.avail.as_ptr()).ring)
+                .cast::<u16>()
+                .add(self.size.into())
+                .cast::<AtomicU16>()
+        }
+    }
+
+    /// Returns the `avail_event` field of the used ring, which follows the last slot in use rather
+    /// than the last slot of `UsedRing<SIZE>` if the queue is smaller than `SIZE`.
+    fn avail_event(&self) -> &AtomicU16 {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing, which has `self.size` slots before `avail_event`.
+        unsafe {
+            &*addr_of_mut!((*self.used.as_ptr()).ring)
+                .cast::<UsedElem>()
+                .add(self.size.into())
+                .cast::<AtomicU16>()
+        }
+    }
+
+    /// Returns the number of descriptors in the queue, which is `SIZE` unless the queue was
+    /// created with [`new_clamped`](Self::new_clamped) on a device which supports fewer.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
     /// Returns whether the driver should notify the device after adding a new buffer to the
     /// virtqueue.
     ///
     /// This will be false if the device has supressed notifications.
     pub fn should_notify(&self) -> bool {
         if self.event_idx {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
-            self.avail_idx >= avail_event.wrapping_add(1)
+            let avail_event = self.avail_event().load(Ordering::Acquire);
+            // The device wants a notification once `avail_idx` has moved past `avail_event`. Both
+            // indices wrap around, so compare them modulo 2^16 rather than directly, to avoid
+            // missing a notification and stalling the queue.
+            self.avail_idx.wrapping_sub(avail_event).wrapping_sub(1) < 0x8000
         } else {
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
             // instance of UsedRing.
@@ -376,28 +735,57 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
     pub fn peek_used(&self) -> Option<u16> {
+        self.peek_used_with_len().map(|(token, _)| token)
+    }
+
+    /// Returns the descriptor index (a.k.a. token) of the next used element and the length which
+    /// the device wrote to it, without popping it, or `None` if the used ring is empty.
+    ///
+    /// This doesn't change the state of the queue as seen by either the driver or the device, so
+    /// it may be called repeatedly before deciding whether to call `pop_used`.
+    pub fn peek_used_with_len(&self) -> Option<(u16, u32)> {
         if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+            let last_used_slot = self.last_used_idx & (self.size - 1);
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
             // readable instance of UsedRing.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
+            let element = unsafe { &(*self.used.as_ptr()).ring[last_used_slot as usize] };
+            Some((element.id as u16, element.len))
         } else {
             None
         }
     }
 
+    /// Returns an iterator over the descriptor indices (a.k.a. tokens) and used lengths of all
+    /// elements currently in the used ring, in the order in which they must be popped.
+    ///
+    /// This only covers elements which the device had used when it was called, and doesn't pop
+    /// them. Each must still be passed to `pop_used` along with its buffers to reclaim its
+    /// descriptors, as unsharing the buffers may involve copying data back into them.
+    pub fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) };
+        (0..used_idx.wrapping_sub(self.last_used_idx)).map(move |i| {
+            let slot = self.last_used_idx.wrapping_add(i) & (self.size - 1);
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+            // readable instance of UsedRing.
+            let element = unsafe { &(*self.used.as_ptr()).ring[usize::from(slot)] };
+            (element.id as u16, element.len)
+        })
+    }
+
     /// Returns the number of free descriptors.
     pub fn available_desc(&self) -> usize {
         #[cfg(feature = "alloc")]
         if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
+            return if self.num_used == self.size {
                 0
             } else {
-                SIZE
+                self.size.into()
             };
         }
 
-        SIZE - usize::from(self.num_used)
+        usize::from(self.size - self.num_used)
     }
 
     /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
@@ -410,11 +798,11 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
     /// queue by `add`.
-    unsafe fn recycle_descriptors<'a>(
+    unsafe fn recycle_descriptors<'a, 'b>(
         &mut self,
         head: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
     ) {
         let original_free_head = self.free_head;
         self.free_head = head;
@@ -426,6 +814,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 // Find the indirect descriptor list, unshare it and move its descriptor to the free
                 // list.
                 let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
+                self.indirect_buffers[usize::from(head)] = None;
                 // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
                 // finished accessing it by this point.
                 let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
@@ -435,7 +824,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 head_desc.next = original_free_head;
 
                 unsafe {
-                    H::unshare(
+                    unshare_from_device::<H>(
                         paddr as usize,
                         indirect_list.as_mut_bytes().into(),
                         BufferDirection::DriverToDevice,
@@ -452,7 +841,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                     unsafe {
                         // Unshare the buffer (and perhaps copy its contents back to the original
                         // buffer).
-                        H::unshare(indirect_list[i].addr as usize, buffer, direction);
+                        unshare_from_device::<H>(indirect_list[i].addr as usize, buffer, direction);
                     }
                 }
                 drop(indirect_list);
@@ -475,12 +864,13 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 }
 
                 self.write_desc(desc_index);
+                self.buffers[usize::from(desc_index)] = None;
 
                 // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
                 // from which we got `paddr`.
                 unsafe {
                     // Unshare the buffer (and perhaps copy its contents back to the original buffer).
-                    H::unshare(paddr as usize, buffer, direction);
+                    unshare_from_device::<H>(paddr as usize, buffer, direction);
                 }
             }
 
@@ -510,7 +900,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
 
         // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+        let last_used_slot = self.last_used_idx & (self.size - 1);
         let index;
         let len;
         // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
@@ -532,15 +922,74 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
         if self.event_idx {
-            unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
-            }
+            self.write_used_event();
         }
 
         Ok(len)
     }
+
+    /// Unshares every buffer still in the queue, whether or not the device has used it, and
+    /// returns all the descriptors to the free list, as if every outstanding chain had been
+    /// popped.
+    ///
+    /// This is for tearing down a queue which the device has stopped using, so that the buffers of
+    /// requests which will never complete can be freed. Tokens for those requests are no longer
+    /// valid afterwards, and the queue shouldn't be used for further requests as its ring indices
+    /// no longer match the device's.
+    ///
+    /// # Safety
+    ///
+    /// The device must not access the queue or any of the buffers in it after this is called, e.g.
+    /// because the device has been reset. The buffers passed to `add` for chains which haven't been
+    /// popped must still be valid.
+    pub unsafe fn reclaim_all(&mut self) {
+        for index in 0..self.size {
+            let i = usize::from(index);
+            #[cfg(feature = "alloc")]
+            if let Some(indirect_list) = self.indirect_lists[i].take() {
+                let buffers = self.indirect_buffers[i].take().unwrap();
+                // SAFETY: We allocated the indirect list in `add_indirect`, and our caller promises
+                // that the device won't access it again.
+                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
+                for (desc, &buffer) in indirect_list.iter().zip(buffers.iter()) {
+                    // SAFETY: Our caller promises that the buffer is still valid, and it was
+                    // shared at this address by `add_indirect`.
+                    unsafe {
+                        unshare_from_device::<H>(desc.addr as usize, buffer, desc.direction());
+                    }
+                }
+                // SAFETY: The indirect list was shared at this address by `add_indirect`.
+                unsafe {
+                    unshare_from_device::<H>(
+                        self.desc_shadow[i].addr as usize,
+                        indirect_list.as_mut_bytes().into(),
+                        BufferDirection::DriverToDevice,
+                    );
+                }
+            }
+            if let Some(buffer) = self.buffers[i].take() {
+                let desc = &self.desc_shadow[i];
+                // SAFETY: Our caller promises that the buffer is still valid, and it was shared at
+                // this address by `add_direct`.
+                unsafe {
+                    unshare_from_device::<H>(desc.addr as usize, buffer, desc.direction());
+                }
+            }
+
+            // Link all the descriptors into the free list again, as `new` does.
+            let desc = &mut self.desc_shadow[i];
+            desc.unset_buf();
+            desc.flags = DescFlags::empty();
+            desc.next = if index + 1 < self.size { index + 1 } else { 0 };
+            self.write_desc(index);
+        }
+        self.free_head = 0;
+        self.num_used = 0;
+        // Don't pop anything the device had already used either.
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        self.last_used_idx = unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) };
+    }
 }
 
 // SAFETY: None of the virt queue resources are tied to a particular thread.
@@ -550,6 +999,232 @@ unsafe impl<H: Hal, const SIZE: usize> Send for VirtQueue<H, SIZE> {}
 // data race.
 unsafe impl<H: Hal, const SIZE: usize> Sync for VirtQueue<H, SIZE> {}
 
+impl<H: Hal, const SIZE: usize> Queue for VirtQueue<H, SIZE> {
+    unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe { Self::add(self, inputs, outputs) }
+    }
+
+    unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe { Self::pop_used(self, token, inputs, outputs) }
+    }
+
+    fn queue_idx(&self) -> u16 {
+        self.queue_idx
+    }
+
+    fn set_dev_notify(&mut self, enable: bool) {
+        Self::set_dev_notify(self, enable)
+    }
+
+    fn should_notify(&self) -> bool {
+        Self::should_notify(self)
+    }
+
+    fn can_pop(&self) -> bool {
+        Self::can_pop(self)
+    }
+
+    fn notification_data(&self) -> u32 {
+        // The next available index, which for a split queue is the index of the available ring.
+        u32::from(self.queue_idx) | u32::from(self.avail_idx) << 16
+    }
+
+    fn peek_used_with_len(&self) -> Option<(u16, u32)> {
+        Self::peek_used_with_len(self)
+    }
+
+    fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        Self::completed(self)
+    }
+
+    fn available_desc(&self) -> usize {
+        Self::available_desc(self)
+    }
+}
+
+/// Either a split or a packed virtqueue, depending on which layout was negotiated with the device.
+#[derive(Debug)]
+pub enum SomeQueue<H: Hal, const SIZE: usize> {
+    /// A split virtqueue.
+    Split(VirtQueue<H, SIZE>),
+    /// A packed virtqueue.
+    Packed(PackedQueue<H, SIZE>),
+}
+
+impl<H: Hal, const SIZE: usize> SomeQueue<H, SIZE> {
+    /// Creates a new virtqueue, using the packed layout if the `VIRTIO_F_RING_PACKED` feature has
+    /// been negotiated with the device or the split layout otherwise.
+    ///
+    /// The other parameters are as for [`VirtQueue::new`].
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        if transport.negotiated_features() & Feature::RING_PACKED.bits() != 0 {
+            PackedQueue::new(transport, idx, indirect, event_idx).map(Self::Packed)
+        } else {
+            VirtQueue::new(transport, idx, indirect, event_idx).map(Self::Split)
+        }
+    }
+
+    /// Creates a new virtqueue like [`new`](Self::new), but using a smaller size than `SIZE` if
+    /// that is all the device supports.
+    ///
+    /// See [`VirtQueue::new_clamped`].
+    pub fn new_clamped<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        if transport.negotiated_features() & Feature::RING_PACKED.bits() != 0 {
+            PackedQueue::new_clamped(transport, idx, indirect, event_idx).map(Self::Packed)
+        } else {
+            VirtQueue::new_clamped(transport, idx, indirect, event_idx).map(Self::Split)
+        }
+    }
+
+    /// Returns the number of descriptors in the queue.
+    pub fn size(&self) -> u16 {
+        match self {
+            Self::Split(queue) => queue.size(),
+            Self::Packed(queue) => queue.size(),
+        }
+    }
+
+    /// Unshares every buffer still in the queue and frees all its descriptors.
+    ///
+    /// See [`VirtQueue::reclaim_all`].
+    ///
+    /// # Safety
+    ///
+    /// The same as [`VirtQueue::reclaim_all`].
+    pub unsafe fn reclaim_all(&mut self) {
+        // SAFETY: Our caller upholds the same requirements.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.reclaim_all(),
+                Self::Packed(queue) => queue.reclaim_all(),
+            }
+        }
+    }
+
+    /// Sets the number of buffers above which a chain uses an indirect descriptor table.
+    ///
+    /// See [`VirtQueue::set_indirect_threshold`].
+    #[cfg(feature = "alloc")]
+    pub fn set_indirect_threshold(&mut self, threshold: usize) {
+        match self {
+            Self::Split(queue) => queue.set_indirect_threshold(threshold),
+            Self::Packed(queue) => queue.set_indirect_threshold(threshold),
+        }
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Queue for SomeQueue<H, SIZE> {
+    unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.add(inputs, outputs),
+                Self::Packed(queue) => queue.add(inputs, outputs),
+            }
+        }
+    }
+
+    unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.pop_used(token, inputs, outputs),
+                Self::Packed(queue) => queue.pop_used(token, inputs, outputs),
+            }
+        }
+    }
+
+    fn queue_idx(&self) -> u16 {
+        match self {
+            Self::Split(queue) => Queue::queue_idx(queue),
+            Self::Packed(queue) => Queue::queue_idx(queue),
+        }
+    }
+
+    fn set_dev_notify(&mut self, enable: bool) {
+        match self {
+            Self::Split(queue) => queue.set_dev_notify(enable),
+            Self::Packed(queue) => queue.set_dev_notify(enable),
+        }
+    }
+
+    fn should_notify(&self) -> bool {
+        match self {
+            Self::Split(queue) => queue.should_notify(),
+            Self::Packed(queue) => queue.should_notify(),
+        }
+    }
+
+    fn can_pop(&self) -> bool {
+        match self {
+            Self::Split(queue) => queue.can_pop(),
+            Self::Packed(queue) => queue.can_pop(),
+        }
+    }
+
+    fn notification_data(&self) -> u32 {
+        match self {
+            Self::Split(queue) => queue.notification_data(),
+            Self::Packed(queue) => queue.notification_data(),
+        }
+    }
+
+    fn peek_used_with_len(&self) -> Option<(u16, u32)> {
+        match self {
+            Self::Split(queue) => queue.peek_used_with_len(),
+            Self::Packed(queue) => queue.peek_used_with_len(),
+        }
+    }
+
+    fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        let (split, packed) = match self {
+            Self::Split(queue) => (Some(queue.completed()), None),
+            Self::Packed(queue) => (None, Some(queue.completed())),
+        };
+        split
+            .into_iter()
+            .flatten()
+            .chain(packed.into_iter().flatten())
+    }
+
+    fn available_desc(&self) -> usize {
+        match self {
+            Self::Split(queue) => queue.available_desc(),
+            Self::Packed(queue) => queue.available_desc(),
+        }
+    }
+}
+
 /// The inner layout of a VirtQueue.
 ///
 /// Ref: 2.6 Split Virtqueues
@@ -575,10 +1250,15 @@ impl<H: Hal> VirtQueueLayout<H> {
     /// Allocates a single DMA region containing all parts of the virtqueue, following the layout
     /// required by legacy interfaces.
     ///
+    /// The rings are laid out for `queue_size` slots, but enough memory is allocated for
+    /// `capacity` slots, so that the rings can be accessed as `AvailRing<capacity>` and
+    /// `UsedRing<capacity>`.
+    ///
     /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
-    fn allocate_legacy(queue_size: u16) -> Result<Self> {
+    fn allocate_legacy(queue_size: u16, capacity: u16) -> Result<Self> {
         let (desc, avail, used) = queue_part_sizes(queue_size);
-        let size = align_up(desc + avail) + align_up(used);
+        let (_, _, used_capacity) = queue_part_sizes(capacity);
+        let size = align_up(desc + avail) + align_up(used.max(used_capacity));
         // Allocate contiguous pages.
         let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
         Ok(Self::Legacy {
@@ -593,8 +1273,12 @@ impl<H: Hal> VirtQueueLayout<H> {
     ///
     /// This is preferred over `allocate_legacy` where possible as it reduces memory fragmentation
     /// and allows the HAL to know which DMA regions are used in which direction.
-    fn allocate_flexible(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
+    ///
+    /// As for `allocate_legacy`, enough memory is allocated for the rings to be accessed with
+    /// `capacity` slots.
+    fn allocate_flexible(queue_size: u16, capacity: u16) -> Result<Self> {
+        let (desc, _, _) = queue_part_sizes(queue_size);
+        let (_, avail, used) = queue_part_sizes(capacity.max(queue_size));
         let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
         let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
         Ok(Self::Modern {
@@ -720,7 +1404,7 @@ impl Descriptor {
     ) {
         // Safe because our caller promises that the buffer is valid.
         unsafe {
-            self.addr = H::share(buf, direction) as u64;
+            self.addr = share_for_device::<H>(buf, direction) as u64;
         }
         self.len = buf.len().try_into().unwrap();
         self.flags = extra_flags
@@ -741,6 +1425,15 @@ impl Descriptor {
         self.len = 0;
     }
 
+    /// Returns the direction in which the buffer was shared, according to the `WRITE` flag.
+    fn direction(&self) -> BufferDirection {
+        if self.flags.contains(DescFlags::WRITE) {
+            BufferDirection::DeviceToDriver
+        } else {
+            BufferDirection::DriverToDevice
+        }
+    }
+
     /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
     /// if it is not (and thus this descriptor is the end of the chain).
     fn next(&self) -> Option<u16> {
@@ -989,9 +1682,63 @@ mod tests {
             DeviceType,
         },
     };
-    use core::ptr::NonNull;
+    use core::{ptr::NonNull, sync::atomic::AtomicUsize};
     use std::sync::{Arc, Mutex};
 
+    /// A HAL which claims that devices can only access the first page of physical memory.
+    #[derive(Debug)]
+    struct NarrowHal;
+
+    unsafe impl Hal for NarrowHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        fn dma_address_bits() -> u8 {
+            12
+        }
+    }
+
+    #[test]
+    fn queue_address_out_of_range() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            VirtQueue::<NarrowHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::DmaAddressOutOfRange
+        );
+    }
+
+    #[test]
+    fn queue_unavailable() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 0);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::NoUsableQueue
+        );
+    }
+
     #[test]
     fn queue_too_big() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
@@ -1004,6 +1751,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn queue_clamped() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 6);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 8>::new_clamped(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.available_desc(), 4);
+
+        // Only 4 descriptors can be used.
+        let token = unsafe { queue.add(&[&[1], &[2], &[3], &[4]], &mut []) }.unwrap();
+        assert_eq!(queue.available_desc(), 0);
+        assert_eq!(
+            unsafe { queue.add(&[&[5]], &mut []) },
+            Err(Error::QueueFull)
+        );
+
+        assert!(fake_read_write_queue(
+            queue.desc.as_ptr() as *const [Descriptor; 4],
+            queue.avail.as_ptr() as *const u8,
+            queue.used.as_ptr() as *mut u8,
+            |_| Vec::new(),
+        ));
+        unsafe { queue.pop_used(token, &[&[1], &[2], &[3], &[4]], &mut []) }.unwrap();
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    #[test]
+    fn queue_full_until_popped() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let token = unsafe { queue.add(&[&[1], &[2], &[3], &[4]], &mut []) }.unwrap();
+
+        // The device has finished with the chain, but its descriptors aren't free until it is
+        // popped.
+        assert!(fake_read_write_queue(
+            queue.desc.as_ptr() as *const [Descriptor; 4],
+            queue.avail.as_ptr() as *const u8,
+            queue.used.as_ptr() as *mut u8,
+            |_| Vec::new(),
+        ));
+        assert_eq!(queue.peek_used(), Some(token));
+        assert_eq!(
+            unsafe { queue.add(&[&[5]], &mut []) },
+            Err(Error::QueueFull)
+        );
+
+        unsafe { queue.pop_used(token, &[&[1], &[2], &[3], &[4]], &mut []) }.unwrap();
+        assert!(unsafe { queue.add(&[&[5]], &mut []) }.is_ok());
+    }
+
     #[test]
     fn queue_already_used() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
@@ -1044,6 +1847,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn add_empty_buffer_mid_chain() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [], &mut [0]]) }.unwrap_err(),
+            Error::InvalidParam
+        );
+
+        // No descriptors should have been leaked, so a chain using all of them still fits.
+        assert_eq!(queue.available_desc(), 4);
+        unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0], &mut [0]]) }.unwrap();
+        assert_eq!(queue.available_desc(), 0);
+    }
+
     #[test]
     fn add_buffers() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
@@ -1161,6 +1982,52 @@ mod tests {
         }
     }
 
+    /// Tests that only chains longer than the threshold are indirect, and that the indirect
+    /// descriptor table is freed when the chain is popped.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn indirect_threshold() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
+        queue.set_indirect_threshold(2);
+
+        // A chain of two buffers is at the threshold, so shouldn't be indirect.
+        let direct = unsafe { queue.add(&[&[1]], &mut [&mut [0]]) }.unwrap();
+        assert!(queue.indirect_lists[usize::from(direct)].is_none());
+        assert!(!queue.desc_shadow[usize::from(direct)]
+            .flags
+            .contains(DescFlags::INDIRECT));
+        assert_eq!(queue.num_used, 2);
+
+        // A chain of three buffers is over the threshold, so should only use one descriptor.
+        let indirect = unsafe { queue.add(&[&[1], &[2]], &mut [&mut [0]]) }.unwrap();
+        assert!(queue.indirect_lists[usize::from(indirect)].is_some());
+        assert_eq!(
+            queue.desc_shadow[usize::from(indirect)].flags,
+            DescFlags::INDIRECT
+        );
+        assert_eq!(queue.num_used, 3);
+
+        // Simulate the device using both chains.
+        // SAFETY: The used ring is properly aligned, dereferenceable and initialised, and nothing
+        // else is accessing it at the same time.
+        unsafe {
+            (*queue.used.as_ptr()).ring[0].id = direct.into();
+            (*queue.used.as_ptr()).ring[1].id = indirect.into();
+            (*queue.used.as_ptr()).idx.store(2, Ordering::Release);
+        }
+        unsafe { queue.pop_used(direct, &[&[1]], &mut [&mut [0]]) }.unwrap();
+        unsafe { queue.pop_used(indirect, &[&[1], &[2]], &mut [&mut [0]]) }.unwrap();
+
+        // The indirect table should have been freed along with all the descriptors.
+        assert!(queue.indirect_lists.iter().all(Option::is_none));
+        assert_eq!(queue.num_used, 0);
+        assert_eq!(queue.available_desc(), 4);
+    }
+
     /// Tests that the queue advises the device that notifications are needed.
     #[test]
     fn set_dev_notify() {
@@ -1196,6 +2063,149 @@ mod tests {
         );
     }
 
+    /// Tests that used buffer notifications can be disabled with `used_event` when
+    /// `VIRTIO_F_EVENT_IDX` has been negotiated, and that popping doesn't re-enable them.
+    #[test]
+    fn set_dev_notify_event_idx() {
+        let state = Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ())));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let used_event = |queue: &VirtQueue<FakeHal, 4>| {
+            // SAFETY: the avail ring is properly aligned, dereferenceable and initialised.
+            unsafe { (*queue.avail.as_ptr()).used_event.load(Ordering::Acquire) }
+        };
+
+        queue.set_dev_notify(false);
+        // The flags must stay 0 when the event index is used.
+        assert_eq!(
+            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            0x0
+        );
+        assert_eq!(used_event(&queue), 0xffff);
+
+        // Simulate the device using a buffer, then pop it.
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        // SAFETY: the used ring is properly aligned, dereferenceable and initialised, and nothing
+        // else is accessing it at the same time.
+        unsafe {
+            (*queue.used.as_ptr()).ring[0].id = token.into();
+            (*queue.used.as_ptr()).idx.store(1, Ordering::Release);
+        }
+        unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        assert_eq!(used_event(&queue), 0);
+
+        queue.set_dev_notify(true);
+        assert_eq!(used_event(&queue), 1);
+    }
+
+    /// Tests that the next available index is sent with notifications when
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    #[test]
+    fn notification_data() {
+        let state = Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ())));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::NOTIFICATION_DATA.bits(),
+            state: state.clone(),
+        };
+        transport.write_driver_features(Feature::NOTIFICATION_DATA.bits());
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        unsafe { queue.add(&[&[43]], &mut []) }.unwrap();
+        Queue::notify(&queue, &mut transport);
+
+        let state = state.lock().unwrap();
+        assert!(state.queues[0].notified.load(Ordering::SeqCst));
+        // The queue index in bits 0-15 and the next available index in bits 16-31.
+        assert_eq!(
+            state.queues[0].notification_data.load(Ordering::SeqCst),
+            0x0002_0000
+        );
+    }
+
+    /// Tests that peeking at the used ring returns the token and used length of the next element
+    /// without popping it.
+    #[test]
+    fn peek_used_with_len() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut output = [0; 4];
+        let token = unsafe { queue.add(&[&[1, 2]], &mut [&mut output]) }.unwrap();
+        assert_eq!(queue.peek_used_with_len(), None);
+
+        assert!(fake_read_write_queue(
+            queue.desc.as_ptr() as *const [Descriptor; 4],
+            queue.avail.as_ptr() as *const u8,
+            queue.used.as_ptr() as *mut u8,
+            |input| {
+                assert_eq!(input, vec![1, 2]);
+                vec![3, 4, 5]
+            },
+        ));
+
+        // Peeking repeatedly shouldn't consume the element. The fake device counts the input as
+        // well as the output in the used length.
+        assert_eq!(queue.peek_used_with_len(), Some((token, 5)));
+        assert_eq!(queue.peek_used_with_len(), Some((token, 5)));
+        assert_eq!(queue.peek_used(), Some(token));
+
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1, 2]], &mut [&mut output]) }.unwrap(),
+            5
+        );
+        assert_eq!(output, [3, 4, 5, 0]);
+        assert_eq!(queue.peek_used_with_len(), None);
+    }
+
+    #[test]
+    fn completed() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut first = [0; 2];
+        let mut second = [0; 3];
+        let first_token = unsafe { queue.add(&[], &mut [&mut first]) }.unwrap();
+        let second_token = unsafe { queue.add(&[], &mut [&mut second]) }.unwrap();
+        assert_eq!(queue.completed().count(), 0);
+
+        for _ in 0..2 {
+            assert!(fake_read_write_queue(
+                queue.desc.as_ptr() as *const [Descriptor; 4],
+                queue.avail.as_ptr() as *const u8,
+                queue.used.as_ptr() as *mut u8,
+                |_| vec![1],
+            ));
+        }
+
+        let completed = queue.completed().collect::<Vec<_>>();
+        assert_eq!(completed, vec![(first_token, 1), (second_token, 1)]);
+        unsafe {
+            queue.pop_used(first_token, &[], &mut [&mut first]).unwrap();
+        }
+        assert_eq!(
+            queue.completed().collect::<Vec<_>>(),
+            vec![(second_token, 1)]
+        );
+        unsafe {
+            queue
+                .pop_used(second_token, &[], &mut [&mut second])
+                .unwrap();
+        }
+        assert_eq!(queue.completed().count(), 0);
+    }
+
     /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
     /// notifications.
     #[test]
@@ -1263,4 +2273,217 @@ mod tests {
         // Check that the transport should be notified again now.
         assert_eq!(queue.should_notify(), true);
     }
+
+    /// Tests that the queue still notifies the device with the `avail_event` index when the avail
+    /// index wraps around.
+    #[test]
+    fn add_notify_event_idx_wrap() {
+        let state = Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ())));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        queue.avail_idx = u16::MAX;
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Ask to be notified once the buffer at index 0xfffe has been added.
+            (*queue.used.as_ptr())
+                .avail_event
+                .store(0xfffe, Ordering::Release);
+        }
+
+        // Add a buffer chain, which wraps the avail index around to 0.
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert_eq!(queue.avail_idx, 0);
+
+        // Check that the transport would be notified.
+        assert!(queue.should_notify());
+
+        // SAFETY: as above.
+        unsafe {
+            (*queue.used.as_ptr())
+                .avail_event
+                .store(0, Ordering::Release);
+        }
+
+        // The device doesn't want a notification until after the buffer at index 0 is added.
+        assert!(!queue.should_notify());
+    }
+
+    #[test]
+    fn some_queue_layout() {
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            (),
+        )));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_PACKED.bits(),
+            state: state.clone(),
+        };
+        assert!(matches!(
+            SomeQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap(),
+            SomeQueue::Split(_)
+        ));
+
+        transport.write_driver_features(Feature::RING_PACKED.bits());
+        let mut queue = SomeQueue::<FakeHal, 4>::new(&mut transport, 1, false, false).unwrap();
+        assert!(matches!(queue, SomeQueue::Packed(_)));
+        assert_eq!(
+            queue.add_notify_wait_pop(&[], &mut [], &mut transport),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(Queue::queue_idx(&queue), 1);
+    }
+
+    static SYNCS_FOR_DEVICE: AtomicUsize = AtomicUsize::new(0);
+    static SYNCS_FOR_CPU: AtomicUsize = AtomicUsize::new(0);
+
+    /// A HAL which counts how many times buffers are synced in each direction.
+    #[derive(Debug)]
+    struct SyncCountingHal;
+
+    unsafe impl Hal for SyncCountingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        unsafe fn dma_sync_for_device(_paddr: PhysAddr, _len: usize, _direction: BufferDirection) {
+            SYNCS_FOR_DEVICE.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe fn dma_sync_for_cpu(_paddr: PhysAddr, _len: usize, _direction: BufferDirection) {
+            SYNCS_FOR_CPU.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Tests that every buffer is synced for the device when added and for the CPU when popped.
+    #[test]
+    fn dma_sync() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue =
+            VirtQueue::<SyncCountingHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut output = [0; 4];
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut output]) }.unwrap();
+        assert_eq!(SYNCS_FOR_DEVICE.load(Ordering::SeqCst), 3);
+        assert_eq!(SYNCS_FOR_CPU.load(Ordering::SeqCst), 0);
+
+        assert!(fake_read_write_queue(
+            queue.desc.as_ptr() as *const [Descriptor; 4],
+            queue.avail.as_ptr() as *const u8,
+            queue.used.as_ptr() as *mut u8,
+            |input| {
+                assert_eq!(input, vec![1, 2, 3]);
+                vec![4, 5]
+            },
+        ));
+
+        unsafe { queue.pop_used(token, &[&[1, 2], &[3]], &mut [&mut output]) }.unwrap();
+        assert_eq!(output, [4, 5, 0, 0]);
+        assert_eq!(SYNCS_FOR_DEVICE.load(Ordering::SeqCst), 3);
+        assert_eq!(SYNCS_FOR_CPU.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn add_batch() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let avail_idx = |queue: &VirtQueue<FakeHal, 4>| unsafe {
+            (*queue.avail.as_ptr()).idx.load(Ordering::Acquire)
+        };
+
+        // A batch which doesn't fit should leave the queue untouched.
+        let (mut a, mut b, mut c) = ([0; 2], [0; 2], [0; 2]);
+        let mut requests: [BatchRequest; 3] = [
+            (&[&[1]], &mut [&mut a]),
+            (&[&[2]], &mut [&mut b]),
+            (&[&[3]], &mut [&mut c]),
+        ];
+        let mut tokens = [0; 3];
+        assert_eq!(
+            unsafe { queue.add_batch(&mut requests, &mut tokens) },
+            Err(Error::QueueFull)
+        );
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(avail_idx(&queue), 0);
+
+        let mut requests: [BatchRequest; 2] = [(&[&[1]], &mut [&mut a]), (&[&[2]], &mut [&mut b])];
+        let mut tokens = [0; 2];
+        unsafe { queue.add_batch(&mut requests, &mut tokens) }.unwrap();
+        assert_eq!(queue.available_desc(), 0);
+        assert_eq!(avail_idx(&queue), 2);
+        assert_ne!(tokens[0], tokens[1]);
+
+        for _ in 0..2 {
+            assert!(fake_read_write_queue(
+                queue.desc.as_ptr() as *const [Descriptor; 4],
+                queue.avail.as_ptr() as *const u8,
+                queue.used.as_ptr() as *mut u8,
+                |input| vec![input[0] * 10],
+            ));
+        }
+        assert_eq!(
+            unsafe { queue.pop_used(tokens[0], &[&[1]], &mut [&mut a]) },
+            Ok(2)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(tokens[1], &[&[2]], &mut [&mut b]) },
+            Ok(2)
+        );
+        assert_eq!(a, [10, 0]);
+        assert_eq!(b, [20, 0]);
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    /// Tests that reclaiming a queue frees the descriptors of chains the device never used, both
+    /// direct and indirect.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn reclaim_all() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
+        queue.set_indirect_threshold(2);
+        let mut output = [0; 4];
+        unsafe { queue.add(&[&[1, 2]], &mut [&mut output]) }.unwrap();
+        unsafe { queue.add(&[&[3], &[4], &[5]], &mut []) }.unwrap();
+        assert_eq!(queue.num_used, 3);
+
+        unsafe { queue.reclaim_all() };
+        assert_eq!(queue.num_used, 0);
+        assert_eq!(queue.available_desc(), 4);
+        assert!(queue.buffers.iter().all(Option::is_none));
+        assert!(queue.indirect_lists.iter().all(Option::is_none));
+        assert!(!queue.can_pop());
+    }
 }
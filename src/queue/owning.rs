@@ -1,4 +1,4 @@
-use super::VirtQueue;
+use super::{Queue, VirtQueue};
 use crate::{transport::Transport, Error, Hal, Result};
 use alloc::boxed::Box;
 use core::convert::TryInto;
@@ -41,11 +41,32 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
         self.queue.should_notify()
     }
 
+    /// Notifies the device about new available buffers.
+    pub fn notify(&self, transport: &mut impl Transport) {
+        Queue::notify(&self.queue, transport);
+    }
+
     /// Tells the device whether to send used buffer notifications.
     pub fn set_dev_notify(&mut self, enable: bool) {
         self.queue.set_dev_notify(enable);
     }
 
+    /// Unshares all the buffers in the queue and frees its descriptors, once the device has stopped
+    /// using it.
+    ///
+    /// The buffers themselves are still freed when the `OwningQueue` is dropped. The queue can't be
+    /// used to receive anything more afterwards.
+    ///
+    /// # Safety
+    ///
+    /// The device must not access the queue or any of the buffers in it after this is called, e.g.
+    /// because the device has been reset.
+    pub unsafe fn reclaim_all(&mut self) {
+        // SAFETY: Our caller promises the device has stopped using the queue, and the buffers live
+        // as long as we do.
+        unsafe { self.queue.reclaim_all() }
+    }
+
     /// Adds the buffer at the given index in `buffers` back to the queue.
     ///
     /// Automatically notifies the device if required.
@@ -70,7 +91,7 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
         }
 
         if self.queue.should_notify() {
-            transport.notify(self.queue.queue_idx);
+            self.notify(transport);
         }
 
         Ok(())
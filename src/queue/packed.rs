@@ -0,0 +1,1214 @@
+//! Packed virtqueues.
+//!
+//! Ref: virtio 2.8 Packed Virtqueues
+
+#[cfg(feature = "alloc")]
+use super::IndirectBuffers;
+use super::{InputOutputIter, Queue};
+use crate::hal::{
+    dma_addressable, share_for_device, unshare_from_device, BufferDirection, Dma, Hal,
+};
+use crate::transport::Transport;
+use crate::{pages, Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec::Vec};
+use bitflags::bitflags;
+use core::convert::TryInto;
+use core::mem::{size_of, take};
+use core::ptr::{addr_of_mut, NonNull};
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+/// A virtqueue using the packed ring layout from virtio 1.1, which keeps the descriptors, available
+/// and used buffers together in a single ring.
+///
+/// This offers the same interface as the split [`VirtQueue`](super::VirtQueue), and may only be
+/// used if the `VIRTIO_F_RING_PACKED` feature has been negotiated with the device.
+///
+/// * `SIZE`: The size of the queue. This is the number of descriptors in the ring. It must be
+///   non-zero and at most 2^15. If the queue is created with [`new_clamped`](Self::new_clamped)
+///   then this is only the maximum size, and the actual size may be smaller.
+#[derive(Debug)]
+pub struct PackedQueue<H: Hal, const SIZE: usize> {
+    /// DMA guard for the descriptor ring and driver event suppression structure.
+    ///
+    /// The device writes used descriptors back into the ring, so this is shared in both
+    /// directions.
+    _ring_dma: Dma<H>,
+    /// DMA guard for the device event suppression structure.
+    _device_to_driver_dma: Dma<H>,
+    /// Descriptor ring
+    ///
+    /// The device writes used descriptors back to this, so values read from it must not be
+    /// trusted. Use `desc_shadow` to keep track of the buffers we have given to the device.
+    ring: NonNull<[PackedDescriptor; SIZE]>,
+    /// Driver event suppression structure, which controls used buffer notifications.
+    driver_event: NonNull<EventSuppress>,
+    /// Device event suppression structure, which controls available buffer notifications.
+    device_event: NonNull<EventSuppress>,
+
+    /// The index of queue
+    queue_idx: u16,
+    /// The actual size of the ring, which is no greater than `SIZE`.
+    size: u16,
+    /// The number of ring slots currently in use.
+    num_used: u16,
+    /// The ring slot at which the next available descriptor will be written.
+    next_avail: u16,
+    /// The driver ring wrap counter.
+    avail_wrap: bool,
+    /// The ring slot at which the device will write the next used descriptor.
+    last_used: u16,
+    /// The device ring wrap counter, as expected for the next used descriptor.
+    used_wrap: bool,
+    /// The head of the list of free buffer IDs.
+    free_head: u16,
+    /// For each buffer ID, our trusted copy of the descriptor we wrote for it.
+    desc_shadow: [PackedDescriptor; SIZE],
+    /// For each buffer ID, the next buffer ID in the same chain or in the free list.
+    next_id: [u16; SIZE],
+    /// For each buffer ID at the head of a chain which has been made available to the device, the
+    /// number of ring slots used by the chain, or 0 if the ID isn't the head of such a chain.
+    chain_len: [u16; SIZE],
+    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
+    event_idx: bool,
+    #[cfg(feature = "alloc")]
+    indirect: bool,
+    /// Chains with more than this many descriptors use an indirect descriptor table.
+    #[cfg(feature = "alloc")]
+    indirect_threshold: usize,
+    #[cfg(feature = "alloc")]
+    indirect_lists: [Option<NonNull<[PackedDescriptor]>>; SIZE],
+    /// For each buffer ID in use which isn't an indirect descriptor, the buffer it points to, so
+    /// that `reclaim_all` can unshare it without the caller passing the buffers back.
+    buffers: [Option<NonNull<[u8]>>; SIZE],
+    /// For each indirect descriptor table, the buffers its descriptors point to.
+    #[cfg(feature = "alloc")]
+    indirect_buffers: [Option<IndirectBuffers>; SIZE],
+}
+
+impl<H: Hal, const SIZE: usize> PackedQueue<H, SIZE> {
+    const SIZE_OK: () = assert!(SIZE > 0 && SIZE <= 0x8000);
+
+    /// Creates a new packed virtqueue.
+    ///
+    /// * `indirect`: Whether to use indirect descriptors. This should be set if the
+    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device.
+    /// * `event_idx`: Whether to use the event suppression structures' descriptor events. This
+    ///   should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated with the device.
+    ///
+    /// Returns [`Error::Unsupported`] if the transport requires the legacy queue layout, which
+    /// predates packed queues, [`Error::NoUsableQueue`] if the device reports a maximum size of 0
+    /// for the queue, or [`Error::InvalidParam`] if the maximum size is smaller than `SIZE`.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        Self::new_with_size(transport, idx, indirect, event_idx, false)
+    }
+
+    /// Creates a new packed virtqueue like [`new`](Self::new), but if the device's maximum size for
+    /// the queue is smaller than `SIZE` then uses that size rather than failing.
+    ///
+    /// The size chosen can be found with [`size`](Self::size).
+    pub fn new_clamped<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        Self::new_with_size(transport, idx, indirect, event_idx, true)
+    }
+
+    fn new_with_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+        clamp: bool,
+    ) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+        // Indirect descriptors require allocation.
+        #[cfg(not(feature = "alloc"))]
+        let _ = indirect;
+
+        if transport.requires_legacy_layout() {
+            return Err(Error::Unsupported);
+        }
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        let max_queue_size = transport.max_queue_size(idx);
+        if max_queue_size == 0 {
+            return Err(Error::NoUsableQueue);
+        }
+        let size = if max_queue_size >= SIZE as u32 {
+            SIZE as u16
+        } else if clamp {
+            max_queue_size as u16
+        } else {
+            return Err(Error::InvalidParam);
+        };
+
+        // The driver event suppression structure follows the part of the ring in use, but
+        // allocate enough for a full ring so that it can be accessed as an array of `SIZE`.
+        let ring_size = size_of::<PackedDescriptor>() * usize::from(size);
+        let ring_dma = Dma::new(
+            pages(size_of::<PackedDescriptor>() * SIZE + size_of::<EventSuppress>()),
+            BufferDirection::Both,
+        )?;
+        let device_to_driver_dma = Dma::new(
+            pages(size_of::<EventSuppress>()),
+            BufferDirection::DeviceToDriver,
+        )?;
+
+        transport.queue_set(
+            idx,
+            size.into(),
+            ring_dma.paddr(),
+            ring_dma.paddr() + ring_size,
+            device_to_driver_dma.paddr(),
+        );
+
+        let ring = ring_dma.vaddr(0).cast();
+        let driver_event: NonNull<EventSuppress> = ring_dma.vaddr(ring_size).cast();
+        let device_event = device_to_driver_dma.vaddr(0).cast();
+
+        if event_idx {
+            // Ask for a notification when the device uses the first descriptor.
+            // SAFETY: `driver_event` is properly aligned, dereferenceable and initialised, and the
+            // device won't rely on it until buffers are made available.
+            unsafe {
+                (*driver_event.as_ptr())
+                    .off_wrap
+                    .store(event_off_wrap(0, true), Ordering::Release);
+                (*driver_event.as_ptr())
+                    .flags
+                    .store(EVENT_FLAGS_DESC, Ordering::Release);
+            }
+        }
+
+        // Link all buffer IDs together into the free list.
+        let mut next_id = [0; SIZE];
+        for (i, next) in next_id.iter_mut().enumerate() {
+            *next = (i + 1) as u16;
+        }
+
+        #[cfg(feature = "alloc")]
+        const NONE: Option<NonNull<[PackedDescriptor]>> = None;
+        #[cfg(feature = "alloc")]
+        const NO_BUFFERS: Option<IndirectBuffers> = None;
+        Ok(PackedQueue {
+            _ring_dma: ring_dma,
+            _device_to_driver_dma: device_to_driver_dma,
+            ring,
+            driver_event,
+            device_event,
+            queue_idx: idx,
+            size,
+            num_used: 0,
+            next_avail: 0,
+            avail_wrap: true,
+            last_used: 0,
+            used_wrap: true,
+            free_head: 0,
+            desc_shadow: FromZeros::new_zeroed(),
+            next_id,
+            chain_len: [0; SIZE],
+            event_idx,
+            #[cfg(feature = "alloc")]
+            indirect,
+            #[cfg(feature = "alloc")]
+            indirect_threshold: 1,
+            #[cfg(feature = "alloc")]
+            indirect_lists: [NONE; SIZE],
+            buffers: [None; SIZE],
+            #[cfg(feature = "alloc")]
+            indirect_buffers: [NO_BUFFERS; SIZE],
+        })
+    }
+
+    /// Sets the number of buffers above which a chain uses an indirect descriptor table, rather
+    /// than a descriptor in the ring for each buffer. The default is 1, so every chain of more than
+    /// one buffer is indirect.
+    ///
+    /// An indirect chain only uses a single descriptor in the ring, so a higher threshold trades
+    /// queue depth for avoiding an allocation for short chains. This has no effect unless indirect
+    /// descriptors were enabled when the queue was created.
+    #[cfg(feature = "alloc")]
+    pub fn set_indirect_threshold(&mut self, threshold: usize) {
+        self.indirect_threshold = threshold.max(1);
+    }
+
+    /// Returns whether a chain of the given number of descriptors should use an indirect
+    /// descriptor table.
+    #[cfg(feature = "alloc")]
+    fn use_indirect(&self, descriptors_needed: usize) -> bool {
+        self.indirect && descriptors_needed > self.indirect_threshold
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// The buffers must not be empty. Returns [`Error::InvalidParam`] if any of them are, without
+    /// using any descriptors, so a failed call leaves the queue as it was.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_add_packed
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let descriptors_needed = inputs.len() + outputs.len();
+        let size = usize::from(self.size);
+        // Only consider indirect descriptors if the alloc feature is enabled, as they require
+        // allocation.
+        #[cfg(feature = "alloc")]
+        if self.num_used as usize + 1 > size
+            || descriptors_needed > size
+            || (!self.use_indirect(descriptors_needed)
+                && self.num_used as usize + descriptors_needed > size)
+        {
+            return Err(Error::QueueFull);
+        }
+        #[cfg(not(feature = "alloc"))]
+        if self.num_used as usize + descriptors_needed > size {
+            return Err(Error::QueueFull);
+        }
+        if inputs.iter().any(|input| input.is_empty())
+            || outputs.iter().any(|output| output.is_empty())
+        {
+            return Err(Error::InvalidParam);
+        }
+
+        #[cfg(feature = "alloc")]
+        let (head, len) = if self.use_indirect(descriptors_needed) {
+            (self.add_indirect(inputs, outputs), 1)
+        } else {
+            (self.add_direct(inputs, outputs), descriptors_needed as u16)
+        };
+        #[cfg(not(feature = "alloc"))]
+        let (head, len) = (self.add_direct(inputs, outputs), descriptors_needed as u16);
+
+        if !self.chain_addressable(head, len) {
+            // SAFETY: The chain hasn't been made available to the device, and the buffers are the
+            // ones it was just built from.
+            unsafe {
+                self.recycle_descriptors(head, len, inputs, outputs);
+            }
+            return Err(Error::DmaAddressOutOfRange);
+        }
+
+        self.write_chain(head, len);
+        self.chain_len[usize::from(head)] = len;
+        self.num_used += len;
+
+        Ok(head)
+    }
+
+    /// Takes buffer IDs from the free list for each of the given buffers, and fills in their
+    /// shadow descriptors. Returns the ID of the head of the chain.
+    fn add_direct<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> u16 {
+        let head = self.free_head;
+        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+            let id = self.free_head;
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                self.desc_shadow[usize::from(id)].set_buf::<H>(
+                    buffer,
+                    direction,
+                    PackedDescFlags::empty(),
+                );
+            }
+            self.buffers[usize::from(id)] = Some(buffer);
+            self.free_head = self.next_id[usize::from(id)];
+        }
+        head
+    }
+
+    /// Allocates and fills in an indirect descriptor table for the given buffers, and takes a
+    /// single buffer ID from the free list for it. Returns the buffer ID.
+    #[cfg(feature = "alloc")]
+    fn add_indirect<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> u16 {
+        let head = self.free_head;
+
+        let mut indirect_list =
+            <[PackedDescriptor]>::new_box_zeroed_with_elems(inputs.len() + outputs.len()).unwrap();
+        let mut buffers = Vec::with_capacity(indirect_list.len());
+        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                indirect_list[i].set_buf::<H>(buffer, direction, PackedDescFlags::empty());
+            }
+            buffers.push(buffer);
+        }
+
+        assert!(self.indirect_lists[usize::from(head)].is_none());
+        self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
+        self.indirect_buffers[usize::from(head)] = Some(buffers.into_boxed_slice());
+
+        // The indirect list is leaked here, and freed by `recycle_descriptors` once the chain has
+        // been used.
+        let desc = &mut self.desc_shadow[usize::from(head)];
+        unsafe {
+            desc.set_buf::<H>(
+                Box::leak(indirect_list).as_bytes().into(),
+                BufferDirection::DriverToDevice,
+                PackedDescFlags::INDIRECT,
+            );
+        }
+        self.free_head = self.next_id[usize::from(head)];
+
+        head
+    }
+
+    /// Returns whether all the buffers in the chain of `len` buffer IDs starting at `head` can be
+    /// accessed by the device, according to `Hal::dma_address_bits`.
+    fn chain_addressable(&self, head: u16, len: u16) -> bool {
+        if H::dma_address_bits() >= 64 {
+            return true;
+        }
+        let mut id = head;
+        for _ in 0..len {
+            let desc = &self.desc_shadow[usize::from(id)];
+            if !dma_addressable::<H>(desc.addr, desc.len as usize) {
+                return false;
+            }
+            #[cfg(feature = "alloc")]
+            if let Some(indirect_list) = self.indirect_lists[usize::from(id)] {
+                // SAFETY: The indirect list was allocated by `add_indirect` and won't be freed until
+                // the chain is recycled.
+                let indirect_list = unsafe { indirect_list.as_ref() };
+                if !indirect_list
+                    .iter()
+                    .all(|desc| dma_addressable::<H>(desc.addr, desc.len as usize))
+                {
+                    return false;
+                }
+            }
+            id = self.next_id[usize::from(id)];
+        }
+        true
+    }
+
+    /// Writes the chain of `len` buffer IDs starting at `head` to the ring, making it available to
+    /// the device.
+    ///
+    /// The flags of the first descriptor are written last, so that the device doesn't see any of
+    /// the chain until all of it has been written.
+    fn write_chain(&mut self, head: u16, len: u16) {
+        let first_slot = self.next_avail;
+        let mut first_flags = PackedDescFlags::empty();
+        let mut id = head;
+        for i in 0..len {
+            let mut desc = self.desc_shadow[usize::from(id)].clone();
+            desc.id = head;
+            if i + 1 < len {
+                desc.flags |= PackedDescFlags::NEXT;
+            }
+            desc.flags |= PackedDescFlags::avail(self.avail_wrap);
+            // Safe because self.ring is properly aligned, dereferenceable and initialised, and the
+            // device won't read this slot until it sees the available flags on the first
+            // descriptor of the chain.
+            unsafe {
+                let slot = addr_of_mut!((*self.ring.as_ptr())[usize::from(self.next_avail)]);
+                if i == 0 {
+                    first_flags = desc.flags;
+                    (*slot).addr = desc.addr;
+                    (*slot).len = desc.len;
+                    (*slot).id = desc.id;
+                } else {
+                    *slot = desc;
+                }
+            }
+
+            self.next_avail += 1;
+            if self.next_avail == self.size {
+                self.next_avail = 0;
+                self.avail_wrap = !self.avail_wrap;
+            }
+            id = self.next_id[usize::from(id)];
+        }
+
+        // Write barrier so that the device sees the rest of the chain before the first descriptor
+        // is marked as available.
+        fence(Ordering::SeqCst);
+        // Safe because self.ring is properly aligned, dereferenceable and initialised.
+        unsafe {
+            self.flags(first_slot)
+                .store(first_flags.bits(), Ordering::Release);
+        }
+    }
+
+    /// Returns the flags of the descriptor in the given ring slot, for atomic access.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must be less than `self.size`.
+    unsafe fn flags(&self, slot: u16) -> &AtomicU16 {
+        // SAFETY: self.ring is properly aligned, dereferenceable and initialised, and the flags
+        // are a properly aligned `u16` which is only accessed atomically while the device may be
+        // using it.
+        unsafe {
+            let desc = addr_of_mut!((*self.ring.as_ptr())[usize::from(slot)]);
+            AtomicU16::from_ptr(addr_of_mut!((*desc).flags).cast())
+        }
+    }
+
+    /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    ///
+    /// This assumes that the device isn't processing any other buffers at the same time.
+    ///
+    /// The buffers must not be empty.
+    pub fn add_notify_wait_pop<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        Queue::add_notify_wait_pop(self, inputs, outputs, transport)
+    }
+
+    /// Advise the device whether used buffer notifications are needed.
+    ///
+    /// See Virtio v1.1 2.8.10 Event Suppression Structure Format
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        // Unlike for split queues, notifications can be disabled even if `VIRTIO_F_EVENT_IDX` has
+        // been negotiated.
+        let flags = match (enable, self.event_idx) {
+            (false, _) => EVENT_FLAGS_DISABLE,
+            (true, false) => EVENT_FLAGS_ENABLE,
+            (true, true) => EVENT_FLAGS_DESC,
+        };
+        // Safe because self.driver_event points to a valid, aligned, initialised, dereferenceable
+        // instance of EventSuppress.
+        unsafe {
+            (*self.driver_event.as_ptr())
+                .flags
+                .store(flags, Ordering::Release);
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has suppressed notifications.
+    pub fn should_notify(&self) -> bool {
+        // Safe because self.device_event points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of EventSuppress.
+        let (flags, off_wrap) = unsafe {
+            (
+                (*self.device_event.as_ptr()).flags.load(Ordering::Acquire),
+                (*self.device_event.as_ptr())
+                    .off_wrap
+                    .load(Ordering::Acquire),
+            )
+        };
+        match flags {
+            EVENT_FLAGS_DISABLE => false,
+            EVENT_FLAGS_DESC if self.event_idx => {
+                // The device wants a notification once the descriptor at the given offset and
+                // wrap counter has been made available. Positions repeat every two passes around
+                // the ring, so compare them modulo twice the ring size.
+                let size = usize::from(self.size);
+                let event = ring_position(size, off_wrap & 0x7fff, off_wrap & 0x8000 != 0);
+                let next = ring_position(size, self.next_avail, self.avail_wrap);
+                (next + 2 * size - event - 1) % (2 * size) < size
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.used_at(self.last_used, self.used_wrap).is_some()
+    }
+
+    /// Returns the buffer ID (a.k.a. token) and length of the used descriptor in the given ring
+    /// slot, if the device has marked it as used with the given wrap counter.
+    fn used_at(&self, slot: u16, wrap: bool) -> Option<(u16, u32)> {
+        // Safe because slot is always less than self.size.
+        let flags =
+            PackedDescFlags::from_bits_retain(unsafe { self.flags(slot) }.load(Ordering::Acquire));
+        if flags.contains(PackedDescFlags::AVAIL) != wrap
+            || flags.contains(PackedDescFlags::USED) != wrap
+        {
+            return None;
+        }
+        // Safe because self.ring is properly aligned, dereferenceable and initialised, and the
+        // device won't write to this slot again until we make it available.
+        let desc = unsafe { &(*self.ring.as_ptr())[usize::from(slot)] };
+        Some((desc.id, desc.len))
+    }
+
+    /// Returns the buffer ID (a.k.a. token) of the next used element without popping it, or
+    /// `None` if there is none.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.peek_used_with_len().map(|(token, _)| token)
+    }
+
+    /// Returns the buffer ID (a.k.a. token) of the next used element and the length which the
+    /// device wrote to it, without popping it, or `None` if there is none.
+    pub fn peek_used_with_len(&self) -> Option<(u16, u32)> {
+        self.used_at(self.last_used, self.used_wrap)
+    }
+
+    /// Returns an iterator over the buffer IDs (a.k.a. tokens) and used lengths of all elements
+    /// currently used by the device, in the order in which they must be popped.
+    ///
+    /// This doesn't pop them, so each must still be passed to `pop_used` along with its buffers.
+    pub fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        let mut slot = self.last_used;
+        let mut wrap = self.used_wrap;
+        let mut remaining = self.num_used;
+        core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let (id, len) = self.used_at(slot, wrap)?;
+            // The device skips over the rest of the chain, so we need to do the same to find the
+            // next used descriptor. Stop if it gave us an ID which we didn't make available.
+            let chain_len = *self.chain_len.get(usize::from(id))?;
+            if chain_len == 0 || chain_len > remaining {
+                return None;
+            }
+            remaining -= chain_len;
+            (slot, wrap) = advance(self.size, slot, wrap, chain_len);
+            Some((id, len))
+        })
+    }
+
+    /// Returns the number of descriptors in the ring, which is `SIZE` unless the queue was created
+    /// with [`new_clamped`](Self::new_clamped) on a device which supports fewer.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        #[cfg(feature = "alloc")]
+        if self.indirect {
+            return if self.num_used == self.size {
+                0
+            } else {
+                self.size.into()
+            };
+        }
+
+        usize::from(self.size - self.num_used)
+    }
+
+    /// Unshares the buffers in the chain of `len` buffer IDs starting at `head` and returns the
+    /// IDs to the free list. Unsharing may involve copying data back to the original buffers, so
+    /// they must be passed in too.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add`.
+    unsafe fn recycle_descriptors<'a, 'b>(
+        &mut self,
+        head: u16,
+        len: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) {
+        let head_desc = &mut self.desc_shadow[usize::from(head)];
+        if head_desc.flags.contains(PackedDescFlags::INDIRECT) {
+            #[cfg(feature = "alloc")]
+            {
+                let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
+                self.indirect_buffers[usize::from(head)] = None;
+                // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
+                // finished accessing it by this point.
+                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
+                let paddr = head_desc.addr;
+                head_desc.unset_buf();
+
+                unsafe {
+                    unshare_from_device::<H>(
+                        paddr as usize,
+                        indirect_list.as_mut_bytes().into(),
+                        BufferDirection::DriverToDevice,
+                    );
+                }
+
+                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
+                for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                    // SAFETY: The caller ensures that the buffer is valid and matches the
+                    // descriptor from which we got the address.
+                    unsafe {
+                        unshare_from_device::<H>(indirect_list[i].addr as usize, buffer, direction);
+                    }
+                }
+            }
+            self.next_id[usize::from(head)] = self.free_head;
+        } else {
+            assert_eq!(
+                usize::from(len),
+                inputs.len() + outputs.len(),
+                "Descriptor chain length doesn't match the buffers."
+            );
+            let mut id = head;
+            let mut last = head;
+            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+                let desc = &mut self.desc_shadow[usize::from(id)];
+                let paddr = desc.addr;
+                desc.unset_buf();
+                self.buffers[usize::from(id)] = None;
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got `paddr`.
+                unsafe {
+                    unshare_from_device::<H>(paddr as usize, buffer, direction);
+                }
+                last = id;
+                id = self.next_id[usize::from(id)];
+            }
+            self.next_id[usize::from(last)] = self.free_head;
+        }
+        self.free_head = head;
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx_packed
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        let (id, len) = self.peek_used_with_len().ok_or(Error::NotReady)?;
+        if id != token || self.chain_len.get(usize::from(id)).copied().unwrap_or(0) == 0 {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+        let chain_len = take(&mut self.chain_len[usize::from(id)]);
+
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(id, chain_len, inputs, outputs);
+        }
+        self.num_used -= chain_len;
+        (self.last_used, self.used_wrap) =
+            advance(self.size, self.last_used, self.used_wrap, chain_len);
+
+        if self.event_idx {
+            // Ask for a notification when the device uses the next descriptor.
+            unsafe {
+                (*self.driver_event.as_ptr()).off_wrap.store(
+                    event_off_wrap(self.last_used, self.used_wrap),
+                    Ordering::Release,
+                );
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Unshares every buffer still in the queue, whether or not the device has used it, and
+    /// returns all the buffer IDs to the free list, as if every outstanding chain had been popped.
+    ///
+    /// This is for tearing down a queue which the device has stopped using, so that the buffers of
+    /// requests which will never complete can be freed. Tokens for those requests are no longer
+    /// valid afterwards, and the queue shouldn't be used for further requests as its ring position
+    /// no longer matches the device's.
+    ///
+    /// # Safety
+    ///
+    /// The device must not access the queue or any of the buffers in it after this is called, e.g.
+    /// because the device has been reset. The buffers passed to `add` for chains which haven't been
+    /// popped must still be valid.
+    pub unsafe fn reclaim_all(&mut self) {
+        for id in 0..self.size {
+            let i = usize::from(id);
+            #[cfg(feature = "alloc")]
+            if let Some(indirect_list) = self.indirect_lists[i].take() {
+                let buffers = self.indirect_buffers[i].take().unwrap();
+                // SAFETY: We allocated the indirect list in `add_indirect`, and our caller promises
+                // that the device won't access it again.
+                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
+                for (desc, &buffer) in indirect_list.iter().zip(buffers.iter()) {
+                    // SAFETY: Our caller promises that the buffer is still valid, and it was
+                    // shared at this address by `add_indirect`.
+                    unsafe {
+                        unshare_from_device::<H>(desc.addr as usize, buffer, desc.direction());
+                    }
+                }
+                // SAFETY: The indirect list was shared at this address by `add_indirect`.
+                unsafe {
+                    unshare_from_device::<H>(
+                        self.desc_shadow[i].addr as usize,
+                        indirect_list.as_mut_bytes().into(),
+                        BufferDirection::DriverToDevice,
+                    );
+                }
+            }
+            if let Some(buffer) = self.buffers[i].take() {
+                let desc = &self.desc_shadow[i];
+                // SAFETY: Our caller promises that the buffer is still valid, and it was shared at
+                // this address by `add_direct`.
+                unsafe {
+                    unshare_from_device::<H>(desc.addr as usize, buffer, desc.direction());
+                }
+            }
+
+            // Link all the buffer IDs into the free list again, as `new` does.
+            let desc = &mut self.desc_shadow[i];
+            desc.unset_buf();
+            desc.flags = PackedDescFlags::empty();
+            self.next_id[i] = id + 1;
+            self.chain_len[i] = 0;
+        }
+        self.free_head = 0;
+        self.num_used = 0;
+        // Don't pop anything the device had already used either.
+        self.last_used = self.next_avail;
+        self.used_wrap = self.avail_wrap;
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Queue for PackedQueue<H, SIZE> {
+    unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe { Self::add(self, inputs, outputs) }
+    }
+
+    unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // SAFETY: The caller upholds the same requirements.
+        unsafe { Self::pop_used(self, token, inputs, outputs) }
+    }
+
+    fn queue_idx(&self) -> u16 {
+        self.queue_idx
+    }
+
+    fn set_dev_notify(&mut self, enable: bool) {
+        Self::set_dev_notify(self, enable)
+    }
+
+    fn should_notify(&self) -> bool {
+        Self::should_notify(self)
+    }
+
+    fn can_pop(&self) -> bool {
+        Self::can_pop(self)
+    }
+
+    fn notification_data(&self) -> u32 {
+        // The offset of the next available descriptor in the ring, and its wrap counter.
+        u32::from(self.queue_idx)
+            | u32::from(self.next_avail) << 16
+            | u32::from(self.avail_wrap) << 31
+    }
+
+    fn peek_used_with_len(&self) -> Option<(u16, u32)> {
+        Self::peek_used_with_len(self)
+    }
+
+    fn completed(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        Self::completed(self)
+    }
+
+    fn available_desc(&self) -> usize {
+        Self::available_desc(self)
+    }
+}
+
+// SAFETY: None of the virt queue resources are tied to a particular thread.
+unsafe impl<H: Hal, const SIZE: usize> Send for PackedQueue<H, SIZE> {}
+
+// SAFETY: A `&PackedQueue` only allows reading from the various pointers it contains, so there is
+// no data race.
+unsafe impl<H: Hal, const SIZE: usize> Sync for PackedQueue<H, SIZE> {}
+
+/// Returns the ring slot and wrap counter `count` slots after the given ones, in a ring of the
+/// given size.
+fn advance(size: u16, slot: u16, wrap: bool, count: u16) -> (u16, bool) {
+    let slot = u32::from(slot) + u32::from(count);
+    if slot >= u32::from(size) {
+        ((slot - u32::from(size)) as u16, !wrap)
+    } else {
+        (slot as u16, wrap)
+    }
+}
+
+/// Returns the position of the given ring slot and wrap counter within two passes around the
+/// ring of the given size, starting with the initial wrap counter of 1.
+fn ring_position(size: usize, slot: u16, wrap: bool) -> usize {
+    usize::from(slot) % size + if wrap { 0 } else { size }
+}
+
+/// Encodes a ring slot and wrap counter for an event suppression structure.
+fn event_off_wrap(slot: u16, wrap: bool) -> u16 {
+    slot | if wrap { 0x8000 } else { 0 }
+}
+
+/// Notifications are enabled.
+const EVENT_FLAGS_ENABLE: u16 = 0x0;
+/// Notifications are disabled.
+const EVENT_FLAGS_DISABLE: u16 = 0x1;
+/// Notifications are enabled for a specific descriptor, given by `off_wrap`.
+const EVENT_FLAGS_DESC: u16 = 0x2;
+
+/// An event suppression structure, used by the driver and device to control notifications from
+/// each other.
+///
+/// Ref: 2.8.10 Event Suppression Structure Format
+#[repr(C)]
+#[derive(Debug)]
+struct EventSuppress {
+    /// The descriptor ring slot (bits 0-14) and wrap counter (bit 15) at which to notify, if
+    /// `flags` is `EVENT_FLAGS_DESC`.
+    off_wrap: AtomicU16,
+    flags: AtomicU16,
+}
+
+/// A descriptor in a packed ring or an indirect descriptor table.
+///
+/// Ref: 2.8.13 Packed Virtqueue Layout
+#[repr(C, align(16))]
+#[derive(Clone, Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: PackedDescFlags,
+}
+
+impl PackedDescriptor {
+    /// Sets the buffer address, length and flags, and shares it with the device.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
+    unsafe fn set_buf<H: Hal>(
+        &mut self,
+        buf: NonNull<[u8]>,
+        direction: BufferDirection,
+        extra_flags: PackedDescFlags,
+    ) {
+        // Safe because our caller promises that the buffer is valid.
+        unsafe {
+            self.addr = share_for_device::<H>(buf, direction) as u64;
+        }
+        self.len = buf.len().try_into().unwrap();
+        self.flags = extra_flags
+            | match direction {
+                BufferDirection::DeviceToDriver => PackedDescFlags::WRITE,
+                BufferDirection::DriverToDevice => PackedDescFlags::empty(),
+                BufferDirection::Both => {
+                    panic!("Buffer passed to device should never use BufferDirection::Both.")
+                }
+            };
+    }
+
+    /// Sets the buffer address and length to 0.
+    ///
+    /// This must only be called once the device has finished using the descriptor.
+    fn unset_buf(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+    }
+
+    /// Returns the direction in which the buffer was shared, according to the `WRITE` flag.
+    fn direction(&self) -> BufferDirection {
+        if self.flags.contains(PackedDescFlags::WRITE) {
+            BufferDirection::DeviceToDriver
+        } else {
+            BufferDirection::DriverToDevice
+        }
+    }
+}
+
+/// Packed descriptor flags
+#[derive(
+    Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
+#[repr(transparent)]
+struct PackedDescFlags(u16);
+
+bitflags! {
+    impl PackedDescFlags: u16 {
+        const NEXT = 1;
+        const WRITE = 2;
+        const INDIRECT = 4;
+        const AVAIL = 1 << 7;
+        const USED = 1 << 15;
+    }
+}
+
+impl PackedDescFlags {
+    /// Returns the `AVAIL` and `USED` flags which mark a descriptor as available with the given
+    /// driver ring wrap counter.
+    fn avail(wrap: bool) -> Self {
+        if wrap {
+            Self::AVAIL
+        } else {
+            Self::USED
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn fake_transport(device_features: Feature) -> FakeTransport<()> {
+        FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: device_features.bits(),
+            state: Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ()))),
+        }
+    }
+
+    /// Simulates the device marking the chain with the given buffer ID as used in the given slot.
+    fn mark_used<const SIZE: usize>(
+        queue: &PackedQueue<FakeHal, SIZE>,
+        slot: u16,
+        id: u16,
+        len: u32,
+        wrap: bool,
+    ) {
+        // SAFETY: The ring is properly aligned, dereferenceable and initialised, and nothing else
+        // is accessing it at the same time.
+        unsafe {
+            let desc = &mut (*queue.ring.as_ptr())[usize::from(slot)];
+            desc.id = id;
+            desc.len = len;
+        }
+        let flags = if wrap {
+            PackedDescFlags::AVAIL | PackedDescFlags::USED
+        } else {
+            PackedDescFlags::empty()
+        };
+        // SAFETY: The slot is less than SIZE.
+        unsafe { queue.flags(slot) }.store(flags.bits(), Ordering::Release);
+    }
+
+    /// Returns the buffer ID and flags of the descriptor in the given slot.
+    fn ring_desc<const SIZE: usize>(
+        queue: &PackedQueue<FakeHal, SIZE>,
+        slot: u16,
+    ) -> (u16, u32, PackedDescFlags) {
+        // SAFETY: The ring is properly aligned, dereferenceable and initialised, and nothing else
+        // is accessing it at the same time.
+        let desc = unsafe { &(*queue.ring.as_ptr())[usize::from(slot)] };
+        (desc.id, desc.len, desc.flags)
+    }
+
+    #[test]
+    fn add_pop_wrap() {
+        let mut transport = fake_transport(Feature::empty());
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0]]) }.unwrap();
+        assert_eq!(queue.available_desc(), 1);
+        assert!(!queue.can_pop());
+        assert_eq!(
+            ring_desc(&queue, 0),
+            (token, 2, PackedDescFlags::NEXT | PackedDescFlags::AVAIL)
+        );
+        assert_eq!(
+            ring_desc(&queue, 1),
+            (token, 1, PackedDescFlags::NEXT | PackedDescFlags::AVAIL)
+        );
+        assert_eq!(
+            ring_desc(&queue, 2),
+            (token, 1, PackedDescFlags::WRITE | PackedDescFlags::AVAIL)
+        );
+
+        mark_used(&queue, 0, token, 1, true);
+        assert_eq!(queue.peek_used_with_len(), Some((token, 1)));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1, 2], &[3]], &mut [&mut [0]]) },
+            Ok(1)
+        );
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+
+        // The next chain wraps around the end of the ring, so its second descriptor should use the
+        // flipped wrap counter.
+        let token = unsafe { queue.add(&[&[4], &[5]], &mut []) }.unwrap();
+        assert_eq!(
+            ring_desc(&queue, 3),
+            (token, 1, PackedDescFlags::NEXT | PackedDescFlags::AVAIL)
+        );
+        assert_eq!(ring_desc(&queue, 0), (token, 1, PackedDescFlags::USED));
+
+        // A used descriptor from the previous pass shouldn't be mistaken for a new one.
+        mark_used(&queue, 3, token, 0, true);
+        assert!(queue.can_pop());
+        assert_eq!(queue.completed().collect::<Vec<_>>(), vec![(token, 0)]);
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[4], &[5]], &mut []) },
+            Ok(0)
+        );
+        assert_eq!((queue.last_used, queue.used_wrap), (1, false));
+        mark_used(&queue, 1, token, 0, true);
+        assert!(!queue.can_pop());
+    }
+
+    #[test]
+    fn reclaim_all() {
+        let mut transport = fake_transport(Feature::empty());
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let first = unsafe { queue.add(&[&[1, 2]], &mut [&mut [0]]) }.unwrap();
+        unsafe { queue.add(&[&[3]], &mut []) }.unwrap();
+        assert_eq!(queue.available_desc(), 1);
+
+        // Even a chain which the device has used is reclaimed without being popped.
+        mark_used(&queue, 0, first, 1, true);
+        unsafe { queue.reclaim_all() };
+        assert_eq!(queue.available_desc(), 4);
+        assert!(queue.buffers.iter().all(Option::is_none));
+        assert!(!queue.can_pop());
+    }
+
+    #[test]
+    fn pop_wrong_token() {
+        let mut transport = fake_transport(Feature::empty());
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.pop_used(0, &[&[1]], &mut []) },
+            Err(Error::NotReady)
+        );
+
+        let first = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        let second = unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert_ne!(first, second);
+
+        // The device completes the second request first.
+        mark_used(&queue, 0, second, 0, true);
+        assert_eq!(
+            unsafe { queue.pop_used(first, &[&[1]], &mut []) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(unsafe { queue.pop_used(second, &[&[2]], &mut []) }, Ok(0));
+        mark_used(&queue, 1, first, 0, true);
+        assert_eq!(unsafe { queue.pop_used(first, &[&[1]], &mut []) }, Ok(0));
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    #[test]
+    fn notification_data() {
+        let mut transport = fake_transport(Feature::empty());
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        // The wrap counter starts at 1.
+        assert_eq!(Queue::notification_data(&queue), 0x8000_0000);
+
+        for i in 0..5 {
+            let token = unsafe { queue.add(&[&[i]], &mut []) }.unwrap();
+            mark_used(&queue, u16::from(i) % 4, token, 0, i < 4);
+            unsafe { queue.pop_used(token, &[&[i]], &mut []) }.unwrap();
+        }
+        // The next available descriptor is slot 1, after wrapping around once.
+        assert_eq!(Queue::notification_data(&queue), 0x0001_0000);
+    }
+
+    #[test]
+    fn should_notify_event_idx() {
+        let mut transport = fake_transport(Feature::RING_EVENT_IDX);
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+
+        // SAFETY: The event suppression structures are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            assert_eq!(
+                (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire),
+                EVENT_FLAGS_DESC
+            );
+            // Ask for a notification once the descriptor in slot 1 is available.
+            (*queue.device_event.as_ptr())
+                .off_wrap
+                .store(event_off_wrap(1, true), Ordering::Release);
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(EVENT_FLAGS_DESC, Ordering::Release);
+        }
+
+        let token = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
+
+        // Popping should move the driver's event to the next used slot.
+        mark_used(&queue, 0, token, 0, true);
+        unsafe { queue.pop_used(token, &[&[1]], &mut []) }.unwrap();
+        assert_eq!(
+            unsafe {
+                (*queue.driver_event.as_ptr())
+                    .off_wrap
+                    .load(Ordering::Acquire)
+            },
+            event_off_wrap(1, true)
+        );
+
+        // SAFETY: As above.
+        unsafe {
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(EVENT_FLAGS_DISABLE, Ordering::Release);
+        }
+        assert!(!queue.should_notify());
+
+        // Used buffer notifications can still be turned off and back on.
+        queue.set_dev_notify(false);
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            EVENT_FLAGS_DISABLE
+        );
+        queue.set_dev_notify(true);
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            EVENT_FLAGS_DESC
+        );
+    }
+}
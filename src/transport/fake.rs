@@ -1,6 +1,6 @@
 //! A fake implementation of `Transport` for unit tests.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, InterruptStatus, Transport};
 use crate::{
     queue::{fake_read_write_queue, Descriptor},
     Error, PhysAddr,
@@ -8,7 +8,7 @@ use crate::{
 use alloc::{sync::Arc, vec::Vec};
 use core::{
     fmt::{self, Debug, Formatter},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
     time::Duration,
 };
 use std::{sync::Mutex, thread};
@@ -40,6 +40,10 @@ impl<C: FromBytes + Immutable + IntoBytes> Transport for FakeTransport<C> {
         self.state.lock().unwrap().driver_features = driver_features;
     }
 
+    fn negotiated_features(&self) -> u64 {
+        self.state.lock().unwrap().driver_features
+    }
+
     fn max_queue_size(&mut self, _queue: u16) -> u32 {
         self.max_queue_size
     }
@@ -50,6 +54,13 @@ impl<C: FromBytes + Immutable + IntoBytes> Transport for FakeTransport<C> {
             .store(true, Ordering::SeqCst);
     }
 
+    fn notify_with_data(&mut self, data: u32) {
+        let state = self.state.lock().unwrap();
+        let queue = &state.queues[usize::from(data as u16)];
+        queue.notification_data.store(data, Ordering::SeqCst);
+        queue.notified.store(true, Ordering::SeqCst);
+    }
+
     fn get_status(&self) -> DeviceStatus {
         self.state.lock().unwrap().status
     }
@@ -93,12 +104,10 @@ impl<C: FromBytes + Immutable + IntoBytes> Transport for FakeTransport<C> {
         self.state.lock().unwrap().queues[queue as usize].descriptors != 0
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt(&mut self) -> InterruptStatus {
         let mut state = self.state.lock().unwrap();
-        let pending = state.interrupt_pending;
-        if pending {
-            state.interrupt_pending = false;
-        }
+        let pending = state.interrupt_status;
+        state.interrupt_status = InterruptStatus::empty();
         pending
     }
 
@@ -150,8 +159,8 @@ pub struct State<C> {
     pub driver_features: u64,
     /// The guest page size set by the driver.
     pub guest_page_size: u32,
-    /// Whether the transport has an interrupt pending.
-    pub interrupt_pending: bool,
+    /// The interrupts which the transport has pending.
+    pub interrupt_status: InterruptStatus,
     /// The state of the transport's queues.
     pub queues: Vec<QueueStatus>,
     /// The config generation which the transport should report.
@@ -166,7 +175,7 @@ impl<C> Debug for State<C> {
             .field("status", &self.status)
             .field("driver_features", &self.driver_features)
             .field("guest_page_size", &self.guest_page_size)
-            .field("interrupt_pending", &self.interrupt_pending)
+            .field("interrupt_status", &self.interrupt_status)
             .field("queues", &self.queues)
             .field("config_generation", &self.config_generation)
             .field("config_space", &"...")
@@ -181,7 +190,7 @@ impl<C> State<C> {
             status: DeviceStatus::empty(),
             driver_features: 0,
             guest_page_size: 0,
-            interrupt_pending: false,
+            interrupt_status: InterruptStatus::empty(),
             queues,
             config_generation: 0,
             config_space,
@@ -282,4 +291,7 @@ pub struct QueueStatus {
     pub device_area: PhysAddr,
     /// Whether the queue has been notified by the driver since last we checked.
     pub notified: AtomicBool,
+    /// The notification data most recently sent for the queue, if `VIRTIO_F_NOTIFICATION_DATA` was
+    /// negotiated.
+    pub notification_data: AtomicU32,
 }
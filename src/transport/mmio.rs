@@ -1,6 +1,6 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -205,7 +205,27 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region id selector
+    ///
+    /// Writing to this register selects the shared memory region which the following
+    /// `shm_len_*` and `shm_base_*` registers apply to.
+    shm_sel: WriteOnly<u32>,
+
+    /// Shared memory region length
+    ///
+    /// Reading from these registers returns the length of the selected shared memory region, or
+    /// all ones if there is no such region.
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+
+    /// Shared memory region base address
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 15],
 
     config_generation: ReadOnly<u32>,
 }
@@ -255,11 +275,45 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: Default::default(),
+            shm_len_high: Default::default(),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
 }
 
+/// A snapshot of the readable registers of a VirtIO MMIO device, as returned by
+/// [`MmioTransport::register_snapshot`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MmioRegisters {
+    /// The magic value, which should be 0x74726976.
+    pub magic: u32,
+    /// The device version number.
+    pub version: u32,
+    /// The VirtIO subsystem device ID.
+    pub device_id: u32,
+    /// The VirtIO subsystem vendor ID.
+    pub vendor_id: u32,
+    /// All 64 bits of the features supported by the device.
+    pub device_features: u64,
+    /// The maximum size of the selected queue, or 0 if it is not available.
+    pub queue_num_max: u32,
+    /// Whether the selected queue is ready. Always 0 for legacy devices.
+    pub queue_ready: u32,
+    /// The guest page number of the selected queue. Always 0 for modern devices.
+    pub legacy_queue_pfn: u32,
+    /// The pending interrupt status bits.
+    pub interrupt_status: u32,
+    /// The device status.
+    pub status: DeviceStatus,
+    /// The config space generation. Always 0 for legacy devices.
+    pub config_generation: u32,
+}
+
 /// MMIO Device Register Interface.
 ///
 /// Ref: 4.2.2 MMIO Device Register Layout and 4.2.4 Legacy interface
@@ -269,6 +323,10 @@ pub struct MmioTransport {
     version: MmioVersion,
     /// The size in bytes of the config space.
     config_space_size: usize,
+    /// Device features which should never be negotiated on this transport.
+    disabled_features: u64,
+    /// The driver features most recently written to the device.
+    negotiated_features: u64,
 }
 
 impl MmioTransport {
@@ -294,6 +352,8 @@ impl MmioTransport {
             header,
             version,
             config_space_size,
+            disabled_features: 0,
+            negotiated_features: 0,
         })
     }
 
@@ -307,6 +367,51 @@ impl MmioTransport {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(self.header, vendor_id) }
     }
+
+    /// Reads the main registers of the transport in one go, for diagnosing problems with a
+    /// device.
+    ///
+    /// The queue registers are read for the given queue, which is selected in the process. The
+    /// driver features can't be read back, so aren't included.
+    pub fn register_snapshot(&mut self, queue: u16) -> MmioRegisters {
+        let device_features = self.read_device_features();
+        // This also selects the queue for the reads below.
+        let queue_num_max = self.max_queue_size(queue);
+        let (queue_ready, legacy_queue_pfn, config_generation) = match self.version {
+            // Safe because self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Legacy => (0, unsafe { volread!(self.header, legacy_queue_pfn) }, 0),
+            MmioVersion::Modern => (
+                // Safe because self.header points to a valid VirtIO MMIO region.
+                unsafe { volread!(self.header, queue_ready) },
+                0,
+                self.read_config_generation(),
+            ),
+        };
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe {
+            MmioRegisters {
+                magic: volread!(self.header, magic),
+                version: volread!(self.header, version),
+                device_id: volread!(self.header, device_id),
+                vendor_id: self.vendor_id(),
+                device_features,
+                queue_num_max,
+                queue_ready,
+                legacy_queue_pfn,
+                interrupt_status: volread!(self.header, interrupt_status),
+                status: self.get_status(),
+                config_generation,
+            }
+        }
+    }
+
+    /// Sets the device features which should never be negotiated on this transport, whatever the
+    /// device offers and the driver supports.
+    ///
+    /// This must be called before the driver is constructed to have any effect.
+    pub fn set_disabled_features(&mut self, disabled_features: u64) {
+        self.disabled_features = disabled_features;
+    }
 }
 
 // SAFETY: `header` is only used for MMIO, which can happen from any thread or CPU core.
@@ -342,6 +447,15 @@ impl Transport for MmioTransport {
             volwrite!(self.header, driver_features_sel, 1); // driver features [32, 64)
             volwrite!(self.header, driver_features, (driver_features >> 32) as u32);
         }
+        self.negotiated_features = driver_features;
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.negotiated_features
+    }
+
+    fn disabled_features(&self) -> u64 {
+        self.disabled_features
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -359,6 +473,13 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn notify_with_data(&mut self, data: u32) {
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe {
+            volwrite!(self.header, queue_notify, data);
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(self.header, status) }
@@ -484,22 +605,48 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt(&mut self) -> InterruptStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
             let interrupt = volread!(self.header, interrupt_status);
             if interrupt != 0 {
                 volwrite!(self.header, interrupt_ack, interrupt);
-                true
-            } else {
-                false
             }
+            InterruptStatus::from_bits_truncate(interrupt)
         }
     }
 
-    fn read_config_generation(&self) -> u32 {
+    fn get_shared_memory_region(&self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            return None;
+        }
         // SAFETY: self.header points to a valid VirtIO MMIO region.
-        unsafe { volread!(self.header, config_generation) }
+        let (length, base) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            let length = u64::from(volread!(self.header, shm_len_low))
+                | u64::from(volread!(self.header, shm_len_high)) << 32;
+            let base = u64::from(volread!(self.header, shm_base_low))
+                | u64::from(volread!(self.header, shm_base_high)) << 32;
+            (length, base)
+        };
+        if length == u64::MAX {
+            None
+        } else {
+            Some(SharedMemoryRegion {
+                paddr: base as PhysAddr,
+                length,
+            })
+        }
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        match self.version {
+            // Legacy devices don't have a config generation register, so the generation never
+            // appears to change.
+            MmioVersion::Legacy => 0,
+            // SAFETY: self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Modern => unsafe { volread!(self.header, config_generation) },
+        }
     }
 
     fn read_config_space<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
@@ -557,3 +704,92 @@ impl Drop for MmioTransport {
         self.set_status(DeviceStatus::empty())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitflags::bitflags;
+
+    bitflags! {
+        #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+        struct TestFeatures: u64 {
+            const A = 1 << 0;
+            const B = 1 << 1;
+            const C = 1 << 2;
+        }
+    }
+
+    #[test]
+    fn disabled_features() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0b111, 4);
+        let header = NonNull::from(&mut header);
+        let mut transport =
+            unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+        transport.set_disabled_features(TestFeatures::B.bits());
+
+        // B is offered by the device and supported by the driver, but still not negotiated.
+        let negotiated = transport.begin_init(TestFeatures::A | TestFeatures::B);
+        assert_eq!(negotiated, TestFeatures::A);
+        assert_eq!(transport.negotiated_features(), TestFeatures::A.bits());
+        assert!(!transport.device_supports_feature(1));
+        assert!(transport.device_supports_feature(2));
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x2000);
+        header.shm_len_high = ReadOnly::new(0x1);
+        header.shm_base_low = ReadOnly::new(0x8000_0000);
+        header.shm_base_high = ReadOnly::new(0x2);
+        let header = NonNull::from(&mut header);
+        let transport = unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+
+        assert_eq!(
+            transport.get_shared_memory_region(3),
+            Some(SharedMemoryRegion {
+                paddr: 0x2_8000_0000,
+                length: 0x1_0000_2000,
+            })
+        );
+        // The region should have been selected before the length and base were read.
+        assert_eq!(unsafe { (*header.as_ptr()).shm_sel.0 }, 3);
+    }
+
+    #[test]
+    fn shared_memory_region_not_present() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        // A length of all ones means that there is no region with the selected ID.
+        header.shm_len_low = ReadOnly::new(u32::MAX);
+        header.shm_len_high = ReadOnly::new(u32::MAX);
+        let header = NonNull::from(&mut header);
+        let transport = unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+
+        assert_eq!(transport.get_shared_memory_region(5), None);
+        assert_eq!(unsafe { (*header.as_ptr()).shm_sel.0 }, 5);
+
+        // Only the high half being all ones is a valid length.
+        unsafe {
+            (*header.as_ptr()).shm_len_low = ReadOnly::new(0);
+        }
+        assert_eq!(
+            transport.get_shared_memory_region(5),
+            Some(SharedMemoryRegion {
+                paddr: 0,
+                length: 0xffff_ffff_0000_0000,
+            })
+        );
+    }
+
+    #[test]
+    fn shared_memory_region_legacy() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x1000);
+        let header = NonNull::from(&mut header);
+        let transport = unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+
+        // Legacy devices don't have the shared memory registers.
+        assert_eq!(transport.get_shared_memory_region(0), None);
+        assert_eq!(unsafe { (*header.as_ptr()).shm_sel.0 }, 0);
+    }
+}
@@ -8,13 +8,17 @@ mod some;
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
-use crate::{PhysAddr, Result, PAGE_SIZE};
+use crate::{Error, PhysAddr, Result, PAGE_SIZE};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd};
+use core::{fmt::Debug, hint::spin_loop, ops::BitAnd};
 use log::debug;
 pub use some::SomeTransport;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
+/// The number of times [`Transport::read_consistent`] will try to read the config space before
+/// giving up, if the config generation keeps changing.
+pub const CONFIG_GENERATION_ATTEMPTS: usize = 64;
+
 /// A VirtIO transport layer.
 pub trait Transport {
     /// Gets the device type.
@@ -26,18 +30,70 @@ pub trait Transport {
     /// Writes device features.
     fn write_driver_features(&mut self, driver_features: u64);
 
+    /// Returns the feature bits most recently written by
+    /// [`write_driver_features`](Self::write_driver_features), or 0 if none have been written.
+    ///
+    /// Once a driver has been initialised this is the set of features negotiated by
+    /// [`begin_init`](Self::begin_init), i.e. those offered by the device, supported by the driver
+    /// and not disabled, so it can be used to check whether optional features such as
+    /// `VIRTIO_F_RING_EVENT_IDX` ended up enabled. Queues use it to choose their ring layout and
+    /// how to notify the device, so every transport must keep track of the features written.
+    fn negotiated_features(&self) -> u64;
+
+    /// Returns the set of device features which should never be negotiated on this transport, even
+    /// if both the device and the driver support them.
+    ///
+    /// This can be used to work around known bugs in a particular host implementation, without
+    /// needing to change every driver.
+    fn disabled_features(&self) -> u64 {
+        0
+    }
+
+    /// Returns whether the device offers the feature with the given bit number, e.g. 3 for
+    /// `VIRTIO_NET_F_MTU`, without negotiating features or otherwise initialising the device.
+    ///
+    /// Features disabled by [`disabled_features`](Self::disabled_features) are reported as not
+    /// supported, as they will never be negotiated.
+    fn device_supports_feature(&mut self, bit: u64) -> bool {
+        bit < 64 && (self.read_device_features() & !self.disabled_features()) & (1 << bit) != 0
+    }
+
     /// Gets the max size of the given queue.
     fn max_queue_size(&mut self, queue: u16) -> u32;
 
     /// Notifies the given queue on the device.
     fn notify(&mut self, queue: u16);
 
+    /// Notifies a queue on the device with the given notification data, for when
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    ///
+    /// The low 16 bits of `data` are the index of the queue, and the rest give the position of the
+    /// next available buffer in the queue.
+    ///
+    /// Ref: virtio 2.9 Driver Notifications
+    fn notify_with_data(&mut self, data: u32) {
+        self.notify(data as u16);
+    }
+
     /// Gets the device status.
     fn get_status(&self) -> DeviceStatus;
 
     /// Sets the device status.
     fn set_status(&mut self, status: DeviceStatus);
 
+    /// Resets the device, and waits until the device reports that the reset is complete.
+    ///
+    /// Once this returns the device won't access any of the queues or buffers which were given to
+    /// it before the reset.
+    ///
+    /// Ref: virtio 4.1.4.3.2 Common configuration structure layout: driver requirements
+    fn reset(&mut self) {
+        self.set_status(DeviceStatus::empty());
+        while self.get_status() != DeviceStatus::empty() {
+            spin_loop();
+        }
+    }
+
     /// Sets the guest page size.
     fn set_guest_page_size(&mut self, guest_page_size: u32);
 
@@ -64,8 +120,11 @@ pub trait Transport {
 
     /// Acknowledges an interrupt.
     ///
-    /// Returns true on success.
-    fn ack_interrupt(&mut self) -> bool;
+    /// Returns which interrupts were pending, so that drivers can tell configuration changes apart
+    /// from used buffer notifications. Drivers should check
+    /// [`read_config_generation`](Self::read_config_generation) as well when reading the config
+    /// space, as it may change again at any time.
+    fn ack_interrupt(&mut self) -> InterruptStatus;
 
     /// Begins initializing the device.
     ///
@@ -79,7 +138,8 @@ pub trait Transport {
         self.set_status(DeviceStatus::empty());
         self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
 
-        let device_features = F::from_bits_truncate(self.read_device_features());
+        let device_features =
+            F::from_bits_truncate(self.read_device_features() & !self.disabled_features());
         debug!("Device features: {:?}", device_features);
         let negotiated_features = device_features & supported_features;
         self.write_driver_features(negotiated_features.bits());
@@ -118,18 +178,60 @@ pub trait Transport {
 
     /// Safely reads multiple fields from config space by ensuring that the config generation is the
     /// same before and after all reads, and retrying if not.
+    ///
+    /// Returns [`Error::ConfigUnstable`] if the generation has changed on every one of
+    /// [`CONFIG_GENERATION_ATTEMPTS`] attempts.
     fn read_consistent<T>(&self, f: impl Fn() -> Result<T>) -> Result<T> {
-        loop {
+        for _ in 0..CONFIG_GENERATION_ATTEMPTS {
             let before = self.read_config_generation();
             let result = f();
             let after = self.read_config_generation();
             if before == after {
-                break result;
+                return result;
             }
         }
+        Err(Error::ConfigUnstable)
+    }
+
+    /// Reads the start of the device config space into a single value, such as a `#[repr(C)]`
+    /// struct laid out like the device-specific configuration structure, retrying as for
+    /// [`read_consistent`](Self::read_consistent) so that all fields come from the same
+    /// generation.
+    ///
+    /// `T` must have an alignment of at most 4 bytes, as that is all that VirtIO guarantees for the
+    /// config space, so 64-bit fields should be split into two `u32`s or use an unaligned type.
+    /// Fields are read as they are laid out by the device, which is little-endian for non-legacy
+    /// devices, so they should be converted with `u32::from_le` and friends or use `zerocopy`'s
+    /// little-endian types.
+    ///
+    /// Returns [`Error::ConfigSpaceTooSmall`] if `T` is larger than the config space.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `T` has an alignment of more than 4 bytes.
+    fn read_config_struct<T: FromBytes>(&self) -> Result<T> {
+        self.read_consistent(|| self.read_config_space(0))
+    }
+
+    /// Returns the location of the device's shared memory region with the given ID, or `None` if
+    /// the device has no such region or the transport doesn't support shared memory regions.
+    ///
+    /// Ref: virtio 2.10 Shared Memory Regions
+    fn get_shared_memory_region(&self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
     }
 }
 
+/// A shared memory region of a device, which the device and driver can both access without copying
+/// data through a virtqueue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The length of the region in bytes.
+    pub length: u64,
+}
+
 bitflags! {
     /// The device status field. Writing 0 into this field resets the device.
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -160,6 +262,17 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The interrupts which were pending when acknowledged by [`Transport::ack_interrupt`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct InterruptStatus: u32 {
+        /// The device has used a buffer in one of the virtqueues.
+        const USED_BUFFER = 1 << 0;
+        /// The device configuration space has changed.
+        const CONFIG_CHANGE = 1 << 1;
+    }
+}
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -233,3 +346,53 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::fake::{FakeTransport, State};
+    use alloc::{sync::Arc, vec::Vec};
+    use std::sync::Mutex;
+
+    bitflags! {
+        #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+        struct TestFeatures: u64 {
+            const A = 1 << 0;
+            const B = 1 << 1;
+            const C = 1 << 2;
+            const D = 1 << 3;
+        }
+    }
+
+    fn fake_transport(device_features: TestFeatures) -> FakeTransport<()> {
+        FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: device_features.bits(),
+            state: Arc::new(Mutex::new(State::new(Vec::new(), ()))),
+        }
+    }
+
+    #[test]
+    fn negotiated_features() {
+        let mut transport = fake_transport(TestFeatures::A | TestFeatures::B | TestFeatures::C);
+        assert_eq!(transport.negotiated_features(), 0);
+
+        // Only the features both offered by the device and supported by the driver are negotiated.
+        let negotiated = transport.begin_init(TestFeatures::B | TestFeatures::C | TestFeatures::D);
+        assert_eq!(negotiated, TestFeatures::B | TestFeatures::C);
+        assert_eq!(transport.negotiated_features(), negotiated.bits());
+    }
+
+    #[test]
+    fn device_supports_feature() {
+        let mut transport = fake_transport(TestFeatures::A | TestFeatures::C);
+        assert!(transport.device_supports_feature(0));
+        assert!(!transport.device_supports_feature(1));
+        assert!(transport.device_supports_feature(2));
+        assert!(!transport.device_supports_feature(64));
+
+        // Checking shouldn't start initialising the device.
+        assert_eq!(transport.get_status(), DeviceStatus::empty());
+    }
+}
@@ -3,9 +3,10 @@
 pub mod bus;
 
 use self::bus::{
-    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR,
+    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_MSIX,
+    PCI_CAP_ID_VNDR,
 };
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
@@ -18,6 +19,7 @@ use core::{
     mem::{align_of, size_of},
     ptr::NonNull,
 };
+use log::warn;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// The PCI vendor ID for VirtIO devices.
@@ -42,6 +44,13 @@ pub(crate) const CAP_BAR_OFFSET_OFFSET: u8 = 8;
 pub(crate) const CAP_LENGTH_OFFSET: u8 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
 pub(crate) const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HI_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HI_OFFSET: u8 = 20;
+
+/// The maximum number of shared memory regions which a `PciTransport` keeps track of.
+const MAX_SHARED_MEMORY_REGIONS: usize = 4;
 
 /// Common configuration.
 pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -51,6 +60,20 @@ pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// Shared memory region.
+pub const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+
+/// The value of `msix_config` or `queue_msix_vector` which means that no MSI-X vector is used.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// The offset of the table offset and BIR field within the MSI-X capability.
+const MSIX_TABLE_OFFSET: u8 = 4;
+/// The mask of the table size field within the MSI-X message control register.
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x07ff;
+/// The bit of the MSI-X message control register which masks all vectors of the function.
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+/// The bit of the MSI-X message control register which enables MSI-X.
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
 
 pub(crate) fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -95,6 +118,14 @@ pub struct PciTransport {
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<NonNull<[u32]>>,
+    /// Device features which should never be negotiated on this transport.
+    disabled_features: u64,
+    /// The driver features most recently written to the device.
+    negotiated_features: u64,
+    /// The MSI-X vectors to use for the device, if MSI-X has been enabled.
+    msix_routing: Option<MsixRouting>,
+    /// The IDs and locations of the device's shared memory regions.
+    shared_memory_regions: [Option<(u8, SharedMemoryRegion)>; MAX_SHARED_MEMORY_REGIONS],
 }
 
 impl PciTransport {
@@ -120,6 +151,8 @@ impl PciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shared_memory_cfgs = [None; MAX_SHARED_MEMORY_REGIONS];
+        let mut shared_memory_cfg_count = 0;
         for capability in root.capabilities(device_function) {
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
@@ -159,6 +192,31 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG if cap_len >= 24 => {
+                    let id = (root
+                        .configuration_access
+                        .read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset =
+                        u64::from(struct_info.offset)
+                            | u64::from(root.configuration_access.read_word(
+                                device_function,
+                                capability.offset + CAP_OFFSET_HI_OFFSET,
+                            )) << 32;
+                    let length =
+                        u64::from(struct_info.length)
+                            | u64::from(root.configuration_access.read_word(
+                                device_function,
+                                capability.offset + CAP_LENGTH_HI_OFFSET,
+                            )) << 32;
+                    if shared_memory_cfg_count == MAX_SHARED_MEMORY_REGIONS {
+                        warn!("Ignoring shared memory region {}, too many regions", id);
+                        continue;
+                    }
+                    shared_memory_cfgs[shared_memory_cfg_count] =
+                        Some((id, struct_info.bar, offset, length));
+                    shared_memory_cfg_count += 1;
+                }
                 _ => {}
             }
         }
@@ -193,6 +251,24 @@ impl PciTransport {
             None
         };
 
+        let mut shared_memory_regions = [None; MAX_SHARED_MEMORY_REGIONS];
+        for (region, (id, bar, offset, length)) in shared_memory_regions
+            .iter_mut()
+            .zip(shared_memory_cfgs.into_iter().flatten())
+        {
+            let (bar_address, _) = root
+                .bar_info(device_function, bar)?
+                .memory_address_size()
+                .ok_or(VirtioPciError::UnexpectedIoBar)?;
+            *region = Some((
+                id,
+                SharedMemoryRegion {
+                    paddr: (bar_address + offset) as PhysAddr,
+                    length,
+                },
+            ));
+        }
+
         Ok(Self {
             device_type,
             device_function,
@@ -201,8 +277,106 @@ impl PciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            disabled_features: 0,
+            negotiated_features: 0,
+            msix_routing: None,
+            shared_memory_regions,
         })
     }
+
+    /// Programs the MSI-X table of the device with the given messages, enables MSI-X, and routes
+    /// configuration change and used buffer interrupts to the given vectors.
+    ///
+    /// This must be called before the driver is constructed, as the queue vectors are set as the
+    /// queues are set up, and the configuration change vector once features have been negotiated.
+    /// Once MSI-X is enabled the ISR status register is no longer used, so
+    /// [`ack_interrupt`](Transport::ack_interrupt) always reports all interrupts as pending and the
+    /// vector which fired should be used to tell which event happened.
+    ///
+    /// The device function must have memory space access enabled so that the MSI-X table can be
+    /// written.
+    pub fn enable_msix<H: Hal, C: ConfigurationAccess>(
+        &mut self,
+        root: &mut PciRoot<C>,
+        vector_config: &MsixVectorConfig,
+    ) -> Result<(), VirtioPciError> {
+        let capability = root
+            .capabilities(self.device_function)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)
+            .ok_or(VirtioPciError::MissingMsixCapability)?;
+        let control = capability.private_header;
+        let table_size = (control & MSIX_CONTROL_TABLE_SIZE) + 1;
+        let vector_count = u16::try_from(vector_config.messages.len())
+            .map_err(|_| VirtioPciError::InvalidMsixVectors)?;
+        if vector_count == 0
+            || vector_count > table_size
+            || vector_config.first_queue_vector >= vector_count
+            || vector_config
+                .config_vector
+                .is_some_and(|vector| vector >= vector_count)
+        {
+            return Err(VirtioPciError::InvalidMsixVectors);
+        }
+
+        let table = root
+            .configuration_access
+            .read_word(self.device_function, capability.offset + MSIX_TABLE_OFFSET);
+        for (index, message) in vector_config.messages.iter().enumerate() {
+            let entry = get_bar_region::<H, MsixTableEntry, _>(
+                root,
+                self.device_function,
+                &VirtioCapabilityInfo {
+                    bar: (table & 0x7) as u8,
+                    offset: (table & !0x7) + (index * size_of::<MsixTableEntry>()) as u32,
+                    length: size_of::<MsixTableEntry>() as u32,
+                },
+            )?;
+            // SAFETY: `get_bar_region` checked that the entry is within the BAR and aligned.
+            unsafe {
+                volwrite!(entry, address_low, message.address as u32);
+                volwrite!(entry, address_high, (message.address >> 32) as u32);
+                volwrite!(entry, data, message.data);
+                volwrite!(entry, vector_control, 0);
+            }
+        }
+
+        let header = root
+            .configuration_access
+            .read_word(self.device_function, capability.offset);
+        let control = control & !MSIX_CONTROL_FUNCTION_MASK | MSIX_CONTROL_ENABLE;
+        root.configuration_access.write_word(
+            self.device_function,
+            capability.offset,
+            header & 0xffff | u32::from(control) << 16,
+        );
+
+        self.msix_routing = Some(MsixRouting {
+            config_vector: vector_config.config_vector.unwrap_or(NO_VECTOR),
+            first_queue_vector: vector_config.first_queue_vector,
+            last_vector: vector_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Returns the offset in bytes within the notify region at which to notify the given queue.
+    fn notify_offset(&mut self, queue: u16) -> usize {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        // TODO: Consider caching this somewhere (per queue).
+        let queue_notify_off = unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volread!(self.common_cfg, queue_notify_off)
+        };
+        usize::from(queue_notify_off) * self.notify_off_multiplier as usize
+    }
+
+    /// Sets the device features which should never be negotiated on this transport, whatever the
+    /// device offers and the driver supports.
+    ///
+    /// This must be called before the driver is constructed to have any effect.
+    pub fn set_disabled_features(&mut self, disabled_features: u64) {
+        self.disabled_features = disabled_features;
+    }
 }
 
 impl Transport for PciTransport {
@@ -235,6 +409,15 @@ impl Transport for PciTransport {
                 (driver_features >> 32) as u32
             );
         }
+        self.negotiated_features = driver_features;
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.negotiated_features
+    }
+
+    fn disabled_features(&self) -> u64 {
+        self.disabled_features
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -247,19 +430,29 @@ impl Transport for PciTransport {
     }
 
     fn notify(&mut self, queue: u16) {
-        // Safe because the common config and notify region pointers are valid and we checked in
-        // get_bar_region that they were aligned.
+        let index = self.notify_offset(queue) / size_of::<u16>();
+        // Safe because the notify region pointer is valid and we checked in get_bar_region that it
+        // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
-
-            let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
-            let index = offset_bytes / size_of::<u16>();
             (&raw mut (*self.notify_region.as_ptr())[index]).vwrite(queue);
         }
     }
 
+    fn notify_with_data(&mut self, data: u32) {
+        let offset = self.notify_offset(data as u16);
+        assert!(offset + size_of::<u32>() <= self.notify_region.len() * size_of::<u16>());
+        // SAFETY: The notify region pointer is valid, and we just checked that the write is within
+        // it. The notify offset multiplier is 0 or a power of 2 which is at least 4 when
+        // notification data is used, so the address is aligned.
+        unsafe {
+            self.notify_region
+                .as_ptr()
+                .cast::<u32>()
+                .byte_add(offset)
+                .write_volatile(data);
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
@@ -273,6 +466,17 @@ impl Transport for PciTransport {
         unsafe {
             volwrite!(self.common_cfg, device_status, status.bits() as u8);
         }
+        // The device resets the MSI-X vectors along with everything else, so route configuration
+        // changes again once features have been negotiated.
+        if let Some(msix_routing) = &self.msix_routing {
+            if status.contains(DeviceStatus::FEATURES_OK) {
+                // Safe because the common config pointer is valid and we checked in
+                // get_bar_region that it was aligned.
+                unsafe {
+                    volwrite!(self.common_cfg, msix_config, msix_routing.config_vector);
+                }
+            }
+        }
     }
 
     fn set_guest_page_size(&mut self, _guest_page_size: u32) {
@@ -299,6 +503,16 @@ impl Transport for PciTransport {
             volwrite!(self.common_cfg, queue_desc, descriptors as u64);
             volwrite!(self.common_cfg, queue_driver, driver_area as u64);
             volwrite!(self.common_cfg, queue_device, device_area as u64);
+            if let Some(msix_routing) = &self.msix_routing {
+                let vector = msix_routing.queue_vector(queue);
+                volwrite!(self.common_cfg, queue_msix_vector, vector);
+                if volread!(self.common_cfg, queue_msix_vector) != vector {
+                    warn!(
+                        "Device couldn't use MSI-X vector {} for queue {}",
+                        vector, queue
+                    );
+                }
+            }
             volwrite!(self.common_cfg, queue_enable, 1);
         }
     }
@@ -317,13 +531,24 @@ impl Transport for PciTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        if self.msix_routing.is_some() {
+            // The ISR status isn't used with MSI-X, so assume that anything may have happened.
+            return InterruptStatus::all();
+        }
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
         let isr_status = unsafe { self.isr_status.as_ptr().vread() };
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        InterruptStatus::from_bits_truncate(isr_status.into())
+    }
+
+    fn get_shared_memory_region(&self, id: u8) -> Option<SharedMemoryRegion> {
+        self.shared_memory_regions
+            .iter()
+            .flatten()
+            .find(|(region_id, _)| *region_id == id)
+            .map(|(_, region)| *region)
     }
 
     fn read_config_generation(&self) -> u32 {
@@ -413,6 +638,57 @@ pub(crate) struct CommonCfg {
     pub queue_device: Volatile<u64>,
 }
 
+/// An MSI-X message, which the device sends by writing `data` to `address`.
+///
+/// The values to use depend on the interrupt controller of the platform.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MsixMessage {
+    /// The address to which the message is written.
+    pub address: u64,
+    /// The data written.
+    pub data: u32,
+}
+
+/// The MSI-X vectors for [`PciTransport::enable_msix`] to set up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MsixVectorConfig<'a> {
+    /// The message for each vector, which are programmed into the MSI-X table from entry 0.
+    pub messages: &'a [MsixMessage],
+    /// The vector to use for configuration change interrupts, or `None` not to have them.
+    pub config_vector: Option<u16>,
+    /// The vector to use for used buffer interrupts from queue 0.
+    ///
+    /// Queue `n` uses vector `first_queue_vector + n`, or the last vector if there aren't that
+    /// many, so all queues can share a vector by making it the last one.
+    pub first_queue_vector: u16,
+}
+
+/// The MSI-X vectors used by a `PciTransport`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct MsixRouting {
+    config_vector: u16,
+    first_queue_vector: u16,
+    last_vector: u16,
+}
+
+impl MsixRouting {
+    /// Returns the vector to use for the given queue.
+    fn queue_vector(&self, queue: u16) -> u16 {
+        self.first_queue_vector
+            .saturating_add(queue)
+            .min(self.last_vector)
+    }
+}
+
+/// An entry in the MSI-X table.
+#[repr(C)]
+struct MsixTableEntry {
+    address_low: Volatile<u32>,
+    address_high: Volatile<u32>,
+    data: Volatile<u32>,
+    vector_control: Volatile<u32>,
+}
+
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct VirtioCapabilityInfo {
@@ -468,6 +744,7 @@ fn get_bar_region_slice<H: Hal, T, C: ConfigurationAccess>(
 
 /// An error encountered initialising a VirtIO PCI transport.
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[non_exhaustive]
 pub enum VirtioPciError {
     /// PCI device vender ID was not the VirtIO vendor ID.
     #[error("PCI device vender ID {0:#06x} was not the VirtIO vendor ID {VIRTIO_VENDOR_ID:#06x}.")]
@@ -502,6 +779,12 @@ pub enum VirtioPciError {
         /// The expected alignment in bytes.
         alignment: usize,
     },
+    /// No MSI-X capability was found.
+    #[error("No MSI-X capability was found.")]
+    MissingMsixCapability,
+    /// The MSI-X vectors requested don't fit the device's MSI-X table.
+    #[error("The MSI-X vectors requested don't fit the device's MSI-X table.")]
+    InvalidMsixVectors,
     /// A generic PCI error,
     #[error(transparent)]
     Pci(PciError),
@@ -522,17 +805,190 @@ unsafe impl Sync for VirtioPciError {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hal::fake::FakeHal;
+    use alloc::{boxed::Box, vec::Vec};
+    use bus::{fake::FakeCam, Command, Status};
+    use core::mem::offset_of;
+
+    const DEVICE_FUNCTION: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 1,
+        function: 0,
+    };
+
+    /// The offsets within the fake device's BAR 0 of its VirtIO structures and MSI-X table.
+    const COMMON_CFG_BAR_OFFSET: u32 = 0x000;
+    const NOTIFY_BAR_OFFSET: u32 = 0x100;
+    const ISR_BAR_OFFSET: u32 = 0x200;
+    const DEVICE_CFG_BAR_OFFSET: u32 = 0x300;
+    const MSIX_TABLE_BAR_OFFSET: u32 = 0x400;
+
+    /// Memory standing in for the 4 KiB BAR 0 of a fake VirtIO PCI device.
+    #[repr(C, align(4096))]
+    struct FakeBar([u32; 1024]);
+
+    impl FakeBar {
+        fn new() -> Box<Self> {
+            Box::new(Self([0; 1024]))
+        }
+
+        /// Returns the words at the given offset in bytes.
+        fn words(&self, offset: u32, count: usize) -> &[u32] {
+            &self.0[offset as usize / 4..][..count]
+        }
+
+        /// Returns the 16-bit field at the given offset in bytes within the common configuration
+        /// structure.
+        fn common_cfg_u16(&self, offset: usize) -> u16 {
+            let offset = COMMON_CFG_BAR_OFFSET as usize + offset;
+            (self.0[offset / 4] >> (offset % 4 * 8)) as u16
+        }
+    }
+
+    /// Returns the first word of a `virtio_pci_cap` with the given type and length.
+    fn vendor_capability_header(cfg_type: u8, cap_len: u8) -> u32 {
+        u32::from(PCI_CAP_ID_VNDR) | u32::from(cap_len) << 16 | u32::from(cfg_type) << 24
+    }
+
+    /// Returns a PCI root with a fake VirtIO block device whose BAR 0 is backed by `bar`.
+    ///
+    /// The device has common, notify, ISR and device configuration capabilities, followed by
+    /// `extra_capabilities`. Each capability is given as a list of words, and the pointers to the
+    /// next capability are filled in.
+    fn fake_pci_root(bar: &FakeBar, extra_capabilities: &[&[u32]]) -> PciRoot<FakeCam> {
+        let mut cam = FakeCam::default();
+        cam.set(
+            DEVICE_FUNCTION,
+            0,
+            0x1042 << 16 | u32::from(VIRTIO_VENDOR_ID),
+        );
+        cam.set(
+            DEVICE_FUNCTION,
+            0x04,
+            u32::from(Status::CAPABILITIES_LIST.bits()) << 16
+                | u32::from(Command::MEMORY_SPACE.bits()),
+        );
+
+        // BAR 0 is a 64-bit memory BAR at the address of `bar`, as the fake HAL maps physical
+        // addresses to the same virtual addresses.
+        let bar_address = bar as *const FakeBar as u64;
+        for (offset, value, mask) in [
+            (0x10, bar_address as u32 | 0x4, 0xffff_f000),
+            (0x14, (bar_address >> 32) as u32, 0xffff_ffff),
+        ] {
+            cam.set(DEVICE_FUNCTION, offset, value);
+            cam.writable_masks.insert(
+                (
+                    DEVICE_FUNCTION.bus,
+                    DEVICE_FUNCTION.device,
+                    DEVICE_FUNCTION.function,
+                    offset,
+                ),
+                mask,
+            );
+        }
+
+        let standard_capabilities: [&[u32]; 4] = [
+            &[
+                vendor_capability_header(VIRTIO_PCI_CAP_COMMON_CFG, 16),
+                0,
+                COMMON_CFG_BAR_OFFSET,
+                size_of::<CommonCfg>() as u32,
+            ],
+            &[
+                vendor_capability_header(VIRTIO_PCI_CAP_NOTIFY_CFG, 20),
+                0,
+                NOTIFY_BAR_OFFSET,
+                0x100,
+                2,
+            ],
+            &[
+                vendor_capability_header(VIRTIO_PCI_CAP_ISR_CFG, 16),
+                0,
+                ISR_BAR_OFFSET,
+                1,
+            ],
+            &[
+                vendor_capability_header(VIRTIO_PCI_CAP_DEVICE_CFG, 16),
+                0,
+                DEVICE_CFG_BAR_OFFSET,
+                0x100,
+            ],
+        ];
+        let capabilities = standard_capabilities
+            .iter()
+            .chain(extra_capabilities)
+            .collect::<Vec<_>>();
+        let mut offset = 0x40;
+        cam.set(DEVICE_FUNCTION, 0x34, u32::from(offset));
+        for (index, capability) in capabilities.iter().enumerate() {
+            let next = if index + 1 < capabilities.len() {
+                offset + 4 * capability.len() as u8
+            } else {
+                0
+            };
+            for (word_index, word) in capability.iter().enumerate() {
+                cam.set(DEVICE_FUNCTION, offset + 4 * word_index as u8, *word);
+            }
+            cam.set(
+                DEVICE_FUNCTION,
+                offset,
+                capability[0] | u32::from(next) << 8,
+            );
+            offset = next;
+        }
+        PciRoot::new(cam)
+    }
+
+    /// Returns the words of an MSI-X capability with the given table size and the table at
+    /// `MSIX_TABLE_BAR_OFFSET` in BAR 0.
+    fn msix_capability(table_size: u16) -> [u32; 3] {
+        [
+            u32::from(PCI_CAP_ID_MSIX)
+                | u32::from(MSIX_CONTROL_FUNCTION_MASK | (table_size - 1)) << 16,
+            MSIX_TABLE_BAR_OFFSET,
+            MSIX_TABLE_BAR_OFFSET + 0x100,
+        ]
+    }
+
+    /// Returns the message control word of the fake device's MSI-X capability.
+    fn msix_control(root: &PciRoot<FakeCam>) -> u16 {
+        root.capabilities(DEVICE_FUNCTION)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)
+            .unwrap()
+            .private_header
+    }
+
+    const MSIX_MESSAGES: [MsixMessage; 3] = [
+        MsixMessage {
+            address: 0x1_fee0_0000,
+            data: 0x40,
+        },
+        MsixMessage {
+            address: 0x1_fee0_1000,
+            data: 0x41,
+        },
+        MsixMessage {
+            address: 0xfee0_2000,
+            data: 0x42,
+        },
+    ];
 
     #[test]
     fn transitional_device_ids() {
         assert_eq!(device_type(0x1000), DeviceType::Network);
+        assert_eq!(device_type(0x1001), DeviceType::Block);
         assert_eq!(device_type(0x1002), DeviceType::MemoryBalloon);
+        assert_eq!(device_type(0x1003), DeviceType::Console);
+        assert_eq!(device_type(0x1004), DeviceType::ScsiHost);
+        assert_eq!(device_type(0x1005), DeviceType::EntropySource);
         assert_eq!(device_type(0x1009), DeviceType::_9P);
     }
 
     #[test]
     fn offset_device_ids() {
         assert_eq!(device_type(0x1040), DeviceType::Invalid);
+        assert_eq!(device_type(0x1042), DeviceType::Block);
         assert_eq!(device_type(0x1045), DeviceType::MemoryBalloon);
         assert_eq!(device_type(0x1049), DeviceType::_9P);
         assert_eq!(device_type(0x1058), DeviceType::Memory);
@@ -586,4 +1042,237 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn msix_queue_vectors() {
+        let per_queue = MsixRouting {
+            config_vector: 0,
+            first_queue_vector: 1,
+            last_vector: 3,
+        };
+        assert_eq!(per_queue.queue_vector(0), 1);
+        assert_eq!(per_queue.queue_vector(2), 3);
+        assert_eq!(per_queue.queue_vector(3), 3);
+
+        let shared = MsixRouting {
+            config_vector: NO_VECTOR,
+            first_queue_vector: 0,
+            last_vector: 0,
+        };
+        assert_eq!(shared.queue_vector(0), 0);
+        assert_eq!(shared.queue_vector(u16::MAX), 0);
+    }
+
+    #[test]
+    fn enable_msix() {
+        let bar = FakeBar::new();
+        let msix_capability = msix_capability(4);
+        let mut root = fake_pci_root(&bar, &[&msix_capability]);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        transport
+            .enable_msix::<FakeHal, _>(
+                &mut root,
+                &MsixVectorConfig {
+                    messages: &MSIX_MESSAGES,
+                    config_vector: Some(2),
+                    first_queue_vector: 0,
+                },
+            )
+            .unwrap();
+
+        // The table entries should be programmed and unmasked.
+        for (index, message) in MSIX_MESSAGES.iter().enumerate() {
+            assert_eq!(
+                bar.words(MSIX_TABLE_BAR_OFFSET + 16 * index as u32, 4),
+                [
+                    message.address as u32,
+                    (message.address >> 32) as u32,
+                    message.data,
+                    0
+                ]
+            );
+        }
+        // MSI-X should be enabled, with the function no longer masked.
+        let control = msix_control(&root);
+        assert_eq!(control, MSIX_CONTROL_ENABLE | 3);
+
+        // The configuration change vector is set once features have been negotiated.
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(bar.common_cfg_u16(offset_of!(CommonCfg, msix_config)), 0);
+        transport.set_status(
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
+        );
+        assert_eq!(bar.common_cfg_u16(offset_of!(CommonCfg, msix_config)), 2);
+
+        // Each queue gets its own vector until they run out, then they share the last.
+        for (queue, vector) in [(0, 0), (1, 1), (2, 2), (5, 2)] {
+            transport.queue_set(queue, 4, 0x1000, 0x2000, 0x3000);
+            assert_eq!(
+                bar.common_cfg_u16(offset_of!(CommonCfg, queue_msix_vector)),
+                vector
+            );
+        }
+    }
+
+    #[test]
+    fn enable_msix_no_config_vector() {
+        let bar = FakeBar::new();
+        let msix_capability = msix_capability(1);
+        let mut root = fake_pci_root(&bar, &[&msix_capability]);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        transport
+            .enable_msix::<FakeHal, _>(
+                &mut root,
+                &MsixVectorConfig {
+                    messages: &MSIX_MESSAGES[..1],
+                    config_vector: None,
+                    first_queue_vector: 0,
+                },
+            )
+            .unwrap();
+
+        // Configuration changes shouldn't be routed to any vector.
+        transport.set_status(
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
+        );
+        assert_eq!(
+            bar.common_cfg_u16(offset_of!(CommonCfg, msix_config)),
+            NO_VECTOR
+        );
+
+        transport.queue_set(0, 4, 0x1000, 0x2000, 0x3000);
+        assert_eq!(
+            bar.common_cfg_u16(offset_of!(CommonCfg, queue_msix_vector)),
+            0
+        );
+    }
+
+    #[test]
+    fn enable_msix_invalid_vectors() {
+        let bar = FakeBar::new();
+        let msix_capability = msix_capability(2);
+        let mut root = fake_pci_root(&bar, &[&msix_capability]);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        for (messages, config_vector, first_queue_vector) in [
+            // No messages.
+            (&MSIX_MESSAGES[..0], None, 0),
+            // More messages than the table has entries.
+            (&MSIX_MESSAGES[..], None, 0),
+            // Vectors past the end of the messages.
+            (&MSIX_MESSAGES[..2], None, 2),
+            (&MSIX_MESSAGES[..2], Some(2), 0),
+        ] {
+            assert_eq!(
+                transport.enable_msix::<FakeHal, _>(
+                    &mut root,
+                    &MsixVectorConfig {
+                        messages,
+                        config_vector,
+                        first_queue_vector,
+                    },
+                ),
+                Err(VirtioPciError::InvalidMsixVectors)
+            );
+        }
+
+        // Nothing should have been programmed or enabled.
+        assert_eq!(bar.words(MSIX_TABLE_BAR_OFFSET, 8), [0; 8]);
+        let control = msix_control(&root);
+        assert_eq!(control, MSIX_CONTROL_FUNCTION_MASK | 1);
+        assert_eq!(transport.msix_routing, None);
+    }
+
+    #[test]
+    fn enable_msix_missing_capability() {
+        let bar = FakeBar::new();
+        let mut root = fake_pci_root(&bar, &[]);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        assert_eq!(
+            transport.enable_msix::<FakeHal, _>(
+                &mut root,
+                &MsixVectorConfig {
+                    messages: &MSIX_MESSAGES,
+                    config_vector: Some(0),
+                    first_queue_vector: 1,
+                },
+            ),
+            Err(VirtioPciError::MissingMsixCapability)
+        );
+    }
+
+    /// Returns the words of a `virtio_pci_cap64` for a shared memory region with the given ID in
+    /// BAR 0.
+    fn shared_memory_capability(id: u8, offset: u64, length: u64) -> [u32; 6] {
+        [
+            vendor_capability_header(VIRTIO_PCI_CAP_SHARED_MEMORY_CFG, 24),
+            u32::from(id) << 8,
+            offset as u32,
+            length as u32,
+            (offset >> 32) as u32,
+            (length >> 32) as u32,
+        ]
+    }
+
+    #[test]
+    fn shared_memory_regions() {
+        let bar = FakeBar::new();
+        let bar_address = &*bar as *const FakeBar as PhysAddr;
+        let small = shared_memory_capability(0, 0x800, 0x400);
+        let large = shared_memory_capability(2, 0x1_0000_0000, 0x2_0000_1000);
+        // A capability too short to be a `virtio_pci_cap64` should be ignored.
+        let short = [
+            vendor_capability_header(VIRTIO_PCI_CAP_SHARED_MEMORY_CFG, 16),
+            3 << 8,
+            0xc00,
+            0x100,
+        ];
+        let mut root = fake_pci_root(&bar, &[&small, &large, &short]);
+        let transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        assert_eq!(
+            transport.get_shared_memory_region(0),
+            Some(SharedMemoryRegion {
+                paddr: bar_address + 0x800,
+                length: 0x400,
+            })
+        );
+        assert_eq!(
+            transport.get_shared_memory_region(2),
+            Some(SharedMemoryRegion {
+                paddr: bar_address + 0x1_0000_0000,
+                length: 0x2_0000_1000,
+            })
+        );
+        assert_eq!(transport.get_shared_memory_region(1), None);
+        assert_eq!(transport.get_shared_memory_region(3), None);
+    }
+
+    #[test]
+    fn too_many_shared_memory_regions() {
+        let bar = FakeBar::new();
+        let capabilities = (0..=MAX_SHARED_MEMORY_REGIONS as u8)
+            .map(|id| shared_memory_capability(id, 0x800, 0x100))
+            .collect::<Vec<_>>();
+        let mut root = fake_pci_root(
+            &bar,
+            &capabilities
+                .iter()
+                .map(|capability| capability.as_slice())
+                .collect::<Vec<_>>(),
+        );
+        let transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        // Regions past the maximum should be ignored rather than failing.
+        for id in 0..MAX_SHARED_MEMORY_REGIONS as u8 {
+            assert!(transport.get_shared_memory_region(id).is_some());
+        }
+        assert_eq!(
+            transport.get_shared_memory_region(MAX_SHARED_MEMORY_REGIONS as u8),
+            None
+        );
+    }
 }
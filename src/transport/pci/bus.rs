@@ -1,12 +1,15 @@
 //! Module for dealing with a PCI bus in general, without anything specific to VirtIO.
 
+#[cfg(test)]
+pub mod fake;
+
 use bitflags::bitflags;
 use core::{
     array,
     convert::TryFrom,
     fmt::{self, Display, Formatter},
 };
-use log::warn;
+use log::{debug, warn};
 use thiserror::Error;
 
 const INVALID_READ: u32 = 0xffffffff;
@@ -18,11 +21,26 @@ const MAX_FUNCTIONS: u8 = 8;
 
 /// The offset in bytes to the status and command fields within PCI configuration space.
 const STATUS_COMMAND_OFFSET: u8 = 0x04;
+/// The offset in bytes to the BIST, header type, latency timer and cache line size fields within
+/// PCI configuration space.
+const BIST_TYPE_LATENCY_CACHE_OFFSET: u8 = 0x0c;
+/// The bit of the BIST, header type, latency timer and cache line size word which indicates that a
+/// device has multiple functions.
+const MULTI_FUNCTION_BIT: u32 = 1 << 23;
 /// The offset in bytes to BAR0 within PCI configuration space.
 const BAR0_OFFSET: u8 = 0x10;
+/// The offset in bytes to the secondary latency timer and bus number fields within the
+/// configuration space of a PCI to PCI bridge.
+const BRIDGE_BUS_NUMBERS_OFFSET: u8 = 0x18;
+
+/// The maximum number of capabilities which fit in the 192 bytes of PCI configuration space after
+/// the standard header, used to stop iterating over a capability list which loops.
+const MAX_CAPABILITIES: u8 = 48;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for MSI-X PCI capabilities.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -88,6 +106,9 @@ pub enum PciError {
     /// The device reported an invalid BAR type.
     #[error("Invalid PCI BAR type")]
     InvalidBarType,
+    /// There wasn't enough space left in the memory range to allocate a BAR.
+    #[error("Not enough space to allocate PCI BAR")]
+    BarAllocationFailed,
 }
 
 /// The root complex of a PCI bus.
@@ -162,6 +183,25 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         }
     }
 
+    /// Enumerates PCI devices on bus 0 and on all buses behind PCI to PCI bridges, following the
+    /// secondary bus numbers programmed into the bridges.
+    ///
+    /// Bridges are returned as well as the devices behind them. Bridges whose secondary bus number
+    /// hasn't been programmed, i.e. is 0, are not followed, and each bus is only enumerated once.
+    pub fn enumerate_all(&self) -> TreeDeviceIterator<C> {
+        let mut seen_buses = [0; 8];
+        seen_buses[0] = 1;
+        TreeDeviceIterator {
+            // Safe because the TreeDeviceIterator only reads from the configuration space.
+            root: PciRoot {
+                configuration_access: unsafe { self.configuration_access.unsafe_clone() },
+            },
+            bus_iterator: self.enumerate_bus(0),
+            seen_buses,
+            pending_buses: [0; 8],
+        }
+    }
+
     /// Reads the status and command registers of the given device function.
     pub fn get_status_command(&self, device_function: DeviceFunction) -> (Status, Command) {
         let status_command = self
@@ -181,12 +221,104 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         );
     }
 
+    /// Enables memory space access and bus mastering for the given device function, leaving the
+    /// other bits of the command register unchanged.
+    ///
+    /// Devices can't access their memory BARs until memory space access is enabled, or perform
+    /// DMA until bus mastering is enabled.
+    pub fn enable_memory_and_bus_master(&mut self, device_function: DeviceFunction) {
+        let (_, command) = self.get_status_command(device_function);
+        self.set_command(
+            device_function,
+            command | Command::MEMORY_SPACE | Command::BUS_MASTER,
+        );
+    }
+
+    /// Reads the 32-bit word at the given offset in the configuration space of the given device
+    /// function.
+    ///
+    /// Panics if the offset is not word-aligned or the device function is invalid.
+    pub fn config_read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+        assert!(device_function.valid());
+        assert!(register_offset & 0x3 == 0);
+        self.configuration_access
+            .read_word(device_function, register_offset)
+    }
+
+    /// Writes the 32-bit word at the given offset in the configuration space of the given device
+    /// function.
+    ///
+    /// Panics if the offset is not word-aligned or the device function is invalid.
+    pub fn config_write_word(
+        &mut self,
+        device_function: DeviceFunction,
+        register_offset: u8,
+        value: u32,
+    ) {
+        assert!(device_function.valid());
+        assert!(register_offset & 0x3 == 0);
+        self.configuration_access
+            .write_word(device_function, register_offset, value);
+    }
+
+    /// Sets the cache line size register of the given device function, in units of 32-bit words.
+    ///
+    /// This should match the CPU's cache line size, e.g. 16 for 64 byte cache lines, so that bus
+    /// masters can use efficient burst transactions. Firmware may leave it as 0.
+    pub fn set_cache_line_size(&mut self, device_function: DeviceFunction, words: u8) {
+        self.write_latency_cache_byte(device_function, 0, words);
+    }
+
+    /// Sets the latency timer register of the given device function, in units of PCI bus clocks.
+    ///
+    /// This limits how long a bus master may keep the bus during a burst once another device
+    /// wants it. Typical values are 32 to 64 clocks. Firmware may leave it as 0, which limits bus
+    /// masters to very short bursts. PCI Express devices ignore it.
+    pub fn set_latency_timer(&mut self, device_function: DeviceFunction, clocks: u8) {
+        self.write_latency_cache_byte(device_function, 1, clocks);
+    }
+
+    /// Writes the given byte of the BIST, header type, latency timer and cache line size word,
+    /// leaving the others unchanged apart from BIST, which is written as 0 so as not to start a
+    /// self-test.
+    fn write_latency_cache_byte(&mut self, device_function: DeviceFunction, byte: u8, value: u8) {
+        let shift = 8 * u32::from(byte);
+        let word = self
+            .configuration_access
+            .read_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET);
+        let word = word & 0x00ff_ffff & !(0xff << shift) | u32::from(value) << shift;
+        self.configuration_access
+            .write_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET, word);
+    }
+
+    /// Returns the primary, secondary and subordinate bus numbers programmed into the given PCI to
+    /// PCI bridge, or `None` if the device function is not a PCI to PCI bridge.
+    pub fn bridge_bus_numbers(&self, device_function: DeviceFunction) -> Option<(u8, u8, u8)> {
+        let bist_type_latency_cache = self
+            .configuration_access
+            .read_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET);
+        if HeaderType::from((bist_type_latency_cache >> 16) as u8 & 0x7f)
+            != HeaderType::PciPciBridge
+        {
+            return None;
+        }
+        let bus_numbers = self
+            .configuration_access
+            .read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        Some((
+            bus_numbers as u8,
+            (bus_numbers >> 8) as u8,
+            (bus_numbers >> 16) as u8,
+        ))
+    }
+
     /// Gets an iterator over the capabilities of the given device function.
     pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator<C> {
         CapabilityIterator {
             configuration_access: &self.configuration_access,
             device_function,
             next_capability_offset: self.capabilities_offset(device_function),
+            remaining: MAX_CAPABILITIES,
         }
     }
 
@@ -283,6 +415,70 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         );
     }
 
+    /// Assigns addresses from the given range to all the memory BARs of the device functions on
+    /// bus 0, and writes them back to the BARs.
+    ///
+    /// This is needed in environments without firmware to program the BARs. Each BAR is aligned to
+    /// its size. I/O BARs and the BARs of bridges are left alone. The device functions' command
+    /// registers are not changed, so memory space access still needs to be enabled with
+    /// [`set_command`](Self::set_command) before the BARs can be used.
+    ///
+    /// Returns the part of the range left after the last allocated BAR, which may be used for
+    /// other buses, or [`PciError::BarAllocationFailed`] if the range is too small or a BAR
+    /// can't be placed within its required address width. All the BARs are checked before any are
+    /// written, so on failure they are left as they were.
+    pub fn allocate_bars(&mut self, mut region: MemoryRange) -> Result<MemoryRange, PciError> {
+        self.assign_bars(&mut region.clone(), false)?;
+        self.assign_bars(&mut region, true)?;
+        Ok(region)
+    }
+
+    /// Assigns addresses from the given range to all the memory BARs of the device functions on
+    /// bus 0, as for [`allocate_bars`](Self::allocate_bars), writing them to the BARs only if
+    /// `write` is true.
+    fn assign_bars(&mut self, region: &mut MemoryRange, write: bool) -> Result<(), PciError> {
+        for (device_function, info) in self.enumerate_bus(0) {
+            if info.header_type != HeaderType::Standard {
+                continue;
+            }
+            for (bar_index, bar) in self.bars(device_function)?.into_iter().enumerate() {
+                let Some(BarInfo::Memory {
+                    address_type, size, ..
+                }) = bar
+                else {
+                    continue;
+                };
+                if size == 0 {
+                    continue;
+                }
+                let address = region
+                    .allocate(size.into())
+                    .ok_or(PciError::BarAllocationFailed)?;
+                let limit = match address_type {
+                    MemoryBarType::Width32 => 1 << 32,
+                    MemoryBarType::Below1MiB => 1 << 20,
+                    MemoryBarType::Width64 => u64::MAX,
+                };
+                if address > limit - u64::from(size) {
+                    return Err(PciError::BarAllocationFailed);
+                }
+                if !write {
+                    continue;
+                }
+                debug!(
+                    "Allocated {:#x} bytes at {:#x} for BAR {} of {}",
+                    size, address, bar_index, device_function
+                );
+                if address_type == MemoryBarType::Width64 {
+                    self.set_bar_64(device_function, bar_index as u8, address);
+                } else {
+                    self.set_bar_32(device_function, bar_index as u8, address as u32);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Gets the capabilities 'pointer' for the device function, if any.
     fn capabilities_offset(&self, device_function: DeviceFunction) -> Option<u8> {
         let (status, _) = self.get_status_command(device_function);
@@ -444,6 +640,31 @@ impl Display for BarInfo {
     }
 }
 
+/// A range of bus addresses from which [`PciRoot::allocate_bars`] can assign memory BARs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryRange {
+    /// The first address in the range.
+    pub start: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+}
+
+impl MemoryRange {
+    /// Takes a region of the given power-of-two size, aligned to its size, from the start of the
+    /// range, and returns its address.
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let end = self.start.checked_add(self.size)?;
+        let address = self.start.checked_next_multiple_of(size)?;
+        let allocated_end = address.checked_add(size)?;
+        if allocated_end > end {
+            return None;
+        }
+        self.start = allocated_end;
+        self.size = end - allocated_end;
+        Some(address)
+    }
+}
+
 /// The location allowed for a memory BAR.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum MemoryBarType {
@@ -484,6 +705,8 @@ pub struct CapabilityIterator<'a, C: ConfigurationAccess> {
     configuration_access: &'a C,
     device_function: DeviceFunction,
     next_capability_offset: Option<u8>,
+    /// The number of capabilities which may still be returned before giving up.
+    remaining: u8,
 }
 
 impl<C: ConfigurationAccess> Iterator for CapabilityIterator<'_, C> {
@@ -491,6 +714,12 @@ impl<C: ConfigurationAccess> Iterator for CapabilityIterator<'_, C> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let offset = self.next_capability_offset?;
+        if self.remaining == 0 {
+            warn!("Too many capabilities, the list may contain a loop");
+            self.next_capability_offset = None;
+            return None;
+        }
+        self.remaining -= 1;
 
         // Read the first 4 bytes of the capability.
         let capability_header = self
@@ -561,8 +790,17 @@ impl<C: ConfigurationAccess> Iterator for BusDeviceIterator<C> {
                 let subclass = (class_revision >> 16) as u8;
                 let prog_if = (class_revision >> 8) as u8;
                 let revision = class_revision as u8;
-                let bist_type_latency_cache = self.configuration_access.read_word(current, 12);
+                let bist_type_latency_cache = self
+                    .configuration_access
+                    .read_word(current, BIST_TYPE_LATENCY_CACHE_OFFSET);
                 let header_type = HeaderType::from((bist_type_latency_cache >> 16) as u8 & 0x7f);
+                // If function 0 of a device doesn't have the multi-function bit set then the
+                // device only has one function, so skip the rest. Some implementations alias
+                // function 0 across all function numbers.
+                if current.function == 0 && bist_type_latency_cache & MULTI_FUNCTION_BIT == 0 {
+                    self.next.function = 0;
+                    self.next.device = current.device + 1;
+                }
                 return Some((
                     current,
                     DeviceFunctionInfo {
@@ -581,6 +819,48 @@ impl<C: ConfigurationAccess> Iterator for BusDeviceIterator<C> {
     }
 }
 
+/// An iterator which enumerates PCI devices and functions on all buses reachable from bus 0,
+/// created by [`PciRoot::enumerate_all`].
+#[derive(Debug)]
+pub struct TreeDeviceIterator<C: ConfigurationAccess> {
+    /// This must only be used to read from the configuration space, and must not be exposed
+    /// outside this module, because it uses the same CAM as the main `PciRoot` instance.
+    root: PciRoot<C>,
+    bus_iterator: BusDeviceIterator<C>,
+    /// A bitmap of the buses which have been enumerated or are waiting to be enumerated.
+    seen_buses: [u32; 8],
+    /// A bitmap of the buses which have been found behind bridges but not yet enumerated.
+    pending_buses: [u32; 8],
+}
+
+impl<C: ConfigurationAccess> Iterator for TreeDeviceIterator<C> {
+    type Item = (DeviceFunction, DeviceFunctionInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((device_function, info)) = self.bus_iterator.next() {
+                if let Some((_, secondary_bus, _)) = self.root.bridge_bus_numbers(device_function) {
+                    let (word, bit) = (usize::from(secondary_bus / 32), secondary_bus % 32);
+                    if secondary_bus != 0 && self.seen_buses[word] & (1 << bit) == 0 {
+                        self.seen_buses[word] |= 1 << bit;
+                        self.pending_buses[word] |= 1 << bit;
+                    }
+                }
+                return Some((device_function, info));
+            }
+
+            let word = self.pending_buses.iter().position(|&bits| bits != 0)?;
+            let bit = self.pending_buses[word].trailing_zeros();
+            self.pending_buses[word] &= !(1 << bit);
+            self.bus_iterator.next = DeviceFunction {
+                bus: (word * 32) as u8 + bit as u8,
+                device: 0,
+                function: 0,
+            };
+        }
+    }
+}
+
 /// An identifier for a PCI bus, device and function.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct DeviceFunction {
@@ -663,3 +943,318 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{fake::FakeCam, *};
+    use alloc::{vec, vec::Vec};
+
+    #[test]
+    fn bridge_bus_numbers() {
+        let bridge = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let standard = DeviceFunction {
+            bus: 0,
+            device: 2,
+            function: 0,
+        };
+        let mut cam = FakeCam::default();
+        cam.set(bridge, BIST_TYPE_LATENCY_CACHE_OFFSET, 0x0001_0000);
+        cam.set(bridge, BRIDGE_BUS_NUMBERS_OFFSET, 0x0005_0100);
+        cam.set(standard, BIST_TYPE_LATENCY_CACHE_OFFSET, 0x0000_0000);
+        let root = PciRoot::new(cam);
+
+        assert_eq!(root.bridge_bus_numbers(bridge), Some((0, 1, 5)));
+        assert_eq!(root.bridge_bus_numbers(standard), None);
+    }
+
+    #[test]
+    fn latency_timer_cache_line_size() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut cam = FakeCam::default();
+        cam.set(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET, 0x8080_0000);
+        let mut root = PciRoot::new(cam);
+
+        root.set_cache_line_size(device_function, 16);
+        root.set_latency_timer(device_function, 64);
+        assert_eq!(
+            root.configuration_access
+                .read_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET),
+            0x0080_4010
+        );
+    }
+
+    #[test]
+    fn enumerate_skips_single_function_aliases() {
+        let mut cam = FakeCam::default();
+        // Device 0 is single-function, but aliases function 0 across all function numbers.
+        for function in 0..MAX_FUNCTIONS {
+            let device_function = DeviceFunction {
+                bus: 0,
+                device: 0,
+                function,
+            };
+            cam.set(device_function, 0, 0x1234_5678);
+            cam.set(device_function, 8, 0);
+            cam.set(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET, 0);
+        }
+        // Device 1 is multi-function, with functions 0 and 2.
+        for function in [0, 2] {
+            let device_function = DeviceFunction {
+                bus: 0,
+                device: 1,
+                function,
+            };
+            cam.set(device_function, 0, 0x1234_5678);
+            cam.set(device_function, 8, 0);
+            cam.set(
+                device_function,
+                BIST_TYPE_LATENCY_CACHE_OFFSET,
+                MULTI_FUNCTION_BIT,
+            );
+        }
+        let root = PciRoot::new(cam);
+
+        let device_functions: Vec<_> = root
+            .enumerate_bus(0)
+            .map(|(device_function, _)| (device_function.device, device_function.function))
+            .collect();
+        assert_eq!(device_functions, vec![(0, 0), (1, 0), (1, 2)]);
+    }
+
+    /// Sets up a standard device function with 32-bit and 64-bit memory BARs and an I/O BAR.
+    fn fake_cam_with_bars(device_function: DeviceFunction) -> FakeCam {
+        let mut cam = FakeCam::default();
+        cam.set(device_function, 0, 0x1041_1af4);
+        cam.set(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET, 0);
+        // BAR0: 32-bit memory, 4 KiB.
+        // BAR1 and BAR2: 64-bit memory, 16 KiB.
+        // BAR3: I/O, 256 bytes.
+        // BAR4 and BAR5: unused.
+        let bars = [
+            (0x0, 0xffff_f000),
+            (0x4, 0xffff_c000),
+            (0x0, 0xffff_ffff),
+            (0x1, 0xffff_ff00),
+            (0x0, 0),
+            (0x0, 0),
+        ];
+        for (bar_index, (flags, mask)) in bars.into_iter().enumerate() {
+            let offset = BAR0_OFFSET + 4 * bar_index as u8;
+            cam.set(device_function, offset, flags);
+            cam.writable_masks.insert(
+                (
+                    device_function.bus,
+                    device_function.device,
+                    device_function.function,
+                    offset,
+                ),
+                mask,
+            );
+        }
+        cam
+    }
+
+    #[test]
+    fn allocate_bars() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut root = PciRoot::new(fake_cam_with_bars(device_function));
+
+        let remaining = root
+            .allocate_bars(MemoryRange {
+                start: 0x1000_0800,
+                size: 0x10000,
+            })
+            .unwrap();
+
+        assert_eq!(
+            remaining,
+            MemoryRange {
+                start: 0x1000_8000,
+                size: 0x8800,
+            }
+        );
+        assert_eq!(
+            root.bar_info(device_function, 0).unwrap(),
+            BarInfo::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0x1000_1000,
+                size: 0x1000,
+            }
+        );
+        assert_eq!(
+            root.bar_info(device_function, 1).unwrap(),
+            BarInfo::Memory {
+                address_type: MemoryBarType::Width64,
+                prefetchable: false,
+                address: 0x1000_4000,
+                size: 0x4000,
+            }
+        );
+        assert_eq!(
+            root.bar_info(device_function, 3).unwrap(),
+            BarInfo::IO {
+                address: 0,
+                size: 0x100,
+            }
+        );
+    }
+
+    #[test]
+    fn allocate_bars_too_small() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut root = PciRoot::new(fake_cam_with_bars(device_function));
+
+        assert_eq!(
+            root.allocate_bars(MemoryRange {
+                start: 0x1000_0000,
+                size: 0x4000,
+            }),
+            Err(PciError::BarAllocationFailed)
+        );
+        assert_eq!(
+            root.allocate_bars(MemoryRange {
+                start: 0x1_0000_0000,
+                size: 0x10000,
+            }),
+            Err(PciError::BarAllocationFailed)
+        );
+
+        // The first BAR would have fitted both times, but shouldn't have been written.
+        assert_eq!(
+            root.bar_info(device_function, 0).unwrap(),
+            BarInfo::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0,
+                size: 0x1000,
+            }
+        );
+    }
+
+    #[test]
+    fn config_read_write_word() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut root = PciRoot::new(FakeCam::default());
+        root.config_write_word(device_function, 0x40, 0x1234_5678);
+        assert_eq!(root.config_read_word(device_function, 0x40), 0x1234_5678);
+
+        root.config_write_word(device_function, STATUS_COMMAND_OFFSET, 0x0010_0001);
+        root.enable_memory_and_bus_master(device_function);
+        assert_eq!(
+            root.get_status_command(device_function).1,
+            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn config_write_word_unaligned() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut root = PciRoot::new(FakeCam::default());
+        root.config_write_word(device_function, 0x42, 0);
+    }
+
+    #[test]
+    fn capabilities() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let mut cam = FakeCam::default();
+        cam.set(
+            device_function,
+            STATUS_COMMAND_OFFSET,
+            u32::from(Status::CAPABILITIES_LIST.bits()) << 16,
+        );
+        cam.set(device_function, 0x34, 0x40);
+        cam.set(device_function, 0x40, 0x1234_5009);
+        cam.set(device_function, 0x50, 0x0000_0011);
+        let mut root = PciRoot::new(cam);
+
+        assert_eq!(
+            root.capabilities(device_function).collect::<Vec<_>>(),
+            vec![
+                CapabilityInfo {
+                    offset: 0x40,
+                    id: PCI_CAP_ID_VNDR,
+                    private_header: 0x1234,
+                },
+                CapabilityInfo {
+                    offset: 0x50,
+                    id: 0x11,
+                    private_header: 0,
+                },
+            ]
+        );
+
+        // A list which points back to itself should still end.
+        root.config_write_word(device_function, 0x50, 0x0000_4011);
+        assert_eq!(
+            root.capabilities(device_function).count(),
+            usize::from(MAX_CAPABILITIES)
+        );
+    }
+
+    #[test]
+    fn enumerate_all() {
+        let bridge = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let looping_bridge = DeviceFunction {
+            bus: 2,
+            device: 0,
+            function: 0,
+        };
+        let standard = DeviceFunction {
+            bus: 2,
+            device: 3,
+            function: 0,
+        };
+        let mut cam = FakeCam::default();
+        for device_function in [bridge, looping_bridge, standard] {
+            cam.set(device_function, 0, 0x1041_1af4);
+            cam.set(device_function, 8, 0);
+        }
+        cam.set(bridge, BIST_TYPE_LATENCY_CACHE_OFFSET, 0x0001_0000);
+        cam.set(bridge, BRIDGE_BUS_NUMBERS_OFFSET, 0x0002_0200);
+        cam.set(looping_bridge, BIST_TYPE_LATENCY_CACHE_OFFSET, 0x0001_0000);
+        cam.set(looping_bridge, BRIDGE_BUS_NUMBERS_OFFSET, 0x0002_0202);
+        cam.set(standard, BIST_TYPE_LATENCY_CACHE_OFFSET, 0);
+        let root = PciRoot::new(cam);
+
+        assert_eq!(
+            root.enumerate_all()
+                .map(|(device_function, _)| device_function)
+                .collect::<Vec<_>>(),
+            vec![bridge, looping_bridge, standard]
+        );
+    }
+}
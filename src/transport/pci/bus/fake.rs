@@ -0,0 +1,65 @@
+//! A fake PCI configuration space for unit tests.
+
+use super::{ConfigurationAccess, DeviceFunction, INVALID_READ};
+use std::collections::HashMap;
+
+/// A fake PCI configuration space, with a map from device function and register offset to the
+/// value of the word at that offset. Unset words read as `INVALID_READ`.
+///
+/// Words with an entry in `writable_masks` only have the bits in the mask changed by writes.
+#[derive(Debug, Default)]
+pub struct FakeCam {
+    words: HashMap<(u8, u8, u8, u8), u32>,
+    /// Masks of the writable bits of words, by device function and register offset.
+    pub writable_masks: HashMap<(u8, u8, u8, u8), u32>,
+}
+
+impl FakeCam {
+    /// Sets the word at the given register offset, ignoring any writable mask.
+    pub fn set(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+        self.words.insert(
+            (
+                device_function.bus,
+                device_function.device,
+                device_function.function,
+                register_offset,
+            ),
+            data,
+        );
+    }
+}
+
+impl ConfigurationAccess for FakeCam {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+        self.words
+            .get(&(
+                device_function.bus,
+                device_function.device,
+                device_function.function,
+                register_offset,
+            ))
+            .copied()
+            .unwrap_or(INVALID_READ)
+    }
+
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+        let key = (
+            device_function.bus,
+            device_function.device,
+            device_function.function,
+            register_offset,
+        );
+        let data = match self.writable_masks.get(&key) {
+            Some(mask) => self.read_word(device_function, register_offset) & !mask | data & mask,
+            None => data,
+        };
+        self.set(device_function, register_offset, data);
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self {
+            words: self.words.clone(),
+            writable_masks: self.writable_masks.clone(),
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
-use super::{mmio::MmioTransport, pci::PciTransport, DeviceStatus, DeviceType, Transport};
+use super::{
+    mmio::MmioTransport, pci::PciTransport, DeviceStatus, DeviceType, InterruptStatus,
+    SharedMemoryRegion, Transport,
+};
 use crate::{PhysAddr, Result};
 
 /// A wrapper for an arbitrary VirtIO transport, either MMIO or PCI.
@@ -55,6 +58,24 @@ impl Transport for SomeTransport {
         }
     }
 
+    fn negotiated_features(&self) -> u64 {
+        match self {
+            Self::Mmio(mmio) => mmio.negotiated_features(),
+            Self::Pci(pci) => pci.negotiated_features(),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.negotiated_features(),
+        }
+    }
+
+    fn disabled_features(&self) -> u64 {
+        match self {
+            Self::Mmio(mmio) => mmio.disabled_features(),
+            Self::Pci(pci) => pci.disabled_features(),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.disabled_features(),
+        }
+    }
+
     fn max_queue_size(&mut self, queue: u16) -> u32 {
         match self {
             Self::Mmio(mmio) => mmio.max_queue_size(queue),
@@ -73,6 +94,15 @@ impl Transport for SomeTransport {
         }
     }
 
+    fn notify_with_data(&mut self, data: u32) {
+        match self {
+            Self::Mmio(mmio) => mmio.notify_with_data(data),
+            Self::Pci(pci) => pci.notify_with_data(data),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.notify_with_data(data),
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         match self {
             Self::Mmio(mmio) => mmio.get_status(),
@@ -143,7 +173,7 @@ impl Transport for SomeTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt(&mut self) -> InterruptStatus {
         match self {
             Self::Mmio(mmio) => mmio.ack_interrupt(),
             Self::Pci(pci) => pci.ack_interrupt(),
@@ -152,6 +182,15 @@ impl Transport for SomeTransport {
         }
     }
 
+    fn get_shared_memory_region(&self, id: u8) -> Option<SharedMemoryRegion> {
+        match self {
+            Self::Mmio(mmio) => mmio.get_shared_memory_region(id),
+            Self::Pci(pci) => pci.get_shared_memory_region(id),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.get_shared_memory_region(id),
+        }
+    }
+
     fn read_config_generation(&self) -> u32 {
         match self {
             Self::Mmio(mmio) => mmio.read_config_generation(),
@@ -11,7 +11,7 @@ use super::{
         VIRTIO_PCI_CAP_COMMON_CFG, VIRTIO_PCI_CAP_DEVICE_CFG, VIRTIO_PCI_CAP_ISR_CFG,
         VIRTIO_PCI_CAP_NOTIFY_CFG, VIRTIO_VENDOR_ID,
     },
-    DeviceStatus, DeviceType, Transport,
+    DeviceStatus, DeviceType, InterruptStatus, Transport,
 };
 use crate::{hal::PhysAddr, Error};
 pub use cam::HypCam;
@@ -45,6 +45,10 @@ pub struct HypPciTransport {
     isr_status: HypIoRegion,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<HypIoRegion>,
+    /// Device features which should never be negotiated on this transport.
+    disabled_features: u64,
+    /// The driver features most recently written to the device.
+    negotiated_features: u64,
 }
 
 impl HypPciTransport {
@@ -149,8 +153,18 @@ impl HypPciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            disabled_features: 0,
+            negotiated_features: 0,
         })
     }
+
+    /// Sets the device features which should never be negotiated on this transport, whatever the
+    /// device offers and the driver supports.
+    ///
+    /// This must be called before the driver is constructed to have any effect.
+    pub fn set_disabled_features(&mut self, disabled_features: u64) {
+        self.disabled_features = disabled_features;
+    }
 }
 
 impl Transport for HypPciTransport {
@@ -175,6 +189,15 @@ impl Transport for HypPciTransport {
             driver_feature,
             (driver_features >> 32) as u32
         );
+        self.negotiated_features = driver_features;
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.negotiated_features
+    }
+
+    fn disabled_features(&self) -> u64 {
+        self.disabled_features
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -236,13 +259,12 @@ impl Transport for HypPciTransport {
         queue_enable == 1
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt(&mut self) -> InterruptStatus {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
         let isr_status: u8 = self.isr_status.read(0);
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        InterruptStatus::from_bits_truncate(isr_status.into())
     }
 
     fn read_config_generation(&self) -> u32 {